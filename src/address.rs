@@ -0,0 +1,157 @@
+use async_std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, UdpSocket};
+use core::fmt;
+use std::io::Error;
+#[cfg(unix)]
+use std::path::PathBuf;
+
+use socket2::{Domain, Socket, Type};
+
+// Where the server listens for public traffic
+#[derive(Clone, Debug, PartialEq)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    Udp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf)
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddress::Tcp(socket_addr) => write!(f, "{}", socket_addr),
+            ListenAddress::Udp(socket_addr) => write!(f, "udp:{}", socket_addr),
+            #[cfg(unix)]
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display())
+        }
+    }
+}
+
+// Parses a comma-separated list of listen addresses
+pub fn parse_listen_addresses(listen_str: &str) -> Result<Vec<ListenAddress>, Error> {
+    listen_str.split(',').map(|s| parse_listen_address(s.trim())).collect()
+}
+
+// Parses "[bind address]", "udp:[bind address]" or "unix:[path]"
+pub fn parse_listen_address(listen_str: &str) -> Result<ListenAddress, Error> {
+    #[cfg(unix)]
+    {
+        if let Some(path) = listen_str.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(Error::other("A path is required for unix:"));
+            }
+
+            return Ok(ListenAddress::Unix(PathBuf::from(path)));
+        }
+    }
+
+    match listen_str.strip_prefix("udp:") {
+        Some(bind_str) => Ok(ListenAddress::Udp(parse_bind_address(bind_str)?)),
+        None => Ok(ListenAddress::Tcp(parse_bind_address(listen_str.strip_prefix("tcp:").unwrap_or(listen_str))?))
+    }
+}
+
+// Parses a comma-separated list of bind addresses
+pub fn parse_bind_addresses(bind_str: &str) -> Result<Vec<SocketAddr>, Error> {
+    bind_str.split(',').map(|s| parse_bind_address(s.trim())).collect()
+}
+
+// Parses "[port]", which binds all IPv4 interfaces, or a full address such as "127.0.0.1:[port]" or "[::]:[port]"
+pub fn parse_bind_address(bind_str: &str) -> Result<SocketAddr, Error> {
+    if let Ok(port) = bind_str.parse::<u16>() {
+        return Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port));
+    }
+
+    match bind_str.parse::<SocketAddr>() {
+        Ok(socket_addr) => Ok(socket_addr),
+        Err(err) => Err(Error::other(format!("Invalid bind address \"{}\": {}", bind_str, err)))
+    }
+}
+
+pub fn bind_tcp(socket_addr: SocketAddr) -> Result<TcpListener, Error> {
+    let socket = bind_socket(socket_addr, Type::STREAM)?;
+    socket.listen(128)?;
+
+    let listener: std::net::TcpListener = socket.into();
+    Ok(listener.into())
+}
+
+pub fn bind_udp(socket_addr: SocketAddr) -> Result<UdpSocket, Error> {
+    let socket: std::net::UdpSocket = bind_socket(socket_addr, Type::DGRAM)?.into();
+    Ok(socket.into())
+}
+
+// "[::]" always accepts IPv4 as well, regardless of the operating system's default
+fn bind_socket(socket_addr: SocketAddr, socket_type: Type) -> Result<Socket, Error> {
+    let socket = Socket::new(Domain::for_address(socket_addr), socket_type, None)?;
+
+    if socket_addr.is_ipv6() && socket_addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+
+    #[cfg(unix)]
+    {
+        if socket_type == Type::STREAM {
+            socket.set_reuse_address(true)?;
+        }
+    }
+
+    socket.set_nonblocking(true)?;
+    socket.bind(&socket_addr.into())?;
+
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::net::{Ipv6Addr, TcpStream};
+
+    use super::*;
+
+    #[test]
+    fn parse_listen_address_test() {
+        assert_eq!(ListenAddress::Tcp("0.0.0.0:80".parse().unwrap()), parse_listen_address("80").unwrap());
+        assert_eq!(ListenAddress::Tcp("0.0.0.0:80".parse().unwrap()), parse_listen_address("tcp:80").unwrap());
+        assert_eq!(ListenAddress::Tcp("[::]:80".parse().unwrap()), parse_listen_address("[::]:80").unwrap());
+        assert_eq!(ListenAddress::Udp("0.0.0.0:53".parse().unwrap()), parse_listen_address("udp:53").unwrap());
+        assert_eq!(ListenAddress::Udp("[::1]:53".parse().unwrap()), parse_listen_address("udp:[::1]:53").unwrap());
+        #[cfg(unix)]
+        assert_eq!(ListenAddress::Unix(PathBuf::from("/tmp/app.sock")), parse_listen_address("unix:/tmp/app.sock").unwrap());
+
+        parse_listen_address("udp:").expect_err("Port is required");
+        parse_listen_address("unix:").expect_err("Path is required");
+        parse_listen_address("sctp:80").expect_err("Unknown protocol");
+        parse_listen_address("localhost:80").expect_err("Host names are not bind addresses");
+    }
+
+    #[test]
+    fn parse_multiple_addresses() {
+        let listen_addresses = parse_listen_addresses("127.0.0.1:80, [::1]:80,udp:53").unwrap();
+        assert_eq!(vec![
+            ListenAddress::Tcp("127.0.0.1:80".parse().unwrap()),
+            ListenAddress::Tcp("[::1]:80".parse().unwrap()),
+            ListenAddress::Udp("0.0.0.0:53".parse().unwrap())
+        ], listen_addresses);
+
+        let bind_addresses = parse_bind_addresses("9000,[::]:9001").unwrap();
+        assert_eq!(vec![
+            "0.0.0.0:9000".parse::<SocketAddr>().unwrap(),
+            "[::]:9001".parse::<SocketAddr>().unwrap()
+        ], bind_addresses);
+
+        parse_bind_addresses("9000,").expect_err("Empty address");
+    }
+
+    #[async_std::test]
+    async fn dual_stack() {
+        let listener = match bind_tcp(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0)) {
+            Ok(listener) => listener,
+            // IPv6 isn't available everywhere that tests run
+            Err(_) => return
+        };
+
+        let port = listener.local_addr().unwrap().port();
+
+        TcpStream::connect(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)).await.expect("Can not connect over IPv4");
+        listener.accept().await.unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+use async_std::net::{SocketAddr, TcpListener};
+use std::collections::BTreeMap;
+
+use rustc_serialize::json::Json;
+
+use crate::connection_id::ConnectionId;
+use crate::http_server::{Request, Response, serve_http};
+use crate::registry::Registry;
+
+pub struct AdminOptions {
+    pub address: SocketAddr,
+    // Every request needs "Authorization: Bearer [token]"
+    pub token: String
+}
+
+// Until canceled, answers:
+// - GET /clients: adapter streams waiting for an incoming connection
+// - DELETE /clients/[id]: disconnects one
+// - GET /bridges: bridged connections
+// - DELETE /bridges/[id]: closes one
+pub async fn serve_admin(listener: TcpListener, registry: Registry, token: String) {
+    serve_http("Admin", listener, move |request: Request| {
+        let response = handle(&request, &registry, &token);
+        async move { response }
+    }).await
+}
+
+fn handle(request: &Request, registry: &Registry, token: &str) -> Response {
+    if !authorized(request, token) {
+        return Response::empty("401 Unauthorized");
+    }
+
+    let path = request.path.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["clients"]) => Response::json("200 OK", clients_json(registry).to_string()),
+        ("GET", ["bridges"]) => Response::json("200 OK", bridges_json(registry).to_string()),
+        ("DELETE", ["clients", id]) => match id.parse::<u64>() {
+            Ok(id) if registry.disconnect_client(id) => Response::empty("204 No Content"),
+            _ => Response::empty("404 Not Found")
+        },
+        ("DELETE", ["bridges", id]) => match id.parse::<ConnectionId>() {
+            Ok(id) if registry.close_bridge(id) => Response::empty("204 No Content"),
+            _ => Response::empty("404 Not Found")
+        },
+        (_, ["clients"]) | (_, ["bridges"]) | (_, ["clients", _]) | (_, ["bridges", _]) => Response::empty("405 Method Not Allowed"),
+        _ => Response::empty("404 Not Found")
+    }
+}
+
+// Compares every byte, so that how long it takes doesn't tell how much of the token was right
+fn authorized(request: &Request, token: &str) -> bool {
+    let expected = format!("Bearer {}", token);
+
+    match request.header("Authorization") {
+        Some(authorization) if authorization.len() == expected.len() =>
+            authorization.bytes().zip(expected.bytes()).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0,
+        _ => false
+    }
+}
+
+pub fn clients_json(registry: &Registry) -> Json {
+    Json::Array(registry.clients().into_iter().map(|client| {
+        let mut object = BTreeMap::new();
+        object.insert("id".to_string(), Json::U64(client.id));
+        object.insert("address".to_string(), Json::String(client.address));
+        object.insert("key".to_string(), Json::String(client.key));
+        object.insert("connected_seconds".to_string(), Json::F64(client.connected.as_secs_f64()));
+        Json::Object(object)
+    }).collect())
+}
+
+fn bridges_json(registry: &Registry) -> Json {
+    Json::Array(registry.bridges().into_iter().map(|bridge| {
+        let mut object = BTreeMap::new();
+        object.insert("id".to_string(), Json::String(bridge.id.to_string()));
+        object.insert("client".to_string(), Json::String(bridge.client));
+        object.insert("peer".to_string(), address_json(bridge.peer_addr));
+        object.insert("local".to_string(), address_json(bridge.local_addr));
+        object.insert("bytes_sent".to_string(), Json::U64(bridge.bytes_sent));
+        object.insert("bytes_received".to_string(), Json::U64(bridge.bytes_received));
+        object.insert("age_seconds".to_string(), Json::F64(bridge.age.as_secs_f64()));
+        Json::Object(object)
+    }).collect())
+}
+
+fn address_json(address: Option<SocketAddr>) -> Json {
+    match address {
+        Some(address) => Json::String(address.to_string()),
+        None => Json::Null
+    }
+}
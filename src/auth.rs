@@ -1,6 +1,5 @@
 use async_std::io;
 use async_std::io::{Read, Write};
-use async_std::net::TcpStream;
 use async_std::prelude::*;
 use async_std::task;
 use core::time::Duration;
@@ -14,16 +13,39 @@ use rand::{RngCore, thread_rng};
 use rand::{Rng, SeedableRng};
 use rand_chacha::ChaCha12Rng;
 
+use crate::compression::{Compression, compression_mask, negotiate_compression};
 use crate::keys::Key;
 use crate::xor::{Xor, Xors};
 
-pub async fn authenticate(key: Key, stream: TcpStream) -> Result<Xors<ChaCha12Rng>, Error> {
+// Both sides start the handshake with this, in the clear
+pub const MAGIC: &[u8] = b"bounce";
+
+// Instead of MAGIC, for a control stream that makes one request, like `bounce status`, instead of waiting to be bridged
+pub const CONTROL_MAGIC: &[u8] = b"bnctrl";
+
+// compressions are what this side accepts on the adapter stream. The tunnel is compressed when both sides accept the same one
+pub async fn authenticate<TStream>(key: Key, stream: TStream, compressions: &[Compression]) -> Result<(Xors<ChaCha12Rng>, Option<Compression>), Error> where
+TStream: Read + Write + Unpin + Clone + Send + Any {
+
+    handshake(key, stream, MAGIC, compressions).await
+}
+
+// The same handshake as an adapter stream, without compression
+pub async fn authenticate_control<TStream>(key: Key, stream: TStream) -> Result<Xors<ChaCha12Rng>, Error> where
+TStream: Read + Write + Unpin + Clone + Send + Any {
+
+    let (xors, _) = handshake(key, stream, CONTROL_MAGIC, &[]).await?;
+    Ok(xors)
+}
+
+async fn handshake<TStream>(key: Key, stream: TStream, magic: &[u8], compressions: &[Compression]) -> Result<(Xors<ChaCha12Rng>, Option<Compression>), Error> where
+TStream: Read + Write + Unpin + Clone + Send + Any {
 
     // TODO: A potential optimization is to send "bounce", nonce, and challenges as one single write
 
     // Read and write "bounce"
-    let bounce_buffer = read_and_write(stream.clone(), &b"bounce".to_vec(), Duration::from_secs_f32(0.5)).await?;
-    if bounce_buffer[..] != b"bounce"[..] {
+    let bounce_buffer = read_and_write(stream.clone(), magic, Duration::from_secs_f32(0.5)).await?;
+    if bounce_buffer[..] != magic[..] {
         return Err(Error::new(ErrorKind::InvalidData, "This is not a bounce server or client"));
     }
 
@@ -40,7 +62,7 @@ pub async fn authenticate(key: Key, stream: TcpStream) -> Result<Xors<ChaCha12Rn
 
     let their_seed_encrypted = read_and_write(stream.clone(), &my_seed_encrypted, Duration::from_secs_f32(0.5)).await?;
 
-    let their_seed = process(&key, &their_nonce, &their_seed_encrypted)[0..32].try_into().expect("Unexpected seed size");
+    let their_seed: [u8; 32] = process(&key, &their_nonce, &their_seed_encrypted)[0..32].try_into().expect("Unexpected seed size");
     let their_seed = <ChaCha12Rng as SeedableRng>::Seed::from(their_seed);
 
     let write_rng = ChaCha12Rng::from_seed(my_seed);
@@ -54,17 +76,27 @@ pub async fn authenticate(key: Key, stream: TcpStream) -> Result<Xors<ChaCha12Rn
     let mut my_bounce = b"bounce".to_vec();
     write_xor.process(&mut my_bounce[..]);
 
-    let mut their_bounce = read_and_write(stream.clone(), &my_bounce, Duration::from_secs_f32(0.5)).await.expect("Handshake error");
+    let mut their_bounce = read_and_write(stream.clone(), &my_bounce, Duration::from_secs_f32(0.5)).await?;
     read_xor.process(&mut their_bounce[..]);
 
     if their_bounce[..] != b"bounce"[..] {
         return Err(Error::new(ErrorKind::InvalidData, "Authentication failed"));
     }
 
-    Ok(Xors {
+    // Read and write which compressions each side accepts
+    let my_mask = compression_mask(compressions);
+    let mut my_compressions = vec![my_mask];
+    write_xor.process(&mut my_compressions[..]);
+
+    let mut their_compressions = read_and_write(stream.clone(), &my_compressions, Duration::from_secs_f32(0.5)).await?;
+    read_xor.process(&mut their_compressions[..]);
+
+    let compression = negotiate_compression(my_mask, their_compressions[0]);
+
+    Ok((Xors {
         write_xor,
         read_xor
-    })
+    }, compression))
 }
 
 async fn read_buffer<TStream>(mut stream: TStream, buffer: &mut [u8], timeout: Duration) -> Result<(), Error>
@@ -77,7 +109,7 @@ where TStream : Read + Write + Unpin {
             return Err(Error::new(ErrorKind::InvalidData, "Socket closed prematurely"));
         }
 
-        total_bytes_read = total_bytes_read + bytes_read;
+        total_bytes_read += bytes_read;
         if total_bytes_read >= buffer.len() {
             return Ok(());
         }
@@ -89,10 +121,10 @@ where TStream : Read + Write + Unpin {
     stream.write_all(&buffer).await
 }
 
-async fn read_and_write<TStream>(stream: TStream, buffer_to_write: &Vec<u8>, timeout: Duration) -> Result<Vec<u8>, Error>
+async fn read_and_write<TStream>(stream: TStream, buffer_to_write: &[u8], timeout: Duration) -> Result<Vec<u8>, Error>
 where TStream : Read + Write + Unpin + Clone + Send + Any {
 
-    let write_future = task::spawn(write_buffer(stream.clone(), buffer_to_write.clone()));
+    let write_future = task::spawn(write_buffer(stream.clone(), buffer_to_write.to_vec()));
 
     let mut buffer_to_read = vec![0u8; buffer_to_write.len()];
 
@@ -102,7 +134,7 @@ where TStream : Read + Write + Unpin + Clone + Send + Any {
     Ok(buffer_to_read)
 }
 
-fn process(key: &Key, nonce: &Vec<u8>, to_process: &[u8]) -> Vec<u8> {
+fn process(key: &Key, nonce: &[u8], to_process: &[u8]) -> Vec<u8> {
     let mut my_ciper = aes::ctr(key.size, &key.key, nonce);
     let mut processed = vec![0u8; to_process.len()];
     my_ciper.process(to_process, &mut processed);
@@ -112,7 +144,7 @@ fn process(key: &Key, nonce: &Vec<u8>, to_process: &[u8]) -> Vec<u8> {
 
 #[cfg(test)]
 mod tests {
-    use async_std::net::{IpAddr, Ipv4Addr, Shutdown, TcpListener, SocketAddr};
+    use async_std::net::{IpAddr, Ipv4Addr, Shutdown, TcpListener, TcpStream, SocketAddr};
     use async_std::prelude::*;
 
     use crypto::aes::KeySize;
@@ -121,7 +153,7 @@ mod tests {
 
     async fn get_key_and_socket_streams() -> (Key, TcpStream, TcpStream) {
         let key = Key {
-            key: vec![1 as u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32],
+            key: vec![1u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32],
             size: KeySize::KeySize256
         };
 
@@ -147,19 +179,31 @@ mod tests {
 
         let (key, client_stream, server_stream) = get_key_and_socket_streams().await;
 
-        let client_authenticate_future = task::spawn(authenticate(key.clone(), client_stream));
-        let server_authenticate_future = task::spawn(authenticate(key.clone(), server_stream));
+        let client_authenticate_future = task::spawn(authenticate(key.clone(), client_stream, &[]));
+        let server_authenticate_future = task::spawn(authenticate(key.clone(), server_stream, &[]));
 
         client_authenticate_future.await.unwrap();
         server_authenticate_future.await.unwrap();
     }
 
+    #[async_std::test]
+    async fn authenticate_negotiates_compression() {
+
+        let (key, client_stream, server_stream) = get_key_and_socket_streams().await;
+
+        let client_authenticate_future = task::spawn(authenticate(key.clone(), client_stream, &[Compression::Zstd, Compression::Deflate]));
+        let server_authenticate_future = task::spawn(authenticate(key.clone(), server_stream, &[Compression::Deflate]));
+
+        assert_eq!(Some(Compression::Deflate), client_authenticate_future.await.unwrap().1);
+        assert_eq!(Some(Compression::Deflate), server_authenticate_future.await.unwrap().1);
+    }
+
     #[async_std::test]
     async fn authenticate_wrong_id() {
 
         let (key, client_stream, server_stream) = get_key_and_socket_streams().await;
 
-        let client_authenticate_future = task::spawn(authenticate(key.clone(), client_stream));
+        let client_authenticate_future = task::spawn(authenticate(key.clone(), client_stream, &[]));
         let server_emulate_future = task::spawn(write_buffer(server_stream.clone(), b"boXXce".to_vec()));
 
         server_emulate_future.await.unwrap();
@@ -175,7 +219,7 @@ mod tests {
 
         let (key, client_stream, server_stream) = get_key_and_socket_streams().await;
 
-        let client_authenticate_future = task::spawn(authenticate(key.clone(), client_stream));
+        let client_authenticate_future = task::spawn(authenticate(key.clone(), client_stream, &[]));
         let server_emulate_future = task::spawn(write_buffer(server_stream.clone(), b"short".to_vec()));
 
         server_emulate_future.await.unwrap();
@@ -191,7 +235,7 @@ mod tests {
 
         let (key, client_stream, server_stream) = get_key_and_socket_streams().await;
 
-        let client_authenticate_future = task::spawn(authenticate(key.clone(), client_stream));
+        let client_authenticate_future = task::spawn(authenticate(key.clone(), client_stream, &[]));
         server_stream.shutdown(Shutdown::Both).unwrap();
         
         match client_authenticate_future.await {
@@ -204,19 +248,19 @@ mod tests {
     async fn authenticate_different_keys() {
 
         let key_1 = Key {
-            key: vec![1 as u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32],
+            key: vec![1u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32],
             size: KeySize::KeySize128
         };
 
         let key_2 = Key {
-            key: vec![2 as u8, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33],
+            key: vec![2u8, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33],
             size: KeySize::KeySize128
         };
 
         let (client_stream, server_stream) = get_socket_streams().await;
 
-        let client_authenticate_future = task::spawn(authenticate(key_1, client_stream));
-        let server_authenticate_future = task::spawn(authenticate(key_2, server_stream));
+        let client_authenticate_future = task::spawn(authenticate(key_1, client_stream, &[]));
+        let server_authenticate_future = task::spawn(authenticate(key_2, server_stream, &[]));
 
         let client_authenticate_result = client_authenticate_future.await;
         let server_authenticate_result = server_authenticate_future.await;
@@ -238,8 +282,8 @@ mod tests {
 
     #[async_std::test]
     async fn verify_read_and_write() {
-        let a = vec![1 as u8, 2, 3, 4, 5, 6, 7, 8, 9, 10];
-        let b = vec![10 as u8, 9, 8, 7, 6, 5, 4, 3, 2, 1];
+        let a = vec![1u8, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        let b = vec![10u8, 9, 8, 7, 6, 5, 4, 3, 2, 1];
 
         let (client_stream, server_stream) = get_socket_streams().await;
 
@@ -255,12 +299,12 @@ mod tests {
     fn different_keys() {
 
         let key_1 = Key {
-            key: vec![1 as u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16],
+            key: vec![1u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16],
             size: KeySize::KeySize128
         };
 
         let key_2 = Key {
-            key: vec![2 as u8, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17],
+            key: vec![2u8, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17],
             size: KeySize::KeySize128
         };
 
@@ -0,0 +1,143 @@
+use core::time::Duration;
+
+use rand::{Rng, thread_rng};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReconnectOptions {
+    // How long to wait after the first failure
+    pub initial_interval: Duration,
+    // Each failure in a row multiplies the wait by this much
+    pub multiplier: f64,
+    // The wait never grows past this
+    pub max_interval: Duration,
+    // Fraction of each wait that is randomized, so that many clients don't reconnect at the same moment
+    pub jitter: f64,
+    // How many times to retry before giving up. None retries forever
+    pub max_retries: Option<u32>
+}
+
+impl Default for ReconnectOptions {
+    fn default() -> Self {
+        ReconnectOptions {
+            initial_interval: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_interval: Duration::from_secs(60),
+            jitter: 0.5,
+            max_retries: None
+        }
+    }
+}
+
+// Exponential backoff with jitter for failures in a row
+pub struct Backoff {
+    options: ReconnectOptions,
+    failures: u32
+}
+
+impl Backoff {
+    pub fn new(options: ReconnectOptions) -> Backoff {
+        Backoff {
+            options,
+            failures: 0
+        }
+    }
+
+    // Called after a success, so that the next failure starts over at the initial interval
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    // Records a failure. Returns how long to wait before retrying, or None when it's time to give up
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if let Some(max_retries) = self.options.max_retries {
+            if self.failures >= max_retries {
+                return None;
+            }
+        }
+
+        let interval = self.interval(self.failures);
+        self.failures = self.failures.saturating_add(1);
+
+        let jitter = interval.as_secs_f64() * self.options.jitter.clamp(0.0, 1.0);
+        let random_jitter = if jitter > 0.0 { thread_rng().gen_range(0.0, jitter) } else { 0.0 };
+
+        Some(Duration::from_secs_f64(interval.as_secs_f64() - random_jitter))
+    }
+
+    // The wait, before jitter, after the given number of failures
+    fn interval(&self, failures: u32) -> Duration {
+        let max_secs = self.options.max_interval.as_secs_f64();
+        let secs = self.options.initial_interval.as_secs_f64() * self.options.multiplier.powi(failures.min(i32::MAX as u32) as i32);
+
+        if secs.is_finite() && secs < max_secs {
+            Duration::from_secs_f64(secs)
+        } else {
+            self.options.max_interval
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grows_to_max_interval() {
+        let mut backoff = Backoff::new(ReconnectOptions {
+            initial_interval: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_interval: Duration::from_secs(10),
+            jitter: 0.0,
+            max_retries: None
+        });
+
+        let delays: Vec<Duration> = (0..6).map(|_| backoff.next_delay().unwrap()).collect();
+        let expected: Vec<Duration> = [1, 2, 4, 8, 10, 10].iter().map(|s| Duration::from_secs(*s)).collect();
+        assert_eq!(expected, delays);
+        assert_eq!(6, backoff.failures());
+
+        backoff.reset();
+        assert_eq!(Duration::from_secs(1), backoff.next_delay().unwrap());
+
+        // Many failures don't overflow
+        backoff.failures = u32::MAX;
+        assert_eq!(Duration::from_secs(10), backoff.next_delay().unwrap());
+    }
+
+    #[test]
+    fn jitter_stays_in_range() {
+        let mut backoff = Backoff::new(ReconnectOptions {
+            jitter: 0.5,
+            ..ReconnectOptions::default()
+        });
+
+        for _ in 0..100 {
+            backoff.reset();
+            let delay = backoff.next_delay().unwrap();
+            assert!(delay > Duration::from_millis(500) && delay <= Duration::from_secs(1), "Delay out of range: {:?}", delay);
+        }
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        let mut backoff = Backoff::new(ReconnectOptions {
+            max_retries: Some(2),
+            ..ReconnectOptions::default()
+        });
+
+        assert!(backoff.next_delay().is_some());
+        assert!(backoff.next_delay().is_some());
+        assert_eq!(None, backoff.next_delay());
+
+        let mut backoff = Backoff::new(ReconnectOptions {
+            max_retries: Some(0),
+            ..ReconnectOptions::default()
+        });
+
+        assert_eq!(None, backoff.next_delay());
+    }
+}
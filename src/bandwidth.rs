@@ -0,0 +1,213 @@
+use async_std::task;
+use core::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+// How often throughput is logged, when there was any
+const THROUGHPUT_LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+// A token bucket that holds up to a second's worth of bytes
+struct Bucket {
+    bytes_per_second: f64,
+    tokens: f64,
+    refilled: Instant
+}
+
+impl Bucket {
+    // Tokens are taken right away, even past zero, so that a large read waits its turn instead of being starved by small ones
+    // Returns how long to wait before the bytes can go through
+    fn take(&mut self, bytes: usize) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.bytes_per_second).min(self.bytes_per_second);
+        self.refilled = now;
+
+        self.tokens -= bytes as f64;
+
+        if self.tokens < 0.0 {
+            Duration::from_secs_f64(-self.tokens / self.bytes_per_second)
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+}
+
+// One direction of traffic
+struct Meter {
+    bucket: Option<Mutex<Bucket>>,
+    // Since throughput was last logged
+    bytes: AtomicU64
+}
+
+impl Meter {
+    fn new(bytes_per_second: Option<u64>) -> Self {
+        Meter {
+            bucket: bytes_per_second.map(|bytes_per_second| Mutex::new(Bucket {
+                bytes_per_second: bytes_per_second as f64,
+                tokens: bytes_per_second as f64,
+                refilled: Instant::now()
+            })),
+            bytes: AtomicU64::new(0)
+        }
+    }
+
+    fn take(&self, bytes: usize) -> Duration {
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+
+        match &self.bucket {
+            Some(bucket) => bucket.lock().unwrap().take(bytes),
+            None => Duration::from_secs(0)
+        }
+    }
+}
+
+// Limits and counts traffic, in each direction separately, for everything that shares it. Clones share the same limits and counts
+#[derive(Clone)]
+pub struct Bandwidth {
+    sent: Arc<Meter>,
+    received: Arc<Meter>
+}
+
+impl Bandwidth {
+    // In bytes per second, for each direction. None only counts
+    pub fn new(bytes_per_second: Option<u64>) -> Self {
+        Bandwidth {
+            sent: Arc::new(Meter::new(bytes_per_second)),
+            received: Arc::new(Meter::new(bytes_per_second))
+        }
+    }
+}
+
+impl Default for Bandwidth {
+    fn default() -> Self {
+        Bandwidth::new(None)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct BandwidthOptions {
+    // Shared by all of a server's (or client's) connections, which all use the same key
+    // Cloning it into other servers or clients in the same process limits them together
+    pub shared: Bandwidth,
+    // In bytes per second, for each direction of each connection
+    pub connection_limit: Option<u64>
+}
+
+impl BandwidthOptions {
+    pub fn connection(&self) -> ConnectionBandwidth {
+        ConnectionBandwidth {
+            shared: self.shared.clone(),
+            connection: Bandwidth::new(self.connection_limit)
+        }
+    }
+}
+
+// Everything that one bridged connection's traffic goes through. Clones share the same limits and counts
+#[derive(Clone)]
+pub struct ConnectionBandwidth {
+    shared: Bandwidth,
+    connection: Bandwidth
+}
+
+impl ConnectionBandwidth {
+    // Waits until bytes read from the clear stream can be sent on the adapter stream
+    // Not reading more in the meantime is what pushes back on the sender
+    pub async fn send(&self, bytes: usize) {
+        let wait = self.shared.sent.take(bytes).max(self.connection.sent.take(bytes));
+        sleep(wait).await;
+    }
+
+    // Waits until bytes read from the adapter stream can be written to the clear stream
+    pub async fn receive(&self, bytes: usize) {
+        let wait = self.shared.received.take(bytes).max(self.connection.received.take(bytes));
+        sleep(wait).await;
+    }
+
+    // Sent and received by this connection since it started. Only the shared counts are reset when throughput is logged
+    pub fn bytes(&self) -> (u64, u64) {
+        (self.connection.sent.bytes.load(Ordering::Relaxed), self.connection.received.bytes.load(Ordering::Relaxed))
+    }
+}
+
+async fn sleep(wait: Duration) {
+    if wait > Duration::from_secs(0) {
+        task::sleep(wait).await;
+    }
+}
+
+// Runs until canceled
+pub async fn log_throughput(bandwidth: Bandwidth) {
+    loop {
+        task::sleep(THROUGHPUT_LOG_INTERVAL).await;
+
+        let sent = bandwidth.sent.bytes.swap(0, Ordering::Relaxed);
+        let received = bandwidth.received.bytes.swap(0, Ordering::Relaxed);
+
+        if sent > 0 || received > 0 {
+            let seconds = THROUGHPUT_LOG_INTERVAL.as_secs_f64();
+            log::info!(
+                "Throughput: {} sent on the adapter stream, {} received",
+                format_rate(sent as f64 / seconds),
+                format_rate(received as f64 / seconds));
+        }
+    }
+}
+
+fn format_rate(bytes_per_second: f64) -> String {
+    if bytes_per_second >= 1024.0 * 1024.0 {
+        format!("{:.1} MiB/s", bytes_per_second / (1024.0 * 1024.0))
+    } else if bytes_per_second >= 1024.0 {
+        format!("{:.1} KiB/s", bytes_per_second / 1024.0)
+    } else {
+        format!("{:.0} B/s", bytes_per_second)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates() {
+        assert_eq!("1.5 KiB/s", format_rate(1536.0));
+    }
+
+    #[async_std::test]
+    async fn limits() {
+        let options = BandwidthOptions {
+            shared: Bandwidth::new(Some(100_000)),
+            connection_limit: Some(10_000)
+        };
+
+        let connection = options.connection();
+
+        // A second's worth goes through right away
+        let start = Instant::now();
+        connection.send(10_000).await;
+        assert!(start.elapsed() < Duration::from_millis(100), "Took {:?}", start.elapsed());
+
+        // The connection's own limit is the tighter one
+        connection.send(2_000).await;
+        assert!(start.elapsed() >= Duration::from_millis(200), "Took {:?}", start.elapsed());
+
+        // Each direction, and each connection, has its own bucket
+        let start = Instant::now();
+        connection.receive(10_000).await;
+        options.connection().send(10_000).await;
+        assert!(start.elapsed() < Duration::from_millis(100), "Took {:?}", start.elapsed());
+
+        // But they all share the server's bucket, which has about 90,000 left
+        let unlimited_connection = BandwidthOptions {
+            shared: options.shared.clone(),
+            connection_limit: None
+        }.connection();
+
+        let start = Instant::now();
+        unlimited_connection.send(110_000).await;
+        assert!(start.elapsed() >= Duration::from_millis(150), "Took {:?}", start.elapsed());
+
+        assert_eq!(132_000, options.shared.sent.bytes.load(Ordering::Relaxed));
+        assert_eq!((12_000, 10_000), connection.bytes());
+    }
+}
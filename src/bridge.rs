@@ -1,121 +1,425 @@
-use async_std::net::{Shutdown, TcpStream};
+use async_std::net::Shutdown;
 use async_std::prelude::*;
 use async_std::task;
-use std::io::Error;
+use core::time::Duration;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
 
 use core::any::Any;
 
-use futures::future::{Either, join, select};
+use futures::future::{Either, select};
 use rand_core::{CryptoRng, RngCore};
 
+use crate::bandwidth::ConnectionBandwidth;
+use crate::buffer_pool::BufferPool;
+use crate::capture::CaptureConnection;
+use crate::compression::{Compression, Compressor, Decompressor, compressors};
+use crate::connection_id::spawn_for_connection;
+use crate::inspector::InspectedConnection;
+use crate::metrics::Metrics;
+use crate::stream::Stream;
 use crate::xor::{Xor, Xors};
 
-pub fn run_bridge<TRng>(xors: Xors<TRng>, clear_stream: TcpStream, clear_stream_name: String, encrypted_stream: TcpStream, encrypted_stream_name: String) where
-TRng: CryptoRng + RngCore + Clone + Any {
+// On the encrypted stream, bridged data is framed so that a close or a reset isn't mistaken for data
+// Each frame is a 1-byte type and a 2-byte big-endian length, followed by that much data
+const FRAME_HEADER_SIZE: usize = 3;
+const MAX_FRAME_DATA_SIZE: usize = u16::MAX as usize;
+// Data that doesn't compress can come out a little larger, so less is read at a time when compressing
+const MAX_COMPRESSIBLE_SIZE: usize = 32 * 1024;
+
+const FRAME_DATA: u8 = 0;
+// The other side's clear stream closed for writing
+const FRAME_FIN: u8 = 1;
+// The other side's clear stream was reset, or failed
+const FRAME_RESET: u8 = 2;
+// Data from the other side's compressor, when compression was negotiated
+const FRAME_COMPRESSED: u8 = 3;
+
+// Bridged connections are closed after either of these, so that abandoned connections don't pile up
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnectionTimeouts {
+    // No bytes in either direction for this long
+    pub idle: Option<Duration>,
+    // No matter what
+    pub max_lifetime: Option<Duration>
+}
 
-    match clear_stream.set_nodelay(true) {
-        Err(err) => {
-            log::error!("Error disabling Nagle on {}: {}", clear_stream_name, err);
-            return;
-        },
-        Ok(()) => {}
+// How bridged data is sent on the adapter stream
+pub struct BridgeOptions {
+    // As negotiated during the handshake
+    pub compression: Option<Compression>,
+    pub bandwidth: ConnectionBandwidth,
+    pub timeouts: ConnectionTimeouts,
+    // Each direction takes a buffer while the connection is bridged
+    pub buffers: BufferPool,
+    pub metrics: Metrics,
+    // When set, the clear side is written to a capture file
+    pub capture: Option<CaptureConnection>,
+    // When set, HTTP on the clear side is kept for the inspector
+    pub inspector: Option<InspectedConnection>
+}
+
+impl BridgeOptions {
+    fn clear_data(&self, data: &[u8]) {
+        if let Some(capture) = &self.capture {
+            capture.clear_data(data);
+        }
+
+        if let Some(inspector) = &self.inspector {
+            inspector.clear_data(data);
+        }
     }
 
-    match encrypted_stream.set_nodelay(true) {
-        Err(err) => {
-            log::error!("Error disabling Nagle on {}: {}", encrypted_stream_name, err);
-            return;
-        },
-        Ok(()) => {}
+    fn adapter_data(&self, data: &[u8]) {
+        if let Some(capture) = &self.capture {
+            capture.adapter_data(data);
+        }
+
+        if let Some(inspector) = &self.inspector {
+            inspector.adapter_data(data);
+        }
     }
 
-    task::spawn(bridge(xors.clone(), clear_stream, clear_stream_name, encrypted_stream, encrypted_stream_name));
+    fn clear_fin(&self) {
+        if let Some(capture) = &self.capture {
+            capture.clear_fin();
+        }
+
+        if let Some(inspector) = &self.inspector {
+            inspector.clear_fin();
+        }
+    }
+
+    fn adapter_fin(&self) {
+        if let Some(capture) = &self.capture {
+            capture.adapter_fin();
+        }
+
+        if let Some(inspector) = &self.inspector {
+            inspector.adapter_fin();
+        }
+    }
 }
 
-pub async fn bridge<TRng>(xors: Xors<TRng>, clear_stream: TcpStream, clear_stream_name: String, encrypted_stream: TcpStream, encrypted_stream_name: String) where
+// When a bridge started, and when bytes last went through in either direction
+pub struct Activity {
+    started: Instant,
+    // Since started
+    last_active_millis: AtomicU64
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Activity {
+            started: Instant::now(),
+            last_active_millis: AtomicU64::new(0)
+        }
+    }
+
+    pub fn active(&self) {
+        self.last_active_millis.store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn idle(&self) -> Duration {
+        self.started.elapsed().saturating_sub(Duration::from_millis(self.last_active_millis.load(Ordering::Relaxed)))
+    }
+}
+
+// The guard is held until the bridge ends, so that shutdown can wait for it, and the admin API only lists running bridges
+pub fn run_bridge<TRng, TGuard>(xors: Xors<TRng>, options: BridgeOptions, clear_stream: Stream, clear_stream_name: String, encrypted_stream: Stream, encrypted_stream_name: String, guard: TGuard) where
+TRng: CryptoRng + RngCore + Clone + Any,
+TGuard: Send + 'static {
+
+    if let Err(err) = clear_stream.set_nodelay(true) {
+        log::error!("Error disabling Nagle on {}: {}", clear_stream_name, err);
+        return;
+    }
+
+    if let Err(err) = encrypted_stream.set_nodelay(true) {
+        log::error!("Error disabling Nagle on {}: {}", encrypted_stream_name, err);
+        return;
+    }
+
+    spawn_for_connection(async move {
+        bridge(xors, options, clear_stream, clear_stream_name, encrypted_stream, encrypted_stream_name).await;
+        drop(guard);
+    });
+}
+
+pub async fn bridge<TRng>(xors: Xors<TRng>, options: BridgeOptions, clear_stream: Stream, clear_stream_name: String, encrypted_stream: Stream, encrypted_stream_name: String) where
 TRng: CryptoRng + RngCore + Clone + Any {
 
-    let write_future = task::spawn(run_bridge_loop(
+    let (compressor, decompressor) = match compressors(options.compression) {
+        Ok(compressors) => compressors,
+        Err(err) => {
+            log::error!("Can not start {:?} compression on {}: {}", options.compression, encrypted_stream_name, err);
+            return;
+        }
+    };
+
+    let activity = Activity::new();
+
+    let clear_to_encrypted_future = Box::pin(clear_to_encrypted(
         xors.write_xor,
+        compressor,
+        &options,
+        &activity,
         clear_stream.clone(),
-        clear_stream_name.clone(),
-        encrypted_stream.clone(),
-        encrypted_stream_name.clone()));
+        &clear_stream_name,
+        encrypted_stream.clone()));
 
-    let read_future = task::spawn(run_bridge_loop(
+    let encrypted_to_clear_future = Box::pin(encrypted_to_clear(
         xors.read_xor,
+        decompressor,
+        &options,
+        &activity,
         encrypted_stream.clone(),
-        encrypted_stream_name.clone(),
-        clear_stream.clone(),
-        clear_stream_name.clone()));
+        &encrypted_stream_name,
+        clear_stream.clone()));
+
+    // When one direction closes, the other keeps going until it closes too, so that half-closed connections work
+    // When either direction fails, or the other side was reset, both end right away
+    let directions_future = Box::pin(async move {
+        match select(clear_to_encrypted_future, encrypted_to_clear_future).await {
+            Either::Left((Ok(()), encrypted_to_clear_future)) => encrypted_to_clear_future.await,
+            Either::Right((Ok(()), clear_to_encrypted_future)) => clear_to_encrypted_future.await,
+            Either::Left((Err(err), _)) | Either::Right((Err(err), _)) => Err(err)
+        }
+    });
 
-    match select(write_future, read_future).await {
-        Either::Left(r) => match r.0 {
-            Ok(()) => {
-                shutdown_both(clear_stream, clear_stream_name.clone(), Shutdown::Write, encrypted_stream, encrypted_stream_name.clone(), Shutdown::Both).await;
-            },
-            Err(err) => {
-                shutdown_both(clear_stream, clear_stream_name.clone(), Shutdown::Both, encrypted_stream, encrypted_stream_name.clone(), Shutdown::Both).await;
-                log::error!("{} -> {} ended in error: {}", clear_stream_name, encrypted_stream_name, err);
-            }
+    let timeouts_future = Box::pin(watch_timeouts(&activity, options.timeouts));
+
+    let result = match select(directions_future, timeouts_future).await {
+        Either::Left((result, _)) => result,
+        Either::Right((err, _)) => Err(err)
+    };
+
+    if let Some(capture) = &options.capture {
+        match &result {
+            Ok(()) => {},
+            Err(err) if err.kind() == ErrorKind::TimedOut => capture.close(),
+            Err(_) => capture.reset()
+        }
+    }
+
+    match result {
+        Ok(()) => {
+            shutdown(clear_stream, &clear_stream_name, Shutdown::Both).await;
+            shutdown(encrypted_stream, &encrypted_stream_name, Shutdown::Both).await;
+
+            log::info!("Connection ended: {} <-> {}", clear_stream_name, encrypted_stream_name);
         },
-        Either::Right(r) => match r.0 {
-            Ok(()) => {
-                shutdown_both(encrypted_stream, encrypted_stream_name.clone(), Shutdown::Write, clear_stream, clear_stream_name.clone(), Shutdown::Both).await;
-            },
-            Err(err) => {
-                shutdown_both(encrypted_stream, encrypted_stream_name.clone(), Shutdown::Both, clear_stream, clear_stream_name.clone(), Shutdown::Both).await;
-                log::error!("{} -> {} ended in error: {}", encrypted_stream_name, clear_stream_name, err);
-            }
+        // Both streams are closed, and the other side notices that the encrypted stream closed without FIN
+        Err(err) if err.kind() == ErrorKind::TimedOut => {
+            shutdown(clear_stream, &clear_stream_name, Shutdown::Both).await;
+            shutdown(encrypted_stream, &encrypted_stream_name, Shutdown::Both).await;
+
+            log::info!("Connection closed: {} <-> {}: {}", clear_stream_name, encrypted_stream_name, err);
         },
-    };
+        Err(err) => {
+            // The clear stream is reset when it's dropped, which is the last thing this function does
+            if let Err(err) = clear_stream.set_reset_on_close() {
+                log::error!("Can not reset {}: {}", clear_stream_name, err);
+            }
+
+            shutdown(encrypted_stream, &encrypted_stream_name, Shutdown::Both).await;
+
+            log::warn!("Connection reset: {} <-> {}: {}", clear_stream_name, encrypted_stream_name, err);
+        }
+    }
 }
 
-async fn run_bridge_loop<TRng>(mut xor: Xor<TRng>, mut reader: TcpStream, reader_name: String, mut writer: TcpStream, writer_name: String) -> Result<(), Error>  where
-TRng: CryptoRng + RngCore + Clone  {
-    
-    let mut buf = vec![0u8; 4098];
+// Returns a TimedOut error once the connection is idle, or reaches its maximum lifetime
+pub async fn watch_timeouts(activity: &Activity, timeouts: ConnectionTimeouts) -> Error {
+    loop {
+        let lifetime = activity.started.elapsed();
+        let idle = activity.idle();
+
+        let until_max_lifetime = match timeouts.max_lifetime {
+            Some(max_lifetime) if lifetime >= max_lifetime => return Error::new(ErrorKind::TimedOut, format!("Reached the maximum lifetime of {:.1}s", max_lifetime.as_secs_f64())),
+            Some(max_lifetime) => Some(max_lifetime - lifetime),
+            None => None
+        };
+
+        let until_idle = match timeouts.idle {
+            Some(idle_timeout) if idle >= idle_timeout => return Error::new(ErrorKind::TimedOut, format!("Idle for {:.1}s", idle.as_secs_f64())),
+            Some(idle_timeout) => Some(idle_timeout - idle),
+            None => None
+        };
+
+        // Activity since then pushes the idle deadline back, which is checked when this wakes up
+        match until_max_lifetime.into_iter().chain(until_idle).min() {
+            Some(wait) => task::sleep(wait).await,
+            None => futures::future::pending().await
+        }
+    }
+}
+
+// Reads from the clear stream, and sends frames on the encrypted stream
+// Each read is its own frame, so that interactive protocols don't wait on the compressor
+// Ends with Ok after the clear stream closes and FIN is sent
+async fn clear_to_encrypted<TRng>(mut xor: Xor<TRng>, mut compressor: Option<Compressor>, options: &BridgeOptions, activity: &Activity, mut clear_stream: Stream, clear_stream_name: &str, mut encrypted_stream: Stream) -> Result<(), Error> where
+TRng: CryptoRng + RngCore + Clone {
+
+    // The frame header goes in front of the data, so that each frame is encrypted and written at once
+    let mut buf = options.buffers.take();
+    let max_data_size = match compressor {
+        Some(_) => MAX_COMPRESSIBLE_SIZE,
+        None => MAX_FRAME_DATA_SIZE
+    };
+    let buf_end = buf.len().min(FRAME_HEADER_SIZE + max_data_size);
+
+    let mut compressed = Vec::new();
+    let mut compressed_frame = Vec::new();
 
     loop {
-        let bytes_read = reader.read(&mut buf).await?;
+        let bytes_read = match clear_stream.read(&mut buf[FRAME_HEADER_SIZE..buf_end]).await {
+            Ok(bytes_read) => bytes_read,
+            Err(err) => {
+                // The other side resets its clear stream too. If this fails, it notices that the encrypted stream closed instead
+                if let Err(err) = write_control_frame(&mut xor, &mut encrypted_stream, FRAME_RESET).await {
+                    log::debug!("Can not send reset for {}: {}", clear_stream_name, err);
+                }
+
+                return Err(err);
+            }
+        };
 
         if bytes_read == 0 {
-            log::debug!("Connected ending: {}", reader_name);
-            return Ok(());
+            log::debug!("Connection ending: {}", clear_stream_name);
+            options.clear_fin();
+
+            return write_control_frame(&mut xor, &mut encrypted_stream, FRAME_FIN).await;
         }
 
-        log::trace!("Read {} bytes from {}", bytes_read, reader_name);
+        log::trace!("Read {} bytes from {}", bytes_read, clear_stream_name);
+        activity.active();
 
-        // Decrypt
-        xor.process(&mut buf[..bytes_read]);
+        // Limits count the clear bytes, so that they mean the same with or without compression
+        options.bandwidth.send(bytes_read).await;
+        options.metrics.sent(bytes_read);
+        options.clear_data(&buf[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + bytes_read]);
 
-        // Forward
-        writer.write_all(&mut buf[..bytes_read]).await?;
+        if let Some(compressor) = &mut compressor {
+            if compressor.compress(&buf[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + bytes_read], &mut compressed)? {
+                if compressed.len() > MAX_FRAME_DATA_SIZE {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("{} bytes from {} compressed to {} bytes", bytes_read, clear_stream_name, compressed.len())));
+                }
+
+                compressed_frame.clear();
+                compressed_frame.push(FRAME_COMPRESSED);
+                compressed_frame.extend_from_slice(&(compressed.len() as u16).to_be_bytes());
+                compressed_frame.extend_from_slice(&compressed);
+
+                xor.process(&mut compressed_frame);
+                encrypted_stream.write_all(&compressed_frame).await?;
+                continue;
+            }
+        }
 
-        log::trace!("Wrote {} bytes to {}", bytes_read, writer_name);
+        buf[0] = FRAME_DATA;
+        buf[1..FRAME_HEADER_SIZE].copy_from_slice(&(bytes_read as u16).to_be_bytes());
+
+        // Encrypt
+        let frame = &mut buf[..FRAME_HEADER_SIZE + bytes_read];
+        xor.process(frame);
+
+        // Forward
+        encrypted_stream.write_all(frame).await?;
     }
 }
 
-async fn shutdown_both(
-    clear_stream: TcpStream,
-    clear_stream_name: String,
-    clear_stream_shutdown: Shutdown,
-    encrypted_stream: TcpStream,
-    encrypted_stream_name: String,
-    encrypted_stream_shutdown: Shutdown) {
-    
-    let clear_flush_future = task::spawn(shutdown(clear_stream.clone(), clear_stream_name.clone(), clear_stream_shutdown));
-    let encrypted_flush_future = task::spawn(shutdown(encrypted_stream.clone(), encrypted_stream_name.clone(), encrypted_stream_shutdown));
+// Reads frames from the encrypted stream, and writes to the clear stream
+// Ends with Ok after FIN arrives and the clear stream is closed for writing
+// Frames can be larger than this side's buffer, so they're read in pieces
+async fn encrypted_to_clear<TRng>(mut xor: Xor<TRng>, mut decompressor: Option<Decompressor>, options: &BridgeOptions, activity: &Activity, mut encrypted_stream: Stream, encrypted_stream_name: &str, mut clear_stream: Stream) -> Result<(), Error> where
+TRng: CryptoRng + RngCore + Clone {
+
+    let mut buf = options.buffers.take();
+    let buf_size = buf.len();
+    let mut decompressed = Vec::new();
+
+    loop {
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+        if let Err(err) = encrypted_stream.read_exact(&mut header).await {
+            // Without FIN, data might have been lost, so this is treated like a reset
+            return Err(Error::new(ErrorKind::ConnectionAborted, format!("{} closed without FIN: {}", encrypted_stream_name, err)));
+        }
+
+        // Decrypt
+        xor.process(&mut header);
+        let len = u16::from_be_bytes([header[1], header[2]]) as usize;
+
+        activity.active();
+
+        match header[0] {
+            FRAME_DATA => {
+                log::trace!("Read {} bytes from {}", len, encrypted_stream_name);
+
+                let mut remaining = len;
+                while remaining > 0 {
+                    let piece = &mut buf[..remaining.min(buf_size)];
+                    encrypted_stream.read_exact(piece).await?;
+                    xor.process(piece);
 
-    join(clear_flush_future, encrypted_flush_future).await;
+                    options.bandwidth.receive(piece.len()).await;
+                    options.metrics.received(piece.len());
+                    options.adapter_data(piece);
+                    clear_stream.write_all(piece).await?;
 
-    log::info!("Connection ended: {} <-> {}", clear_stream_name, encrypted_stream_name);
+                    remaining -= piece.len();
+                }
+            },
+            FRAME_COMPRESSED => {
+                let decompressor = match &mut decompressor {
+                    Some(decompressor) => decompressor,
+                    None => return Err(Error::new(ErrorKind::InvalidData, format!("Compressed frame from {}, but compression wasn't negotiated", encrypted_stream_name)))
+                };
+
+                log::trace!("Read {} compressed bytes from {}", len, encrypted_stream_name);
+
+                let mut remaining = len;
+                while remaining > 0 {
+                    let piece = &mut buf[..remaining.min(buf_size)];
+                    encrypted_stream.read_exact(piece).await?;
+                    xor.process(piece);
+
+                    // The other side's frames never hold more than this, whatever its buffer size
+                    decompressor.decompress(piece, &mut decompressed, MAX_COMPRESSIBLE_SIZE)?;
+
+                    options.bandwidth.receive(decompressed.len()).await;
+                    options.metrics.received(decompressed.len());
+                    options.adapter_data(&decompressed);
+                    clear_stream.write_all(&decompressed).await?;
+
+                    remaining -= piece.len();
+                }
+            },
+            FRAME_FIN => {
+                log::debug!("Connection ending: {}", encrypted_stream_name);
+                options.adapter_fin();
+
+                clear_stream.shutdown(Shutdown::Write)?;
+                return Ok(());
+            },
+            FRAME_RESET => return Err(Error::new(ErrorKind::ConnectionReset, format!("Reset by the other side of {}", encrypted_stream_name))),
+            frame_type => return Err(Error::new(ErrorKind::InvalidData, format!("Unknown frame type {} from {}", frame_type, encrypted_stream_name)))
+        }
+    }
 }
 
+async fn write_control_frame<TRng>(xor: &mut Xor<TRng>, encrypted_stream: &mut Stream, frame_type: u8) -> Result<(), Error> where
+TRng: CryptoRng + RngCore + Clone {
+
+    let mut header = [frame_type, 0, 0];
+    xor.process(&mut header);
+    encrypted_stream.write_all(&header).await
+}
 
 async fn shutdown(
-    mut stream: TcpStream,
-    stream_name: String,
+    mut stream: Stream,
+    stream_name: &str,
     shutdown: Shutdown) {
 
     match stream.flush().await {
@@ -123,20 +427,28 @@ async fn shutdown(
         Err(err) => log::error!("Can not flush {}: {}", stream_name, err),
     }
 
+    // Closing a stream that the other side already closed isn't a problem
     match stream.shutdown(shutdown) {
         Ok(()) => log::debug!("Successfully shut down {}", stream_name),
+        Err(err) if err.kind() == ErrorKind::NotConnected => log::debug!("{} was already closed", stream_name),
         Err(err) => log::error!("Error shutting down {}: {}", stream_name, err)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use async_std::net::{IpAddr, Ipv4Addr, Shutdown, TcpListener, SocketAddr};
+    use async_std::io;
+    use async_std::net::{IpAddr, Ipv4Addr, Shutdown, TcpListener, TcpStream, SocketAddr};
     use async_std::prelude::*;
+    use core::time::Duration;
 
     use rand::{RngCore, SeedableRng, thread_rng};
     use rand_chacha::ChaCha8Rng;
 
+    use crate::bandwidth::{Bandwidth, BandwidthOptions};
+    use crate::buffer_pool::MIN_BUFFER_SIZE;
+    use crate::shutdown::ActiveConnections;
+
     use super::*;
 
     struct TcpStreams {
@@ -148,15 +460,10 @@ mod tests {
         final_client_clear_stream: TcpStream
     }
 
-    impl Drop for TcpStreams {
-        fn drop(&mut self) {
-            self.initiating_client_clear_stream.shutdown(Shutdown::Both).ok();
-            self.bounce_server_clear_stream.shutdown(Shutdown::Both).ok();
-            self.bounce_server_encrypted_stream.shutdown(Shutdown::Both).ok();
-            self.bounce_client_encrypted_stream.shutdown(Shutdown::Both).ok();
-            self.bounce_client_clear_stream.shutdown(Shutdown::Both).ok();
-            self.final_client_clear_stream.shutdown(Shutdown::Both).ok();
-        }
+    // What the tests use, after the streams in the middle are handed to the bridges
+    struct BridgedStreams {
+        initiating_client_clear_stream: TcpStream,
+        final_client_clear_stream: TcpStream
     }
 
     async fn get_socket_streams() -> TcpStreams {
@@ -190,7 +497,16 @@ mod tests {
         }
     }
 
-    async fn start() -> TcpStreams {
+    async fn start() -> BridgedStreams {
+        start_with_compression(None).await
+    }
+
+    async fn start_with_compression(compression: Option<Compression>) -> BridgedStreams {
+        start_with_options(compression, BandwidthOptions::default(), ConnectionTimeouts::default(), BufferPool::default()).await
+    }
+
+    // Only the server's bridge has timeouts, so that it's always the one to close the connection
+    async fn start_with_options(compression: Option<Compression>, bandwidth: BandwidthOptions, server_timeouts: ConnectionTimeouts, server_buffers: BufferPool) -> BridgedStreams {
 
         let streams = get_socket_streams().await;
 
@@ -202,10 +518,20 @@ mod tests {
         // server
         run_bridge(
             xors,
-            streams.bounce_server_clear_stream.clone(),
+            BridgeOptions {
+                compression,
+                bandwidth: bandwidth.connection(),
+                timeouts: server_timeouts,
+                buffers: server_buffers,
+                metrics: Metrics::default(),
+                capture: None,
+                inspector: None
+            },
+            streams.bounce_server_clear_stream.into(),
             "bounce_server_clear_stream".to_string(),
-            streams.bounce_server_encrypted_stream.clone(),
-            "bounce_server_encrypted_stream".to_string());
+            streams.bounce_server_encrypted_stream.into(),
+            "bounce_server_encrypted_stream".to_string(),
+            ActiveConnections::default().track());
 
         let xors = Xors {
             read_xor: Xor::new(ChaCha8Rng::seed_from_u64(2)),
@@ -215,18 +541,29 @@ mod tests {
         // client
         run_bridge(
             xors,
-            streams.bounce_client_clear_stream.clone(),
+            BridgeOptions {
+                compression,
+                bandwidth: bandwidth.connection(),
+                timeouts: ConnectionTimeouts::default(),
+                buffers: BufferPool::default(),
+                metrics: Metrics::default(),
+                capture: None,
+                inspector: None
+            },
+            streams.bounce_client_clear_stream.into(),
             "bounce_client_clear_stream".to_string(),
-            streams.bounce_client_encrypted_stream.clone(),
-            "bounce_client_encrypted_stream".to_string());
+            streams.bounce_client_encrypted_stream.into(),
+            "bounce_client_encrypted_stream".to_string(),
+            ActiveConnections::default().track());
 
-        streams
+        BridgedStreams {
+            initiating_client_clear_stream: streams.initiating_client_clear_stream,
+            final_client_clear_stream: streams.final_client_clear_stream
+        }
     }
 
-    #[async_std::test]
-    async fn bridge_works() {
-        let streams = start().await;
-
+    // Sends back and forth, random data, or text when compressible is set
+    async fn exchange(streams: BridgedStreams, compressible: bool) {
         let mut write_stream = &streams.initiating_client_clear_stream;
         let mut read_stream = &streams.final_client_clear_stream;
 
@@ -235,7 +572,15 @@ mod tests {
 
             let size = (rng.next_u64() % 4098) as usize;
             let mut send_buf = vec![0u8; size];
-            rng.fill_bytes(&mut send_buf);
+
+            if compressible {
+                let text = b"{\"bounce\": [1, 2, 3]}\n";
+                for (i, b) in send_buf.iter_mut().enumerate() {
+                    *b = text[i % text.len()];
+                }
+            } else {
+                rng.fill_bytes(&mut send_buf);
+            }
 
             write_stream.write_all(&send_buf[..]).await.expect("Can not write to initiating_client_clear_stream");
 
@@ -245,22 +590,165 @@ mod tests {
             while total_bytes_read < size {
                 let bytes_read = read_stream.read(&mut recieve_buf[total_bytes_read..]).await.expect("Can not read from final_client_clear_stream");
                 assert_ne!(bytes_read, 0, "Unexpected end of stream");
-                total_bytes_read = total_bytes_read + bytes_read;
+                total_bytes_read += bytes_read;
             }
 
             assert_eq!(send_buf, recieve_buf, "Wrong contents sent");
 
             // Exchange
-            let i = write_stream;
-            write_stream = read_stream;
-            read_stream = i;
+            std::mem::swap(&mut write_stream, &mut read_stream);
+        }
+    }
+
+    #[async_std::test]
+    async fn bridge_works() {
+        exchange(start().await, false).await;
+    }
+
+    #[async_std::test]
+    async fn small_buffers() {
+        // The client's frames are larger than the server's buffers
+        exchange(start_with_options(None, BandwidthOptions::default(), ConnectionTimeouts::default(), BufferPool::new(MIN_BUFFER_SIZE)).await, false).await;
+        exchange(start_with_options(Some(Compression::Zstd), BandwidthOptions::default(), ConnectionTimeouts::default(), BufferPool::new(MIN_BUFFER_SIZE)).await, true).await;
+    }
+
+    #[async_std::test]
+    async fn compressed_bridge_works() {
+        for compression in [Compression::Deflate, Compression::Zstd].iter() {
+            exchange(start_with_compression(Some(*compression)).await, true).await;
+
+            // Incompressible data is mostly sent as-is
+            exchange(start_with_compression(Some(*compression)).await, false).await;
+        }
+    }
+
+    #[async_std::test]
+    async fn bandwidth_limit() {
+        let bandwidth = BandwidthOptions {
+            shared: Bandwidth::default(),
+            connection_limit: Some(50_000)
+        };
+
+        let streams = start_with_options(None, bandwidth, ConnectionTimeouts::default(), BufferPool::default()).await;
+
+        let mut write_stream = streams.initiating_client_clear_stream.clone();
+        let mut read_stream = &streams.final_client_clear_stream;
+
+        // The first second's worth goes through right away, and then the writer is held back instead of data being dropped
+        let start = std::time::Instant::now();
+        let write_future = task::spawn(async move {
+            write_stream.write_all(&vec![1u8; 100_000]).await.unwrap();
+        });
+
+        let mut buf = vec![0u8; 100_000];
+        read_stream.read_exact(&mut buf).await.unwrap();
+        write_future.await;
+
+        assert!(buf.iter().all(|b| *b == 1), "Wrong contents sent");
+        assert!(start.elapsed() >= Duration::from_millis(800), "Took {:?}", start.elapsed());
+    }
+
+    #[async_std::test]
+    async fn idle_timeout() {
+        let timeouts = ConnectionTimeouts {
+            idle: Some(Duration::from_millis(300)),
+            max_lifetime: None
+        };
+
+        let streams = start_with_options(None, BandwidthOptions::default(), timeouts, BufferPool::default()).await;
+
+        let mut initiating_stream = &streams.initiating_client_clear_stream;
+        let mut final_stream = &streams.final_client_clear_stream;
+
+        // Activity keeps the connection open past the idle timeout
+        let start = std::time::Instant::now();
+        for _ in 0..5 {
+            task::sleep(Duration::from_millis(100)).await;
+            initiating_stream.write_all(b"keepalive").await.unwrap();
+
+            let mut buf = [0u8; 9];
+            final_stream.read_exact(&mut buf).await.unwrap();
+        }
+
+        // Then both streams are closed
+        let mut buf = [0u8; 16];
+        let bytes_read = io::timeout(Duration::from_secs(5), initiating_stream.read(&mut buf)).await.expect("The server's clear stream should be shut down");
+        assert_eq!(0, bytes_read);
+        assert!(start.elapsed() >= Duration::from_millis(800), "Took {:?}", start.elapsed());
+
+        io::timeout(Duration::from_secs(5), final_stream.read(&mut buf)).await.expect_err("The client's clear stream should be reset");
+    }
+
+    #[async_std::test]
+    async fn max_lifetime() {
+        let timeouts = ConnectionTimeouts {
+            idle: Some(Duration::from_secs(60)),
+            max_lifetime: Some(Duration::from_millis(300))
+        };
+
+        // The bridge's lifetime starts while the streams are set up
+        let start = std::time::Instant::now();
+        let streams = start_with_options(None, BandwidthOptions::default(), timeouts, BufferPool::default()).await;
+
+        let mut initiating_stream = &streams.initiating_client_clear_stream;
+
+        // Even while it's in use
+        loop {
+            task::sleep(Duration::from_millis(50)).await;
+            if initiating_stream.write_all(b"busy").await.is_err() {
+                break;
+            }
+
+            let mut buf = [0u8; 16];
+            // Writing after the close can also reset it
+            match io::timeout(Duration::from_millis(10), initiating_stream.read(&mut buf)).await {
+                Err(err) if err.kind() == ErrorKind::TimedOut => {},
+                Ok(0) | Err(_) => break,
+                Ok(bytes_read) => panic!("Unexpected {} bytes", bytes_read)
+            }
+
+            assert!(start.elapsed() < Duration::from_secs(5), "The connection wasn't closed");
+        }
+
+        assert!(start.elapsed() >= Duration::from_millis(300), "Took {:?}", start.elapsed());
+    }
+
+    // Run with: cargo test --release benchmark -- --ignored --nocapture
+    #[async_std::test]
+    #[ignore]
+    async fn benchmark() {
+        const SIZE: usize = 256 * 1024 * 1024;
+
+        for buffer_size in [4 * 1024, crate::buffer_pool::DEFAULT_BUFFER_SIZE].iter() {
+            let streams = start_with_options(None, BandwidthOptions::default(), ConnectionTimeouts::default(), BufferPool::new(*buffer_size)).await;
+
+            let mut write_stream = streams.initiating_client_clear_stream.clone();
+            let mut read_stream = &streams.final_client_clear_stream;
+
+            let start = std::time::Instant::now();
+            let write_future = task::spawn(async move {
+                let buf = vec![0u8; 1024 * 1024];
+                for _ in 0..SIZE / buf.len() {
+                    write_stream.write_all(&buf).await.unwrap();
+                }
+            });
+
+            let mut buf = vec![0u8; 1024 * 1024];
+            let mut total_bytes_read = 0;
+            while total_bytes_read < SIZE {
+                total_bytes_read += read_stream.read(&mut buf).await.unwrap();
+            }
+
+            write_future.await;
+
+            println!("{} byte buffers: {:.0} MiB/s", buffer_size, SIZE as f64 / (1024.0 * 1024.0) / start.elapsed().as_secs_f64());
         }
     }
 
     async fn shutdown_read(write_stream: &TcpStream, read_stream: &mut TcpStream) {
         write_stream.shutdown(Shutdown::Both).unwrap();
 
-        let mut read_buf = vec![0u8, 16];
+        let mut read_buf = [0u8, 16];
         let bytes_read = read_stream.read(&mut read_buf[..]).await.unwrap();
 
         assert_eq!(bytes_read, 0, "Socket should be shut down");
@@ -277,4 +765,43 @@ mod tests {
         let mut streams = start().await;
         shutdown_read(&streams.final_client_clear_stream, &mut streams.initiating_client_clear_stream).await;
     }
+
+    #[async_std::test]
+    async fn half_close() {
+        let streams = start().await;
+
+        let mut initiating_stream = &streams.initiating_client_clear_stream;
+        let mut final_stream = &streams.final_client_clear_stream;
+
+        initiating_stream.write_all(b"request").await.unwrap();
+        initiating_stream.shutdown(Shutdown::Write).unwrap();
+
+        let mut buf = Vec::new();
+        final_stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(b"request".to_vec(), buf);
+
+        // The other direction stays open after the first one closes
+        final_stream.write_all(b"response").await.unwrap();
+        final_stream.shutdown(Shutdown::Write).unwrap();
+
+        let mut buf = Vec::new();
+        initiating_stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(b"response".to_vec(), buf);
+    }
+
+    #[async_std::test]
+    async fn reset() {
+        let streams = start().await;
+
+        let mut initiating_stream = streams.initiating_client_clear_stream;
+        let final_stream = streams.final_client_clear_stream;
+
+        initiating_stream.write_all(b"request").await.unwrap();
+
+        crate::stream::Stream::from(final_stream).set_reset_on_close().unwrap();
+
+        let mut buf = [0u8; 16];
+        let err = io::timeout(Duration::from_secs(5), initiating_stream.read(&mut buf)).await.expect_err("The reset should come through the bridge");
+        assert_eq!(ErrorKind::ConnectionReset, err.kind());
+    }
 }
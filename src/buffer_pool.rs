@@ -0,0 +1,103 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+pub const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+pub const MIN_BUFFER_SIZE: usize = 1024;
+
+// Buffers beyond this many are freed when they're returned, so that a burst of connections doesn't hold onto memory
+const MAX_POOLED_BUFFERS: usize = 64;
+
+// Buffers for bridged connections, which are reused instead of allocated for each connection. Clones share the same buffers
+#[derive(Clone)]
+pub struct BufferPool {
+    buffer_size: usize,
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>
+}
+
+impl BufferPool {
+    pub fn new(buffer_size: usize) -> Self {
+        BufferPool {
+            buffer_size,
+            buffers: Arc::new(Mutex::new(Vec::new()))
+        }
+    }
+
+    // The buffer goes back to the pool when it's dropped. Its contents are whatever the last user left in it
+    pub fn take(&self) -> PooledBuffer {
+        let buffer = self.buffers.lock().unwrap().pop().unwrap_or_else(|| vec![0u8; self.buffer_size]);
+
+        PooledBuffer {
+            buffer,
+            buffers: self.buffers.clone()
+        }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        BufferPool::new(DEFAULT_BUFFER_SIZE)
+    }
+}
+
+pub struct PooledBuffer {
+    buffer: Vec<u8>,
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < MAX_POOLED_BUFFERS {
+            buffers.push(std::mem::take(&mut self.buffer));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse() {
+        let pool = BufferPool::new(1024);
+
+        let mut buffer = pool.take();
+        assert_eq!(1024, buffer.len());
+        buffer[0] = 1;
+        let address = buffer.as_ptr();
+        drop(buffer);
+
+        // The same buffer comes back
+        let buffer = pool.take();
+        assert_eq!(address, buffer.as_ptr());
+        assert_eq!(1, buffer[0]);
+
+        // While it's in use, another one is allocated
+        let other_buffer = pool.clone().take();
+        assert_ne!(address, other_buffer.as_ptr());
+    }
+
+    #[test]
+    fn limit() {
+        let pool = BufferPool::new(16);
+
+        let buffers: Vec<PooledBuffer> = (0..MAX_POOLED_BUFFERS + 10).map(|_| pool.take()).collect();
+        drop(buffers);
+
+        assert_eq!(MAX_POOLED_BUFFERS, pool.buffers.lock().unwrap().len());
+    }
+}
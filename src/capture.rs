@@ -0,0 +1,572 @@
+use async_std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::fs::{File, rename};
+use std::io::{BufWriter, Error, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Raw IPv4 or IPv6 packets, without a link layer
+const LINKTYPE_RAW: u16 = 101;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B3C4D;
+// The section header and interface description that start every file
+const FILE_HEADER_SIZE: u64 = 48;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+// Larger writes are split into segments like Ethernet's, so that the IP length always fits and tools reassemble them as
+// they would a real capture
+const MAX_SEGMENT_SIZE: usize = 1460;
+
+pub const DEFAULT_MAX_SIZE: u64 = 100 * 1024 * 1024;
+
+pub struct CaptureOptions {
+    pub path: PathBuf,
+    // Only connections to these public ports are captured, each port being one mapping. Empty for all
+    pub ports: Vec<u16>,
+    // Once the file would grow past this, it's renamed to [path].1, replacing the previous one, and a new file is started
+    pub max_size: u64
+}
+
+// Writes the clear side of bridged connections to a pcapng file, with made-up TCP/IP headers so that Wireshark can follow each connection
+// Clones share the same file
+#[derive(Clone)]
+pub struct Capture {
+    ports: Arc<Vec<u16>>,
+    file: Arc<Mutex<CaptureFile>>
+}
+
+impl Capture {
+    pub fn open(options: CaptureOptions) -> Result<Self, Error> {
+        let writer = create(&options.path)?;
+
+        log::info!("Capturing to {}", options.path.display());
+
+        Ok(Capture {
+            ports: Arc::new(options.ports),
+            file: Arc::new(Mutex::new(CaptureFile {
+                path: options.path,
+                max_size: options.max_size,
+                writer,
+                size: FILE_HEADER_SIZE,
+                failing: false
+            }))
+        })
+    }
+
+    // Starts capturing a connection from client to server, where server is the public address that it connected to
+    // The clear stream is the client's end on the bounce server, and the server's end on the bounce client
+    // None when the connection's port isn't captured. Missing addresses are captured as 0.0.0.0:0
+    pub fn connection(&self, client: Option<SocketAddr>, server: Option<SocketAddr>, clear_is_client: bool) -> Option<CaptureConnection> {
+        let unspecified = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+        if !self.ports.is_empty() && !server.map(|server| self.ports.contains(&server.port())).unwrap_or(false) {
+            return None;
+        }
+
+        let connection = CaptureConnection {
+            file: self.file.clone(),
+            client: client.unwrap_or(unspecified),
+            server: server.unwrap_or(unspecified),
+            clear_is_client,
+            state: Mutex::new(ConnectionState::default())
+        };
+
+        {
+            let mut state = connection.state.lock().unwrap();
+            connection.write(&mut state, true, TCP_SYN, &[]);
+            connection.write(&mut state, false, TCP_SYN | TCP_ACK, &[]);
+            connection.write(&mut state, true, TCP_ACK, &[]);
+        }
+
+        Some(connection)
+    }
+}
+
+struct CaptureFile {
+    path: PathBuf,
+    max_size: u64,
+    writer: BufWriter<File>,
+    size: u64,
+    // So that a full disk is only logged once
+    failing: bool
+}
+
+impl CaptureFile {
+    // Each packet is flushed, so that the file can be opened while connections are still going
+    // This blocks, but capturing is only meant for debugging
+    fn write_packet(&mut self, packet: &[u8]) {
+        let result = self.try_write_packet(packet);
+
+        match result {
+            Ok(()) => self.failing = false,
+            Err(err) => {
+                if !self.failing {
+                    log::error!("Can not write capture to {}: {}", self.path.display(), err);
+                }
+
+                self.failing = true;
+            }
+        }
+    }
+
+    fn try_write_packet(&mut self, packet: &[u8]) -> Result<(), Error> {
+        let block = enhanced_packet_block(packet);
+
+        if self.size > FILE_HEADER_SIZE && self.size + block.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        self.writer.write_all(&block)?;
+        self.writer.flush()?;
+        self.size += block.len() as u64;
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<(), Error> {
+        self.writer.flush()?;
+
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        rename(&self.path, &rotated)?;
+
+        self.writer = create(&self.path)?;
+        self.size = FILE_HEADER_SIZE;
+
+        log::debug!("Rotated capture to {}", PathBuf::from(rotated).display());
+
+        Ok(())
+    }
+}
+
+fn create(path: &Path) -> Result<BufWriter<File>, Error> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(&file_header())?;
+    writer.flush()?;
+    Ok(writer)
+}
+
+// A section header with no options, and one interface for raw IP packets of any length
+fn file_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(FILE_HEADER_SIZE as usize);
+
+    header.extend_from_slice(&SECTION_HEADER_BLOCK.to_le_bytes());
+    header.extend_from_slice(&28u32.to_le_bytes());
+    header.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&0u16.to_le_bytes());
+    // The section's length isn't known
+    header.extend_from_slice(&u64::MAX.to_le_bytes());
+    header.extend_from_slice(&28u32.to_le_bytes());
+
+    header.extend_from_slice(&INTERFACE_DESCRIPTION_BLOCK.to_le_bytes());
+    header.extend_from_slice(&20u32.to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    header.extend_from_slice(&0u16.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&20u32.to_le_bytes());
+
+    header
+}
+
+// Timestamped in microseconds, which is the default resolution
+fn enhanced_packet_block(packet: &[u8]) -> Vec<u8> {
+    let padded_len = (packet.len() + 3) & !3;
+    let block_len = (32 + padded_len) as u32;
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+
+    let mut block = Vec::with_capacity(block_len as usize);
+    block.extend_from_slice(&ENHANCED_PACKET_BLOCK.to_le_bytes());
+    block.extend_from_slice(&block_len.to_le_bytes());
+    block.extend_from_slice(&0u32.to_le_bytes());
+    block.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+    block.extend_from_slice(&(timestamp as u32).to_le_bytes());
+    block.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    block.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    block.extend_from_slice(packet);
+    block.resize(32 - 4 + padded_len, 0);
+    block.extend_from_slice(&block_len.to_le_bytes());
+
+    block
+}
+
+// The next sequence number from each end, and which ends have sent FIN
+#[derive(Default)]
+struct ConnectionState {
+    client_sequence: u32,
+    server_sequence: u32,
+    client_closed: bool,
+    server_closed: bool
+}
+
+// One captured connection. Both directions of the bridge write to it
+pub struct CaptureConnection {
+    file: Arc<Mutex<CaptureFile>>,
+    client: SocketAddr,
+    server: SocketAddr,
+    clear_is_client: bool,
+    state: Mutex<ConnectionState>
+}
+
+impl CaptureConnection {
+    // Data read from the clear stream
+    pub fn clear_data(&self, data: &[u8]) {
+        self.data(self.clear_is_client, data);
+    }
+
+    // Data written to the clear stream
+    pub fn adapter_data(&self, data: &[u8]) {
+        self.data(!self.clear_is_client, data);
+    }
+
+    // The clear stream closed for writing
+    pub fn clear_fin(&self) {
+        self.fin(self.clear_is_client);
+    }
+
+    // The other side's clear stream closed for writing
+    pub fn adapter_fin(&self) {
+        self.fin(!self.clear_is_client);
+    }
+
+    // Ends with FIN from whichever ends haven't closed yet
+    pub fn close(&self) {
+        self.fin(true);
+        self.fin(false);
+    }
+
+    // The bridge resets the clear stream, so the reset comes from the other end
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        self.write(&mut state, !self.clear_is_client, TCP_RST | TCP_ACK, &[]);
+    }
+
+    fn data(&self, from_client: bool, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        for segment in data.chunks(MAX_SEGMENT_SIZE) {
+            self.write(&mut state, from_client, TCP_PSH | TCP_ACK, segment);
+        }
+    }
+
+    fn fin(&self, from_client: bool) {
+        let mut state = self.state.lock().unwrap();
+        let closed = match from_client {
+            true => &mut state.client_closed,
+            false => &mut state.server_closed
+        };
+
+        if !*closed {
+            *closed = true;
+            self.write(&mut state, from_client, TCP_FIN | TCP_ACK, &[]);
+        }
+    }
+
+    // SYN and FIN count as a byte of the sequence
+    fn write(&self, state: &mut ConnectionState, from_client: bool, flags: u8, payload: &[u8]) {
+        let (source, destination, sequence, acknowledgment) = match from_client {
+            true => (self.client, self.server, state.client_sequence, state.server_sequence),
+            false => (self.server, self.client, state.server_sequence, state.client_sequence)
+        };
+
+        let acknowledgment = match flags & TCP_ACK {
+            0 => 0,
+            _ => acknowledgment
+        };
+
+        let packet = tcp_packet(source, destination, sequence, acknowledgment, flags, payload);
+        self.file.lock().unwrap().write_packet(&packet);
+
+        let mut length = payload.len() as u32;
+        if flags & (TCP_SYN | TCP_FIN) != 0 {
+            length += 1;
+        }
+
+        match from_client {
+            true => state.client_sequence = state.client_sequence.wrapping_add(length),
+            false => state.server_sequence = state.server_sequence.wrapping_add(length)
+        }
+    }
+}
+
+// When only one of the addresses is IPv6, both are written as IPv6
+fn tcp_packet(source: SocketAddr, destination: SocketAddr, sequence: u32, acknowledgment: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+    let mut tcp = Vec::with_capacity(20 + payload.len());
+    tcp.extend_from_slice(&source.port().to_be_bytes());
+    tcp.extend_from_slice(&destination.port().to_be_bytes());
+    tcp.extend_from_slice(&sequence.to_be_bytes());
+    tcp.extend_from_slice(&acknowledgment.to_be_bytes());
+    // 5 words of header, and no options
+    tcp.push(5 << 4);
+    tcp.push(flags);
+    tcp.extend_from_slice(&u16::MAX.to_be_bytes());
+    tcp.extend_from_slice(&[0, 0, 0, 0]);
+    tcp.extend_from_slice(payload);
+
+    let tcp_len = tcp.len();
+
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            let mut pseudo_header = Vec::with_capacity(12);
+            pseudo_header.extend_from_slice(&source.octets());
+            pseudo_header.extend_from_slice(&destination.octets());
+            pseudo_header.extend_from_slice(&[0, 6]);
+            pseudo_header.extend_from_slice(&(tcp_len as u16).to_be_bytes());
+            let tcp_checksum = checksum(&[&pseudo_header, &tcp]);
+            tcp[16..18].copy_from_slice(&tcp_checksum.to_be_bytes());
+
+            let mut packet = Vec::with_capacity(20 + tcp_len);
+            packet.push(0x45);
+            packet.push(0);
+            packet.extend_from_slice(&((20 + tcp_len) as u16).to_be_bytes());
+            // No ID, and don't fragment
+            packet.extend_from_slice(&[0, 0, 0x40, 0]);
+            packet.push(64);
+            packet.push(6);
+            packet.extend_from_slice(&[0, 0]);
+            packet.extend_from_slice(&source.octets());
+            packet.extend_from_slice(&destination.octets());
+            let ip_checksum = checksum(&[&packet]);
+            packet[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+
+            packet.extend_from_slice(&tcp);
+            packet
+        },
+        (source, destination) => {
+            let source = to_ipv6(source).octets();
+            let destination = to_ipv6(destination).octets();
+
+            let mut pseudo_header = Vec::with_capacity(40);
+            pseudo_header.extend_from_slice(&source);
+            pseudo_header.extend_from_slice(&destination);
+            pseudo_header.extend_from_slice(&(tcp_len as u32).to_be_bytes());
+            pseudo_header.extend_from_slice(&[0, 0, 0, 6]);
+            let tcp_checksum = checksum(&[&pseudo_header, &tcp]);
+            tcp[16..18].copy_from_slice(&tcp_checksum.to_be_bytes());
+
+            let mut packet = Vec::with_capacity(40 + tcp_len);
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(tcp_len as u16).to_be_bytes());
+            packet.push(6);
+            packet.push(64);
+            packet.extend_from_slice(&source);
+            packet.extend_from_slice(&destination);
+
+            packet.extend_from_slice(&tcp);
+            packet
+        }
+    }
+}
+
+fn to_ipv6(address: IpAddr) -> std::net::Ipv6Addr {
+    match address {
+        IpAddr::V4(address) => address.to_ipv6_mapped(),
+        IpAddr::V6(address) => address
+    }
+}
+
+// The internet checksum. Only the last part can have an odd length
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        for word in part.chunks(2) {
+            let word = match word {
+                [high, low] => u16::from_be_bytes([*high, *low]),
+                [high] => u16::from_be_bytes([*high, 0]),
+                _ => 0
+            };
+
+            sum += word as u32;
+        }
+    }
+
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+// A comma-separated list of public ports
+pub fn parse_ports(ports_str: &str) -> Result<Vec<u16>, Error> {
+    ports_str.split(',').map(|port_str| match port_str.trim().parse::<u16>() {
+        Ok(port) if port > 0 => Ok(port),
+        _ => Err(Error::other(format!("Invalid port in capture-ports: \"{}\"", port_str)))
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{read, remove_file};
+
+    use super::*;
+
+    fn capture_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("bounce-capture-{}-{}.pcapng", name, std::process::id()))
+    }
+
+    fn u32_at(buf: &[u8], position: usize) -> u32 {
+        u32::from_le_bytes([buf[position], buf[position + 1], buf[position + 2], buf[position + 3]])
+    }
+
+    // The packet in each enhanced packet block, after the file header
+    fn packets(file: &[u8]) -> Vec<Vec<u8>> {
+        assert_eq!(SECTION_HEADER_BLOCK, u32_at(file, 0));
+        assert_eq!(INTERFACE_DESCRIPTION_BLOCK, u32_at(file, 28));
+
+        let mut packets = Vec::new();
+        let mut position = FILE_HEADER_SIZE as usize;
+        while position < file.len() {
+            assert_eq!(ENHANCED_PACKET_BLOCK, u32_at(file, position));
+            let block_len = u32_at(file, position + 4) as usize;
+            assert_eq!(block_len as u32, u32_at(file, position + block_len - 4));
+
+            let packet_len = u32_at(file, position + 20) as usize;
+            packets.push(file[position + 28..position + 28 + packet_len].to_vec());
+            position += block_len;
+        }
+
+        assert_eq!(file.len(), position);
+        packets
+    }
+
+    #[test]
+    fn capture() {
+        let path = capture_path("connection");
+        let capture = Capture::open(CaptureOptions {
+            path: path.clone(),
+            ports: vec![443],
+            max_size: DEFAULT_MAX_SIZE
+        }).unwrap();
+
+        let client: SocketAddr = "192.0.2.1:50000".parse().unwrap();
+        let server: SocketAddr = "192.0.2.2:443".parse().unwrap();
+
+        assert!(capture.connection(Some(client), Some("192.0.2.2:80".parse().unwrap()), true).is_none());
+        assert!(capture.connection(Some(client), None, true).is_none());
+
+        // On the bounce client, the clear stream is the server's end
+        let connection = capture.connection(Some(client), Some(server), false).unwrap();
+        connection.adapter_data(b"request");
+        connection.clear_data(b"response");
+        connection.adapter_fin();
+        connection.close();
+        drop(connection);
+
+        let packets = packets(&read(&path).unwrap());
+        remove_file(&path).unwrap();
+
+        let flags: Vec<u8> = packets.iter().map(|packet| packet[33]).collect();
+        assert_eq!(vec![TCP_SYN, TCP_SYN | TCP_ACK, TCP_ACK, TCP_PSH | TCP_ACK, TCP_PSH | TCP_ACK, TCP_FIN | TCP_ACK, TCP_FIN | TCP_ACK], flags);
+
+        for packet in &packets {
+            assert_eq!(0x45, packet[0]);
+            assert_eq!(packet.len(), u16::from_be_bytes([packet[2], packet[3]]) as usize);
+            assert_eq!(0, checksum(&[&packet[..20]]));
+        }
+
+        let request = &packets[3];
+        assert_eq!(&[192, 0, 2, 1], &request[12..16]);
+        assert_eq!(&443u16.to_be_bytes(), &request[22..24]);
+        assert_eq!(b"request", &request[40..]);
+        // After the SYN
+        assert_eq!(1, u32::from_be_bytes([request[24], request[25], request[26], request[27]]));
+
+        let response = &packets[4];
+        assert_eq!(&[192, 0, 2, 2], &response[12..16]);
+        assert_eq!(b"response", &response[40..]);
+        // Acknowledges the request
+        assert_eq!(8, u32::from_be_bytes([response[28], response[29], response[30], response[31]]));
+
+        let mut pseudo_header = Vec::new();
+        pseudo_header.extend_from_slice(&request[12..20]);
+        pseudo_header.extend_from_slice(&[0, 6]);
+        pseudo_header.extend_from_slice(&((request.len() - 20) as u16).to_be_bytes());
+        assert_eq!(0, checksum(&[&pseudo_header, &request[20..]]));
+    }
+
+    #[test]
+    fn segments() {
+        let path = capture_path("segments");
+        let capture = Capture::open(CaptureOptions {
+            path: path.clone(),
+            ports: Vec::new(),
+            max_size: DEFAULT_MAX_SIZE
+        }).unwrap();
+
+        let data: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+        let connection = capture.connection(Some("[2001:db8::1]:50000".parse().unwrap()), Some("[2001:db8::2]:443".parse().unwrap()), true).unwrap();
+        connection.clear_data(&data);
+        drop(connection);
+
+        let packets = packets(&read(&path).unwrap());
+        remove_file(&path).unwrap();
+
+        // After the handshake
+        let segments = &packets[3..];
+        assert_eq!(data.len().div_ceil(MAX_SEGMENT_SIZE), segments.len());
+
+        let mut sequence = 1;
+        let mut captured = Vec::new();
+        for segment in segments {
+            let payload_len = u16::from_be_bytes([segment[4], segment[5]]) as usize - 20;
+            assert_eq!(segment.len(), 40 + 20 + payload_len);
+            assert!(payload_len <= MAX_SEGMENT_SIZE);
+
+            assert_eq!(sequence, u32::from_be_bytes([segment[44], segment[45], segment[46], segment[47]]));
+            sequence += payload_len as u32;
+            captured.extend_from_slice(&segment[60..]);
+        }
+
+        assert_eq!(data, captured);
+    }
+
+    #[test]
+    fn ipv6() {
+        let packet = tcp_packet("[2001:db8::1]:50000".parse().unwrap(), "192.0.2.2:443".parse().unwrap(), 1, 1, TCP_PSH | TCP_ACK, b"data");
+        assert_eq!(0x60, packet[0]);
+        assert_eq!(24, u16::from_be_bytes([packet[4], packet[5]]));
+        assert_eq!(&"::ffff:192.0.2.2".parse::<std::net::Ipv6Addr>().unwrap().octets(), &packet[24..40]);
+        assert_eq!(b"data", &packet[60..]);
+    }
+
+    #[test]
+    fn rotation() {
+        let path = capture_path("rotation");
+        let mut rotated = path.clone().into_os_string();
+        rotated.push(".1");
+
+        let capture = Capture::open(CaptureOptions {
+            path: path.clone(),
+            ports: Vec::new(),
+            max_size: 1024
+        }).unwrap();
+
+        let connection = capture.connection(None, None, true).unwrap();
+        connection.clear_data(&[1u8; 600]);
+        connection.clear_data(&[2u8; 600]);
+
+        let rotated_packets = packets(&read(&rotated).unwrap());
+        let packets = packets(&read(&path).unwrap());
+        remove_file(&path).unwrap();
+        remove_file(&rotated).unwrap();
+
+        assert_eq!(4, rotated_packets.len());
+        assert_eq!(1, packets.len());
+        assert_eq!(2, packets[0][40]);
+        assert!(read(&path).is_err());
+    }
+
+    #[test]
+    fn settings() {
+        assert_eq!(vec![80, 443], parse_ports("80, 443").unwrap());
+        parse_ports("80,http").expect_err("Not a port");
+    }
+}
@@ -1,113 +1,414 @@
-use async_std::net::{Shutdown, TcpStream};
+use async_std::io;
+use async_std::net::{Shutdown, SocketAddr, UdpSocket};
 use async_std::prelude::*;
 use async_std::task;
 use async_std::task::JoinHandle;
+use futures::FutureExt;
+use rand_chacha::ChaCha12Rng;
 use std::io::{ Error, ErrorKind };
+use std::time::Instant;
 use sync_tokens::cancelation_token::{ CancelationToken, Cancelable };
 
+use crate::address::bind_tcp;
 use crate::auth::authenticate;
-use crate::bridge::run_bridge;
+use crate::backoff::{Backoff, ReconnectOptions};
+use crate::bandwidth::{BandwidthOptions, log_throughput};
+use crate::bridge::{BridgeOptions, ConnectionTimeouts, run_bridge};
+use crate::buffer_pool::BufferPool;
+use crate::capture::Capture;
+use crate::compression::Compression;
+use crate::connection_id::{ConnectionId, connection_id, set_connection_id};
+use crate::connection_info::read_connection_info;
+use crate::datagram::{DatagramBridgeOptions, connect_datagram_destination, run_datagram_destination_bridge};
+use crate::http_proxy::{self, HttpProxy};
+use crate::inspector::{Inspector, InspectorOptions, serve_inspector};
 use crate::keys::Key;
+use crate::metrics::{Metrics, serve_metrics};
+use crate::proxy_protocol::{ProxyProtocol, proxy_header};
+use crate::shutdown::ActiveConnections;
+use crate::signal::{CONNECTED, DATAGRAMS, DESTINATION_CONNECT_TIMEOUT, GOINGAWAY, HEARTBEAT, HeartbeatOptions, Heartbeats, SIGNAL_LENGTH, Signal, UNAVAILABLE};
+use crate::stream::Stream;
+use crate::websocket;
+use crate::xor::Xors;
+
+#[derive(Default)]
+pub struct ClientOptions {
+    // When set, a PROXY protocol header with the original peer's address is sent to the destination
+    pub proxy_protocol: Option<ProxyProtocol>,
+    pub heartbeat: HeartbeatOptions,
+    // How to retry when the bounce server can't be reached
+    pub reconnect: ReconnectOptions,
+    // Bridged connections are counted here, so that shutdown can wait for them
+    pub connections: ActiveConnections,
+    // When set, the adapter stream goes through this proxy with HTTP CONNECT
+    pub proxy: Option<HttpProxy>,
+    // Compressions to offer the server for the adapter stream. Empty for none
+    pub compression: Vec<Compression>,
+    pub bandwidth: BandwidthOptions,
+    pub timeouts: ConnectionTimeouts,
+    // Shared by all of the bridged connections
+    pub buffers: BufferPool,
+    pub metrics: Metrics,
+    // When set, metrics are served for Prometheus at /metrics on this address
+    pub metrics_address: Option<SocketAddr>,
+    // When set, the clear side of bridged connections is written here
+    pub capture: Option<Capture>,
+    // When set, HTTP requests and responses are kept, and served on a local web page
+    pub inspector: Option<InspectorOptions>
+}
 
-pub fn run_client(bounce_server: String, destination_host: String, key: Key) -> (JoinHandle<Result<(), Error>>, CancelationToken) {
+pub fn run_client(bounce_server: String, destination_host: String, key: Key, options: ClientOptions) -> (JoinHandle<Result<(), Error>>, CancelationToken) {
     let (cancelation_token, cancelable) = CancelationToken::new();
-    let client_future = task::spawn(run_client_int(bounce_server, destination_host, key, cancelable));
+    let client_future = task::spawn(async move {
+        let metrics_listener = match options.metrics_address {
+            Some(metrics_address) => Some(bind_tcp(metrics_address)?),
+            None => None
+        };
+
+        let (inspector, inspector_listener) = match &options.inspector {
+            Some(inspector_options) => (Some(Inspector::new(inspector_options.max_exchanges, inspector_options.max_body_size)), Some(bind_tcp(inspector_options.address)?)),
+            None => (None, None)
+        };
+
+        let metrics_task = metrics_listener.map(|listener| task::spawn(serve_metrics(listener, options.metrics.clone(), options.connections.clone(), destination_host.clone())));
+        let inspector_task = inspector.clone().zip(inspector_listener).map(|(inspector, listener)| task::spawn(serve_inspector(listener, inspector, destination_host.clone())));
+        let throughput_task = task::spawn(log_throughput(options.bandwidth.shared.clone()));
+        let result = run_client_int(bounce_server, destination_host, key, options, inspector, cancelable).await;
+        throughput_task.cancel().await;
+
+        if let Some(metrics_task) = metrics_task {
+            metrics_task.cancel().await;
+        }
+
+        if let Some(inspector_task) = inspector_task {
+            inspector_task.cancel().await;
+        }
+
+        result
+    });
 
     (client_future, cancelation_token)
 }
 
-async fn run_client_int(bounce_server: String, destination_host: String, key: Key, cancelable: Cancelable) -> Result<(), Error> {
+async fn run_client_int(bounce_server: String, destination_host: String, key: Key, options: ClientOptions, inspector: Option<Inspector>, cancelable: Cancelable) -> Result<(), Error> {
     log::info!("Bounce client: Connecting to bounce server at {}, bouncing to {}", bounce_server, destination_host);
 
-    let connected = b"connected".to_vec();
+    let mut backoff = Backoff::new(options.reconnect);
+
+    // A server that closes the adapter stream before sending anything, even a heartbeat, isn't really reachable
+    // So that the client doesn't reconnect to it in a tight loop, that counts as a failure too
+    let mut awaiting_first_signal = false;
 
     'client_loop: loop {
-        let mut bounce_stream = TcpStream::connect(bounce_server.clone()).await?;
+        set_connection_id(None);
+
+        if awaiting_first_signal {
+            awaiting_first_signal = false;
+
+            let err = Error::new(ErrorKind::ConnectionAborted, "The connection ended before the server sent anything");
+            wait_to_retry(&mut backoff, &bounce_server, err, &cancelable).await?;
+        }
 
-        let xors = authenticate(key.clone(), bounce_stream.clone()).await?;
+        let connect_future = Box::pin(connect_and_authenticate(&bounce_server, &key, options.proxy.as_ref(), &options.compression, &options.metrics));
+        let (mut bounce_stream, mut xors, compression) = match cancelable.allow_cancel(connect_future, Err(Error::new(ErrorKind::Interrupted, "Canceled"))).await {
+            Ok(connected) => {
+                awaiting_first_signal = true;
+                connected
+            },
+            Err(err) if err.kind() == ErrorKind::Interrupted => return Err(err),
+            Err(err) => {
+                wait_to_retry(&mut backoff, &bounce_server, err, &cancelable).await?;
+                continue 'client_loop;
+            }
+        };
 
-        let mut buf = vec!(0u8; connected.len());
-        let mut read = 0;
+        // Each pass waits for the server to start a connection. When the destination is unavailable, the server is told and the adapter stream is reused
+        'connection_loop: loop {
+            // Until the server starts the next connection, log lines aren't about any one connection
+            set_connection_id(None);
+            let mut heartbeats = Heartbeats::new(options.heartbeat);
+
+            let mut buf = [0u8; SIGNAL_LENGTH];
+            let mut read = 0;
+
+            // Waits for the server to start a connection, while sending heartbeats and watching for the server's heartbeats
+            'read_loop: loop {
+                let read_future = Box::pin(io::timeout(heartbeats.until_due(), bounce_stream.read(&mut buf[read..])));
+                let r = match cancelable.allow_cancel(read_future, Err(Error::new(ErrorKind::Interrupted, "Canceled"))).await {
+                    Err(err) if err.kind() == ErrorKind::TimedOut => {
+                        if heartbeats.missed() {
+                            log::error!("Bounce server {} stopped sending heartbeats", bounce_server);
+                            shutdown_bounce_stream(&bounce_stream, Shutdown::Both);
+                            continue 'client_loop;
+                        }
+
+                        if heartbeats.send_due() {
+                            if let Err(err) = heartbeats.send(&mut bounce_stream).await {
+                                log::error!("Can not send heartbeat to bounce server {}: {}", bounce_server, err);
+                                shutdown_bounce_stream(&bounce_stream, Shutdown::Both);
+                                continue 'client_loop;
+                            }
+                        }
+
+                        continue 'read_loop;
+                    },
+                    Err(err) if err.kind() == ErrorKind::Interrupted => return Err(err),
+                    Err(err) => {
+                        log::error!("Can not read from bounce server {}: {}", bounce_server, err);
+                        shutdown_bounce_stream(&bounce_stream, Shutdown::Both);
+
+                        // Waits out the backoff here, instead of at the top of the loop, so that it's only counted once
+                        awaiting_first_signal = false;
+                        wait_to_retry(&mut backoff, &bounce_server, err, &cancelable).await?;
+                        continue 'client_loop;
+                    },
+                    Ok(r) => r
+                };
+
+                if r == 0 {
+                    log::error!("Connection to bounce server {} ended", bounce_server);
+                    shutdown_bounce_stream(&bounce_stream, Shutdown::Write);
+                    continue 'client_loop;
+                }
+
+                read += r;
+
+                if read >= SIGNAL_LENGTH {
+                    if awaiting_first_signal {
+                        awaiting_first_signal = false;
+
+                        if backoff.failures() > 0 {
+                            log::info!("Reconnected to bounce server {}", bounce_server);
+                        }
+
+                        backoff.reset();
+                    }
+
+                    if &buf == HEARTBEAT {
+                        log::trace!("Heartbeat from bounce server {}", bounce_server);
+                        heartbeats.received();
+                        read = 0;
+                    } else {
+                        break 'read_loop;
+                    }
+                }
+            }
 
-        'read_loop: loop {
-            let read_future = bounce_stream.read(&mut buf[read..]);
-            let r = cancelable.allow_cancel(
-                read_future,
-                Err(Error::new(ErrorKind::Interrupted, "Canceled"))).await?;
+            if &buf == GOINGAWAY {
+                log::info!("Bounce server {} is going away, reconnecting", bounce_server);
+                shutdown_bounce_stream(&bounce_stream, Shutdown::Both);
+                continue 'client_loop;
+            }
 
-            if r == 0 {
-                log::error!("Connection to bounce server {} ended", bounce_server);
-                bounce_stream.shutdown(Shutdown::Write)?;
+            if &buf != CONNECTED && &buf != DATAGRAMS {
+                log::error!("Bounce server did not initiate the connection correctly");
+                shutdown_bounce_stream(&bounce_stream, Shutdown::Both);
                 continue 'client_loop;
             }
 
-            read = read + r;
+            let connection_info = match read_connection_info(&mut xors.read_xor, &mut bounce_stream).await {
+                Err(err) => {
+                    log::error!("Bounce server sent invalid connection info: {}", err);
+                    shutdown_bounce_stream(&bounce_stream, Shutdown::Both);
+                    continue 'client_loop;
+                },
+                Ok(connection_info) => connection_info
+            };
+
+            // Older servers don't send an ID, but connections should still be told apart
+            set_connection_id(Some(connection_info.id.unwrap_or_else(ConnectionId::random)));
+
+            let destination = match io::timeout(DESTINATION_CONNECT_TIMEOUT, connect_destination(&buf, &destination_host)).await {
+                Err(err) => {
+                    log::error!("Can not connect to host \"{}\" for {:?}: {}", destination_host, connection_info.peer_addr, err);
+
+                    if let Err(err) = bounce_stream.write_all(UNAVAILABLE).await {
+                        log::error!("Can not report unavailable destination to bounce server {}: {}", bounce_server, err);
+                        shutdown_bounce_stream(&bounce_stream, Shutdown::Both);
+                        continue 'client_loop;
+                    }
+
+                    continue 'connection_loop;
+                },
+                Ok(destination) => destination
+            };
+
+            // Acknowledging the signal tells the server that no more heartbeats will come before the bridged data
+            if let Err(err) = bounce_stream.write_all(&buf).await {
+                log::error!("Can not acknowledge connection to bounce server {}: {}", bounce_server, err);
+                shutdown_bounce_stream(&bounce_stream, Shutdown::Both);
+                continue 'client_loop;
+            }
 
-            if read >= connected.len() {
-                break 'read_loop;
+            match destination {
+                Destination::Datagrams(socket) => {
+                    log::info!("Bridging datagram session from {:?}", connection_info.peer_addr);
+
+                    let datagram_options = DatagramBridgeOptions {
+                        bandwidth: options.bandwidth.connection(),
+                        timeouts: options.timeouts,
+                        metrics: options.metrics.clone()
+                    };
+
+                    run_datagram_destination_bridge(xors, datagram_options, socket, destination_host.clone(), bounce_stream.clone(), options.connections.track());
+                },
+                Destination::Stream(mut destination_stream) => {
+                    if let Some(proxy_protocol) = options.proxy_protocol {
+                        if let Err(err) = destination_stream.write_all(&proxy_header(proxy_protocol, &connection_info)).await {
+                            log::error!("Can not send PROXY protocol header to host \"{}\": {}", destination_host, err);
+                            shutdown_bounce_stream(&bounce_stream, Shutdown::Both);
+                            continue 'client_loop;
+                        }
+                    }
+
+                    log::info!("Bridging connection from {:?}", connection_info.peer_addr);
+
+                    let bridge_options = BridgeOptions {
+                        compression,
+                        bandwidth: options.bandwidth.connection(),
+                        timeouts: options.timeouts,
+                        buffers: options.buffers.clone(),
+                        metrics: options.metrics.clone(),
+                        // Captured with the public addresses, so that it looks the same as on the server
+                        capture: options.capture.as_ref().and_then(|capture| capture.connection(connection_info.peer_addr, connection_info.local_addr, false)),
+                        inspector: inspector.as_ref().map(|inspector| inspector.connection(connection_id(), false))
+                    };
+
+                    run_bridge(xors, bridge_options, destination_stream, "outgoing".to_string(), bounce_stream, "bounce-incoming".to_string(), options.connections.track());
+                }
             }
-        }
 
-        if connected != buf {
-            log::error!("Bounce server did not initiate the connection correctly");
-            bounce_stream.shutdown(Shutdown::Both)?;
             continue 'client_loop;
         }
+    }
+}
 
-        match TcpStream::connect(destination_host.clone()).await {
-            Err(err) => {
-                log::error!("Can not connect to host \"{}\": {}", destination_host, err);
-                break 'client_loop;
-            },
-            Ok(destination_stream) => {
+enum Destination {
+    Stream(Stream),
+    Datagrams(UdpSocket)
+}
 
-                log::info!("Bridging connection");
+async fn connect_destination(signal: &Signal, destination_host: &str) -> Result<Destination, Error> {
+    if signal == DATAGRAMS {
+        Ok(Destination::Datagrams(connect_datagram_destination(destination_host).await?))
+    } else {
+        Ok(Destination::Stream(Stream::connect(destination_host).await?))
+    }
+}
 
-                run_bridge(xors, destination_stream, "outgoing".to_string(), bounce_stream, "bounce-incoming".to_string());
-            }
-        }        
+// Closing an adapter stream that the server already closed or reset isn't a problem
+fn shutdown_bounce_stream(bounce_stream: &Stream, shutdown: Shutdown) {
+    match bounce_stream.shutdown(shutdown) {
+        Ok(()) => {},
+        Err(err) if err.kind() == ErrorKind::NotConnected => log::debug!("The adapter stream was already closed"),
+        Err(err) => log::error!("Error shutting down the adapter stream: {}", err)
     }
+}
 
-    Ok(())
+// Waits out the backoff after a failure, or returns the error once it's time to give up
+async fn wait_to_retry(backoff: &mut Backoff, bounce_server: &str, err: Error, cancelable: &Cancelable) -> Result<(), Error> {
+    match backoff.next_delay() {
+        Some(delay) => {
+            log::warn!("Can not connect to bounce server {} (attempt {}): {}. Retrying in {:.1}s", bounce_server, backoff.failures(), err, delay.as_secs_f64());
+
+            let sleep_future = Box::pin(task::sleep(delay).map(Ok));
+            cancelable.allow_cancel(sleep_future, Err(Error::new(ErrorKind::Interrupted, "Canceled"))).await
+        },
+        None => {
+            log::error!("Giving up on bounce server {} after {} retries", bounce_server, backoff.failures());
+            Err(err)
+        }
+    }
+}
+
+async fn connect_and_authenticate(bounce_server: &str, key: &Key, proxy: Option<&HttpProxy>, compressions: &[Compression], metrics: &Metrics) -> Result<(Stream, Xors<ChaCha12Rng>, Option<Compression>), Error> {
+    let bounce_stream = connect_bounce_server(bounce_server, proxy).await?;
+
+    let started = Instant::now();
+    let authenticated = authenticate(key.clone(), bounce_stream.clone(), compressions).await;
+    metrics.handshake(authenticated.is_ok(), started.elapsed());
+    let (xors, compression) = authenticated?;
+
+    if let Some(compression) = compression {
+        log::debug!("Compressing the adapter stream with {:?}", compression);
+    }
+
+    Ok((bounce_stream, xors, compression))
+}
+
+// The bounce server is either "host:port", or a ws:// or wss:// URL when the adapter stream goes through an HTTP router
+pub async fn connect_bounce_server(bounce_server: &str, proxy: Option<&HttpProxy>) -> Result<Stream, Error> {
+    if websocket::is_websocket_url(bounce_server) {
+        Ok(websocket::connect(bounce_server, proxy).await?.into())
+    } else {
+        Ok(http_proxy::connect(bounce_server, proxy).await?.into())
+    }
 }
 
 // Note: Tests are error conditions only, happy-path tests will be handled in general integration tests
 #[cfg(test)]
 mod tests {
-    use async_std::net::{IpAddr, Ipv4Addr, Shutdown, TcpListener, SocketAddr};
+    use async_std::net::{IpAddr, Ipv4Addr, Shutdown, TcpListener, TcpStream, SocketAddr};
     use async_std::prelude::*;
     use async_std::task::JoinHandle;
     use std::io::{Error, ErrorKind};
 
+    use core::time::Duration;
     use crypto::aes::KeySize;
 
+    use crate::connection_info::{ConnectionInfo, write_connection_info};
+    use crate::signal::read_signal;
+
     use super::*;
 
-    async fn get_server_stream_and_client_future() -> (TcpStream, JoinHandle<Result<(), Error>>) {
-        let key = Key {
-            key: vec![1 as u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32],
+    fn get_key() -> Key {
+        Key {
+            key: vec![1u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32],
             size: KeySize::KeySize256
+        }
+    }
+
+    // Retries quickly, and then gives up, so that tests can observe the error
+    fn fast_reconnect(max_retries: Option<u32>) -> ReconnectOptions {
+        ReconnectOptions {
+            initial_interval: Duration::from_millis(10),
+            max_interval: Duration::from_millis(50),
+            max_retries,
+            ..ReconnectOptions::default()
+        }
+    }
+
+    async fn get_server_stream_and_client_future() -> (TcpStream, Xors<ChaCha12Rng>, JoinHandle<Result<(), Error>>) {
+        let options = ClientOptions {
+            reconnect: fast_reconnect(Some(2)),
+            ..ClientOptions::default()
         };
 
+        get_server_stream_and_client_future_with_options(options).await
+    }
+
+    async fn get_server_stream_and_client_future_with_options(options: ClientOptions) -> (TcpStream, Xors<ChaCha12Rng>, JoinHandle<Result<(), Error>>) {
+        let key = get_key();
+
         let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
         let listener = TcpListener::bind(socket_addr).await.unwrap();
 
         let local_addr = listener.local_addr().unwrap();
 
-        let (client_future, _) = run_client(local_addr.to_string(), "no destination".to_string(), key.clone());
+        let (client_future, _) = run_client(local_addr.to_string(), "no destination".to_string(), key.clone(), options);
 
         let server_stream = listener.incoming().next().await.unwrap().expect("Did not get incoming connection from the client");
         drop(listener);
 
-        authenticate(key, server_stream.clone()).await.expect("Can not authenticate server stream");
+        let (xors, _) = authenticate(key, server_stream.clone(), &[]).await.expect("Can not authenticate server stream");
 
-        (server_stream, client_future)
+        (server_stream, xors, client_future)
     }
 
     #[async_std::test]
     async fn server_drops_connection() {
 
-        let (server_stream, client_future) = get_server_stream_and_client_future().await;
+        let (server_stream, _, client_future) = get_server_stream_and_client_future().await;
 
         server_stream.shutdown(Shutdown::Both).expect("Can not shut down server stream");
 
@@ -119,7 +420,7 @@ mod tests {
     #[async_std::test]
     async fn server_sends_incorrect_token() {
 
-        let (mut server_stream, client_future) = get_server_stream_and_client_future().await;
+        let (mut server_stream, _, client_future) = get_server_stream_and_client_future().await;
 
         server_stream.write_all(b"xxxxxxxxx").await.expect("Can not send incorrect data");
 
@@ -129,5 +430,191 @@ mod tests {
 
         server_stream.shutdown(Shutdown::Write).expect("Can not shut down server stream");
     }
-}
 
+    #[async_std::test]
+    async fn server_stops_heartbeats() {
+
+        let options = ClientOptions {
+            heartbeat: HeartbeatOptions {
+                interval: Duration::from_millis(100),
+                misses: 2
+            },
+            reconnect: fast_reconnect(Some(2)),
+            ..ClientOptions::default()
+        };
+
+        let (mut server_stream, _, client_future) = get_server_stream_and_client_future_with_options(options).await;
+
+        // The client sends heartbeats, and then gives up on the server
+        let mut buf = Vec::new();
+        io::timeout(Duration::from_secs(5), server_stream.read_to_end(&mut buf)).await.expect("Client did not close the connection");
+
+        assert!(!buf.is_empty(), "No heartbeats sent");
+        for signal in buf.chunks(SIGNAL_LENGTH) {
+            assert_eq!(HEARTBEAT, signal, "Unexpected data from the client");
+        }
+
+        let err = client_future.await.expect_err("The client should end in error");
+
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[async_std::test]
+    async fn destination_unavailable() {
+
+        let (mut server_stream, mut xors, client_future) = get_server_stream_and_client_future().await;
+
+        // The client reports each connection it can't make, and keeps the bounce stream
+        for _ in 0..2 {
+            server_stream.write_all(CONNECTED).await.expect("Can not start connection");
+            write_connection_info(&mut xors.write_xor, &mut server_stream, &ConnectionInfo::default()).await.expect("Can not send connection info");
+
+            let signal = read_signal(&mut server_stream, Duration::from_secs(5)).await.expect("Client did not reply");
+            assert_eq!(UNAVAILABLE, &signal);
+        }
+
+        server_stream.shutdown(Shutdown::Both).expect("Can not shut down server stream");
+
+        let err = client_future.await.expect_err("The client should end in error");
+
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[async_std::test]
+    async fn reconnects_when_server_returns() {
+        let key = get_key();
+
+        // Reserve a port that nothing listens on yet
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let options = ClientOptions {
+            reconnect: fast_reconnect(None),
+            ..ClientOptions::default()
+        };
+
+        let (client_future, cancelation_token) = run_client(local_addr.to_string(), "no destination".to_string(), key.clone(), options);
+
+        // The client keeps retrying until the server is listening
+        task::sleep(Duration::from_millis(200)).await;
+        let listener = TcpListener::bind(local_addr).await.unwrap();
+
+        let server_stream = io::timeout(Duration::from_secs(5), listener.accept()).await.expect("Client did not reconnect").0;
+        authenticate(key, server_stream.clone(), &[]).await.expect("Can not authenticate server stream");
+
+        cancelation_token.cancel();
+        let err = client_future.await.expect_err("The client should end when canceled");
+        assert_eq!(err.kind(), ErrorKind::Interrupted);
+    }
+
+    #[async_std::test]
+    async fn server_closes_right_away() {
+        let key = get_key();
+
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+
+        let options = ClientOptions {
+            reconnect: fast_reconnect(Some(2)),
+            ..ClientOptions::default()
+        };
+
+        let (client_future, _) = run_client(local_addr.to_string(), "no destination".to_string(), key.clone(), options);
+
+        // Authenticating isn't enough to reset the backoff, so the client gives up like when it can't connect
+        for _ in 0..3 {
+            let server_stream = io::timeout(Duration::from_secs(5), listener.accept()).await.expect("Client did not reconnect").0;
+            authenticate(key.clone(), server_stream.clone(), &[]).await.expect("Can not authenticate server stream");
+            server_stream.shutdown(Shutdown::Both).expect("Can not shut down server stream");
+        }
+
+        let err = io::timeout(Duration::from_secs(5), client_future).await.expect_err("The client should give up");
+        assert_eq!(err.kind(), ErrorKind::ConnectionAborted);
+    }
+
+    #[async_std::test]
+    async fn server_resets_connection() {
+        let key = get_key();
+
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+
+        let options = ClientOptions {
+            reconnect: fast_reconnect(Some(2)),
+            ..ClientOptions::default()
+        };
+
+        let (client_future, cancelation_token) = run_client(local_addr.to_string(), "no destination".to_string(), key.clone(), options);
+
+        let mut server_stream = listener.accept().await.unwrap().0;
+        authenticate(key.clone(), server_stream.clone(), &[]).await.expect("Can not authenticate server stream");
+
+        // The client has heard from the server, so a reset is a reason to reconnect, not to stop
+        server_stream.write_all(HEARTBEAT).await.expect("Can not send heartbeat");
+        let server_stream = crate::stream::Stream::from(server_stream);
+        server_stream.set_reset_on_close().expect("Can not set the server stream to reset");
+        drop(server_stream);
+
+        let server_stream = io::timeout(Duration::from_secs(5), listener.accept()).await.expect("Client did not reconnect").0;
+        authenticate(key, server_stream.clone(), &[]).await.expect("Can not authenticate server stream");
+
+        cancelation_token.cancel();
+        let err = client_future.await.expect_err("The client should end when canceled");
+        assert_eq!(err.kind(), ErrorKind::Interrupted);
+    }
+
+    #[async_std::test]
+    async fn server_going_away() {
+        let key = get_key();
+
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+
+        // A long initial interval shows that the client reconnects without waiting
+        let options = ClientOptions {
+            reconnect: ReconnectOptions {
+                initial_interval: Duration::from_secs(60),
+                ..ReconnectOptions::default()
+            },
+            ..ClientOptions::default()
+        };
+
+        let (client_future, cancelation_token) = run_client(local_addr.to_string(), "no destination".to_string(), key.clone(), options);
+
+        let mut server_stream = listener.accept().await.unwrap().0;
+        authenticate(key.clone(), server_stream.clone(), &[]).await.expect("Can not authenticate server stream");
+
+        server_stream.write_all(GOINGAWAY).await.expect("Can not send goingaway");
+
+        let server_stream = io::timeout(Duration::from_secs(5), listener.accept()).await.expect("Client did not reconnect").0;
+        authenticate(key, server_stream.clone(), &[]).await.expect("Can not authenticate server stream");
+
+        cancelation_token.cancel();
+        let err = client_future.await.expect_err("The client should end when canceled");
+        assert_eq!(err.kind(), ErrorKind::Interrupted);
+    }
+
+    #[async_std::test]
+    async fn cancel_while_waiting_to_reconnect() {
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let options = ClientOptions {
+            reconnect: ReconnectOptions {
+                initial_interval: Duration::from_secs(60),
+                ..ReconnectOptions::default()
+            },
+            ..ClientOptions::default()
+        };
+
+        let (client_future, cancelation_token) = run_client(local_addr.to_string(), "no destination".to_string(), get_key(), options);
+
+        task::sleep(Duration::from_millis(100)).await;
+        cancelation_token.cancel();
+
+        let err = io::timeout(Duration::from_secs(5), client_future).await.expect_err("The client should end when canceled");
+        assert_eq!(err.kind(), ErrorKind::Interrupted);
+    }
+}
@@ -0,0 +1,305 @@
+use std::io::{Error, ErrorKind};
+
+use flate2::{Compress, Decompress, FlushCompress, FlushDecompress};
+use zstd::stream::raw::{Decoder, Encoder, InBuffer, Operation, OutBuffer};
+
+// zstd's default level, which is fast enough for interactive traffic
+const ZSTD_LEVEL: i32 = 3;
+
+// After a frame doesn't get smaller, this many frames are sent as-is before trying again
+// It doubles each time compression doesn't help, so that incompressible traffic (like TLS passthrough) mostly skips the compressor
+const INITIAL_SKIP_FRAMES: u32 = 16;
+const MAX_SKIP_FRAMES: u32 = 1024;
+
+// How much more output space to reserve each time the compressor runs out
+const RESERVE_SIZE: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    Deflate,
+    Zstd
+}
+
+// When both sides accept more than one, the first one here is used
+const PREFERENCE: [Compression; 2] = [Compression::Zstd, Compression::Deflate];
+
+// A comma-separated list, like "zstd,deflate", or "none"
+pub fn parse_compression(compression_str: &str) -> Result<Vec<Compression>, Error> {
+    if compression_str == "none" {
+        return Ok(Vec::new());
+    }
+
+    compression_str.split(',').map(|compression| match compression.trim() {
+        "deflate" => Ok(Compression::Deflate),
+        "zstd" => Ok(Compression::Zstd),
+        _ => Err(Error::other(format!("Unknown compression \"{}\", must be zstd, deflate, or none", compression)))
+    }).collect()
+}
+
+impl Compression {
+    fn bit(self) -> u8 {
+        match self {
+            Compression::Deflate => 1,
+            Compression::Zstd => 2
+        }
+    }
+}
+
+// Sent during the handshake, so that each side knows what the other accepts
+pub fn compression_mask(compressions: &[Compression]) -> u8 {
+    compressions.iter().fold(0, |mask, compression| mask | compression.bit())
+}
+
+// Both sides come to the same answer. No compression unless both sides accept the same one
+pub fn negotiate_compression(my_mask: u8, their_mask: u8) -> Option<Compression> {
+    PREFERENCE.iter().copied().find(|compression| my_mask & their_mask & compression.bit() != 0)
+}
+
+enum Codec {
+    Deflate(Box<Compress>),
+    Zstd(Encoder<'static>)
+}
+
+// One direction of a tunnel. The compressor keeps its history between frames, so later frames can refer back to earlier ones
+pub struct Compressor {
+    codec: Codec,
+    // Frames left to send as-is
+    skip_frames: u32,
+    // How many frames to skip the next time compression doesn't help
+    next_skip_frames: u32
+}
+
+impl Compressor {
+    pub fn new(compression: Compression) -> Result<Self, Error> {
+        let codec = match compression {
+            // Raw deflate, because the adapter stream already has framing
+            Compression::Deflate => Codec::Deflate(Box::new(Compress::new(flate2::Compression::default(), false))),
+            Compression::Zstd => Codec::Zstd(Encoder::new(ZSTD_LEVEL)?)
+        };
+
+        Ok(Compressor {
+            codec,
+            skip_frames: 0,
+            next_skip_frames: INITIAL_SKIP_FRAMES
+        })
+    }
+
+    // Returns false when data should be sent as-is, which the other side's decompressor never sees
+    // Otherwise, compressed is flushed, so that the other side can decompress all of data without waiting for more
+    pub fn compress(&mut self, data: &[u8], compressed: &mut Vec<u8>) -> Result<bool, Error> {
+        if self.skip_frames > 0 {
+            self.skip_frames -= 1;
+            return Ok(false);
+        }
+
+        compressed.clear();
+
+        match &mut self.codec {
+            Codec::Deflate(compress) => {
+                let start_in = compress.total_in();
+
+                // The flush is done when it has space left over
+                loop {
+                    compressed.reserve(RESERVE_SIZE);
+                    let consumed = (compress.total_in() - start_in) as usize;
+                    compress.compress_vec(&data[consumed..], compressed, FlushCompress::Sync).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+
+                    if (compress.total_in() - start_in) as usize == data.len() && compressed.len() < compressed.capacity() {
+                        break;
+                    }
+                }
+            },
+            Codec::Zstd(encoder) => {
+                let mut input = InBuffer::around(data);
+
+                while input.pos < data.len() {
+                    compressed.reserve(RESERVE_SIZE);
+                    encoder.run(&mut input, &mut OutBuffer::around_pos(compressed, compressed.len()))?;
+                }
+
+                loop {
+                    compressed.reserve(RESERVE_SIZE);
+                    if encoder.flush(&mut OutBuffer::around_pos(compressed, compressed.len()))? == 0 {
+                        break;
+                    }
+                }
+            }
+        }
+
+        // The compressed frame still has to be sent, because the other side's decompressor needs it for later frames
+        if compressed.len() >= data.len() {
+            self.skip_frames = self.next_skip_frames;
+            self.next_skip_frames = (self.next_skip_frames * 2).min(MAX_SKIP_FRAMES);
+        } else {
+            self.next_skip_frames = INITIAL_SKIP_FRAMES;
+        }
+
+        Ok(true)
+    }
+}
+
+pub enum Decompressor {
+    Deflate(Box<Decompress>),
+    Zstd(Decoder<'static>)
+}
+
+impl Decompressor {
+    pub fn new(compression: Compression) -> Result<Self, Error> {
+        Ok(match compression {
+            Compression::Deflate => Decompressor::Deflate(Box::new(Decompress::new(false))),
+            Compression::Zstd => Decompressor::Zstd(Decoder::new()?)
+        })
+    }
+
+    // Decompresses one frame from the other side's Compressor
+    // Fails if it comes out larger than max_size, so that a small frame can't expand without limit
+    pub fn decompress(&mut self, compressed: &[u8], data: &mut Vec<u8>, max_size: usize) -> Result<(), Error> {
+        data.clear();
+
+        match self {
+            Decompressor::Deflate(decompress) => {
+                let start_in = decompress.total_in();
+
+                loop {
+                    data.reserve(RESERVE_SIZE);
+                    let consumed = (decompress.total_in() - start_in) as usize;
+                    decompress.decompress_vec(&compressed[consumed..], data, FlushDecompress::Sync).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+                    check_size(data, max_size)?;
+
+                    if (decompress.total_in() - start_in) as usize == compressed.len() && data.len() < data.capacity() {
+                        return Ok(());
+                    }
+                }
+            },
+            Decompressor::Zstd(decoder) => {
+                let mut input = InBuffer::around(compressed);
+
+                loop {
+                    data.reserve(RESERVE_SIZE);
+                    decoder.run(&mut input, &mut OutBuffer::around_pos(data, data.len()))?;
+                    check_size(data, max_size)?;
+
+                    if input.pos == compressed.len() && data.len() < data.capacity() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn check_size(data: &[u8], max_size: usize) -> Result<(), Error> {
+    match data.len() > max_size {
+        true => Err(Error::new(ErrorKind::InvalidData, format!("Frame decompressed to more than {} bytes", max_size))),
+        false => Ok(())
+    }
+}
+
+// Each direction of a tunnel has its own compressor, and decompresses what the other side's compressor sent
+pub fn compressors(compression: Option<Compression>) -> Result<(Option<Compressor>, Option<Decompressor>), Error> {
+    match compression {
+        Some(compression) => Ok((Some(Compressor::new(compression)?), Some(Decompressor::new(compression)?))),
+        None => Ok((None, None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{RngCore, thread_rng};
+
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(Vec::<Compression>::new(), parse_compression("none").unwrap());
+        assert_eq!(vec![Compression::Zstd], parse_compression("zstd").unwrap());
+        assert_eq!(vec![Compression::Zstd, Compression::Deflate], parse_compression("zstd, deflate").unwrap());
+        parse_compression("gzip").expect_err("gzip isn't supported");
+    }
+
+    #[test]
+    fn negotiate() {
+        let both = compression_mask(&[Compression::Deflate, Compression::Zstd]);
+        let deflate = compression_mask(&[Compression::Deflate]);
+        let none = compression_mask(&[]);
+
+        assert_eq!(Some(Compression::Zstd), negotiate_compression(both, both));
+        assert_eq!(Some(Compression::Deflate), negotiate_compression(both, deflate));
+        assert_eq!(Some(Compression::Deflate), negotiate_compression(deflate, both));
+        assert_eq!(None, negotiate_compression(both, none));
+        assert_eq!(None, negotiate_compression(none, deflate));
+    }
+
+    fn round_trip(compression: Compression) {
+        let mut compressor = Compressor::new(compression).unwrap();
+        let mut decompressor = Decompressor::new(compression).unwrap();
+
+        let mut compressed = Vec::new();
+        let mut decompressed = Vec::new();
+
+        // Each frame decompresses on its own, and repeated data gets smaller
+        for _ in 0..4 {
+            let json = br#"{"id": 12345, "name": "bounce", "tags": ["tunnel", "tunnel", "tunnel"], "description": "verbose JSON verbose JSON verbose JSON"}"#;
+
+            assert!(compressor.compress(json, &mut compressed).unwrap(), "Compressible data wasn't compressed");
+            assert!(compressed.len() < json.len(), "{:?} didn't compress: {} bytes", compression, compressed.len());
+
+            decompressor.decompress(&compressed, &mut decompressed, 4096).unwrap();
+            assert_eq!(&json[..], &decompressed[..]);
+        }
+
+        // Random data doesn't compress, so after one try the next frames are sent as-is
+        let mut random = vec![0u8; 4096];
+        thread_rng().fill_bytes(&mut random);
+
+        assert!(compressor.compress(&random, &mut compressed).unwrap());
+        decompressor.decompress(&compressed, &mut decompressed, 4096).unwrap();
+        assert_eq!(random, decompressed);
+
+        for _ in 0..INITIAL_SKIP_FRAMES {
+            assert!(!compressor.compress(&random, &mut compressed).unwrap(), "Incompressible data wasn't skipped");
+        }
+
+        // Frames that were skipped don't throw off the decompressor
+        let text = b"back to text, back to text, back to text";
+        assert!(compressor.compress(text, &mut compressed).unwrap());
+        decompressor.decompress(&compressed, &mut decompressed, 4096).unwrap();
+        assert_eq!(&text[..], &decompressed[..]);
+    }
+
+    fn limit(compression: Compression) {
+        let mut compressor = Compressor::new(compression).unwrap();
+        let mut decompressor = Decompressor::new(compression).unwrap();
+
+        let mut compressed = Vec::new();
+        let mut decompressed = Vec::new();
+
+        let zeros = vec![0u8; 1024 * 1024];
+        assert!(compressor.compress(&zeros, &mut compressed).unwrap());
+        assert!(compressed.len() < 16 * 1024, "{:?} didn't compress: {} bytes", compression, compressed.len());
+
+        let err = decompressor.decompress(&compressed, &mut decompressed, 64 * 1024).expect_err("Decompressed past the limit");
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+        assert!(decompressed.capacity() < 256 * 1024, "Decompressed {} bytes before stopping", decompressed.capacity());
+    }
+
+    #[test]
+    fn deflate_limit() {
+        limit(Compression::Deflate);
+    }
+
+    #[test]
+    fn zstd_limit() {
+        limit(Compression::Zstd);
+    }
+
+    #[test]
+    fn deflate_round_trip() {
+        round_trip(Compression::Deflate);
+    }
+
+    #[test]
+    fn zstd_round_trip() {
+        round_trip(Compression::Zstd);
+    }
+}
@@ -0,0 +1,90 @@
+use async_std::task;
+use async_std::task::JoinHandle;
+use core::cell::Cell;
+use core::fmt;
+use core::future::Future;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
+use rand::{RngCore, thread_rng};
+
+// Identifies one connection (or datagram session) in both the server's and the client's logs
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    // Random, so that IDs don't repeat when the server restarts, or across servers
+    pub fn random() -> Self {
+        ConnectionId(thread_rng().next_u64())
+    }
+}
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl FromStr for ConnectionId {
+    type Err = Error;
+
+    fn from_str(id_str: &str) -> Result<Self, Error> {
+        match u64::from_str_radix(id_str, 16) {
+            Ok(id) if id_str.len() == 16 => Ok(ConnectionId(id)),
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("Malformed connection id \"{}\"", id_str)))
+        }
+    }
+}
+
+async_std::task_local! {
+    static CONNECTION_ID: Cell<Option<ConnectionId>> = Cell::new(None);
+}
+
+// Log lines from the current task are tagged with this connection, until it's set again
+pub fn set_connection_id(id: Option<ConnectionId>) {
+    CONNECTION_ID.with(|connection_id| connection_id.set(id));
+}
+
+// None outside of a task, or when the task isn't working on a connection
+pub fn connection_id() -> Option<ConnectionId> {
+    CONNECTION_ID.try_with(|connection_id| connection_id.get()).ok().flatten()
+}
+
+// The spawned task's log lines are tagged with the current task's connection
+pub fn spawn_for_connection<F, T>(future: F) -> JoinHandle<T> where
+F: Future<Output = T> + Send + 'static,
+T: Send + 'static {
+
+    let id = connection_id();
+
+    task::spawn(async move {
+        set_connection_id(id);
+        future.await
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let id = ConnectionId::random();
+        assert_eq!(id, id.to_string().parse().unwrap());
+        assert_eq!("000000000000002a", ConnectionId(42).to_string());
+
+        "2a".parse::<ConnectionId>().expect_err("Too short");
+        "000000000000002g".parse::<ConnectionId>().expect_err("Not hex");
+    }
+
+    #[async_std::test]
+    async fn spawned_tasks_inherit() {
+        assert_eq!(None, connection_id());
+
+        let id = ConnectionId::random();
+        set_connection_id(Some(id));
+
+        assert_eq!(Some(id), spawn_for_connection(async { connection_id() }).await);
+        assert_eq!(None, task::spawn(async { connection_id() }).await);
+    }
+}
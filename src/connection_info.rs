@@ -0,0 +1,145 @@
+use async_std::io::{Read, Write};
+use async_std::net::SocketAddr;
+use async_std::prelude::*;
+use std::io::{Error, ErrorKind};
+use std::marker::Unpin;
+
+use rand_core::{CryptoRng, RngCore};
+
+use crate::connection_id::ConnectionId;
+use crate::xor::Xor;
+
+// What the server knows about an incoming clear connection. It's sent, encrypted, right after the "connected" or "datagrams" signal
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConnectionInfo {
+    // Who connected to the server. None for connections that don't have an IP address, like Unix sockets
+    pub peer_addr: Option<SocketAddr>,
+    // The server address that they connected to
+    pub local_addr: Option<SocketAddr>,
+    // Assigned when the server accepted the connection, so that the server's and client's logs for it can be matched up
+    pub id: Option<ConnectionId>
+}
+
+impl ConnectionInfo {
+    // Serialized as "name=value" lines, so fields can be added without breaking older clients
+    fn serialize(&self) -> String {
+        let mut serialized = String::new();
+
+        if let Some(peer_addr) = self.peer_addr {
+            serialized.push_str(&format!("peer={}\n", peer_addr));
+        }
+
+        if let Some(local_addr) = self.local_addr {
+            serialized.push_str(&format!("local={}\n", local_addr));
+        }
+
+        if let Some(id) = self.id {
+            serialized.push_str(&format!("id={}\n", id));
+        }
+
+        serialized
+    }
+
+    fn deserialize(serialized: &str) -> Result<ConnectionInfo, Error> {
+        let mut connection_info = ConnectionInfo::default();
+
+        for line in serialized.lines() {
+            let (name, value) = match line.find('=') {
+                Some(i) => (&line[..i], &line[i + 1..]),
+                None => return Err(Error::new(ErrorKind::InvalidData, format!("Malformed connection info: \"{}\"", line)))
+            };
+
+            match name {
+                "peer" => connection_info.peer_addr = Some(parse_socket_addr(value)?),
+                "local" => connection_info.local_addr = Some(parse_socket_addr(value)?),
+                "id" => connection_info.id = Some(value.parse()?),
+                _ => log::debug!("Ignoring unknown connection info: {}", name)
+            }
+        }
+
+        Ok(connection_info)
+    }
+}
+
+fn parse_socket_addr(value: &str) -> Result<SocketAddr, Error> {
+    value.parse().map_err(|err| Error::new(ErrorKind::InvalidData, format!("Malformed address in connection info \"{}\": {}", value, err)))
+}
+
+// Framed as a 2-byte big-endian length, followed by the serialized info
+pub async fn write_connection_info<TRng, TStream>(xor: &mut Xor<TRng>, stream: &mut TStream, connection_info: &ConnectionInfo) -> Result<(), Error> where
+TRng: CryptoRng + RngCore + Clone,
+TStream: Write + Unpin {
+
+    let serialized = connection_info.serialize();
+
+    let mut frame = Vec::with_capacity(serialized.len() + 2);
+    frame.extend_from_slice(&(serialized.len() as u16).to_be_bytes());
+    frame.extend_from_slice(serialized.as_bytes());
+
+    xor.process(&mut frame);
+    stream.write_all(&frame).await
+}
+
+pub async fn read_connection_info<TRng, TStream>(xor: &mut Xor<TRng>, stream: &mut TStream) -> Result<ConnectionInfo, Error> where
+TRng: CryptoRng + RngCore + Clone,
+TStream: Read + Unpin {
+
+    let mut len_buf = [0u8; 2];
+    stream.read_exact(&mut len_buf).await?;
+    xor.process(&mut len_buf);
+
+    let mut serialized = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+    stream.read_exact(&mut serialized).await?;
+    xor.process(&mut serialized);
+
+    match String::from_utf8(serialized) {
+        Ok(serialized) => ConnectionInfo::deserialize(&serialized),
+        Err(_) => Err(Error::new(ErrorKind::InvalidData, "Connection info is not valid UTF-8"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::io::Cursor;
+
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+
+    #[async_std::test]
+    async fn round_trip() {
+        let connection_infos = [
+            ConnectionInfo {
+                peer_addr: Some("192.168.1.2:45678".parse().unwrap()),
+                local_addr: Some("[2001:db8::1]:443".parse().unwrap()),
+                id: Some(ConnectionId::random())
+            },
+            ConnectionInfo::default()
+        ];
+
+        let mut write_xor = Xor::new(ChaCha8Rng::seed_from_u64(1));
+        let mut read_xor = Xor::new(ChaCha8Rng::seed_from_u64(1));
+
+        let mut stream = Cursor::new(Vec::new());
+        for connection_info in connection_infos.iter() {
+            write_connection_info(&mut write_xor, &mut stream, connection_info).await.unwrap();
+        }
+
+        stream.set_position(0);
+        for connection_info in connection_infos.iter() {
+            let read = read_connection_info(&mut read_xor, &mut stream).await.unwrap();
+            assert_eq!(*connection_info, read);
+        }
+    }
+
+    #[test]
+    fn unknown_fields_are_ignored() {
+        let connection_info = ConnectionInfo::deserialize("future=1\npeer=10.0.0.1:80\n").unwrap();
+        assert_eq!(Some("10.0.0.1:80".parse().unwrap()), connection_info.peer_addr);
+
+        ConnectionInfo::deserialize("peer").expect_err("Missing value");
+        ConnectionInfo::deserialize("peer=nowhere").expect_err("Bad address");
+        ConnectionInfo::deserialize("id=nobody").expect_err("Bad id");
+    }
+}
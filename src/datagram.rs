@@ -0,0 +1,494 @@
+use async_std::io::{Read, Write};
+use async_std::net::{Shutdown, SocketAddr, ToSocketAddrs, UdpSocket};
+use async_std::prelude::*;
+use core::any::Any;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex};
+
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::future::{BoxFuture, Either, select};
+use futures::stream::StreamExt;
+use rand_core::{CryptoRng, RngCore};
+
+use crate::address::bind_udp;
+use crate::bandwidth::ConnectionBandwidth;
+use crate::bridge::{Activity, ConnectionTimeouts, watch_timeouts};
+use crate::connection_id::spawn_for_connection;
+use crate::metrics::Metrics;
+use crate::stream::Stream;
+use crate::xor::{Xor, Xors};
+
+// Datagrams are framed on the adapter stream as a 2-byte big-endian length, followed by the datagram
+const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
+
+// How many datagrams can wait for a session, and how many sessions can wait for a client, before new ones are dropped
+const SESSION_QUEUE_SIZE: usize = 64;
+
+type Sessions = Arc<Mutex<HashMap<SocketAddr, Sender<Vec<u8>>>>>;
+
+// All datagrams from a single source address, on the public UDP port
+// Dropping a session, bridged or not, ends it, so that the next datagram from the same source starts a new session
+pub struct DatagramSession {
+    pub source: SocketAddr,
+    socket: Arc<UdpSocket>,
+    receiver: Receiver<Vec<u8>>,
+    sessions: Sessions
+}
+
+impl DatagramSession {
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.socket.local_addr().ok()
+    }
+}
+
+// Like BridgeOptions, for what applies to datagrams
+pub struct DatagramBridgeOptions {
+    // Limits count the datagrams, not their framing
+    pub bandwidth: ConnectionBandwidth,
+    pub timeouts: ConnectionTimeouts,
+    pub metrics: Metrics
+}
+
+// Where datagrams go after they come off of the adapter stream
+enum DatagramDestination {
+    // Reply to the original sender (server)
+    Source(Arc<UdpSocket>, SocketAddr),
+    // Forward to the destination host (client)
+    Connected(Arc<UdpSocket>)
+}
+
+impl Drop for DatagramSession {
+    fn drop(&mut self) {
+        self.sessions.lock().unwrap().remove(&self.source);
+    }
+}
+
+// Where datagrams come from before they are sent on the adapter stream
+enum DatagramSource {
+    Session(DatagramSession),
+    Connected(Arc<UdpSocket>)
+}
+
+// Binds the public UDP port. New sessions, one per source address, come out of the returned receiver while the returned future runs
+// Dropping the future stops receiving, and the port is released once its sessions end too
+pub fn listen_datagrams(socket_addr: SocketAddr) -> Result<(Receiver<DatagramSession>, BoxFuture<'static, ()>), Error> {
+    let socket = Arc::new(bind_udp(socket_addr)?);
+    let (new_session_sender, new_session_receiver) = channel(SESSION_QUEUE_SIZE);
+
+    Ok((new_session_receiver, Box::pin(receive_datagrams(socket, new_session_sender))))
+}
+
+async fn receive_datagrams(socket: Arc<UdpSocket>, mut new_session_sender: Sender<DatagramSession>) {
+    let sessions: Sessions = Arc::new(Mutex::new(HashMap::new()));
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let (bytes_read, source) = match socket.recv_from(&mut buf).await {
+            Ok(r) => r,
+            Err(err) => {
+                log::error!("Error receiving datagram: {}", err);
+                continue;
+            }
+        };
+
+        let datagram = buf[..bytes_read].to_vec();
+
+        let mut sessions_locked = sessions.lock().unwrap();
+        match sessions_locked.get_mut(&source) {
+            Some(sender) => {
+                if sender.try_send(datagram).is_err() {
+                    log::warn!("Dropping datagram from {}, the session is not keeping up", source);
+                }
+            },
+            None => {
+                let (mut sender, receiver) = channel(SESSION_QUEUE_SIZE);
+                sender.try_send(datagram).expect("New session can not queue its first datagram");
+
+                let new_session = DatagramSession {
+                    source,
+                    socket: socket.clone(),
+                    receiver,
+                    sessions: sessions.clone()
+                };
+
+                // The session waits in the channel until a client is available. If it can't, dropping it removes it again,
+                // which needs the lock
+                sessions_locked.insert(source, sender);
+                drop(sessions_locked);
+
+                match new_session_sender.try_send(new_session) {
+                    Ok(()) => {},
+                    Err(err) if err.is_disconnected() => {
+                        log::info!("Server stopped, no longer receiving datagrams");
+                        return;
+                    },
+                    Err(_) => log::warn!("Dropping datagram from {}, too many sessions are waiting for a client", source)
+                }
+            }
+        }
+    }
+}
+
+// Server side: bridges a session on the public UDP port to the adapter stream
+// The guard is held until the session ends, like with run_bridge
+pub fn run_datagram_session_bridge<TRng, TGuard>(xors: Xors<TRng>, options: DatagramBridgeOptions, session: DatagramSession, adapter_stream: Stream, guard: TGuard) where
+TRng: CryptoRng + RngCore + Clone + Any + Send,
+TGuard: Send + 'static {
+
+    let source = session.source;
+    let destination = DatagramDestination::Source(session.socket.clone(), source);
+
+    spawn_for_connection(async move {
+        datagram_bridge(
+            xors,
+            DatagramSource::Session(session),
+            destination,
+            adapter_stream,
+            source.to_string(),
+            options).await;
+
+        drop(guard);
+    });
+}
+
+// Connected before the client acknowledges DATAGRAMS, so that it can report when the destination is unavailable
+pub async fn connect_datagram_destination(destination_host: &str) -> Result<UdpSocket, Error> {
+    let destination_addr = destination_host.to_socket_addrs().await?.next()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("Can not resolve \"{}\"", destination_host)))?;
+
+    let bind_addr = if destination_addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(destination_addr).await?;
+
+    Ok(socket)
+}
+
+// Client side: bridges the adapter stream to a UDP socket connected to the destination
+pub fn run_datagram_destination_bridge<TRng, TGuard>(xors: Xors<TRng>, options: DatagramBridgeOptions, socket: UdpSocket, destination_host: String, adapter_stream: Stream, guard: TGuard) where
+TRng: CryptoRng + RngCore + Clone + Any + Send,
+TGuard: Send + 'static {
+
+    let socket = Arc::new(socket);
+
+    spawn_for_connection(async move {
+        datagram_bridge(
+            xors,
+            DatagramSource::Connected(socket.clone()),
+            DatagramDestination::Connected(socket),
+            adapter_stream,
+            destination_host,
+            options).await;
+
+        drop(guard);
+    });
+}
+
+async fn datagram_bridge<TRng>(
+    xors: Xors<TRng>,
+    source: DatagramSource,
+    destination: DatagramDestination,
+    adapter_stream: Stream,
+    name: String,
+    options: DatagramBridgeOptions) where
+TRng: CryptoRng + RngCore + Clone {
+
+    let activity = Activity::new();
+
+    let outgoing_future = Box::pin(datagrams_to_adapter(xors.write_xor, source, adapter_stream.clone(), &options, &activity));
+    let incoming_future = Box::pin(adapter_to_datagrams(xors.read_xor, adapter_stream.clone(), destination, &options, &activity));
+
+    // Either direction ending ends the session, since datagrams have no half-close
+    let directions_future = Box::pin(async move {
+        match select(outgoing_future, incoming_future).await {
+            Either::Left((r, _)) => r,
+            Either::Right((r, _)) => r
+        }
+    });
+
+    let timeouts_future = Box::pin(watch_timeouts(&activity, options.timeouts));
+
+    let result = match select(directions_future, timeouts_future).await {
+        Either::Left((result, _)) => result,
+        Either::Right((err, _)) => Err(err)
+    };
+
+    match result {
+        Ok(()) => log::info!("Datagram session ended: {}", name),
+        Err(err) if err.kind() == ErrorKind::TimedOut => log::info!("Datagram session ended: {}: {}", name, err),
+        Err(err) => log::error!("Datagram session {} ended in error: {}", name, err)
+    }
+
+    if let Err(err) = adapter_stream.shutdown(Shutdown::Both) {
+        log::debug!("Error shutting down adapter stream for datagram session {}: {}", name, err);
+    }
+}
+
+async fn datagrams_to_adapter<TRng>(
+    mut xor: Xor<TRng>,
+    mut source: DatagramSource,
+    mut adapter_stream: Stream,
+    options: &DatagramBridgeOptions,
+    activity: &Activity) -> Result<(), Error> where
+TRng: CryptoRng + RngCore + Clone {
+
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let datagram = match source.recv(&mut buf).await? {
+            Some(datagram) => datagram,
+            None => return Ok(())
+        };
+
+        activity.active();
+
+        options.bandwidth.send(datagram.len()).await;
+        options.metrics.sent(datagram.len());
+
+        write_datagram(&mut xor, &mut adapter_stream, datagram).await?;
+    }
+}
+
+async fn adapter_to_datagrams<TRng>(
+    mut xor: Xor<TRng>,
+    mut adapter_stream: Stream,
+    destination: DatagramDestination,
+    options: &DatagramBridgeOptions,
+    activity: &Activity) -> Result<(), Error> where
+TRng: CryptoRng + RngCore + Clone {
+
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+
+    loop {
+        let datagram = match read_datagram(&mut xor, &mut adapter_stream, &mut buf).await? {
+            Some(datagram) => datagram,
+            None => return Ok(())
+        };
+
+        activity.active();
+
+        options.bandwidth.receive(datagram.len()).await;
+        options.metrics.received(datagram.len());
+
+        destination.send(datagram).await?;
+    }
+}
+
+impl DatagramSource {
+    // Returns None when there will be no more datagrams
+    async fn recv<'a>(&mut self, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>, Error> {
+        match self {
+            DatagramSource::Session(session) => match session.receiver.next().await {
+                Some(datagram) => {
+                    buf[..datagram.len()].copy_from_slice(&datagram);
+                    Ok(Some(&buf[..datagram.len()]))
+                },
+                None => Ok(None)
+            },
+            DatagramSource::Connected(socket) => {
+                let bytes_read = socket.recv(buf).await?;
+                Ok(Some(&buf[..bytes_read]))
+            }
+        }
+    }
+}
+
+impl DatagramDestination {
+    async fn send(&self, datagram: &[u8]) -> Result<(), Error> {
+        match self {
+            DatagramDestination::Source(socket, source) => socket.send_to(datagram, source).await?,
+            DatagramDestination::Connected(socket) => socket.send(datagram).await?
+        };
+
+        Ok(())
+    }
+}
+
+async fn write_datagram<TRng, TStream>(xor: &mut Xor<TRng>, adapter_stream: &mut TStream, datagram: &[u8]) -> Result<(), Error> where
+TRng: CryptoRng + RngCore + Clone,
+TStream: Write + Unpin {
+
+    let mut frame = Vec::with_capacity(datagram.len() + 2);
+    frame.extend_from_slice(&(datagram.len() as u16).to_be_bytes());
+    frame.extend_from_slice(datagram);
+
+    xor.process(&mut frame);
+    adapter_stream.write_all(&frame).await
+}
+
+// Returns None if the adapter stream ended cleanly between datagrams
+async fn read_datagram<'a, TRng, TStream>(xor: &mut Xor<TRng>, adapter_stream: &mut TStream, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>, Error> where
+TRng: CryptoRng + RngCore + Clone,
+TStream: Read + Unpin {
+
+    let mut len_buf = [0u8; 2];
+    let bytes_read = adapter_stream.read(&mut len_buf[..1]).await?;
+    if bytes_read == 0 {
+        return Ok(None);
+    }
+
+    adapter_stream.read_exact(&mut len_buf[1..]).await?;
+    xor.process(&mut len_buf);
+
+    let len = u16::from_be_bytes(len_buf) as usize;
+    adapter_stream.read_exact(&mut buf[..len]).await?;
+    xor.process(&mut buf[..len]);
+
+    Ok(Some(&buf[..len]))
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream};
+
+    use async_std::future;
+    use async_std::task;
+    use core::time::Duration;
+
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use crate::bandwidth::BandwidthOptions;
+
+    use super::*;
+
+    async fn get_socket_streams() -> (TcpStream, TcpStream) {
+        let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let listener = TcpListener::bind(socket_addr).await.unwrap();
+
+        let local_addr = listener.local_addr().unwrap();
+
+        let write_stream = TcpStream::connect(local_addr).await.unwrap();
+        let read_stream = listener.incoming().next().await.unwrap().unwrap();
+
+        (write_stream, read_stream)
+    }
+
+    #[async_std::test]
+    async fn datagram_framing() {
+        let (mut write_stream, mut read_stream) = get_socket_streams().await;
+
+        let mut write_xor = Xor::new(ChaCha8Rng::seed_from_u64(1));
+        let mut read_xor = Xor::new(ChaCha8Rng::seed_from_u64(1));
+
+        let datagrams = [b"first".to_vec(), vec![], vec![7u8; MAX_DATAGRAM_SIZE]];
+
+        for datagram in datagrams.iter() {
+            write_datagram(&mut write_xor, &mut write_stream, datagram).await.expect("Can not write datagram");
+        }
+
+        write_stream.shutdown(Shutdown::Write).unwrap();
+
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        for datagram in datagrams.iter() {
+            let read = read_datagram(&mut read_xor, &mut read_stream, &mut buf).await.expect("Can not read datagram");
+            assert_eq!(Some(&datagram[..]), read, "Wrong datagram");
+        }
+
+        let read = read_datagram(&mut read_xor, &mut read_stream, &mut buf).await.expect("Can not read end of stream");
+        assert_eq!(None, read, "Stream should end between datagrams");
+    }
+
+    #[async_std::test]
+    async fn session_per_source() {
+        let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let port_finder = UdpSocket::bind(socket_addr).await.unwrap();
+        let listen_addr = port_finder.local_addr().unwrap();
+        drop(port_finder);
+
+        let (mut new_sessions, receiving) = listen_datagrams(listen_addr).expect("Can not listen for datagrams");
+        task::spawn(receiving);
+
+        let client_a = UdpSocket::bind(socket_addr).await.unwrap();
+        let client_b = UdpSocket::bind(socket_addr).await.unwrap();
+
+        client_a.send_to(b"a1", listen_addr).await.unwrap();
+        let mut session_a = new_sessions.next().await.expect("No session for a");
+        assert_eq!(client_a.local_addr().unwrap(), session_a.source);
+
+        client_a.send_to(b"a2", listen_addr).await.unwrap();
+        client_b.send_to(b"b1", listen_addr).await.unwrap();
+        let mut session_b = new_sessions.next().await.expect("No session for b");
+        assert_eq!(client_b.local_addr().unwrap(), session_b.source);
+
+        assert_eq!(Some(b"a1".to_vec()), session_a.receiver.next().await);
+        assert_eq!(Some(b"a2".to_vec()), session_a.receiver.next().await);
+        assert_eq!(Some(b"b1".to_vec()), session_b.receiver.next().await);
+
+        // Once a session ends, the next datagram from the same source starts a new one
+        drop(session_a);
+        client_a.send_to(b"a3", listen_addr).await.unwrap();
+        let mut session_a = new_sessions.next().await.expect("No new session for a");
+        assert_eq!(Some(b"a3".to_vec()), session_a.receiver.next().await);
+    }
+
+    #[async_std::test]
+    async fn dropped_session_starts_over() {
+        let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let port_finder = UdpSocket::bind(socket_addr).await.unwrap();
+        let listen_addr = port_finder.local_addr().unwrap();
+        drop(port_finder);
+
+        let (mut new_sessions, receiving) = listen_datagrams(listen_addr).expect("Can not listen for datagrams");
+        task::spawn(receiving);
+        let client = UdpSocket::bind(socket_addr).await.unwrap();
+
+        // Like a session that expired in the pending queue, or whose client couldn't reach the destination
+        client.send_to(b"first", listen_addr).await.unwrap();
+        let session = new_sessions.next().await.expect("No session");
+        drop(session);
+
+        client.send_to(b"second", listen_addr).await.unwrap();
+        let mut session = new_sessions.next().await.expect("No new session after the first was dropped");
+        assert_eq!(client.local_addr().unwrap(), session.source);
+        assert_eq!(Some(b"second".to_vec()), session.receiver.next().await);
+    }
+
+    #[async_std::test]
+    async fn counts_and_times_out() {
+        let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let peer = UdpSocket::bind(socket_addr).await.unwrap();
+        let socket = UdpSocket::bind(socket_addr).await.unwrap();
+        socket.connect(peer.local_addr().unwrap()).await.unwrap();
+        let socket = Arc::new(socket);
+
+        let (adapter_stream, mut read_stream) = get_socket_streams().await;
+
+        let options = DatagramBridgeOptions {
+            bandwidth: BandwidthOptions::default().connection(),
+            timeouts: ConnectionTimeouts {
+                idle: Some(Duration::from_millis(200)),
+                max_lifetime: None
+            },
+            metrics: Metrics::default()
+        };
+        let bandwidth = options.bandwidth.clone();
+
+        let xors = Xors {
+            write_xor: Xor::new(ChaCha8Rng::seed_from_u64(1)),
+            read_xor: Xor::new(ChaCha8Rng::seed_from_u64(2))
+        };
+
+        let bridge = task::spawn(datagram_bridge(
+            xors,
+            DatagramSource::Connected(socket.clone()),
+            DatagramDestination::Connected(socket.clone()),
+            Stream::from(adapter_stream),
+            "test".to_string(),
+            options));
+
+        peer.send_to(b"hello", socket.local_addr().unwrap()).await.unwrap();
+
+        let mut read_xor = Xor::new(ChaCha8Rng::seed_from_u64(1));
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let read = read_datagram(&mut read_xor, &mut read_stream, &mut buf).await.expect("Can not read datagram");
+        assert_eq!(Some(&b"hello"[..]), read);
+
+        // Nothing else comes through, so the session ends once it's idle
+        future::timeout(Duration::from_secs(5), bridge).await.expect("Datagram session did not time out");
+        let read = read_datagram(&mut read_xor, &mut read_stream, &mut buf).await.expect("Can not read end of stream");
+        assert_eq!(None, read, "Adapter stream should be closed");
+
+        assert_eq!((5, 0), bandwidth.bytes());
+    }
+}
@@ -0,0 +1,68 @@
+use async_std::net::TcpListener;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use rustc_serialize::json::Json;
+
+use crate::http_server::{Request, Response, serve_http};
+use crate::registry::Registry;
+
+// Liveness and readiness of a server, for load balancers and platform health checks. Clones share the same registry
+// Answering never takes an adapter stream
+#[derive(Clone)]
+pub struct Health {
+    registry: Registry,
+    // The incoming addresses, like the metrics label
+    mapping: Arc<str>
+}
+
+impl Health {
+    pub fn new(registry: Registry, mapping: String) -> Health {
+        Health {
+            registry,
+            mapping: mapping.into()
+        }
+    }
+
+    // Paths are relative to where health is served:
+    // - [base]/live: 200 while the server runs
+    // - [base]/ready, or [base] itself: 200 when a client is waiting for a connection, 503 when an incoming connection would have to wait
+    pub fn respond(&self, request: &Request, base: &str) -> Response {
+        let path = request.path.split('?').next().unwrap_or_default();
+        let path = path.strip_prefix(base).unwrap_or(path).trim_end_matches('/');
+
+        match (request.method.as_str(), path) {
+            ("GET", "/live") => Response::json("200 OK", self.json().to_string()),
+            ("GET", "/ready") | ("GET", "") => match self.ready() {
+                true => Response::json("200 OK", self.json().to_string()),
+                false => Response::json("503 Service Unavailable", self.json().to_string())
+            },
+            (_, "/live") | (_, "/ready") | (_, "") => Response::empty("405 Method Not Allowed"),
+            _ => Response::empty("404 Not Found")
+        }
+    }
+
+    // Every listen address is served by the same clients, so one is enough for all of them
+    fn ready(&self) -> bool {
+        !self.registry.clients().is_empty()
+    }
+
+    fn json(&self) -> Json {
+        let clients = self.registry.clients().len();
+
+        let mut object = BTreeMap::new();
+        object.insert("live".to_string(), Json::Boolean(true));
+        object.insert("ready".to_string(), Json::Boolean(clients > 0));
+        object.insert("mapping".to_string(), Json::String(self.mapping.to_string()));
+        object.insert("clients".to_string(), Json::U64(clients as u64));
+        Json::Object(object)
+    }
+}
+
+// Until canceled, answers /live and /ready
+pub async fn serve_health(listener: TcpListener, health: Health) {
+    serve_http("Health", listener, move |request: Request| {
+        let response = health.respond(&request, "");
+        async move { response }
+    }).await
+}
@@ -0,0 +1,414 @@
+// Longer heads, or chunk lines, aren't parsed
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+const MAX_LINE_SIZE: usize = 4096;
+
+// Past this many trailer lines after a chunked body, the rest isn't parsed
+const MAX_TRAILER_LINES: usize = 100;
+
+// The start line and headers of a request or response
+#[derive(Clone, Debug, PartialEq)]
+pub struct Head {
+    // The method, target and version of a request, or the version, status and reason of a response
+    pub start_line: [String; 3],
+    pub headers: Vec<(String, String)>
+}
+
+impl Head {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header_name, _)| header_name.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    fn chunked(&self) -> bool {
+        self.header("Transfer-Encoding").map(|encoding| encoding.to_ascii_lowercase().contains("chunked")).unwrap_or(false)
+    }
+
+    // Chunked, a Content-Length, or no body
+    pub fn request_body_length(&self) -> BodyLength {
+        match (self.chunked(), self.header("Content-Length")) {
+            (true, _) => BodyLength::Chunked,
+            (false, Some(content_length)) => match content_length.parse::<u64>() {
+                Ok(content_length) => BodyLength::Length(content_length),
+                Err(_) => BodyLength::Stop
+            },
+            (false, None) => BodyLength::Length(0)
+        }
+    }
+
+    // Like a request, except that responses to HEAD, and some statuses, have no body, and otherwise a response without a length ends when the connection closes
+    pub fn response_body_length(&self, request_method: &str) -> BodyLength {
+        match self.status() {
+            Some(101) => BodyLength::Stop,
+            Some(status) if request_method == "CONNECT" && (200..300).contains(&status) => BodyLength::Stop,
+            Some(status) if request_method == "HEAD" || (100..200).contains(&status) || status == 204 || status == 304 => BodyLength::Length(0),
+            Some(_) => match self.request_body_length() {
+                BodyLength::Length(0) if self.header("Content-Length").is_none() => BodyLength::UntilClose,
+                body_length => body_length
+            },
+            None => BodyLength::Stop
+        }
+    }
+
+    // For a response
+    pub fn status(&self) -> Option<u16> {
+        self.start_line[1].parse().ok()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BodyLength {
+    Length(u64),
+    Chunked,
+    UntilClose,
+    // The rest of the stream isn't HTTP, like after switching protocols
+    Stop
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Event<'a> {
+    // The parser waits for start_body before it goes on
+    Head(Head),
+    // Without chunked encoding
+    Body(&'a [u8]),
+    End
+}
+
+enum State {
+    Head,
+    WaitingForBodyLength,
+    Body(u64),
+    ChunkSize,
+    ChunkData(u64),
+    ChunkDataEnd,
+    // How many trailer lines were read
+    Trailers(usize),
+    UntilClose,
+    End,
+    // Not HTTP, or no longer HTTP, so the rest is ignored
+    Stopped
+}
+
+// Parses one direction of an HTTP/1.1 connection as it goes through, without holding on to bodies
+pub struct MessageParser {
+    state: State,
+    // The head, or the current line of a chunked body
+    buf: Vec<u8>
+}
+
+impl Default for MessageParser {
+    fn default() -> Self {
+        MessageParser {
+            state: State::Head,
+            buf: Vec::new()
+        }
+    }
+}
+
+impl MessageParser {
+    // Returns how much of data was used, and the next event, if any. Call it again with the rest until there's no event
+    pub fn parse<'a>(&mut self, data: &'a [u8]) -> (usize, Option<Event<'a>>) {
+        // Lines don't have events, so they're all read before going on with the rest
+        let mut used = 0;
+        while matches!(self.state, State::ChunkSize | State::ChunkDataEnd | State::Trailers(_)) {
+            match self.parse_line(&data[used..]) {
+                Some(line_used) => used += line_used,
+                None => return (data.len(), None)
+            }
+        }
+
+        let data = &data[used..];
+        let (rest_used, event) = match self.state {
+            State::Head => {
+                for (position, byte) in data.iter().enumerate() {
+                    // Blank lines between messages are allowed
+                    if self.buf.is_empty() && (*byte == b'\r' || *byte == b'\n') {
+                        continue;
+                    }
+
+                    self.buf.push(*byte);
+
+                    if self.buf.ends_with(b"\r\n\r\n") {
+                        let head = parse_head(&self.buf);
+                        self.buf.clear();
+
+                        return match head {
+                            Some(head) => {
+                                self.state = State::WaitingForBodyLength;
+                                (position + 1, Some(Event::Head(head)))
+                            },
+                            None => self.stop(data)
+                        };
+                    }
+
+                    if self.buf.len() > MAX_HEAD_SIZE {
+                        return self.stop(data);
+                    }
+                }
+
+                (data.len(), None)
+            },
+            State::WaitingForBodyLength | State::Stopped => (0, None),
+            State::Body(remaining) | State::ChunkData(remaining) => {
+                if data.is_empty() {
+                    return (0, None);
+                }
+
+                let len = remaining.min(data.len() as u64) as usize;
+                let remaining = remaining - len as u64;
+
+                self.state = match (&self.state, remaining) {
+                    (State::Body(_), 0) => State::End,
+                    (State::Body(_), remaining) => State::Body(remaining),
+                    (_, 0) => State::ChunkDataEnd,
+                    (_, remaining) => State::ChunkData(remaining)
+                };
+
+                (len, Some(Event::Body(&data[..len])))
+            },
+            State::ChunkSize | State::ChunkDataEnd | State::Trailers(_) => unreachable!("Lines are parsed first"),
+            State::UntilClose => match data.is_empty() {
+                true => (0, None),
+                false => (data.len(), Some(Event::Body(data)))
+            },
+            State::End => {
+                self.state = State::Head;
+                (0, Some(Event::End))
+            }
+        };
+
+        (used + rest_used, event)
+    }
+
+    // How much of data was used by a line of a chunked body. None when data ran out before the line did, or parsing
+    // stopped, and all of data was used
+    fn parse_line(&mut self, data: &[u8]) -> Option<usize> {
+        let (used, line) = match self.read_line(data) {
+            Some((used, line)) => (used, line),
+            None if self.buf.len() > MAX_LINE_SIZE => {
+                self.stop(data);
+                return None;
+            },
+            None => return None
+        };
+
+        self.state = match self.state {
+            State::ChunkSize => {
+                let size = line.split(';').next().unwrap_or_default().trim();
+                match u64::from_str_radix(size, 16) {
+                    Ok(0) => State::Trailers(0),
+                    Ok(size) => State::ChunkData(size),
+                    Err(_) => State::Stopped
+                }
+            },
+            State::ChunkDataEnd if line.is_empty() => State::ChunkSize,
+            _ if line.is_empty() => State::End,
+            State::Trailers(lines) if lines < MAX_TRAILER_LINES => State::Trailers(lines + 1),
+            _ => State::Stopped
+        };
+
+        match self.state {
+            State::Stopped => {
+                self.stop(data);
+                None
+            },
+            _ => Some(used)
+        }
+    }
+
+    // After Head
+    pub fn start_body(&mut self, body_length: BodyLength) {
+        self.state = match body_length {
+            BodyLength::Length(0) => State::End,
+            BodyLength::Length(len) => State::Body(len),
+            BodyLength::Chunked => State::ChunkSize,
+            BodyLength::UntilClose => State::UntilClose,
+            BodyLength::Stop => State::Stopped
+        };
+    }
+
+    // When the other direction stopped being HTTP
+    pub fn stop_parsing(&mut self) {
+        self.state = State::Stopped;
+    }
+
+    // The stream closed. True if that ended a body that lasts until then
+    pub fn finish(&mut self) -> bool {
+        let until_close = matches!(self.state, State::UntilClose);
+        self.state = State::Stopped;
+        until_close
+    }
+
+    fn stop(&mut self, data: &[u8]) -> (usize, Option<Event<'static>>) {
+        log::debug!("Stopped parsing HTTP");
+        self.state = State::Stopped;
+        self.buf.clear();
+        (data.len(), None)
+    }
+
+    // A line without its CRLF, and how much of data was used, once the whole line is there
+    fn read_line(&mut self, data: &[u8]) -> Option<(usize, String)> {
+        match data.iter().position(|byte| *byte == b'\n') {
+            Some(position) => {
+                self.buf.extend_from_slice(&data[..position]);
+                let line = String::from_utf8_lossy(&self.buf).trim_end_matches('\r').to_string();
+                self.buf.clear();
+                Some((position + 1, line))
+            },
+            None => {
+                self.buf.extend_from_slice(data);
+                None
+            }
+        }
+    }
+}
+
+fn parse_head(head: &[u8]) -> Option<Head> {
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.split("\r\n");
+
+    let mut start_line = lines.next()?.splitn(3, ' ');
+    let start_line = [
+        start_line.next()?.to_string(),
+        start_line.next()?.to_string(),
+        start_line.next().unwrap_or_default().to_string()
+    ];
+
+    // One of them is the version
+    if !start_line[0].starts_with("HTTP/1.") && !start_line[2].starts_with("HTTP/1.") {
+        return None;
+    }
+
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    Some(Head {
+        start_line,
+        headers
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Owned {
+        Head(String),
+        Body(Vec<u8>),
+        End
+    }
+
+    // Feeds the pieces one at a time, with body_length for each head
+    fn parse(parser: &mut MessageParser, pieces: &[&[u8]], body_length: impl Fn(&Head) -> BodyLength) -> Vec<Owned> {
+        let mut events = Vec::new();
+
+        for piece in pieces {
+            let mut data = *piece;
+            loop {
+                let (used, event) = parser.parse(data);
+                data = &data[used..];
+
+                match event {
+                    Some(Event::Head(head)) => {
+                        parser.start_body(body_length(&head));
+                        events.push(Owned::Head(head.start_line.join(" ")));
+                    },
+                    // Pieces of the body are joined, because where they're split doesn't matter
+                    Some(Event::Body(body)) => match events.last_mut() {
+                        Some(Owned::Body(previous)) => previous.extend_from_slice(body),
+                        _ => events.push(Owned::Body(body.to_vec()))
+                    },
+                    Some(Event::End) => events.push(Owned::End),
+                    None => break
+                }
+            }
+        }
+
+        events
+    }
+
+    #[test]
+    fn requests() {
+        let mut parser = MessageParser::default();
+
+        // Pipelined, and split in awkward places
+        let events = parse(&mut parser, &[
+            b"POST /hook HTTP/1.1\r\nContent-Length: 5\r",
+            b"\n\r\nhel",
+            b"loGET / HTTP/1.1\r\nHost: x\r\n\r\n",
+            b"\r\nPUT /chunked HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n6\r",
+            b"\n world\r\n0\r\nTrailer: x\r\n\r\n"
+        ], Head::request_body_length);
+
+        assert_eq!(vec![
+            Owned::Head("POST /hook HTTP/1.1".to_string()),
+            Owned::Body(b"hello".to_vec()),
+            Owned::End,
+            Owned::Head("GET / HTTP/1.1".to_string()),
+            Owned::End,
+            Owned::Head("PUT /chunked HTTP/1.1".to_string()),
+            Owned::Body(b"hello world".to_vec()),
+            Owned::End
+        ], events);
+    }
+
+    #[test]
+    fn responses() {
+        let mut parser = MessageParser::default();
+
+        let events = parse(&mut parser, &[b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nHTTP/1.1 100 Continue\r\n\r\n"], |head| head.response_body_length("HEAD"));
+        assert_eq!(vec![
+            Owned::Head("HTTP/1.1 200 OK".to_string()),
+            Owned::End,
+            Owned::Head("HTTP/1.1 100 Continue".to_string()),
+            Owned::End
+        ], events);
+
+        let mut parser = MessageParser::default();
+        let events = parse(&mut parser, &[b"HTTP/1.0 200 OK\r\n\r\nuntil", b" close"], |head| head.response_body_length("GET"));
+        assert_eq!(vec![Owned::Head("HTTP/1.0 200 OK".to_string()), Owned::Body(b"until close".to_vec())], events);
+        assert!(parser.finish());
+
+        // Switching protocols stops parsing
+        let mut parser = MessageParser::default();
+        let events = parse(&mut parser, &[b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n\x81\x00"], |head| head.response_body_length("GET"));
+        assert_eq!(vec![Owned::Head("HTTP/1.1 101 Switching Protocols".to_string())], events);
+        assert!(!parser.finish());
+    }
+
+    #[test]
+    fn not_http() {
+        let mut parser = MessageParser::default();
+        assert!(parse(&mut parser, &[b"\x16\x03\x01 binary\r\n\r\nGET / HTTP/1.1\r\n\r\n"], Head::request_body_length).is_empty());
+
+        let mut parser = MessageParser::default();
+        let events = parse(&mut parser, &[b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nnot hex\r\n"], Head::request_body_length);
+        assert_eq!(vec![Owned::Head("POST / HTTP/1.1".to_string())], events);
+    }
+
+    #[test]
+    fn many_lines() {
+        // Lines are parsed in a loop, however many come at once
+        let mut message = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n".to_vec();
+        for _ in 0..MAX_TRAILER_LINES {
+            message.extend_from_slice(b"Trailer: x\r\n");
+        }
+        message.extend_from_slice(b"\r\n");
+
+        let mut parser = MessageParser::default();
+        let events = parse(&mut parser, &[&message], Head::request_body_length);
+        assert_eq!(vec![Owned::Head("POST / HTTP/1.1".to_string()), Owned::End], events);
+
+        // Too many trailers stop parsing
+        let mut message = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n".to_vec();
+        for _ in 0..100_000 {
+            message.extend_from_slice(b"Trailer: x\r\n");
+        }
+        message.extend_from_slice(b"\r\n");
+
+        let mut parser = MessageParser::default();
+        let events = parse(&mut parser, &[&message], Head::request_body_length);
+        assert_eq!(vec![Owned::Head("POST / HTTP/1.1".to_string())], events);
+    }
+}
@@ -0,0 +1,208 @@
+use async_std::io;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::task;
+use core::future::Future;
+use core::time::Duration;
+use std::io::{Error, ErrorKind};
+
+// Requests that take longer than this to arrive, or are larger, are dropped. Responses have as long to be sent
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_HEADER_SIZE: usize = 8192;
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+// One request to a local endpoint, like metrics or the admin API. Every connection is closed after one response
+pub struct Request {
+    pub method: String,
+    // Including the query string
+    pub path: String,
+    headers: Vec<(String, String)>,
+    pub body: Vec<u8>
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(header_name, _)| header_name.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+}
+
+pub struct Response {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: Vec<u8>
+}
+
+impl Response {
+    pub fn new(status: &'static str, content_type: &'static str, body: Vec<u8>) -> Self {
+        Response {
+            status,
+            content_type,
+            body
+        }
+    }
+
+    pub fn json(status: &'static str, body: String) -> Self {
+        Response::new(status, "application/json", body.into_bytes())
+    }
+
+    pub fn empty(status: &'static str) -> Self {
+        Response::new(status, "text/plain", Vec::new())
+    }
+}
+
+// Answers each request with handle until canceled. name is only for logs
+pub async fn serve_http<F, TFuture>(name: &'static str, listener: TcpListener, handle: F) where
+F: Fn(Request) -> TFuture + Clone + Send + 'static,
+TFuture: Future<Output = Response> + Send {
+
+    log::info!("{}: Listening on {}", name, listener.local_addr().map(|a| a.to_string()).unwrap_or_default());
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let handle = handle.clone();
+                task::spawn(async move {
+                    if let Err(err) = respond(stream, handle).await {
+                        log::debug!("{}: Request failed: {}", name, err);
+                    }
+                });
+            },
+            Err(err) => {
+                log::error!("{}: Can not accept: {}", name, err);
+                return;
+            }
+        }
+    }
+}
+
+// Answers one request on a stream that was accepted elsewhere, like a health check on a public port
+pub async fn respond<F, TFuture>(mut stream: TcpStream, handle: F) -> Result<(), Error> where
+F: Fn(Request) -> TFuture,
+TFuture: Future<Output = Response> {
+
+    // Handling isn't timed, because replaying a request waits on the destination
+    let response = match io::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(request) => handle(request).await,
+        Err(err) if err.kind() == ErrorKind::InvalidData => Response::new("400 Bad Request", "text/plain", err.to_string().into_bytes()),
+        Err(err) => return Err(err)
+    };
+
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len());
+
+    io::timeout(REQUEST_TIMEOUT, async {
+        stream.write_all(header.as_bytes()).await?;
+        stream.write_all(&response.body).await?;
+        stream.flush().await
+    }).await
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<Request, Error> {
+    let mut raw = Vec::new();
+    let mut buf = [0u8; 1024];
+
+    let header_end = loop {
+        if let Some(position) = raw.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position + 4;
+        }
+
+        if raw.len() > MAX_HEADER_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData, "Request header is too long"));
+        }
+
+        let bytes_read = stream.read(&mut buf).await?;
+        if bytes_read == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Request ended early"));
+        }
+
+        raw.extend_from_slice(&buf[..bytes_read]);
+    };
+
+    let header = String::from_utf8_lossy(&raw[..header_end]).to_string();
+    let mut lines = header.split("\r\n");
+
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (method, path) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version)) if version.starts_with("HTTP/1.") => (method.to_string(), path.to_string()),
+        _ => return Err(Error::new(ErrorKind::InvalidData, format!("Malformed request line: \"{}\"", request_line)))
+    };
+
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    let mut request = Request {
+        method,
+        path,
+        headers,
+        body: raw[header_end..].to_vec()
+    };
+
+    let content_length = match request.header("Content-Length") {
+        Some(content_length) => match content_length.parse::<usize>() {
+            Ok(content_length) if content_length <= MAX_BODY_SIZE => content_length,
+            _ => return Err(Error::new(ErrorKind::InvalidData, format!("Invalid Content-Length: \"{}\"", content_length)))
+        },
+        None => 0
+    };
+
+    if request.body.len() < content_length {
+        let already_read = request.body.len();
+        request.body.resize(content_length, 0);
+        stream.read_exact(&mut request.body[already_read..]).await?;
+    }
+
+    request.body.truncate(content_length);
+
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use super::*;
+
+    async fn request(address: SocketAddr, raw: &[u8]) -> String {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(raw).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[async_std::test]
+    async fn requests() {
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server_task = task::spawn(serve_http("Test", listener, |request: Request| async move {
+            let body = format!("{} {} {:?} {}", request.method, request.path, request.header("x-test"), String::from_utf8_lossy(&request.body));
+            Response::new("200 OK", "text/plain", body.into_bytes())
+        }));
+
+        let response = request(address, b"GET /path?query HTTP/1.1\r\nX-Test: value\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nGET /path?query Some(\"value\") "), "{}", response);
+
+        // The body can come in more than one read
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(b"POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello").await.unwrap();
+        task::sleep(Duration::from_millis(50)).await;
+        stream.write_all(b" world").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.ends_with("POST / None hello world"), "{}", response);
+
+        let response = request(address, b"nonsense\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response);
+
+        server_task.cancel().await;
+    }
+}
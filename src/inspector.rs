@@ -0,0 +1,629 @@
+use async_std::net::{SocketAddr, TcpListener};
+use core::time::Duration;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use chrono::Local;
+use rustc_serialize::base64::{STANDARD, ToBase64};
+use rustc_serialize::json::Json;
+
+use crate::connection_id::ConnectionId;
+use crate::http_parser::{BodyLength, Event, Head, MessageParser};
+use crate::http_server::{Request, Response, serve_http};
+use crate::replay::{parse_edits, replay};
+
+pub const DEFAULT_MAX_EXCHANGES: usize = 100;
+pub const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024;
+
+pub struct InspectorOptions {
+    pub address: SocketAddr,
+    // Older exchanges are forgotten
+    pub max_exchanges: usize,
+    // Bodies are cut off after this many bytes
+    pub max_body_size: usize
+}
+
+#[derive(Clone)]
+pub struct Message {
+    pub head: Head,
+    pub body: Vec<u8>,
+    // Including what was cut off
+    pub body_size: u64,
+    pub complete: bool
+}
+
+impl Message {
+    fn new(head: Head) -> Self {
+        Message {
+            head,
+            body: Vec::new(),
+            body_size: 0,
+            complete: false
+        }
+    }
+
+    fn append(&mut self, data: &[u8], max_body_size: usize) {
+        let room = max_body_size.saturating_sub(self.body.len());
+        self.body.extend_from_slice(&data[..room.min(data.len())]);
+        self.body_size += data.len() as u64;
+    }
+}
+
+// A request, and its response once it starts
+#[derive(Clone)]
+pub struct Exchange {
+    pub id: u64,
+    pub connection: Option<ConnectionId>,
+    // RFC 3339
+    pub started: String,
+    started_at: Instant,
+    pub request: Message,
+    pub response: Option<Message>,
+    // From the start of the request to the end of the response
+    pub duration: Option<Duration>,
+    // The exchange that this one replayed
+    pub replay_of: Option<u64>
+}
+
+struct Exchanges {
+    next_id: u64,
+    // Oldest first
+    exchanges: VecDeque<Exchange>
+}
+
+// The last HTTP requests and responses that went through bridged connections
+// Clones share the same exchanges
+#[derive(Clone)]
+pub struct Inspector {
+    max_exchanges: usize,
+    max_body_size: usize,
+    exchanges: Arc<Mutex<Exchanges>>
+}
+
+impl Inspector {
+    pub fn new(max_exchanges: usize, max_body_size: usize) -> Self {
+        Inspector {
+            max_exchanges,
+            max_body_size,
+            exchanges: Arc::new(Mutex::new(Exchanges {
+                next_id: 1,
+                exchanges: VecDeque::new()
+            }))
+        }
+    }
+
+    // Newest first
+    pub fn exchanges(&self) -> Vec<Exchange> {
+        self.exchanges.lock().unwrap().exchanges.iter().rev().cloned().collect()
+    }
+
+    pub fn exchange(&self, id: u64) -> Option<Exchange> {
+        self.exchanges.lock().unwrap().exchanges.iter().find(|exchange| exchange.id == id).cloned()
+    }
+
+    // The clear stream is the client's end on the bounce server, and the server's end on the bounce client
+    pub fn connection(&self, connection: Option<ConnectionId>, clear_is_client: bool) -> InspectedConnection {
+        InspectedConnection {
+            inspector: self.clone(),
+            connection,
+            clear_is_client,
+            state: Mutex::new(ConnectionState::default())
+        }
+    }
+
+    fn add(&self, connection: Option<ConnectionId>, head: Head) -> u64 {
+        let mut exchanges = self.exchanges.lock().unwrap();
+        let id = exchanges.next_id;
+        exchanges.next_id += 1;
+
+        exchanges.exchanges.push_back(Exchange {
+            id,
+            connection,
+            started: Local::now().to_rfc3339(),
+            started_at: Instant::now(),
+            request: Message::new(head),
+            response: None,
+            duration: None,
+            replay_of: None
+        });
+
+        while exchanges.exchanges.len() > self.max_exchanges {
+            exchanges.exchanges.pop_front();
+        }
+
+        id
+    }
+
+    pub fn mark_replay(&self, id: u64, replay_of: u64) {
+        self.update(id, |exchange, _| exchange.replay_of = Some(replay_of));
+    }
+
+    // Does nothing if the exchange was already forgotten
+    fn update(&self, id: u64, update: impl FnOnce(&mut Exchange, usize)) {
+        if let Some(exchange) = self.exchanges.lock().unwrap().exchanges.iter_mut().rev().find(|exchange| exchange.id == id) {
+            update(exchange, self.max_body_size);
+        }
+    }
+}
+
+#[derive(Default)]
+struct ConnectionState {
+    requests: MessageParser,
+    responses: MessageParser,
+    // The exchange whose request is being read
+    request: Option<u64>,
+    // The exchange whose request was read last
+    last_request: Option<u64>,
+    // Exchanges whose response hasn't started, oldest first, with their request's method
+    waiting: VecDeque<(u64, String)>,
+    // The exchange whose response is being read
+    response: Option<u64>
+}
+
+// One inspected connection. Both directions of the bridge parse into it
+pub struct InspectedConnection {
+    inspector: Inspector,
+    connection: Option<ConnectionId>,
+    clear_is_client: bool,
+    state: Mutex<ConnectionState>
+}
+
+impl InspectedConnection {
+    // Data read from the clear stream
+    pub fn clear_data(&self, data: &[u8]) {
+        self.data(self.clear_is_client, data);
+    }
+
+    // Data written to the clear stream
+    pub fn adapter_data(&self, data: &[u8]) {
+        self.data(!self.clear_is_client, data);
+    }
+
+    // The clear stream closed for writing, which ends a response without a length
+    pub fn clear_fin(&self) {
+        if !self.clear_is_client {
+            self.response_fin();
+        }
+    }
+
+    // The other side's clear stream closed for writing
+    pub fn adapter_fin(&self) {
+        if self.clear_is_client {
+            self.response_fin();
+        }
+    }
+
+    pub fn last_request(&self) -> Option<u64> {
+        self.state.lock().unwrap().last_request
+    }
+
+    fn data(&self, from_client: bool, data: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        match from_client {
+            true => self.request_data(&mut state, data),
+            false => self.response_data(&mut state, data)
+        }
+    }
+
+    fn request_data(&self, state: &mut ConnectionState, mut data: &[u8]) {
+        loop {
+            let (used, event) = state.requests.parse(data);
+            data = &data[used..];
+
+            match event {
+                Some(Event::Head(head)) => {
+                    state.requests.start_body(head.request_body_length());
+
+                    let method = head.start_line[0].clone();
+                    let id = self.inspector.add(self.connection, head);
+                    state.request = Some(id);
+                    state.last_request = Some(id);
+                    state.waiting.push_back((id, method));
+                },
+                Some(Event::Body(body)) => if let Some(id) = state.request {
+                    self.inspector.update(id, |exchange, max_body_size| exchange.request.append(body, max_body_size));
+                },
+                Some(Event::End) => if let Some(id) = state.request.take() {
+                    self.inspector.update(id, |exchange, _| exchange.request.complete = true);
+                },
+                None => return
+            }
+        }
+    }
+
+    fn response_data(&self, state: &mut ConnectionState, mut data: &[u8]) {
+        loop {
+            let (used, event) = state.responses.parse(data);
+            data = &data[used..];
+
+            match event {
+                Some(Event::Head(head)) => {
+                    // A response without a request means this isn't HTTP after all
+                    let (id, method) = match state.waiting.front() {
+                        Some(waiting) => waiting.clone(),
+                        None => {
+                            state.responses.stop_parsing();
+                            return;
+                        }
+                    };
+
+                    let body_length = head.response_body_length(&method);
+                    state.responses.start_body(body_length);
+
+                    match head.status() {
+                        // Like 100 Continue, before the real response
+                        Some(status) if (100..200).contains(&status) && status != 101 => continue,
+                        _ => {}
+                    }
+
+                    state.waiting.pop_front();
+
+                    // Switching protocols ends the exchange, and the rest of the connection isn't parsed
+                    if body_length == BodyLength::Stop {
+                        state.requests.stop_parsing();
+                        self.inspector.update(id, |exchange, _| {
+                            let mut response = Message::new(head);
+                            response.complete = true;
+                            exchange.response = Some(response);
+                            exchange.duration = Some(exchange.started_at.elapsed());
+                        });
+                        continue;
+                    }
+
+                    state.response = Some(id);
+                    self.inspector.update(id, |exchange, _| exchange.response = Some(Message::new(head)));
+                },
+                Some(Event::Body(body)) => if let Some(id) = state.response {
+                    self.inspector.update(id, |exchange, max_body_size| if let Some(response) = &mut exchange.response {
+                        response.append(body, max_body_size);
+                    });
+                },
+                Some(Event::End) => if let Some(id) = state.response.take() {
+                    end_response(&self.inspector, id);
+                },
+                None => return
+            }
+        }
+    }
+
+    fn response_fin(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.responses.finish() {
+            if let Some(id) = state.response.take() {
+                end_response(&self.inspector, id);
+            }
+        }
+    }
+}
+
+fn end_response(inspector: &Inspector, id: u64) {
+    inspector.update(id, |exchange, _| {
+        if let Some(response) = &mut exchange.response {
+            response.complete = true;
+        }
+
+        exchange.duration = Some(exchange.started_at.elapsed());
+    });
+}
+
+// Until canceled, answers:
+// - GET /: a page that shows the exchanges
+// - GET /api/exchanges: newest first, without headers or bodies
+// - GET /api/exchanges/[id]: one exchange, with headers and bodies
+// - POST /api/exchanges/[id]/replay: sends the request to the destination again, with the edits in the body, and returns the new exchange
+pub async fn serve_inspector(listener: TcpListener, inspector: Inspector, destination_host: String) {
+    let local_addr = match listener.local_addr() {
+        Ok(local_addr) => local_addr,
+        Err(err) => {
+            log::error!("Inspector: Can not get the listen address: {}", err);
+            return;
+        }
+    };
+
+    serve_http("Inspector", listener, move |request: Request| {
+        let inspector = inspector.clone();
+        let destination_host = destination_host.clone();
+        async move {
+            match allowed(&request, local_addr) {
+                true => handle(&request, &inspector, &destination_host).await,
+                false => Response::empty("403 Forbidden")
+            }
+        }
+    }).await
+}
+
+// Without a token, the inspector relies on only being reachable from this host. Other sites open in a browser here can
+// still send it requests, so the Host has to be the address it's served on, which a rebound DNS name isn't, and a POST
+// from a page has to come from the inspector's own page
+fn allowed(request: &Request, local_addr: SocketAddr) -> bool {
+    let mut hosts = vec![local_addr.to_string()];
+    if local_addr.ip().is_loopback() {
+        hosts.push(format!("localhost:{}", local_addr.port()));
+    }
+
+    let host_allowed = match request.header("Host") {
+        Some(host) => hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host)),
+        None => false
+    };
+
+    // Tools like curl and `bounce replay` don't send an Origin
+    let origin_allowed = match (request.method.as_str(), request.header("Origin")) {
+        ("GET", _) | (_, None) => true,
+        (_, Some(origin)) => hosts.iter().any(|allowed| origin.eq_ignore_ascii_case(&format!("http://{}", allowed)))
+    };
+
+    host_allowed && origin_allowed
+}
+
+async fn handle(request: &Request, inspector: &Inspector, destination_host: &str) -> Response {
+    let path = request.path.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", [""]) => Response::new("200 OK", "text/html; charset=utf-8", PAGE.as_bytes().to_vec()),
+        ("GET", ["api", "exchanges"]) => Response::json("200 OK", Json::Array(inspector.exchanges().iter().map(summary_json).collect()).to_string()),
+        ("GET", ["api", "exchanges", id]) => match id.parse::<u64>().ok().and_then(|id| inspector.exchange(id)) {
+            Some(exchange) => Response::json("200 OK", exchange_json(&exchange).to_string()),
+            None => Response::empty("404 Not Found")
+        },
+        ("POST", ["api", "exchanges", id, "replay"]) => match id.parse::<u64>() {
+            Ok(id) => replay_response(inspector, destination_host, id, &request.body).await,
+            Err(_) => Response::empty("404 Not Found")
+        },
+        (_, [""]) | (_, ["api", "exchanges"]) | (_, ["api", "exchanges", _]) | (_, ["api", "exchanges", _, "replay"]) => Response::empty("405 Method Not Allowed"),
+        _ => Response::empty("404 Not Found")
+    }
+}
+
+async fn replay_response(inspector: &Inspector, destination_host: &str, id: u64, body: &[u8]) -> Response {
+    let edits = match parse_edits(body) {
+        Ok(edits) => edits,
+        Err(err) => return Response::new("400 Bad Request", "text/plain", err.to_string().into_bytes())
+    };
+
+    match replay(inspector, destination_host, id, &edits).await {
+        Ok(exchange) => Response::json("200 OK", exchange_json(&exchange).to_string()),
+        Err(err) => {
+            let status = match err.kind() {
+                ErrorKind::NotFound => "404 Not Found",
+                ErrorKind::InvalidData => "400 Bad Request",
+                _ => "502 Bad Gateway"
+            };
+
+            Response::new(status, "text/plain", err.to_string().into_bytes())
+        }
+    }
+}
+
+fn summary_object(exchange: &Exchange) -> BTreeMap<String, Json> {
+    let mut object = BTreeMap::new();
+    object.insert("id".to_string(), Json::U64(exchange.id));
+    object.insert("connection".to_string(), match exchange.connection {
+        Some(connection) => Json::String(connection.to_string()),
+        None => Json::Null
+    });
+    object.insert("started".to_string(), Json::String(exchange.started.clone()));
+    object.insert("method".to_string(), Json::String(exchange.request.head.start_line[0].clone()));
+    object.insert("target".to_string(), Json::String(exchange.request.head.start_line[1].clone()));
+    object.insert("request_body_size".to_string(), Json::U64(exchange.request.body_size));
+    object.insert("status".to_string(), match exchange.response.as_ref().and_then(|response| response.head.status()) {
+        Some(status) => Json::U64(status as u64),
+        None => Json::Null
+    });
+    object.insert("response_body_size".to_string(), match &exchange.response {
+        Some(response) => Json::U64(response.body_size),
+        None => Json::Null
+    });
+    object.insert("duration_ms".to_string(), match exchange.duration {
+        Some(duration) => Json::F64(duration.as_secs_f64() * 1000.0),
+        None => Json::Null
+    });
+    object.insert("replay_of".to_string(), match exchange.replay_of {
+        Some(replay_of) => Json::U64(replay_of),
+        None => Json::Null
+    });
+    object
+}
+
+fn summary_json(exchange: &Exchange) -> Json {
+    Json::Object(summary_object(exchange))
+}
+
+pub fn exchange_json(exchange: &Exchange) -> Json {
+    let mut object = summary_object(exchange);
+    object.insert("request".to_string(), message_json(&exchange.request));
+    object.insert("response".to_string(), match &exchange.response {
+        Some(response) => message_json(response),
+        None => Json::Null
+    });
+    Json::Object(object)
+}
+
+// Bodies that aren't UTF-8 are base64
+fn message_json(message: &Message) -> Json {
+    let mut object = BTreeMap::new();
+    object.insert("start_line".to_string(), Json::String(message.head.start_line.join(" ").trim_end().to_string()));
+    object.insert("headers".to_string(), Json::Array(message.head.headers.iter()
+        .map(|(name, value)| Json::Array(vec![Json::String(name.clone()), Json::String(value.clone())]))
+        .collect()));
+
+    let (body, encoding) = match std::str::from_utf8(&message.body) {
+        Ok(body) => (body.to_string(), "utf-8"),
+        Err(_) => (message.body.to_base64(STANDARD), "base64")
+    };
+
+    object.insert("body".to_string(), Json::String(body));
+    object.insert("body_encoding".to_string(), Json::String(encoding.to_string()));
+    object.insert("body_size".to_string(), Json::U64(message.body_size));
+    object.insert("body_truncated".to_string(), Json::Boolean((message.body.len() as u64) < message.body_size));
+    object.insert("complete".to_string(), Json::Boolean(message.complete));
+    Json::Object(object)
+}
+
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>bounce inspector</title>
+<style>
+body { font-family: sans-serif; margin: 0; display: flex; height: 100vh; }
+#list { width: 45%; overflow: auto; border-right: 1px solid #ccc; }
+#detail { flex: 1; overflow: auto; padding: 0 1em; }
+table { border-collapse: collapse; width: 100%; font-size: 13px; }
+td, th { padding: 4px 6px; text-align: left; border-bottom: 1px solid #eee; }
+tr.exchange { cursor: pointer; }
+tr.exchange:hover, tr.selected { background: #eef; }
+pre { background: #f6f6f6; padding: 8px; white-space: pre-wrap; word-break: break-all; }
+</style>
+</head>
+<body>
+<div id="list"><table><thead><tr><th>Time</th><th>Method</th><th>Target</th><th>Status</th><th>ms</th><th>Bytes</th></tr></thead><tbody id="exchanges"></tbody></table></div>
+<div id="detail"><p>Select a request</p></div>
+<script>
+let selected = null;
+
+function cell(row, text) {
+  const td = document.createElement('td');
+  td.textContent = text === null || text === undefined ? '' : text;
+  row.appendChild(td);
+}
+
+function message(title, m) {
+  const section = document.createElement('div');
+  const heading = document.createElement('h3');
+  heading.textContent = title;
+  section.appendChild(heading);
+  if (!m) {
+    const p = document.createElement('p');
+    p.textContent = 'No response yet';
+    section.appendChild(p);
+    return section;
+  }
+  const head = document.createElement('pre');
+  head.textContent = m.start_line + '\n' + m.headers.map(h => h[0] + ': ' + h[1]).join('\n');
+  section.appendChild(head);
+  const body = document.createElement('pre');
+  body.textContent = (m.body_encoding === 'base64' ? '(base64) ' : '') + m.body +
+    (m.body_truncated ? '\n... ' + m.body_size + ' bytes in all' : '') + (m.complete ? '' : '\n(incomplete)');
+  section.appendChild(body);
+  return section;
+}
+
+async function show(id) {
+  selected = id;
+  const response = await fetch('/api/exchanges/' + id);
+  const detail = document.getElementById('detail');
+  detail.replaceChildren();
+  if (!response.ok) {
+    detail.textContent = 'Forgotten';
+    return;
+  }
+  const exchange = await response.json();
+  const replay = document.createElement('button');
+  replay.textContent = 'Replay';
+  replay.onclick = async () => {
+    const replayed = await fetch('/api/exchanges/' + id + '/replay', { method: 'POST' });
+    if (replayed.ok) {
+      show((await replayed.json()).id);
+    } else {
+      alert(await replayed.text());
+    }
+  };
+  detail.appendChild(replay);
+  detail.appendChild(message('Request', exchange.request));
+  detail.appendChild(message('Response', exchange.response));
+  refresh();
+}
+
+async function refresh() {
+  const exchanges = await (await fetch('/api/exchanges')).json();
+  const tbody = document.getElementById('exchanges');
+  tbody.replaceChildren();
+  for (const exchange of exchanges) {
+    const row = document.createElement('tr');
+    row.className = 'exchange' + (exchange.id === selected ? ' selected' : '');
+    cell(row, new Date(exchange.started).toLocaleTimeString());
+    cell(row, exchange.method);
+    cell(row, exchange.target + (exchange.replay_of === null ? '' : ' (replay of ' + exchange.replay_of + ')'));
+    cell(row, exchange.status);
+    cell(row, exchange.duration_ms === null ? null : exchange.duration_ms.toFixed(1));
+    cell(row, exchange.request_body_size + ' / ' + (exchange.response_body_size === null ? '-' : exchange.response_body_size));
+    row.onclick = () => show(exchange.id);
+    tbody.appendChild(row);
+  }
+}
+
+refresh();
+setInterval(refresh, 2000);
+</script>
+</body>
+</html>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exchanges() {
+        let inspector = Inspector::new(2, 8);
+
+        // On the bounce client, requests are written to the clear stream
+        let connection = inspector.connection(None, false);
+        connection.adapter_data(b"POST /hook HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello");
+        connection.adapter_data(b" world");
+        connection.clear_data(b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok");
+
+        let exchanges = inspector.exchanges();
+        assert_eq!(1, exchanges.len());
+        assert_eq!("POST", exchanges[0].request.head.start_line[0]);
+        assert_eq!(b"hello wo", &exchanges[0].request.body[..]);
+        assert_eq!(11, exchanges[0].request.body_size);
+        assert!(exchanges[0].request.complete);
+
+        let response = exchanges[0].response.as_ref().unwrap();
+        assert_eq!(Some(201), response.head.status());
+        assert_eq!(b"ok", &response.body[..]);
+        assert!(response.complete);
+        assert!(exchanges[0].duration.is_some());
+
+        // Pipelined requests get their responses in order, and a response without a length ends with the connection
+        connection.adapter_data(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n");
+        connection.clear_data(b"HTTP/1.1 200 OK\r\nContent-Length: 1\r\n\r\naHTTP/1.1 200 OK\r\n\r\nb");
+
+        let exchanges = inspector.exchanges();
+        assert_eq!(2, exchanges.len(), "Oldest is forgotten");
+        assert_eq!("/b", exchanges[0].request.head.start_line[1]);
+        assert_eq!("/a", exchanges[1].request.head.start_line[1]);
+        assert_eq!(b"a", &exchanges[1].response.as_ref().unwrap().body[..]);
+        assert!(!exchanges[0].response.as_ref().unwrap().complete);
+
+        connection.clear_fin();
+        let exchange = inspector.exchange(exchanges[0].id).unwrap();
+        assert_eq!(b"b", &exchange.response.as_ref().unwrap().body[..]);
+        assert!(exchange.response.unwrap().complete);
+        assert!(inspector.exchange(1).is_none());
+    }
+
+    #[test]
+    fn json() {
+        let inspector = Inspector::new(DEFAULT_MAX_EXCHANGES, DEFAULT_MAX_BODY_SIZE);
+
+        // On the bounce server, requests are read from the clear stream
+        let connection = inspector.connection(Some(ConnectionId::random()), true);
+        connection.clear_data(b"PUT /binary HTTP/1.1\r\nContent-Length: 2\r\n\r\n\xff\xfe");
+
+        let exchanges = inspector.exchanges();
+        let json = exchange_json(&exchanges[0]);
+        let request = json.find("request").unwrap();
+
+        assert_eq!(Some("PUT /binary HTTP/1.1"), request.find("start_line").unwrap().as_string());
+        assert_eq!(Some("Content-Length"), request.find("headers").unwrap()[0][0].as_string());
+        assert_eq!(Some("//4="), request.find("body").unwrap().as_string());
+        assert_eq!(Some("base64"), request.find("body_encoding").unwrap().as_string());
+        assert!(json.find("response").unwrap().is_null());
+        assert!(json.find("status").unwrap().is_null());
+    }
+}
@@ -1,6 +1,8 @@
 extern crate rand;
 
 use crypto::aes::KeySize;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
 use rand::RngCore;
 use rand::rngs::OsRng;
 use rustc_serialize::base64::{FromBase64, STANDARD, ToBase64};
@@ -12,6 +14,19 @@ pub struct Key {
     pub size: KeySize
 }
 
+impl Key {
+    // Names the key in logs and the admin API without giving it away: the first 8 bytes of its SHA-256, in hex
+    pub fn fingerprint(&self) -> String {
+        let mut sha256 = Sha256::new();
+        sha256.input(&self.key);
+
+        let mut hash = [0u8; 32];
+        sha256.result(&mut hash);
+
+        hash[..8].iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
 pub fn generate_keys() {
     let mut key = vec![0u8; 256 / 8];
     OsRng.fill_bytes(&mut key);
@@ -52,11 +67,18 @@ mod tests {
 
     #[test]
     fn parse_key_test_256() {
-        let key = vec![1 as u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32];
+        let key = vec![1u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32];
         let key_str = key.to_base64(STANDARD);
 
         let parsed_key = parse_key(&key_str);
         assert_eq!(key, parsed_key.key);
         matches!(KeySize::KeySize256, parsed_key.size);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn fingerprint() {
+        let key = parse_key(&[0u8; 32].to_base64(STANDARD));
+        // SHA-256 of 32 zero bytes starts with 66687aadf862bd77
+        assert_eq!("66687aadf862bd77", key.fingerprint());
+    }
+}
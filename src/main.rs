@@ -1,20 +1,69 @@
+mod address;
+mod admin;
 mod auth;
+mod backoff;
+mod bandwidth;
 mod bridge;
+mod buffer_pool;
+mod capture;
 mod client;
+mod compression;
+mod connection_id;
+mod connection_info;
+mod datagram;
+mod health;
+mod http_proxy;
+mod http_parser;
+mod http_server;
+mod inspector;
 mod keys;
+mod metrics;
+mod pending;
+mod proxy_protocol;
+mod registry;
+mod replay;
 mod server;
+mod shutdown;
+mod signal;
+mod sniff;
+mod status;
+mod stream;
+mod websocket;
 mod xor;
 
+use core::time::Duration;
+use std::collections::HashMap;
 use std::env::{args, var};
-use std::io::{ Error, ErrorKind, Write };
+use std::io::{ Error, Write };
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
 use chrono::Local;
 use env_logger::Builder;
-use log::LevelFilter;
+use log::{LevelFilter, Record};
+use rustc_serialize::json::Json;
 
-use client::run_client;
+use address::{parse_bind_address, parse_bind_addresses, parse_listen_addresses};
+use admin::AdminOptions;
+use backoff::ReconnectOptions;
+use bandwidth::{Bandwidth, BandwidthOptions};
+use bridge::ConnectionTimeouts;
+use buffer_pool::{BufferPool, MIN_BUFFER_SIZE};
+use capture::{Capture, CaptureOptions, DEFAULT_MAX_SIZE, parse_ports};
+use client::{ClientOptions, run_client};
+use compression::parse_compression;
+use connection_id::{ConnectionId, connection_id};
+use http_proxy::{HttpProxy, parse_proxy_url, proxy_from_env};
+use inspector::{DEFAULT_MAX_BODY_SIZE, DEFAULT_MAX_EXCHANGES, InspectorOptions};
 use keys::{Key, generate_keys, parse_key};
-use server::run_server;
+use proxy_protocol::parse_proxy_protocol;
+use replay::{Edits, format_exchange, request_replay};
+use server::{ServerOptions, parse_unavailable_response, run_server};
+use shutdown::{ShutdownResult, run_until_shutdown};
+use signal::{HeartbeatOptions, MAX_HEARTBEAT_INTERVAL};
+use status::{format_status, request_status};
+
+// Leaves time before a typical SIGKILL, which comes 30 seconds after SIGTERM
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(25);
 
 #[async_std::main]
 async fn main() {
@@ -27,90 +76,172 @@ async fn main() {
         Err(_) => main_args().await
     };
 
-    match result {
-        Ok(()) => {},
-        Err(err) => log::error!("Bounce terminated in error:\n\t{}", err)
+    // Exits with 2 when connections were still open at the drain deadline
+    let exit_code = match result {
+        Ok(ShutdownResult::Drained) => 0,
+        Ok(ShutdownResult::DeadlinePassed(_)) => 2,
+        Err(err) => {
+            log::error!("Bounce terminated in error:\n\t{}", err);
+            1
+        }
+    };
+
+    std::process::exit(exit_code);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LogFormat {
+    Text,
+    // One JSON object per line, for log collectors
+    Json
+}
+
+fn parse_log_format(log_format_str: &str) -> Result<LogFormat, Error> {
+    match log_format_str {
+        "text" => Ok(LogFormat::Text),
+        "json" => Ok(LogFormat::Json),
+        _ => Err(Error::other(format!("Invalid BOUNCE_LOG_FORMAT, must be text or json: \"{}\"", log_format_str)))
     }
 }
 
+// BOUNCE_LOG_FORMAT is read from the environment even when options are passed as arguments, like BOUNCE_LOG
 fn setup_logging() {
+    let parsed = var("BOUNCE_LOG_FORMAT").map(|log_format_str| parse_log_format(&log_format_str)).unwrap_or(Ok(LogFormat::Text));
+    let log_format = *parsed.as_ref().unwrap_or(&LogFormat::Text);
+
     Builder::new()
         .parse_env("BOUNCE_LOG")
-        .format(|buf, record| {
-            writeln!(buf,
-                "{} [{}] - {}",
-                Local::now().format("%Y-%m-%dT%H:%M:%S"),
-                record.level(),
-                record.args()
-            )
+        .format(move |buf, record| {
+            let time = Local::now();
+            match log_format {
+                LogFormat::Json => writeln!(buf, "{}", format_json_log(&time.format("%Y-%m-%dT%H:%M:%S%.3f%:z").to_string(), record, connection_id())),
+                LogFormat::Text => writeln!(buf, "{}", format_text_log(&time.format("%Y-%m-%dT%H:%M:%S").to_string(), record, connection_id()))
+            }
         })
         .filter(None, LevelFilter::Info)
         .init();
+
+    if let Err(err) = parsed {
+        log::error!("{}, logging as text", err);
+    }
+}
+
+fn format_text_log(time: &str, record: &Record, id: Option<ConnectionId>) -> String {
+    match id {
+        Some(id) => format!("{} [{}] {} - {}", time, record.level(), id, record.args()),
+        None => format!("{} [{}] - {}", time, record.level(), record.args())
+    }
+}
+
+fn format_json_log(time: &str, record: &Record, id: Option<ConnectionId>) -> String {
+    let mut line = format!(
+        "{{\"time\":{},\"level\":{},\"target\":{}",
+        json_string(time),
+        json_string(record.level().as_str()),
+        json_string(record.target()));
+
+    if let Some(id) = id {
+        line.push_str(&format!(",\"connection\":{}", json_string(&id.to_string())));
+    }
+
+    line.push_str(&format!(",\"message\":{}}}", json_string(&record.args().to_string())));
+    line
+}
+
+fn json_string(value: &str) -> String {
+    Json::String(value.to_string()).to_string()
 }
 
-async fn main_env(mode: String) -> Result<(), Error> {
+async fn main_env(mode: String) -> Result<ShutdownResult, Error> {
 
     // Environment errors are logged, because it's assumed these need to go into a standard logger
 
     match parse_mode(&mode) {
         Mode::Server => {
-            let port = get_port_from_env("BOUNCE_PORT")?;
-            let adapter_port = get_port_from_env("BOUNCE_ADAPTER_PORT")?;
+            let listen_addresses = parse_listen_addresses(&get_env_var("BOUNCE_PORT")?)?;
+            let options = get_server_options(&Settings::Env)?;
+
+            // Hosts that only route one port use BOUNCE_WEBSOCKET_PATH or BOUNCE_SHARED_PORT instead of an adapter port
+            let adapter_optional = options.websocket_path.is_some() || options.shared_port;
+            let adapter_addresses = if adapter_optional && var("BOUNCE_ADAPTER_PORT").is_err() {
+                Vec::new()
+            } else {
+                parse_bind_addresses(&get_env_var("BOUNCE_ADAPTER_PORT")?)?
+            };
+
             let key = get_key_from_env("BOUNCE_KEY")?;
+            let drain_timeout = get_drain_timeout(&Settings::Env)?;
+            let connections = options.connections.clone();
         
-            let (server_future, _, _) = run_server(port, adapter_port, key);
-            server_future.await?;
+            let (server_future, _, cancelation_token) = run_server(listen_addresses, adapter_addresses, key, options);
+            run_until_shutdown(server_future, cancelation_token, connections, drain_timeout).await
         },
         Mode::Client => {
             let bounce_server = get_env_var("BOUNCE_SERVER")?;
             let destination_host = get_env_var("BOUNCE_DESTINATION_HOST")?;
             let key = get_key_from_env("BOUNCE_KEY")?;
+            let options = get_client_options(&Settings::Env)?;
+            let drain_timeout = get_drain_timeout(&Settings::Env)?;
+            let connections = options.connections.clone();
 
-            let (client_future, _) = run_client(bounce_server, destination_host, key);
-            client_future.await?;
+            let (client_future, cancelation_token) = run_client(bounce_server, destination_host, key, options);
+            run_until_shutdown(client_future, cancelation_token, connections, drain_timeout).await
         },
         Mode::Keys => {
             generate_keys();
-        }
+            Ok(ShutdownResult::Drained)
+        },
+        Mode::Replay => Err(Error::other("Replay only runs from the command line")),
+        Mode::Status => Err(Error::other("Status only runs from the command line"))
     }
-
-    Ok(())
 }
 
-async fn main_args() -> Result<(), Error> {
-    let args: Vec<String> = args().collect();
+async fn main_args() -> Result<ShutdownResult, Error> {
 
     // Panics are used instead of logging because it's assumed that bounce is being run interactively
 
+    let (args, settings) = split_args(args().collect());
+
     if args.len() < 2 {
         panic!("Must pass the mode (Server or Client) as the first argument");
     }
 
     match parse_mode(&args[1]) {
         Mode::Server => {
-            if args.len() != 5 {
-                panic!("Please specify the ports as command-line arguments:\n\t bounce server [port] [adapter port] [key]");
-            }
+            let options = get_server_options(&settings).unwrap();
+
+            // With --websocket-path or --shared-port, the adapter port can be left out
+            let adapter_optional = options.websocket_path.is_some() || options.shared_port;
+            let (listen_str, adapter_str, key_str) = match (args.len(), adapter_optional) {
+                (5, _) => (&args[2], Some(&args[3]), &args[4]),
+                (4, true) => (&args[2], None, &args[3]),
+                _ => panic!("Please specify the ports as command-line arguments:\n\t bounce server [port | address:port | udp:address:port | unix:path],... [adapter port | address:port],... [key] [--option value]...\n\t bounce server [port | address:port],... [key] [--websocket-path [path] | --shared-port true] [--option value]...")
+            };
         
-            let port = parse_port(&args[2]).unwrap();
-            let adapter_port = parse_port(&args[3]).unwrap();
-            let key = parse_key(&args[4]);
+            let listen_addresses = parse_listen_addresses(listen_str).unwrap();
+            let adapter_addresses = adapter_str.map(|a| parse_bind_addresses(a).unwrap()).unwrap_or_default();
+            let key = parse_key(key_str);
+            let drain_timeout = get_drain_timeout(&settings).unwrap();
+            let connections = options.connections.clone();
         
-            let (server_future, _, _) = run_server(port, adapter_port, key);
-            server_future.await?;
+            let (server_future, _, cancelation_token) = run_server(listen_addresses, adapter_addresses, key, options);
+            run_until_shutdown(server_future, cancelation_token, connections, drain_timeout).await
         },
         Mode::Client => {
 
             if args.len() != 5 {
-                panic!("Please specify the host and port as command-line arguments:\n\t bounce client [bounce server:port] [destination:port] [key]");
+                panic!("Please specify the host and port as command-line arguments:\n\t bounce client [bounce server:port | ws://host/path | wss://host/path] [destination:port | unix:path] [key] [--option value]...");
             }
         
             let bounce_server = args[2].clone();
             let destination_host = args[3].clone();
             let key = parse_key(&args[4]);
+            let options = get_client_options(&settings).unwrap();
+            let drain_timeout = get_drain_timeout(&settings).unwrap();
+            let connections = options.connections.clone();
         
-            let (client_future, _) = run_client(bounce_server, destination_host, key);
-            client_future.await?;
+            let (client_future, cancelation_token) = run_client(bounce_server, destination_host, key, options);
+            run_until_shutdown(client_future, cancelation_token, connections, drain_timeout).await
         },
         Mode::Keys => {
             if args.len() != 2 {
@@ -118,40 +249,420 @@ async fn main_args() -> Result<(), Error> {
             }
 
             generate_keys();
+            Ok(ShutdownResult::Drained)
+        },
+        Mode::Replay => {
+            if args.len() != 4 {
+                panic!("Please specify the client's inspector and the exchange to replay:\n\t bounce replay [inspector port | address:port] [exchange id] [--set-header \"Name: value\"] [--remove-header Name,...] [--body text | --body-file path]");
+            }
+
+            let inspector_address = parse_local_address(&args[2]).unwrap();
+            let id = args[3].parse::<u64>().unwrap_or_else(|_| panic!("Invalid exchange id: \"{}\"", args[3]));
+            let edits = get_replay_edits(&settings).unwrap();
+
+            let exchange = request_replay(inspector_address, id, &edits).await?;
+            println!("{}", format_exchange(&exchange));
+            Ok(ShutdownResult::Drained)
+        },
+        Mode::Status => {
+            if args.len() != 4 {
+                panic!("Please specify the server and key as command-line arguments:\n\t bounce status [bounce server:port | ws://host/path | wss://host/path] [key] [--proxy url]");
+            }
+
+            let key = parse_key(&args[3]);
+            let proxy = get_proxy(&settings).unwrap();
+
+            let status = request_status(&args[2], key, proxy.as_ref()).await?;
+            println!("{}", format_status(&status));
+            Ok(ShutdownResult::Drained)
         }
     }
-
-    Ok(())
 }
 
 fn get_env_var(var_name: &str) -> Result<String, Error> {
     match var(var_name) {
         Ok(val) => Ok(val),
-        Err(_) => Err(Error::new(ErrorKind::Other, format!("{} must be set", var_name)))
+        Err(_) => Err(Error::other(format!("{} must be set", var_name)))
     }
 }
 
-fn get_port_from_env(var_name: &str) -> Result<u16, Error> {
-    let port_str = get_env_var(var_name)?;
-    Ok(parse_port(&port_str)?)
+fn get_key_from_env(var_name: &str) -> Result<Key, Error> {
+    let key_str = get_env_var(var_name)?;
+    Ok(parse_key(&key_str))
+}
+
+// Optional settings are passed as "--name value" after the positional arguments,
+// or as BOUNCE_NAME environment variables when BOUNCE_MODE is set
+enum Settings {
+    Args(HashMap<String, String>),
+    Env
 }
 
-fn parse_port(port_str: &str) -> Result<u16, Error> {
-    match port_str.parse::<u16>() {
-        Ok(port) => Ok(port),
-        Err(err) => Err(Error::new(ErrorKind::Other, format!("Invalid port \"{}\": {}", port_str, err)))
+impl Settings {
+    fn get(&self, name: &str) -> Option<String> {
+        match self {
+            Settings::Args(args) => args.get(name).cloned(),
+            Settings::Env => var(format!("BOUNCE_{}", name.to_uppercase().replace('-', "_"))).ok()
+        }
     }
 }
 
-fn get_key_from_env(var_name: &str) -> Result<Key, Error> {
-    let key_str = get_env_var(var_name)?;
-    Ok(parse_key(&key_str))
+fn split_args(args: Vec<String>) -> (Vec<String>, Settings) {
+    let positional_len = args.iter().position(|arg| arg.starts_with("--")).unwrap_or(args.len());
+    let positional = args[..positional_len].to_vec();
+
+    let mut settings = HashMap::new();
+    let mut options = args[positional_len..].iter();
+    while let Some(name) = options.next() {
+        let name = name.strip_prefix("--").unwrap_or_else(|| panic!("Expected an option, got \"{}\"", name));
+        let value = options.next().unwrap_or_else(|| panic!("Option --{} needs a value", name));
+        settings.insert(name.to_string(), value.clone());
+    }
+
+    (positional, Settings::Args(settings))
+}
+
+fn get_server_options(settings: &Settings) -> Result<ServerOptions, Error> {
+    let mut options = ServerOptions::default();
+
+    if let Some(udp_idle_timeout) = settings.get("udp-idle-timeout") {
+        options.udp_idle_timeout = parse_seconds("udp-idle-timeout", &udp_idle_timeout)?;
+    }
+
+    if let Some(unavailable_response) = settings.get("unavailable-response") {
+        options.unavailable_response = parse_unavailable_response(&unavailable_response)?;
+    }
+
+    if let Some(pending_queue_size) = settings.get("pending-queue-size") {
+        options.pending.max_size = match pending_queue_size.parse::<usize>() {
+            Ok(max_size) if max_size > 0 => max_size,
+            _ => return Err(Error::other(format!("Invalid pending-queue-size: \"{}\"", pending_queue_size)))
+        };
+    }
+
+    if let Some(pending_timeout) = settings.get("pending-timeout") {
+        options.pending.timeout = parse_seconds("pending-timeout", &pending_timeout)?;
+    }
+
+    if let Some(websocket_path) = settings.get("websocket-path") {
+        if !websocket_path.starts_with('/') {
+            return Err(Error::other(format!("Invalid websocket-path, must start with /: \"{}\"", websocket_path)));
+        }
+
+        options.websocket_path = Some(websocket_path);
+    }
+
+    if let Some(shared_port) = settings.get("shared-port") {
+        options.shared_port = parse_bool("shared-port", &shared_port)?;
+    }
+
+    if let Some(health_path) = settings.get("health-path") {
+        let trimmed = health_path.trim_end_matches('/');
+        if !trimmed.starts_with('/') {
+            return Err(Error::other(format!("Invalid health-path, must start with / and not be just /: \"{}\"", health_path)));
+        }
+
+        options.health_path = Some(trimmed.to_string());
+    }
+
+    if let Some(sniff_timeout) = settings.get("sniff-timeout") {
+        options.sniff_timeout = parse_seconds("sniff-timeout", &sniff_timeout)?;
+    }
+
+    if let Some(compression) = settings.get("compression") {
+        options.compression = parse_compression(&compression)?;
+    }
+
+    options.heartbeat = get_heartbeat_options(settings)?;
+    options.bandwidth = get_bandwidth_options(settings)?;
+    options.timeouts = get_connection_timeouts(settings)?;
+    options.buffers = get_buffer_pool(settings)?;
+    options.metrics_address = get_metrics_address(settings)?;
+    options.admin = get_admin_options(settings)?;
+    options.health_address = get_health_address(settings)?;
+    options.capture = get_capture(settings)?;
+
+    Ok(options)
+}
+
+fn get_client_options(settings: &Settings) -> Result<ClientOptions, Error> {
+    let mut options = ClientOptions::default();
+
+    if let Some(proxy_protocol) = settings.get("proxy-protocol") {
+        options.proxy_protocol = Some(parse_proxy_protocol(&proxy_protocol)?);
+    }
+
+    if let Some(compression) = settings.get("compression") {
+        options.compression = parse_compression(&compression)?;
+    }
+
+    options.heartbeat = get_heartbeat_options(settings)?;
+    options.reconnect = get_reconnect_options(settings)?;
+    options.bandwidth = get_bandwidth_options(settings)?;
+    options.timeouts = get_connection_timeouts(settings)?;
+    options.buffers = get_buffer_pool(settings)?;
+    options.metrics_address = get_metrics_address(settings)?;
+    options.capture = get_capture(settings)?;
+    options.inspector = get_inspector_options(settings)?;
+
+    options.proxy = get_proxy(settings)?;
+
+    Ok(options)
+}
+
+// --proxy takes precedence over HTTPS_PROXY
+fn get_proxy(settings: &Settings) -> Result<Option<HttpProxy>, Error> {
+    match settings.get("proxy") {
+        Some(proxy) => Ok(Some(parse_proxy_url(&proxy)?)),
+        None => proxy_from_env()
+    }
+}
+
+fn get_reconnect_options(settings: &Settings) -> Result<ReconnectOptions, Error> {
+    let mut options = ReconnectOptions::default();
+
+    if let Some(reconnect_initial) = settings.get("reconnect-initial") {
+        options.initial_interval = parse_seconds("reconnect-initial", &reconnect_initial)?;
+    }
+
+    if let Some(reconnect_max) = settings.get("reconnect-max") {
+        options.max_interval = parse_seconds("reconnect-max", &reconnect_max)?;
+    }
+
+    if let Some(reconnect_multiplier) = settings.get("reconnect-multiplier") {
+        options.multiplier = match reconnect_multiplier.parse::<f64>() {
+            Ok(multiplier) if multiplier >= 1.0 => multiplier,
+            _ => return Err(Error::other(format!("Invalid reconnect-multiplier, must be at least 1: \"{}\"", reconnect_multiplier)))
+        };
+    }
+
+    if let Some(reconnect_jitter) = settings.get("reconnect-jitter") {
+        options.jitter = match reconnect_jitter.parse::<f64>() {
+            Ok(jitter) if (0.0..=1.0).contains(&jitter) => jitter,
+            _ => return Err(Error::other(format!("Invalid reconnect-jitter, must be between 0 and 1: \"{}\"", reconnect_jitter)))
+        };
+    }
+
+    if let Some(reconnect_retries) = settings.get("reconnect-retries") {
+        options.max_retries = match reconnect_retries.parse::<u32>() {
+            Ok(max_retries) => Some(max_retries),
+            _ => return Err(Error::other(format!("Invalid reconnect-retries: \"{}\"", reconnect_retries)))
+        };
+    }
+
+    Ok(options)
+}
+
+// Rates are in bytes per second, in each direction
+fn get_bandwidth_options(settings: &Settings) -> Result<BandwidthOptions, Error> {
+    let mut options = BandwidthOptions::default();
+
+    if let Some(bandwidth_limit) = settings.get("bandwidth-limit") {
+        options.shared = Bandwidth::new(Some(parse_size("bandwidth-limit", &bandwidth_limit)?));
+    }
+
+    if let Some(connection_bandwidth_limit) = settings.get("connection-bandwidth-limit") {
+        options.connection_limit = Some(parse_size("connection-bandwidth-limit", &connection_bandwidth_limit)?);
+    }
+
+    Ok(options)
+}
+
+fn get_connection_timeouts(settings: &Settings) -> Result<ConnectionTimeouts, Error> {
+    let mut timeouts = ConnectionTimeouts::default();
+
+    if let Some(idle_timeout) = settings.get("idle-timeout") {
+        timeouts.idle = Some(parse_seconds("idle-timeout", &idle_timeout)?);
+    }
+
+    if let Some(max_lifetime) = settings.get("max-lifetime") {
+        timeouts.max_lifetime = Some(parse_seconds("max-lifetime", &max_lifetime)?);
+    }
+
+    Ok(timeouts)
+}
+
+fn get_buffer_pool(settings: &Settings) -> Result<BufferPool, Error> {
+    match settings.get("buffer-size") {
+        Some(buffer_size) => match buffer_size.parse::<usize>() {
+            Ok(buffer_size) if buffer_size >= MIN_BUFFER_SIZE => Ok(BufferPool::new(buffer_size)),
+            _ => Err(Error::other(format!("Invalid buffer-size, must be at least {} bytes: \"{}\"", MIN_BUFFER_SIZE, buffer_size)))
+        },
+        None => Ok(BufferPool::default())
+    }
+}
+
+// "[port]" or "address:[port]" to serve Prometheus metrics on
+fn get_metrics_address(settings: &Settings) -> Result<Option<SocketAddr>, Error> {
+    settings.get("metrics-address").map(|metrics_address| parse_bind_address(&metrics_address)).transpose()
+}
+
+// "[port]" or "address:[port]" to serve liveness and readiness on
+fn get_health_address(settings: &Settings) -> Result<Option<SocketAddr>, Error> {
+    settings.get("health-address").map(|health_address| parse_bind_address(&health_address)).transpose()
+}
+
+// A bare port only listens on loopback, for endpoints that shouldn't be public
+fn parse_local_address(address_str: &str) -> Result<SocketAddr, Error> {
+    match address_str.parse::<u16>() {
+        Ok(port) => Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)),
+        Err(_) => parse_bind_address(address_str)
+    }
+}
+
+// The admin API can close connections
+fn get_admin_options(settings: &Settings) -> Result<Option<AdminOptions>, Error> {
+    let address = match settings.get("admin-address") {
+        Some(admin_address) => parse_local_address(&admin_address)?,
+        None => return Ok(None)
+    };
+
+    match settings.get("admin-token") {
+        Some(token) if !token.is_empty() => Ok(Some(AdminOptions { address, token })),
+        _ => Err(Error::other("admin-token is required with admin-address"))
+    }
+}
+
+// The inspector shows request and response bodies
+fn get_inspector_options(settings: &Settings) -> Result<Option<InspectorOptions>, Error> {
+    let address = match settings.get("inspector-address") {
+        Some(inspector_address) => parse_local_address(&inspector_address)?,
+        None => return Ok(None)
+    };
+
+    let max_exchanges = match settings.get("inspector-max-exchanges") {
+        Some(max_exchanges) => match max_exchanges.parse::<usize>() {
+            Ok(max_exchanges) if max_exchanges > 0 => max_exchanges,
+            _ => return Err(Error::other(format!("Invalid inspector-max-exchanges: \"{}\"", max_exchanges)))
+        },
+        None => DEFAULT_MAX_EXCHANGES
+    };
+
+    let max_body_size = match settings.get("inspector-max-body-size") {
+        Some(max_body_size) => match max_body_size.parse::<usize>() {
+            Ok(max_body_size) => max_body_size,
+            _ => return Err(Error::other(format!("Invalid inspector-max-body-size, must be bytes: \"{}\"", max_body_size)))
+        },
+        None => DEFAULT_MAX_BODY_SIZE
+    };
+
+    Ok(Some(InspectorOptions { address, max_exchanges, max_body_size }))
+}
+
+// One header can be set, and a comma-separated list removed. The API can do more
+fn get_replay_edits(settings: &Settings) -> Result<Edits, Error> {
+    let mut edits = Edits::default();
+
+    if let Some(set_header) = settings.get("set-header") {
+        match set_header.split_once(':') {
+            Some((name, value)) if !name.trim().is_empty() => edits.set_headers.push((name.trim().to_string(), value.trim().to_string())),
+            _ => return Err(Error::other(format!("Invalid set-header, must be \"Name: value\": \"{}\"", set_header)))
+        }
+    }
+
+    if let Some(remove_header) = settings.get("remove-header") {
+        edits.remove_headers = remove_header.split(',').map(|name| name.trim().to_string()).collect();
+    }
+
+    edits.body = match (settings.get("body"), settings.get("body-file")) {
+        (Some(_), Some(_)) => return Err(Error::other("Only one of body and body-file can be set")),
+        (Some(body), None) => Some(body.into_bytes()),
+        (None, Some(body_file)) => Some(std::fs::read(&body_file).map_err(|err| Error::other(format!("Can not read body-file \"{}\": {}", body_file, err)))?),
+        (None, None) => None
+    };
+
+    Ok(edits)
+}
+
+// Opens the pcapng file that bridged connections are captured to
+fn get_capture(settings: &Settings) -> Result<Option<Capture>, Error> {
+    let path = match settings.get("capture") {
+        Some(path) => path,
+        None => return Ok(None)
+    };
+
+    let ports = match settings.get("capture-ports") {
+        Some(ports) => parse_ports(&ports)?,
+        None => Vec::new()
+    };
+
+    let max_size = match settings.get("capture-max-size") {
+        Some(max_size) => parse_size("capture-max-size", &max_size)?,
+        None => DEFAULT_MAX_SIZE
+    };
+
+    match Capture::open(CaptureOptions { path: path.clone().into(), ports, max_size }) {
+        Ok(capture) => Ok(Some(capture)),
+        Err(err) => Err(Error::other(format!("Can not open capture file \"{}\": {}", path, err)))
+    }
+}
+
+// How long to wait for bridged connections to finish after SIGTERM or SIGINT
+fn get_drain_timeout(settings: &Settings) -> Result<Duration, Error> {
+    match settings.get("drain-timeout") {
+        Some(drain_timeout) => parse_seconds("drain-timeout", &drain_timeout),
+        None => Ok(DEFAULT_DRAIN_TIMEOUT)
+    }
+}
+
+fn get_heartbeat_options(settings: &Settings) -> Result<HeartbeatOptions, Error> {
+    let mut options = HeartbeatOptions::default();
+
+    if let Some(heartbeat_interval) = settings.get("heartbeat-interval") {
+        options.interval = parse_seconds("heartbeat-interval", &heartbeat_interval)?;
+
+        if options.interval.as_secs_f64() == 0.0 || options.interval > MAX_HEARTBEAT_INTERVAL {
+            return Err(Error::other(format!("heartbeat-interval must be more than 0, and at most {} seconds", MAX_HEARTBEAT_INTERVAL.as_secs())));
+        }
+    }
+
+    if let Some(heartbeat_misses) = settings.get("heartbeat-misses") {
+        options.misses = match heartbeat_misses.parse::<u32>() {
+            Ok(misses) if misses > 0 => misses,
+            _ => return Err(Error::other(format!("Invalid heartbeat-misses: \"{}\"", heartbeat_misses)))
+        };
+    }
+
+    Ok(options)
+}
+
+fn parse_bool(name: &str, bool_str: &str) -> Result<bool, Error> {
+    match bool_str {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err(Error::other(format!("Invalid {}, must be true or false: \"{}\"", name, bool_str)))
+    }
+}
+
+fn parse_seconds(name: &str, seconds_str: &str) -> Result<Duration, Error> {
+    // Negative, infinite and too large numbers can't be a Duration
+    match seconds_str.parse::<f64>().ok().and_then(|seconds| Duration::try_from_secs_f64(seconds).ok()) {
+        Some(duration) => Ok(duration),
+        None => Err(Error::other(format!("Invalid number of seconds for {}: \"{}\"", name, seconds_str)))
+    }
+}
+
+// Bytes, optionally with a K, M, or G suffix (powers of 1024), like "512K" or "10M"
+fn parse_size(name: &str, size_str: &str) -> Result<u64, Error> {
+    let (number, multiplier) = match size_str.chars().last() {
+        Some('K') | Some('k') => (&size_str[..size_str.len() - 1], 1024),
+        Some('M') | Some('m') => (&size_str[..size_str.len() - 1], 1024 * 1024),
+        Some('G') | Some('g') => (&size_str[..size_str.len() - 1], 1024 * 1024 * 1024),
+        _ => (size_str, 1)
+    };
+
+    match number.parse::<u64>().ok().filter(|size| *size > 0).and_then(|size| size.checked_mul(multiplier)) {
+        Some(size) => Ok(size),
+        None => Err(Error::other(format!("Invalid number of bytes for {}, like 512K or 10M: \"{}\"", name, size_str)))
+    }
 }
 
 enum Mode {
     Server,
     Client,
-    Keys
+    Keys,
+    Replay,
+    Status
 }
 
 fn parse_mode(mode: &String) -> Mode {
@@ -161,6 +672,10 @@ fn parse_mode(mode: &String) -> Mode {
         Mode::Client
     } else if mode == "keys" {
         Mode::Keys
+    } else if mode == "replay" {
+        Mode::Replay
+    } else if mode == "status" {
+        Mode::Status
     } else {
         panic!("Unknown mode: {}", mode);
     }
@@ -168,50 +683,128 @@ fn parse_mode(mode: &String) -> Mode {
 
 #[cfg(test)]
 mod tests {
-    use async_std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream, Shutdown, SocketAddr};
+    use async_std::io;
+    use async_std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream, Shutdown, SocketAddr, UdpSocket};
     use async_std::prelude::*;
     use async_std::task;
     use async_std::task::JoinHandle;
-    use std::io::Error;
+    use std::io::{Error, ErrorKind};
 
     use crypto::aes::KeySize;
     use rand::{RngCore, thread_rng};
     use sync_tokens::cancelation_token::CancelationToken;
 
+    use address::ListenAddress;
+
     use super::*;
 
-    async fn get_server_and_client_futures() -> (JoinHandle<Result<(), Error>>, JoinHandle<Result<(), Error>>, SocketAddr, CancelationToken, TcpListener, CancelationToken) {
-        let key = Key {
-            key: vec![1 as u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32],
+    fn get_key() -> Key {
+        Key {
+            key: vec![1u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32],
             size: KeySize::KeySize256
-        };
+        }
+    }
+
+    struct TestServer {
+        future: JoinHandle<Result<(), Error>>,
+        cancelation_token: CancelationToken,
+        listen_addresses: Vec<ListenAddress>,
+        // Where clients connect: the adapter address, or the public address when they share it
+        bounce_server: String
+    }
+
+    impl TestServer {
+        // The first public address
+        fn client_address(&self) -> SocketAddr {
+            match &self.listen_addresses[0] {
+                ListenAddress::Tcp(address) | ListenAddress::Udp(address) => *address,
+                #[cfg(unix)]
+                ListenAddress::Unix(path) => panic!("{} isn't a socket address", path.display())
+            }
+        }
+    }
 
-        let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
-        let client_listener = TcpListener::bind(socket_addr).await.unwrap();
-        let adapter_listener = TcpListener::bind(socket_addr).await.unwrap();
+    struct TestClient {
+        future: JoinHandle<Result<(), Error>>,
+        cancelation_token: CancelationToken,
+        listener: TcpListener
+    }
 
-        let client_address = client_listener.local_addr().unwrap();
-        let adapter_address = adapter_listener.local_addr().unwrap();
+    // A localhost address that was free a moment ago
+    async fn unused_address() -> SocketAddr {
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await.unwrap();
+        listener.local_addr().unwrap()
+    }
 
-        drop(client_listener);
-        drop(adapter_listener);
+    async fn start_server(server_options: ServerOptions) -> TestServer {
+        let client_address = unused_address().await;
+        let adapter_address = unused_address().await;
 
-        let (server_future, listening_token, server_cancelation_token) = run_server(client_address.port(), adapter_address.port(), key.clone());
+        start_server_on(vec![ListenAddress::Tcp(client_address)], Some(adapter_address), server_options).await
+    }
 
+    // Without an adapter address, clients connect to the first public address
+    async fn start_server_on(listen_addresses: Vec<ListenAddress>, adapter_address: Option<SocketAddr>, server_options: ServerOptions) -> TestServer {
+        let (future, listening_token, cancelation_token) = run_server(listen_addresses.clone(), adapter_address.into_iter().collect(), get_key(), server_options);
         listening_token.await;
 
-        let listener = TcpListener::bind(socket_addr).await.unwrap();
-        let (client_future, client_cancelation_token) = run_client(adapter_address.to_string(), listener.local_addr().unwrap().to_string(), key.clone());
+        let mut server = TestServer { future, cancelation_token, listen_addresses, bounce_server: String::new() };
+        server.bounce_server = adapter_address.unwrap_or_else(|| server.client_address()).to_string();
+        server
+    }
+
+    // The client's destination is a listener that the test accepts bridged connections from
+    async fn start_client(server: &TestServer, client_options: ClientOptions) -> TestClient {
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await.unwrap();
+        let (future, cancelation_token) = run_client(server.bounce_server.clone(), listener.local_addr().unwrap().to_string(), get_key(), client_options);
+
+        TestClient { future, cancelation_token, listener }
+    }
+
+    async fn start_tunnel(server_options: ServerOptions, client_options: ClientOptions) -> (TestServer, TestClient) {
+        let server = start_server(server_options).await;
+        let client = start_client(&server, client_options).await;
+        (server, client)
+    }
+
+    // Polls until the result is done, giving up after about 5 seconds
+    async fn wait_until<T, F, R>(mut poll: F, done: impl Fn(&T) -> bool) -> T where F: FnMut() -> R, R: Future<Output = T> {
+        let mut result = poll().await;
+        for _ in 0..50 {
+            if done(&result) {
+                break;
+            }
+
+            task::sleep(Duration::from_millis(100)).await;
+            result = poll().await;
+        }
+
+        result
+    }
+
+    async fn stop_server(server: TestServer) {
+        server.cancelation_token.cancel();
+        let err = server.future.await.expect_err("Server terminated without error");
+        assert_eq!(ErrorKind::Interrupted, err.kind(), "Unexpected error when the server exits");
+    }
+
+    async fn stop_client(future: JoinHandle<Result<(), Error>>, cancelation_token: CancelationToken) {
+        cancelation_token.cancel();
+        let err = future.await.expect_err("Client terminated without error");
+        assert_eq!(ErrorKind::Interrupted, err.kind(), "Unexpected error when the client exits");
+    }
 
-        (server_future, client_future, client_address, server_cancelation_token, listener, client_cancelation_token)
+    async fn stop_tunnel(server: TestServer, client: TestClient) {
+        stop_client(client.future, client.cancelation_token).await;
+        stop_server(server).await;
     }
 
     #[async_std::test]
     async fn happy_path() {
-        let (server_future, client_future, client_address, server_cancelation_token, listener, client_cancelation_token) = get_server_and_client_futures().await;
+        let (server, client) = start_tunnel(ServerOptions::default(), ClientOptions::default()).await;
 
-        let outgoing_stream = TcpStream::connect(client_address).await.expect("Can't connect");
-        let (incoming_stream, _) = listener.accept().await.expect("Incoming socket didn't come");
+        let outgoing_stream = TcpStream::connect(server.client_address()).await.expect("Can't connect");
+        let (incoming_stream, _) = client.listener.accept().await.expect("Incoming socket didn't come");
 
         let mut rng = thread_rng();
 
@@ -219,7 +812,8 @@ mod tests {
         let mut b = incoming_stream.clone();
 
         for _ in 0usize..100 {
-            let len = (rng.next_u64() % 2000) as usize;
+            // A zero-length read returns 0, which looks like the socket closing, so every write has at least one byte
+            let len = (rng.next_u64() % 2000) as usize + 1;
             let mut write_buf = vec!(0u8; len);
             rng.fill_bytes(&mut write_buf);
 
@@ -230,13 +824,13 @@ mod tests {
             let mut total_bytes_read = 0;
 
             'read_loop: loop {
-                let bytes_read = b.read(&mut read_buf).await.expect("Can't read");
+                let bytes_read = b.read(&mut read_buf[total_bytes_read..]).await.expect("Can't read");
 
                 if bytes_read == 0 {
                     panic!("Socket closed early")
                 }
 
-                total_bytes_read = total_bytes_read + bytes_read;
+                total_bytes_read += bytes_read;
 
                 if total_bytes_read >= len {
                     break 'read_loop;
@@ -247,25 +841,700 @@ mod tests {
 
             assert_eq!(write_buf, read_buf, "Contents garbled");
 
-            let c = a;
-            a = b;
-            b = c;
+            std::mem::swap(&mut a, &mut b);
         }
 
         outgoing_stream.shutdown(Shutdown::Both).expect("Can't shutdown outgoing_stream");
         incoming_stream.shutdown(Shutdown::Both).expect("Can't shutdown incoming_stream");
 
-        server_cancelation_token.cancel();
+        stop_tunnel(server, client).await;
+    }
 
-        let err = server_future.await.expect_err("Server terminated in error");
-        assert_eq!(ErrorKind::Interrupted, err.kind(), "Unexpected error when the server exits");
+    async fn admin_request(admin_address: SocketAddr, method: &str, path: &str, token: &str) -> String {
+        let mut stream = TcpStream::connect(admin_address).await.expect("Can't connect to admin");
+        stream.write_all(format!("{} {} HTTP/1.1\r\nAuthorization: Bearer {}\r\n\r\n", method, path, token).as_bytes()).await.unwrap();
 
-        client_cancelation_token.cancel();
-        let err = client_future.await.expect_err("Client terminated without error");
-        assert_eq!(ErrorKind::Interrupted, err.kind(), "Unexpected error when the client exits");
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    fn json_body(response: &str) -> Json {
+        let body = &response[response.find("\r\n\r\n").expect("No body") + 4..];
+        Json::from_str(body).expect("Body isn't JSON")
+    }
+
+    #[async_std::test]
+    async fn admin_happy_path() {
+        let admin_address = unused_address().await;
+        let server_options = ServerOptions {
+            admin: Some(AdminOptions {
+                address: admin_address,
+                token: "secret".to_string()
+            }),
+            ..ServerOptions::default()
+        };
+
+        let server = start_server(server_options).await;
+
+        let response = admin_request(admin_address, "GET", "/clients", "wrong").await;
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{}", response);
+
+        let client = start_client(&server, ClientOptions::default()).await;
+
+        let mut outgoing_stream = TcpStream::connect(server.client_address()).await.expect("Can't connect");
+        let (mut incoming_stream, _) = client.listener.accept().await.expect("Incoming socket didn't come");
+
+        outgoing_stream.write_all(b"hello").await.unwrap();
+        let mut read_buf = [0u8; 5];
+        incoming_stream.read_exact(&mut read_buf).await.unwrap();
+
+        let response = admin_request(admin_address, "GET", "/bridges", "secret").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains(&format!("\"local\":\"{}\"", server.client_address())), "{}", response);
+        assert!(response.contains(&format!("\"peer\":\"{}\"", outgoing_stream.local_addr().unwrap())), "{}", response);
+        assert!(response.contains("\"bytes_sent\":5"), "{}", response);
+
+        // Closing the bridge closes the clear streams on both sides
+        let bridge_id = json_body(&response)[0]["id"].as_string().unwrap().to_string();
+        let response = admin_request(admin_address, "DELETE", &format!("/bridges/{}", bridge_id), "secret").await;
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", response);
+        assert_eq!(0, io::timeout(Duration::from_secs(5), outgoing_stream.read(&mut read_buf)).await.unwrap_or(0));
+        assert_eq!(0, io::timeout(Duration::from_secs(5), incoming_stream.read(&mut read_buf)).await.unwrap_or(0));
+
+        let response = admin_request(admin_address, "DELETE", &format!("/bridges/{}", bridge_id), "secret").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+
+        // The client's next adapter stream is waiting for a connection
+        let get_clients = || async { json_body(&admin_request(admin_address, "GET", "/clients", "secret").await) };
+        let clients = wait_until(get_clients, |clients| !clients.as_array().unwrap().is_empty()).await;
+        assert_eq!(Some(get_key().fingerprint().as_str()), clients[0]["key"].as_string(), "{}", clients);
+
+        // Every waiting client is listed, not only the one the next connection goes to
+        let second_client = start_client(&server, ClientOptions::default()).await;
+        let clients = wait_until(get_clients, |clients| clients.as_array().unwrap().len() > 1).await;
+        assert_eq!(2, clients.as_array().unwrap().len(), "{}", clients);
+
+        let client_id = clients[0]["id"].as_u64().unwrap();
+        let response = admin_request(admin_address, "DELETE", &format!("/clients/{}", client_id), "secret").await;
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"), "{}", response);
+
+        let response = admin_request(admin_address, "DELETE", "/clients/1000", "secret").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+
+        stop_client(second_client.future, second_client.cancelation_token).await;
+
+        stop_tunnel(server, client).await;
+    }
+
+    #[async_std::test]
+    async fn status_happy_path() {
+        let key = get_key();
+        let (server, client) = start_tunnel(ServerOptions::default(), ClientOptions::default()).await;
+        let client_address = server.client_address();
+
+        let mut outgoing_stream = TcpStream::connect(client_address).await.expect("Can't connect");
+        let (mut incoming_stream, _) = client.listener.accept().await.expect("Incoming socket didn't come");
+
+        outgoing_stream.write_all(b"hello").await.unwrap();
+        let mut read_buf = [0u8; 5];
+        incoming_stream.read_exact(&mut read_buf).await.unwrap();
+
+        // Answered while the client's next adapter stream waits for a connection
+        let get_status = || async { request_status(&server.bounce_server, key.clone(), None).await.expect("Can't get the status") };
+        let status = wait_until(get_status, |status| status["clients"].as_array().map(|clients| !clients.is_empty()).unwrap_or(false)).await;
+
+        assert_eq!(Some(1), status["active_connections"].as_u64(), "{}", status);
+        assert_eq!(Some(client_address.to_string().as_str()), status["listen"][0]["address"].as_string(), "{}", status);
+        assert_eq!(Some(1), status["listen"][0]["active_connections"].as_u64(), "{}", status);
+        assert_eq!(Some(key.fingerprint().as_str()), status["clients"][0]["key"].as_string(), "{}", status);
+        assert!(format_status(&status).contains(&format!("{} (1 active)", client_address)));
+
+        let wrong_key = Key {
+            key: vec![0u8; 32],
+            size: KeySize::KeySize256
+        };
+        request_status(&server.bounce_server, wrong_key, None).await.expect_err("The wrong key should be refused");
+
+        // Control streams don't take the place of the waiting client
+        outgoing_stream.shutdown(Shutdown::Both).unwrap();
+        incoming_stream.shutdown(Shutdown::Both).unwrap();
+
+        let mut outgoing_stream = TcpStream::connect(client_address).await.expect("Can't connect");
+        let (mut incoming_stream, _) = client.listener.accept().await.expect("Incoming socket didn't come");
+        outgoing_stream.write_all(b"again").await.unwrap();
+        incoming_stream.read_exact(&mut read_buf).await.unwrap();
+        assert_eq!(b"again", &read_buf);
+
+        stop_tunnel(server, client).await;
+    }
+
+    #[async_std::test]
+    async fn health_happy_path() {
+        let health_address = unused_address().await;
+        let server_options = ServerOptions {
+            health_address: Some(health_address),
+            health_path: Some("/healthz".to_string()),
+            ..ServerOptions::default()
+        };
+
+        let server = start_server(server_options).await;
+        let client_address = server.client_address();
+
+        // Alive, but nothing can be bridged yet
+        let response = http_get(health_address, "/live").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        let response = http_get(health_address, "/ready").await;
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", response);
+        let response = http_get(client_address, "/healthz/ready").await;
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", response);
+
+        let client = start_client(&server, ClientOptions::default()).await;
+
+        let response = wait_until(|| http_get(health_address, "/ready"), |response| response.starts_with("HTTP/1.1 200 OK\r\n")).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("\"clients\":1"), "{}", response);
+
+        // Checks on the public port don't use up the waiting client
+        for _ in 0..3 {
+            let response = http_get(client_address, "/healthz").await;
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        }
+
+        let response = http_get(client_address, "/healthz/other").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+
+        let mut outgoing_stream = TcpStream::connect(client_address).await.expect("Can't connect");
+        let (mut incoming_stream, _) = client.listener.accept().await.expect("Incoming socket didn't come");
+
+        outgoing_stream.write_all(b"hello").await.unwrap();
+        let mut read_buf = [0u8; 5];
+        incoming_stream.read_exact(&mut read_buf).await.unwrap();
+        assert_eq!(b"hello", &read_buf);
+
+        stop_tunnel(server, client).await;
+    }
+
+    #[test]
+    fn seconds() {
+        assert_eq!(Duration::from_secs(30), parse_seconds("udp-idle-timeout", "30").unwrap());
+        assert_eq!(Duration::from_millis(500), parse_seconds("udp-idle-timeout", "0.5").unwrap());
+        parse_seconds("udp-idle-timeout", "-1").expect_err("Negative");
+        parse_seconds("udp-idle-timeout", "NaN").expect_err("Not a number");
+        parse_seconds("udp-idle-timeout", "inf").expect_err("Infinite");
+        parse_seconds("udp-idle-timeout", "1e30").expect_err("Too large for a Duration");
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(100, parse_size("bandwidth-limit", "100").unwrap());
+        assert_eq!(512 * 1024, parse_size("bandwidth-limit", "512K").unwrap());
+        assert_eq!(10 * 1024 * 1024, parse_size("bandwidth-limit", "10m").unwrap());
+        assert_eq!(2 * 1024 * 1024 * 1024, parse_size("capture-max-size", "2G").unwrap());
+        parse_size("bandwidth-limit", "0").expect_err("A limit of 0 can't send anything");
+        parse_size("bandwidth-limit", "fast").expect_err("Not a number");
+        parse_size("capture-max-size", "18014398509481984K").expect_err("Too large for 64 bits");
+    }
+
+    #[test]
+    fn log_formats() {
+        assert_eq!(LogFormat::Json, parse_log_format("json").unwrap());
+        parse_log_format("xml").expect_err("Only text and json are supported");
+
+        let id: ConnectionId = "00000000000004d2".parse().unwrap();
+
+        let record = Record::builder().args(format_args!("Connection ended: \"incoming\"")).level(log::Level::Info).target("bounce::bridge").build();
+        assert_eq!(
+            "2026-01-02T03:04:05 [INFO] 00000000000004d2 - Connection ended: \"incoming\"",
+            format_text_log("2026-01-02T03:04:05", &record, Some(id)));
+        assert_eq!(
+            r#"{"time":"2026-01-02T03:04:05","level":"INFO","target":"bounce::bridge","connection":"00000000000004d2","message":"Connection ended: \"incoming\""}"#,
+            format_json_log("2026-01-02T03:04:05", &record, Some(id)));
+
+        let record = Record::builder().args(format_args!("Listening")).level(log::Level::Warn).target("bounce::server").build();
+        assert_eq!("2026-01-02T03:04:05 [WARN] - Listening", format_text_log("2026-01-02T03:04:05", &record, None));
+        assert_eq!(
+            r#"{"time":"2026-01-02T03:04:05","level":"WARN","target":"bounce::server","message":"Listening"}"#,
+            format_json_log("2026-01-02T03:04:05", &record, None));
     }
 
     async fn write_all(mut stream: TcpStream, buf: Vec<u8>) -> Result<(), Error> {
         stream.write_all(&buf).await
     }
+
+    async fn http_get(address: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).await.expect("Can't connect");
+        stream.write_all(format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, address).as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    async fn scrape(metrics_address: SocketAddr) -> String {
+        http_get(metrics_address, "/metrics").await
+    }
+
+    #[async_std::test]
+    async fn metrics_happy_path() {
+        let metrics_address = unused_address().await;
+        let server_options = ServerOptions {
+            metrics_address: Some(metrics_address),
+            ..ServerOptions::default()
+        };
+
+        let (server, client) = start_tunnel(server_options, ClientOptions::default()).await;
+
+        let mut outgoing_stream = TcpStream::connect(server.client_address()).await.expect("Can't connect");
+        let (mut incoming_stream, _) = client.listener.accept().await.expect("Incoming socket didn't come");
+
+        outgoing_stream.write_all(b"hello").await.unwrap();
+        let mut read_buf = [0u8; 5];
+        incoming_stream.read_exact(&mut read_buf).await.unwrap();
+
+        let metrics = scrape(metrics_address).await;
+        let mapping = server.client_address().to_string();
+
+        assert!(metrics.starts_with("HTTP/1.1 200 OK\r\n"), "{}", metrics);
+        assert!(metrics.contains(&format!("bounce_clear_streams_accepted_total{{mapping=\"{}\"}} 1\n", mapping)), "{}", metrics);
+        // The client may already have connected its next adapter stream
+        assert!(!metrics.contains(&format!("bounce_handshakes_total{{mapping=\"{}\",result=\"success\"}} 0\n", mapping)), "{}", metrics);
+        assert!(metrics.contains(&format!("bounce_handshakes_total{{mapping=\"{}\",result=\"failure\"}} 0\n", mapping)), "{}", metrics);
+        assert!(metrics.contains(&format!("bounce_bridged_bytes_total{{mapping=\"{}\",direction=\"sent\"}} 5\n", mapping)), "{}", metrics);
+        assert!(metrics.contains(&format!("bounce_bridged_connections{{mapping=\"{}\"}} 1\n", mapping)), "{}", metrics);
+
+        outgoing_stream.shutdown(Shutdown::Both).unwrap();
+        incoming_stream.shutdown(Shutdown::Both).unwrap();
+
+        stop_tunnel(server, client).await;
+
+        // The metrics listener stops with the server
+        TcpStream::connect(metrics_address).await.expect_err("Metrics are still served");
+    }
+
+    #[async_std::test]
+    async fn capture_happy_path() {
+        let server = start_server(ServerOptions::default()).await;
+
+        // Only the public port is captured
+        let capture_path = std::env::temp_dir().join(format!("bounce-capture-happy-path-{}.pcapng", std::process::id()));
+        let capture = Capture::open(CaptureOptions {
+            path: capture_path.clone(),
+            ports: vec![server.client_address().port()],
+            max_size: DEFAULT_MAX_SIZE
+        }).unwrap();
+
+        let client_options = ClientOptions {
+            capture: Some(capture),
+            ..ClientOptions::default()
+        };
+
+        let client = start_client(&server, client_options).await;
+
+        let mut outgoing_stream = TcpStream::connect(server.client_address()).await.expect("Can't connect");
+        let (mut incoming_stream, _) = client.listener.accept().await.expect("Incoming socket didn't come");
+
+        // Each side is captured before it's written to the clear stream
+        let mut read_buf = [0u8; 5];
+        outgoing_stream.write_all(b"hello").await.unwrap();
+        incoming_stream.read_exact(&mut read_buf).await.unwrap();
+        incoming_stream.write_all(b"world").await.unwrap();
+        outgoing_stream.read_exact(&mut read_buf).await.unwrap();
+
+        let captured = std::fs::read(&capture_path).unwrap();
+        std::fs::remove_file(&capture_path).unwrap();
+
+        assert!(captured.windows(5).any(|window| window == b"hello"), "Request wasn't captured");
+        assert!(captured.windows(5).any(|window| window == b"world"), "Response wasn't captured");
+
+        outgoing_stream.shutdown(Shutdown::Both).unwrap();
+        incoming_stream.shutdown(Shutdown::Both).unwrap();
+
+        stop_tunnel(server, client).await;
+    }
+
+    #[async_std::test]
+    async fn inspector_happy_path() {
+        let inspector_address = unused_address().await;
+        let client_options = ClientOptions {
+            inspector: Some(InspectorOptions {
+                address: inspector_address,
+                max_exchanges: DEFAULT_MAX_EXCHANGES,
+                max_body_size: DEFAULT_MAX_BODY_SIZE
+            }),
+            ..ClientOptions::default()
+        };
+
+        let (server, client) = start_tunnel(ServerOptions::default(), client_options).await;
+
+        let mut outgoing_stream = TcpStream::connect(server.client_address()).await.expect("Can't connect");
+        let (mut incoming_stream, _) = client.listener.accept().await.expect("Incoming socket didn't come");
+
+        let request = b"POST /webhook HTTP/1.1\r\nHost: example.com\r\nContent-Length: 13\r\n\r\n{\"event\":\"a\"}";
+        outgoing_stream.write_all(request).await.unwrap();
+        let mut read_buf = vec![0u8; request.len()];
+        incoming_stream.read_exact(&mut read_buf).await.unwrap();
+
+        // The response is inspected before it's sent on
+        let response = b"HTTP/1.1 204 No Content\r\n\r\n";
+        incoming_stream.write_all(response).await.unwrap();
+        let mut read_buf = vec![0u8; response.len()];
+        outgoing_stream.read_exact(&mut read_buf).await.unwrap();
+
+        let exchanges = json_body(&http_get(inspector_address, "/api/exchanges").await);
+        assert_eq!(1, exchanges.as_array().unwrap().len());
+        assert_eq!(Some("POST"), exchanges[0].find("method").unwrap().as_string());
+        assert_eq!(Some("/webhook"), exchanges[0].find("target").unwrap().as_string());
+        assert_eq!(Some(204), exchanges[0].find("status").unwrap().as_u64());
+
+        let id = exchanges[0].find("id").unwrap().as_u64().unwrap();
+        let exchange = json_body(&http_get(inspector_address, &format!("/api/exchanges/{}", id)).await);
+        assert_eq!(Some("{\"event\":\"a\"}"), exchange.find_path(&["request", "body"]).unwrap().as_string());
+
+        let page = http_get(inspector_address, "/").await;
+        assert!(page.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/html"), "{}", page);
+
+        // Only for requests to the inspector's own address, and not from other sites
+        for request in [
+            "GET /api/exchanges HTTP/1.1\r\nHost: attacker.example:80\r\n\r\n".to_string(),
+            format!("POST /api/exchanges/{}/replay HTTP/1.1\r\nHost: {}\r\nOrigin: http://attacker.example\r\nContent-Length: 2\r\n\r\n{{}}", id, inspector_address)
+        ] {
+            let mut stream = TcpStream::connect(inspector_address).await.expect("Can't connect");
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 403 Forbidden"), "{}", response);
+        }
+
+        outgoing_stream.shutdown(Shutdown::Both).unwrap();
+        incoming_stream.shutdown(Shutdown::Both).unwrap();
+
+        stop_tunnel(server, client).await;
+
+        // The inspector stops with the client
+        TcpStream::connect(inspector_address).await.expect_err("Inspector is still served");
+    }
+
+    #[async_std::test]
+    async fn udp_happy_path() {
+        let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let public_socket = UdpSocket::bind(socket_addr).await.unwrap();
+        let public_address = public_socket.local_addr().unwrap();
+        drop(public_socket);
+
+        let server = start_server_on(vec![ListenAddress::Udp(public_address)], Some(unused_address().await), ServerOptions::default()).await;
+
+        // The destination echoes each datagram back to whoever sent it
+        let destination_socket = UdpSocket::bind(socket_addr).await.unwrap();
+        let destination_address = destination_socket.local_addr().unwrap();
+        task::spawn(async move {
+            let mut buf = vec![0u8; 1024];
+            loop {
+                let (bytes_read, source) = destination_socket.recv_from(&mut buf).await.unwrap();
+                let mut echo = b"echo:".to_vec();
+                echo.extend_from_slice(&buf[..bytes_read]);
+                destination_socket.send_to(&echo, source).await.unwrap();
+            }
+        });
+
+        let (client_future, client_cancelation_token) = run_client(server.bounce_server.clone(), destination_address.to_string(), get_key(), ClientOptions::default());
+
+        let source_a = UdpSocket::bind(socket_addr).await.unwrap();
+        let source_b = UdpSocket::bind(socket_addr).await.unwrap();
+
+        let mut buf = vec![0u8; 1024];
+        for (source, datagram) in [(&source_a, b"a1"), (&source_b, b"b1"), (&source_a, b"a2"), (&source_b, b"b2")].iter() {
+            source.send_to(&datagram[..], public_address).await.expect("Can not send datagram");
+
+            let bytes_read = io::timeout(Duration::from_secs(5), source.recv(&mut buf)).await.expect("No reply");
+
+            let mut expected = b"echo:".to_vec();
+            expected.extend_from_slice(&datagram[..]);
+            assert_eq!(expected, buf[..bytes_read].to_vec(), "Reply routed incorrectly");
+        }
+
+        stop_client(client_future, client_cancelation_token).await;
+        stop_server(server).await;
+    }
+
+    #[cfg(unix)]
+    #[async_std::test]
+    async fn unix_happy_path() {
+        use async_std::os::unix::net::{UnixListener, UnixStream};
+
+        let temp_dir = std::env::temp_dir();
+        let public_path = temp_dir.join(format!("bounce-public-{}.sock", std::process::id()));
+        let destination_path = temp_dir.join(format!("bounce-destination-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&destination_path);
+
+        let server = start_server_on(vec![ListenAddress::Unix(public_path.clone())], Some(unused_address().await), ServerOptions::default()).await;
+
+        let destination_listener = UnixListener::bind(&destination_path).await.unwrap();
+        let (client_future, client_cancelation_token) = run_client(server.bounce_server.clone(), format!("unix:{}", destination_path.display()), get_key(), ClientOptions::default());
+
+        let mut outgoing_stream = UnixStream::connect(&public_path).await.expect("Can't connect");
+        let (mut incoming_stream, _) = destination_listener.accept().await.expect("Incoming socket didn't come");
+
+        outgoing_stream.write_all(b"request").await.unwrap();
+        let mut buf = [0u8; 7];
+        incoming_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"request", &buf);
+
+        incoming_stream.write_all(b"response").await.unwrap();
+        incoming_stream.shutdown(Shutdown::Both).unwrap();
+        let mut buf = Vec::new();
+        outgoing_stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(b"response".to_vec(), buf);
+
+        stop_client(client_future, client_cancelation_token).await;
+        stop_server(server).await;
+
+        std::fs::remove_file(&public_path).unwrap();
+        std::fs::remove_file(&destination_path).unwrap();
+    }
+
+    #[async_std::test]
+    async fn websocket_happy_path() {
+        // Like a host that only routes HTTP on one port: the client connects through the public port
+        let options = ServerOptions {
+            websocket_path: Some("/bounce".to_string()),
+            ..ServerOptions::default()
+        };
+
+        let server = start_server_on(vec![ListenAddress::Tcp(unused_address().await)], None, options).await;
+        let server = TestServer {
+            bounce_server: format!("ws://{}/bounce", server.client_address()),
+            ..server
+        };
+
+        let client = start_client(&server, ClientOptions::default()).await;
+
+        let mut outgoing_stream = TcpStream::connect(server.client_address()).await.expect("Can't connect");
+        let request = b"GET / HTTP/1.1\r\nHost: bounce\r\n\r\n";
+        outgoing_stream.write_all(request).await.unwrap();
+
+        let (mut incoming_stream, _) = client.listener.accept().await.expect("Incoming socket didn't come");
+
+        let mut buf = vec![0u8; request.len()];
+        incoming_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(request.to_vec(), buf);
+
+        incoming_stream.write_all(b"response").await.unwrap();
+        incoming_stream.shutdown(Shutdown::Both).unwrap();
+        let mut buf = Vec::new();
+        outgoing_stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(b"response".to_vec(), buf);
+
+        stop_tunnel(server, client).await;
+    }
+
+    #[async_std::test]
+    async fn shared_port_happy_path() {
+        // The client and the public traffic both connect to the public port
+        let options = ServerOptions {
+            shared_port: true,
+            ..ServerOptions::default()
+        };
+
+        let server = start_server_on(vec![ListenAddress::Tcp(unused_address().await)], None, options).await;
+        let client = start_client(&server, ClientOptions::default()).await;
+
+        let mut outgoing_stream = TcpStream::connect(server.client_address()).await.expect("Can't connect");
+        let request = b"GET / HTTP/1.1\r\nHost: bounce\r\n\r\n";
+        outgoing_stream.write_all(request).await.unwrap();
+
+        let (mut incoming_stream, _) = client.listener.accept().await.expect("Incoming socket didn't come");
+
+        let mut buf = vec![0u8; request.len()];
+        incoming_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(request.to_vec(), buf);
+
+        incoming_stream.write_all(b"response").await.unwrap();
+        incoming_stream.shutdown(Shutdown::Both).unwrap();
+        let mut buf = Vec::new();
+        outgoing_stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(b"response".to_vec(), buf);
+
+        stop_tunnel(server, client).await;
+    }
+
+    // A CONNECT proxy that relays one connection, and returns the request's first line
+    async fn run_test_proxy(listener: TcpListener) -> String {
+        let (mut proxy_stream, _) = listener.accept().await.unwrap();
+
+        let mut request = Vec::new();
+        let mut byte = [0u8; 1];
+        while !request.ends_with(b"\r\n\r\n") {
+            proxy_stream.read_exact(&mut byte).await.unwrap();
+            request.push(byte[0]);
+        }
+
+        let request = String::from_utf8(request).unwrap();
+        let request_line = request.lines().next().unwrap().to_string();
+        let target = request_line.split_whitespace().nth(1).unwrap();
+
+        let target_stream = TcpStream::connect(target).await.unwrap();
+        proxy_stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await.unwrap();
+
+        let (mut proxy_reader, mut target_writer) = (proxy_stream.clone(), target_stream.clone());
+        task::spawn(async move { io::copy(&mut proxy_reader, &mut target_writer).await });
+
+        let (mut target_reader, mut proxy_writer) = (target_stream, proxy_stream);
+        task::spawn(async move { io::copy(&mut target_reader, &mut proxy_writer).await });
+
+        request_line
+    }
+
+    #[async_std::test]
+    async fn proxy_happy_path() {
+        let server = start_server(ServerOptions::default()).await;
+
+        let proxy_listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await.unwrap();
+        let proxy_url = format!("http://{}", proxy_listener.local_addr().unwrap());
+        let proxy_future = task::spawn(run_test_proxy(proxy_listener));
+
+        let options = ClientOptions {
+            proxy: Some(parse_proxy_url(&proxy_url).unwrap()),
+            ..ClientOptions::default()
+        };
+
+        let client = start_client(&server, options).await;
+
+        assert_eq!(format!("CONNECT {} HTTP/1.1", server.bounce_server), proxy_future.await);
+
+        let mut outgoing_stream = TcpStream::connect(server.client_address()).await.expect("Can't connect");
+        let (mut incoming_stream, _) = client.listener.accept().await.expect("Incoming socket didn't come");
+
+        outgoing_stream.write_all(b"request").await.unwrap();
+        let mut buf = [0u8; 7];
+        incoming_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"request", &buf);
+
+        incoming_stream.write_all(b"response").await.unwrap();
+        incoming_stream.shutdown(Shutdown::Both).unwrap();
+        let mut buf = Vec::new();
+        outgoing_stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(b"response".to_vec(), buf);
+
+        stop_tunnel(server, client).await;
+    }
+
+    #[async_std::test]
+    async fn multiple_listen_addresses() {
+        let public_address_a = unused_address().await;
+        let public_address_b = unused_address().await;
+
+        let server = start_server_on(
+            vec![ListenAddress::Tcp(public_address_a), ListenAddress::Tcp(public_address_b)],
+            Some(unused_address().await),
+            ServerOptions::default()).await;
+
+        let client = start_client(&server, ClientOptions::default()).await;
+
+        for public_address in [public_address_a, public_address_b].iter() {
+            let mut outgoing_stream = TcpStream::connect(public_address).await.expect("Can't connect");
+            let (mut incoming_stream, _) = client.listener.accept().await.expect("Incoming socket didn't come");
+
+            outgoing_stream.write_all(b"bounce").await.unwrap();
+            let mut buf = [0u8; 6];
+            incoming_stream.read_exact(&mut buf).await.unwrap();
+            assert_eq!(b"bounce", &buf);
+        }
+
+        stop_tunnel(server, client).await;
+    }
+
+    #[async_std::test]
+    async fn proxy_protocol_header() {
+        let options = ClientOptions {
+            proxy_protocol: Some(proxy_protocol::ProxyProtocol::V1),
+            ..ClientOptions::default()
+        };
+        let (server, client) = start_tunnel(ServerOptions::default(), options).await;
+
+        let mut outgoing_stream = TcpStream::connect(server.client_address()).await.expect("Can't connect");
+        let (mut incoming_stream, _) = client.listener.accept().await.expect("Incoming socket didn't come");
+
+        outgoing_stream.write_all(b"bounce").await.unwrap();
+
+        let expected = format!(
+            "PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\nbounce",
+            outgoing_stream.local_addr().unwrap().port(),
+            server.client_address().port()).into_bytes();
+
+        let mut buf = vec![0u8; expected.len()];
+        incoming_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(String::from_utf8(expected).unwrap(), String::from_utf8(buf).unwrap());
+
+        stop_tunnel(server, client).await;
+    }
+
+    #[async_std::test]
+    async fn destination_unavailable() {
+        let destination_address = unused_address().await;
+        let options = ServerOptions {
+            unavailable_response: server::UnavailableResponse::Http502,
+            ..ServerOptions::default()
+        };
+
+        let server = start_server(options).await;
+        let (client_future, client_cancelation_token) = run_client(server.bounce_server.clone(), destination_address.to_string(), get_key(), ClientOptions::default());
+
+        // Nothing is listening on the destination, so the incoming connection is turned away
+        let mut outgoing_stream = TcpStream::connect(server.client_address()).await.expect("Can't connect");
+        let mut buf = Vec::new();
+        io::timeout(Duration::from_secs(5), outgoing_stream.read_to_end(&mut buf)).await.expect("Incoming connection was not closed");
+        assert!(buf.starts_with(b"HTTP/1.1 502 "), "Unexpected response: {}", String::from_utf8_lossy(&buf));
+
+        // The client keeps serving once the destination comes back
+        let destination_listener = TcpListener::bind(destination_address).await.unwrap();
+
+        let mut outgoing_stream = TcpStream::connect(server.client_address()).await.expect("Can't connect");
+        let (mut incoming_stream, _) = io::timeout(Duration::from_secs(5), destination_listener.accept()).await.expect("Incoming socket didn't come");
+
+        outgoing_stream.write_all(b"bounce").await.unwrap();
+        let mut buf = [0u8; 6];
+        incoming_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"bounce", &buf);
+
+        stop_client(client_future, client_cancelation_token).await;
+        stop_server(server).await;
+    }
+
+    #[async_std::test]
+    async fn graceful_shutdown() {
+        let server_options = ServerOptions::default();
+        let server_connections = server_options.connections.clone();
+        let client_options = ClientOptions::default();
+        let client_connections = client_options.connections.clone();
+
+        let (server, client) = start_tunnel(server_options, client_options).await;
+        let public_address = server.client_address();
+
+        let mut outgoing_stream = TcpStream::connect(public_address).await.expect("Can't connect");
+        let (mut incoming_stream, _) = client.listener.accept().await.expect("Incoming socket didn't come");
+
+        outgoing_stream.write_all(b"before").await.unwrap();
+        let mut buf = [0u8; 6];
+        incoming_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"before", &buf);
+
+        // The server stops accepting, but the bridged connection keeps working
+        stop_server(server).await;
+
+        assert_eq!(1, server_connections.count());
+        assert!(TcpStream::connect(public_address).await.is_err(), "The server should no longer accept connections");
+
+        incoming_stream.write_all(b"during").await.unwrap();
+        outgoing_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(b"during", &buf);
+
+        outgoing_stream.shutdown(Shutdown::Both).expect("Can't shutdown outgoing_stream");
+        incoming_stream.shutdown(Shutdown::Both).expect("Can't shutdown incoming_stream");
+
+        assert!(server_connections.drain(Duration::from_secs(5)).await, "The server's connections should finish");
+        assert!(client_connections.drain(Duration::from_secs(5)).await, "The client's connections should finish");
+
+        stop_client(client.future, client.cancelation_token).await;
+    }
 }
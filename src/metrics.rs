@@ -0,0 +1,245 @@
+use async_std::net::TcpListener;
+use core::time::Duration;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crate::http_server::{Request, Response, serve_http};
+use crate::shutdown::ActiveConnections;
+
+// Upper bounds, in seconds, of the handshake latency buckets
+const HANDSHAKE_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+// A value that goes up and down, like the number of connections waiting in the pending queue
+#[derive(Clone, Default)]
+pub struct Gauge {
+    value: Arc<AtomicUsize>
+}
+
+impl Gauge {
+    pub fn set(&self, value: usize) {
+        self.value.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> usize {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+struct Histogram {
+    // Not cumulative, each bucket only counts what didn't fit in the one before it. The last one is +Inf
+    buckets: [AtomicU64; HANDSHAKE_BUCKETS.len() + 1],
+    sum_micros: AtomicU64
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            buckets: Default::default(),
+            sum_micros: AtomicU64::new(0)
+        }
+    }
+
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let bucket = HANDSHAKE_BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(HANDSHAKE_BUCKETS.len());
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+struct Counters {
+    clear_streams_accepted: AtomicU64,
+    handshakes_succeeded: AtomicU64,
+    handshakes_failed: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    handshake_latency: Histogram
+}
+
+// What a server or client has done since it started, for Prometheus to scrape. Clones share the same counters
+#[derive(Clone)]
+pub struct Metrics {
+    counters: Arc<Counters>,
+    pub queue_depth: Gauge
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            counters: Arc::new(Counters {
+                clear_streams_accepted: AtomicU64::new(0),
+                handshakes_succeeded: AtomicU64::new(0),
+                handshakes_failed: AtomicU64::new(0),
+                bytes_sent: AtomicU64::new(0),
+                bytes_received: AtomicU64::new(0),
+                handshake_latency: Histogram::new()
+            }),
+            queue_depth: Gauge::default()
+        }
+    }
+}
+
+impl Metrics {
+    pub fn clear_stream_accepted(&self) {
+        self.counters.clear_streams_accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn handshake(&self, succeeded: bool, duration: Duration) {
+        if succeeded {
+            self.counters.handshakes_succeeded.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.counters.handshakes_failed.fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.counters.handshake_latency.observe(duration);
+    }
+
+    // Bytes of clear traffic sent on the adapter stream, before compression
+    pub fn sent(&self, bytes: usize) {
+        self.counters.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    // Bytes of clear traffic received on the adapter stream, after decompression
+    pub fn received(&self, bytes: usize) {
+        self.counters.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    // Prometheus text format. Every sample is labeled with the mapping, so that several bounce processes can be told apart
+    fn render(&self, mapping: &str, connections: &ActiveConnections) -> String {
+        let counters = &self.counters;
+        let mapping = escape_label(mapping);
+        let mut text = String::new();
+
+        let _ = writeln!(text, "# HELP bounce_clear_streams_accepted_total Clear streams accepted on the incoming ports");
+        let _ = writeln!(text, "# TYPE bounce_clear_streams_accepted_total counter");
+        let _ = writeln!(text, "bounce_clear_streams_accepted_total{{mapping=\"{}\"}} {}", mapping, counters.clear_streams_accepted.load(Ordering::Relaxed));
+
+        let _ = writeln!(text, "# HELP bounce_handshakes_total Adapter stream handshakes, by result");
+        let _ = writeln!(text, "# TYPE bounce_handshakes_total counter");
+        let _ = writeln!(text, "bounce_handshakes_total{{mapping=\"{}\",result=\"success\"}} {}", mapping, counters.handshakes_succeeded.load(Ordering::Relaxed));
+        let _ = writeln!(text, "bounce_handshakes_total{{mapping=\"{}\",result=\"failure\"}} {}", mapping, counters.handshakes_failed.load(Ordering::Relaxed));
+
+        let _ = writeln!(text, "# HELP bounce_bridged_bytes_total Bytes bridged through the adapter stream, by direction");
+        let _ = writeln!(text, "# TYPE bounce_bridged_bytes_total counter");
+        let _ = writeln!(text, "bounce_bridged_bytes_total{{mapping=\"{}\",direction=\"sent\"}} {}", mapping, counters.bytes_sent.load(Ordering::Relaxed));
+        let _ = writeln!(text, "bounce_bridged_bytes_total{{mapping=\"{}\",direction=\"received\"}} {}", mapping, counters.bytes_received.load(Ordering::Relaxed));
+
+        let _ = writeln!(text, "# HELP bounce_bridged_connections Connections currently bridged");
+        let _ = writeln!(text, "# TYPE bounce_bridged_connections gauge");
+        let _ = writeln!(text, "bounce_bridged_connections{{mapping=\"{}\"}} {}", mapping, connections.count());
+
+        let _ = writeln!(text, "# HELP bounce_pending_connections Incoming connections waiting for a client");
+        let _ = writeln!(text, "# TYPE bounce_pending_connections gauge");
+        let _ = writeln!(text, "bounce_pending_connections{{mapping=\"{}\"}} {}", mapping, self.queue_depth.get());
+
+        let histogram = &counters.handshake_latency;
+        let _ = writeln!(text, "# HELP bounce_handshake_duration_seconds How long adapter stream handshakes take");
+        let _ = writeln!(text, "# TYPE bounce_handshake_duration_seconds histogram");
+
+        let mut count = 0;
+        for (bucket, bound) in histogram.buckets.iter().zip(HANDSHAKE_BUCKETS.iter().map(|bound| bound.to_string()).chain(Some("+Inf".to_string()))) {
+            count += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(text, "bounce_handshake_duration_seconds_bucket{{mapping=\"{}\",le=\"{}\"}} {}", mapping, bound, count);
+        }
+
+        let _ = writeln!(text, "bounce_handshake_duration_seconds_sum{{mapping=\"{}\"}} {}", mapping, histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0);
+        let _ = writeln!(text, "bounce_handshake_duration_seconds_count{{mapping=\"{}\"}} {}", mapping, count);
+
+        text
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// Answers GET /metrics until canceled
+pub async fn serve_metrics(listener: TcpListener, metrics: Metrics, connections: ActiveConnections, mapping: String) {
+    serve_http("Metrics", listener, move |request: Request| {
+        let body = metrics.render(&mapping, &connections);
+        async move {
+            match (request.method.as_str(), request.path.split('?').next()) {
+                ("GET", Some("/metrics")) => Response::new("200 OK", "text/plain; version=0.0.4", body.into_bytes()),
+                _ => Response::empty("404 Not Found")
+            }
+        }
+    }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+    use async_std::prelude::*;
+    use async_std::task;
+
+    use crate::address::bind_tcp;
+
+    use super::*;
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+        let connections = ActiveConnections::default();
+        let _guard = connections.track();
+
+        metrics.clear_stream_accepted();
+        metrics.handshake(true, Duration::from_millis(3));
+        metrics.handshake(false, Duration::from_secs(10));
+        metrics.clone().sent(100);
+        metrics.received(50);
+        metrics.queue_depth.set(2);
+
+        let text = metrics.render("8000 \"public\"", &connections);
+
+        assert!(text.contains("bounce_clear_streams_accepted_total{mapping=\"8000 \\\"public\\\"\"} 1\n"), "{}", text);
+        assert!(text.contains("bounce_handshakes_total{mapping=\"8000 \\\"public\\\"\",result=\"success\"} 1\n"), "{}", text);
+        assert!(text.contains("bounce_handshakes_total{mapping=\"8000 \\\"public\\\"\",result=\"failure\"} 1\n"), "{}", text);
+        assert!(text.contains("direction=\"sent\"} 100\n"), "{}", text);
+        assert!(text.contains("direction=\"received\"} 50\n"), "{}", text);
+        assert!(text.contains("bounce_bridged_connections{mapping=\"8000 \\\"public\\\"\"} 1\n"), "{}", text);
+        assert!(text.contains("bounce_pending_connections{mapping=\"8000 \\\"public\\\"\"} 2\n"), "{}", text);
+
+        // Buckets are cumulative
+        assert!(text.contains("le=\"0.001\"} 0\n"), "{}", text);
+        assert!(text.contains("le=\"0.005\"} 1\n"), "{}", text);
+        assert!(text.contains("le=\"2.5\"} 1\n"), "{}", text);
+        assert!(text.contains("le=\"+Inf\"} 2\n"), "{}", text);
+        assert!(text.contains("bounce_handshake_duration_seconds_sum{mapping=\"8000 \\\"public\\\"\"} 10.003\n"), "{}", text);
+        assert!(text.contains("bounce_handshake_duration_seconds_count{mapping=\"8000 \\\"public\\\"\"} 2\n"), "{}", text);
+    }
+
+    async fn get(address: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[async_std::test]
+    async fn scrape() {
+        let listener = bind_tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let metrics = Metrics::default();
+        metrics.clear_stream_accepted();
+        let metrics_task = task::spawn(serve_metrics(listener, metrics.clone(), ActiveConnections::default(), "8000".to_string()));
+
+        let response = get(address, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("bounce_clear_streams_accepted_total{mapping=\"8000\"} 1\n"), "{}", response);
+
+        // Each scrape sees the latest counts
+        metrics.clear_stream_accepted();
+        let response = get(address, "/metrics").await;
+        assert!(response.contains("bounce_clear_streams_accepted_total{mapping=\"8000\"} 2\n"), "{}", response);
+
+        let response = get(address, "/").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"), "{}", response);
+
+        metrics_task.cancel().await;
+    }
+}
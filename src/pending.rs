@@ -0,0 +1,346 @@
+use async_std::task;
+use core::time::Duration;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::time::Instant;
+
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::channel::oneshot;
+use futures::future::{self, Either, select};
+use futures::sink::SinkExt;
+use futures::stream::StreamExt;
+
+use crate::metrics::Gauge;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PendingOptions {
+    // How many accepted connections can wait for a client. When full, new connections wait in the operating system's backlog
+    pub max_size: usize,
+    // How long an accepted connection can wait for a client before it's closed
+    pub timeout: Duration
+}
+
+impl Default for PendingOptions {
+    fn default() -> Self {
+        PendingOptions {
+            max_size: 128,
+            timeout: Duration::from_secs(30)
+        }
+    }
+}
+
+struct Pending<T> {
+    deadline: Instant,
+    item: T
+}
+
+enum Message<T> {
+    // An adapter stream is ready for the next pending item. Requests are answered in order
+    Request(oneshot::Sender<Result<Pending<T>, Error>>),
+    // An item that was handed over, but not used, goes back to the front of the queue
+    Return(Result<Pending<T>, Error>)
+}
+
+// The server's end of the queue. Each adapter stream waits with its own clone
+pub struct PendingReceiver<T> {
+    messages: Sender<Message<T>>,
+    // A request stays outstanding when next() is canceled, so an item that was already handed over isn't lost
+    outstanding: Option<oneshot::Receiver<Result<Pending<T>, Error>>>,
+    // When the last item from next() expires, in case it's given back
+    deadline: Option<Instant>
+}
+
+impl<T> Clone for PendingReceiver<T> {
+    fn clone(&self) -> Self {
+        PendingReceiver {
+            messages: self.messages.clone(),
+            outstanding: None,
+            deadline: None
+        }
+    }
+}
+
+impl<T> PendingReceiver<T> {
+    // Accept errors end the server
+    pub async fn next(&mut self) -> Result<T, Error> {
+        if self.outstanding.is_none() {
+            let (sender, receiver) = oneshot::channel();
+            if self.messages.send(Message::Request(sender)).await.is_err() {
+                return Err(no_longer_accepting());
+            }
+
+            self.outstanding = Some(receiver);
+        }
+
+        let result = match self.outstanding.as_mut() {
+            Some(receiver) => receiver.await,
+            None => unreachable!()
+        };
+
+        self.outstanding = None;
+
+        match result {
+            Ok(Ok(pending)) => {
+                self.deadline = Some(pending.deadline);
+                Ok(pending.item)
+            },
+            Ok(Err(err)) => Err(err),
+            Err(_) => Err(no_longer_accepting())
+        }
+    }
+
+    // Gives back the last item from next(), when this receiver's client couldn't take it, so that another client can before it expires
+    pub async fn give_back(&mut self, item: T) {
+        let pending = Pending {
+            deadline: self.deadline.take().unwrap_or_else(Instant::now),
+            item
+        };
+
+        let _ = self.messages.send(Message::Return(Ok(pending))).await;
+    }
+
+    // Called when this receiver's client is gone, so that anything already handed over goes to another client, or can still expire
+    pub async fn release(&mut self) {
+        if let Some(mut receiver) = self.outstanding.take() {
+            receiver.close();
+
+            if let Ok(Some(pending)) = receiver.try_recv() {
+                let _ = self.messages.send(Message::Return(pending)).await;
+            }
+        }
+    }
+}
+
+fn no_longer_accepting() -> Error {
+    Error::new(ErrorKind::BrokenPipe, "No longer accepting connections")
+}
+
+// Queues items from accepted as soon as they arrive, until they are requested or expire
+// depth is kept up to date with how many items are waiting
+pub fn run_pending_queue<T, F>(accepted: Receiver<Result<T, Error>>, options: PendingOptions, expire: F, depth: Gauge) -> PendingReceiver<T> where
+T: Send + 'static,
+F: Fn(T) + Send + 'static {
+
+    let (messages_sender, messages_receiver) = channel(0);
+
+    task::spawn(pending_queue(accepted, messages_receiver, options, expire, depth));
+
+    PendingReceiver {
+        messages: messages_sender,
+        outstanding: None,
+        deadline: None
+    }
+}
+
+async fn pending_queue<T, F>(mut accepted: Receiver<Result<T, Error>>, mut messages: Receiver<Message<T>>, options: PendingOptions, expire: F, depth: Gauge) where
+F: Fn(T) {
+
+    let mut queue: VecDeque<Pending<T>> = VecDeque::new();
+    let mut error: Option<Error> = None;
+    let mut accepting = true;
+    let mut requests: VecDeque<oneshot::Sender<Result<Pending<T>, Error>>> = VecDeque::new();
+
+    loop {
+        // All items wait the same amount of time, so the oldest always expires first
+        let now = Instant::now();
+        while queue.front().map(|p| p.deadline <= now).unwrap_or(false) {
+            if let Some(pending) = queue.pop_front() {
+                expire(pending.item);
+            }
+        }
+
+        // Once accepting stops, and everything was handed over, requests are dropped, which ends them
+        while let Some(sender) = requests.pop_front() {
+            if let Some(pending) = queue.pop_front() {
+                if let Err(Ok(pending)) = sender.send(Ok(pending)) {
+                    queue.push_front(pending);
+                }
+            } else if let Some(err) = error.take() {
+                if let Err(Err(err)) = sender.send(Err(err)) {
+                    error = Some(err);
+                }
+            } else if accepting && !sender.is_canceled() {
+                requests.push_front(sender);
+                break;
+            }
+        }
+
+        depth.set(queue.len());
+
+        let accept_future = if accepting && queue.len() < options.max_size {
+            Either::Left(accepted.next())
+        } else {
+            Either::Right(future::pending())
+        };
+
+        let expire_future = match queue.front() {
+            Some(pending) => Either::Left(task::sleep(pending.deadline.saturating_duration_since(now))),
+            None => Either::Right(future::pending())
+        };
+
+        match select(Box::pin(accept_future), select(messages.next(), Box::pin(expire_future))).await {
+            Either::Left((Some(Ok(item)), _)) => {
+                queue.push_back(Pending {
+                    deadline: Instant::now() + options.timeout,
+                    item
+                });
+
+                log::debug!("{} connection(s) waiting for a client", queue.len());
+            },
+            Either::Left((Some(Err(err)), _)) => {
+                error = Some(err);
+                accepting = false;
+            },
+            Either::Left((None, _)) => accepting = false,
+            Either::Right((Either::Left((Some(Message::Request(sender)), _)), _)) => requests.push_back(sender),
+            Either::Right((Either::Left((Some(Message::Return(pending)), _)), _)) => match pending {
+                Ok(pending) => queue.push_front(pending),
+                Err(err) => error = Some(err)
+            },
+            // The server stopped, so nothing that's still waiting will get a client
+            Either::Right((Either::Left((None, _)), _)) => {
+                for pending in queue.drain(..) {
+                    expire(pending.item);
+                }
+
+                depth.set(0);
+                return;
+            },
+            Either::Right((Either::Right(_), _)) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::io;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    type Expired = Arc<Mutex<Vec<u32>>>;
+
+    fn run_test_queue(options: PendingOptions) -> (Sender<Result<u32, Error>>, PendingReceiver<u32>, Expired) {
+        let (sender, receiver) = channel(0);
+        let expired = Arc::new(Mutex::new(Vec::new()));
+
+        let expired_clone = expired.clone();
+        let pending_receiver = run_pending_queue(receiver, options, move |item| expired_clone.lock().unwrap().push(item), Gauge::default());
+
+        (sender, pending_receiver, expired)
+    }
+
+    #[async_std::test]
+    async fn queues_in_order() {
+        let (mut sender, mut pending_receiver, expired) = run_test_queue(PendingOptions::default());
+
+        for item in 1..=3 {
+            sender.send(Ok(item)).await.unwrap();
+        }
+
+        for item in 1..=3 {
+            assert_eq!(item, pending_receiver.next().await.unwrap());
+        }
+
+        // An item handed over while nobody is waiting goes back to the queue
+        let next_future = Box::pin(pending_receiver.next());
+        io::timeout(Duration::from_millis(50), next_future).await.expect_err("Nothing is queued");
+        sender.send(Ok(4)).await.unwrap();
+        task::sleep(Duration::from_millis(50)).await;
+        pending_receiver.release().await;
+        sender.send(Ok(5)).await.unwrap();
+
+        assert_eq!(4, pending_receiver.next().await.unwrap());
+        assert_eq!(5, pending_receiver.next().await.unwrap());
+
+        sender.send(Err(Error::other("Accept failed"))).await.unwrap();
+        pending_receiver.next().await.expect_err("Accept errors are passed on");
+
+        assert!(expired.lock().unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn receivers_in_order() {
+        let (mut sender, mut first_receiver, _) = run_test_queue(PendingOptions::default());
+        let mut second_receiver = first_receiver.clone();
+
+        // Both are waiting, and the first to ask gets the first item
+        let first_future = Box::pin(first_receiver.next());
+        io::timeout(Duration::from_millis(50), first_future).await.expect_err("Nothing is queued");
+        let second_future = task::spawn(async move { (second_receiver.next().await, second_receiver) });
+        task::sleep(Duration::from_millis(50)).await;
+
+        sender.send(Ok(1)).await.unwrap();
+        sender.send(Ok(2)).await.unwrap();
+
+        assert_eq!(1, first_receiver.next().await.unwrap());
+        let (second, mut second_receiver) = second_future.await;
+        assert_eq!(2, second.unwrap());
+
+        // What was handed to a receiver that's gone goes to the next one
+        let second_future = Box::pin(second_receiver.next());
+        io::timeout(Duration::from_millis(50), second_future).await.expect_err("Nothing is queued");
+        sender.send(Ok(3)).await.unwrap();
+        task::sleep(Duration::from_millis(50)).await;
+        second_receiver.release().await;
+        drop(second_receiver);
+
+        assert_eq!(3, first_receiver.next().await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn expires() {
+        let (mut sender, mut pending_receiver, expired) = run_test_queue(PendingOptions {
+            max_size: 10,
+            timeout: Duration::from_millis(100)
+        });
+
+        sender.send(Ok(1)).await.unwrap();
+        sender.send(Ok(2)).await.unwrap();
+        task::sleep(Duration::from_millis(300)).await;
+        sender.send(Ok(3)).await.unwrap();
+
+        assert_eq!([1, 2], expired.lock().unwrap()[..]);
+        assert_eq!(3, pending_receiver.next().await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn gives_back() {
+        let (mut sender, mut pending_receiver, expired) = run_test_queue(PendingOptions {
+            max_size: 10,
+            timeout: Duration::from_millis(200)
+        });
+
+        sender.send(Ok(1)).await.unwrap();
+        sender.send(Ok(2)).await.unwrap();
+
+        // A given back item goes ahead of the others, and still expires when it would have
+        let item = pending_receiver.next().await.unwrap();
+        pending_receiver.give_back(item).await;
+        assert_eq!(1, pending_receiver.next().await.unwrap());
+
+        pending_receiver.give_back(1).await;
+        task::sleep(Duration::from_millis(300)).await;
+        sender.send(Ok(3)).await.unwrap();
+
+        assert_eq!([1, 2], expired.lock().unwrap()[..]);
+        assert_eq!(3, pending_receiver.next().await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn bounded() {
+        let (mut sender, mut pending_receiver, expired) = run_test_queue(PendingOptions {
+            max_size: 1,
+            timeout: Duration::from_millis(200)
+        });
+
+        sender.send(Ok(1)).await.unwrap();
+        task::spawn(async move { sender.send(Ok(2)).await });
+
+        // 2 isn't queued, and doesn't start waiting, until 1 expires
+        task::sleep(Duration::from_millis(300)).await;
+
+        assert_eq!([1], expired.lock().unwrap()[..]);
+        assert_eq!(2, pending_receiver.next().await.unwrap());
+    }
+}
@@ -0,0 +1,165 @@
+// HAProxy PROXY protocol, so that the destination can see who originally connected
+// See https://www.haproxy.org/download/2.3/doc/proxy-protocol.txt
+
+use async_std::net::{IpAddr, SocketAddr};
+use std::io::Error;
+
+use crate::connection_info::ConnectionInfo;
+
+const V2_SIGNATURE: [u8; 12] = [0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProxyProtocol {
+    V1,
+    V2
+}
+
+pub fn parse_proxy_protocol(version_str: &str) -> Result<ProxyProtocol, Error> {
+    match version_str {
+        "v1" | "1" => Ok(ProxyProtocol::V1),
+        "v2" | "2" => Ok(ProxyProtocol::V2),
+        _ => Err(Error::other(format!("Unknown PROXY protocol version \"{}\", must be v1 or v2", version_str)))
+    }
+}
+
+// The header to write to the destination before any other data
+pub fn proxy_header(proxy_protocol: ProxyProtocol, connection_info: &ConnectionInfo) -> Vec<u8> {
+    let addresses = match (connection_info.peer_addr, connection_info.local_addr) {
+        (Some(peer_addr), Some(local_addr)) => Some(same_family(peer_addr, local_addr)),
+        _ => None
+    };
+
+    match proxy_protocol {
+        ProxyProtocol::V1 => v1_header(addresses),
+        ProxyProtocol::V2 => v2_header(addresses)
+    }
+}
+
+// Both addresses must be in the same family, so IPv4 is mapped to IPv6 when they differ
+fn same_family(peer_addr: SocketAddr, local_addr: SocketAddr) -> (SocketAddr, SocketAddr) {
+    if peer_addr.is_ipv4() == local_addr.is_ipv4() {
+        (peer_addr, local_addr)
+    } else {
+        (to_ipv6(peer_addr), to_ipv6(local_addr))
+    }
+}
+
+fn to_ipv6(socket_addr: SocketAddr) -> SocketAddr {
+    match socket_addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), socket_addr.port()),
+        IpAddr::V6(_) => socket_addr
+    }
+}
+
+fn v1_header(addresses: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    match addresses {
+        Some((peer_addr, local_addr)) => format!(
+            "PROXY {} {} {} {} {}\r\n",
+            if peer_addr.is_ipv4() { "TCP4" } else { "TCP6" },
+            peer_addr.ip(),
+            local_addr.ip(),
+            peer_addr.port(),
+            local_addr.port()).into_bytes(),
+        None => b"PROXY UNKNOWN\r\n".to_vec()
+    }
+}
+
+fn v2_header(addresses: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+
+    match addresses {
+        Some((peer_addr, local_addr)) => {
+            // Version 2, PROXY command
+            header.push(0x21);
+
+            let mut address_block = Vec::new();
+
+            match (peer_addr.ip(), local_addr.ip()) {
+                (IpAddr::V4(peer_ip), IpAddr::V4(local_ip)) => {
+                    // AF_INET, STREAM
+                    header.push(0x11);
+                    address_block.extend_from_slice(&peer_ip.octets());
+                    address_block.extend_from_slice(&local_ip.octets());
+                },
+                (peer_ip, local_ip) => {
+                    // AF_INET6, STREAM
+                    header.push(0x21);
+                    address_block.extend_from_slice(&to_ipv6_octets(peer_ip));
+                    address_block.extend_from_slice(&to_ipv6_octets(local_ip));
+                }
+            }
+
+            address_block.extend_from_slice(&peer_addr.port().to_be_bytes());
+            address_block.extend_from_slice(&local_addr.port().to_be_bytes());
+
+            header.extend_from_slice(&(address_block.len() as u16).to_be_bytes());
+            header.extend_from_slice(&address_block);
+        },
+        None => {
+            // Version 2, LOCAL command, AF_UNSPEC, no addresses
+            header.push(0x20);
+            header.push(0x00);
+            header.extend_from_slice(&0u16.to_be_bytes());
+        }
+    }
+
+    header
+}
+
+fn to_ipv6_octets(ip: IpAddr) -> [u8; 16] {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection_info(peer_addr: &str, local_addr: &str) -> ConnectionInfo {
+        ConnectionInfo {
+            peer_addr: Some(peer_addr.parse().unwrap()),
+            local_addr: Some(local_addr.parse().unwrap()),
+            id: None
+        }
+    }
+
+    #[test]
+    fn v1() {
+        assert_eq!(
+            b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n".to_vec(),
+            proxy_header(ProxyProtocol::V1, &connection_info("192.168.0.1:56324", "192.168.0.11:443")));
+
+        assert_eq!(
+            b"PROXY TCP6 2001:db8::1 ::ffff:10.0.0.1 56324 443\r\n".to_vec(),
+            proxy_header(ProxyProtocol::V1, &connection_info("[2001:db8::1]:56324", "10.0.0.1:443")));
+
+        assert_eq!(b"PROXY UNKNOWN\r\n".to_vec(), proxy_header(ProxyProtocol::V1, &ConnectionInfo::default()));
+    }
+
+    #[test]
+    fn v2() {
+        let header = proxy_header(ProxyProtocol::V2, &connection_info("192.168.0.1:56324", "192.168.0.11:443"));
+
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0, 12, 192, 168, 0, 1, 192, 168, 0, 11, 0xDC, 0x04, 0x01, 0xBB]);
+        assert_eq!(expected, header);
+
+        let header = proxy_header(ProxyProtocol::V2, &connection_info("[2001:db8::1]:1", "[2001:db8::2]:2"));
+        assert_eq!(16 + 36, header.len());
+        assert_eq!([0x21, 0x21, 0, 36], header[12..16]);
+
+        let header = proxy_header(ProxyProtocol::V2, &ConnectionInfo::default());
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(expected, header);
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(ProxyProtocol::V1, parse_proxy_protocol("v1").unwrap());
+        assert_eq!(ProxyProtocol::V2, parse_proxy_protocol("2").unwrap());
+        parse_proxy_protocol("v3").expect_err("There is no v3");
+    }
+}
@@ -0,0 +1,272 @@
+use async_std::net::{Shutdown, SocketAddr};
+use core::time::Duration;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::bandwidth::ConnectionBandwidth;
+use crate::connection_id::ConnectionId;
+use crate::stream::Stream;
+
+struct Client {
+    key: String,
+    connected: Instant,
+    adapter_stream: Stream
+}
+
+struct Bridge {
+    client: String,
+    started: Instant,
+    bandwidth: ConnectionBandwidth,
+    peer_addr: Option<SocketAddr>,
+    local_addr: Option<SocketAddr>,
+    // None for datagram sessions, which only end when the adapter stream does
+    clear_stream: Option<Stream>,
+    adapter_stream: Stream
+}
+
+#[derive(Default)]
+struct Entries {
+    next_client_id: u64,
+    clients: HashMap<u64, Client>,
+    bridges: HashMap<ConnectionId, Bridge>
+}
+
+// The server's authenticated clients and bridged connections, so that the admin API can list and close them
+// Clones share the same registry
+#[derive(Clone, Default)]
+pub struct Registry {
+    entries: Arc<Mutex<Entries>>
+}
+
+// An adapter stream that is waiting for an incoming connection
+pub struct ClientInfo {
+    pub id: u64,
+    pub address: String,
+    // The fingerprint of the key it authenticated with
+    pub key: String,
+    pub connected: Duration
+}
+
+pub struct BridgeInfo {
+    pub id: ConnectionId,
+    // The adapter stream's address
+    pub client: String,
+    pub peer_addr: Option<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub age: Duration
+}
+
+impl Registry {
+    // The client is listed until the registration is dropped
+    pub fn add_client(&self, adapter_stream: &Stream, key: &str) -> Registration {
+        let mut entries = self.entries.lock().unwrap();
+        let id = entries.next_client_id;
+        entries.next_client_id += 1;
+
+        entries.clients.insert(id, Client {
+            key: key.to_string(),
+            connected: Instant::now(),
+            adapter_stream: adapter_stream.clone()
+        });
+
+        Registration {
+            entries: self.entries.clone(),
+            entry: Entry::Client(id)
+        }
+    }
+
+    // The bridge is listed until the registration is dropped, which run_bridge does when the bridge ends
+    pub fn add_bridge(&self, id: ConnectionId, clear_stream: &Stream, adapter_stream: &Stream, bandwidth: ConnectionBandwidth) -> Registration {
+        self.entries.lock().unwrap().bridges.insert(id, Bridge {
+            client: adapter_stream.peer_name(),
+            started: Instant::now(),
+            bandwidth,
+            peer_addr: clear_stream.peer_addr(),
+            local_addr: clear_stream.local_addr(),
+            clear_stream: Some(clear_stream.clone()),
+            adapter_stream: adapter_stream.clone()
+        });
+
+        Registration {
+            entries: self.entries.clone(),
+            entry: Entry::Bridge(id)
+        }
+    }
+
+    // Like add_bridge, for a datagram session from the source address
+    pub fn add_datagram_bridge(&self, id: ConnectionId, source: SocketAddr, local_addr: Option<SocketAddr>, adapter_stream: &Stream, bandwidth: ConnectionBandwidth) -> Registration {
+        self.entries.lock().unwrap().bridges.insert(id, Bridge {
+            client: adapter_stream.peer_name(),
+            started: Instant::now(),
+            bandwidth,
+            peer_addr: Some(source),
+            local_addr,
+            clear_stream: None,
+            adapter_stream: adapter_stream.clone()
+        });
+
+        Registration {
+            entries: self.entries.clone(),
+            entry: Entry::Bridge(id)
+        }
+    }
+
+    // Oldest first
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        let entries = self.entries.lock().unwrap();
+
+        let mut clients: Vec<ClientInfo> = entries.clients.iter().map(|(id, client)| ClientInfo {
+            id: *id,
+            address: client.adapter_stream.peer_name(),
+            key: client.key.clone(),
+            connected: client.connected.elapsed()
+        }).collect();
+
+        clients.sort_by_key(|client| client.id);
+        clients
+    }
+
+    // Oldest first
+    pub fn bridges(&self) -> Vec<BridgeInfo> {
+        let entries = self.entries.lock().unwrap();
+
+        let mut bridges: Vec<BridgeInfo> = entries.bridges.iter().map(|(id, bridge)| {
+            let (bytes_sent, bytes_received) = bridge.bandwidth.bytes();
+
+            BridgeInfo {
+                id: *id,
+                client: bridge.client.clone(),
+                peer_addr: bridge.peer_addr,
+                local_addr: bridge.local_addr,
+                bytes_sent,
+                bytes_received,
+                age: bridge.started.elapsed()
+            }
+        }).collect();
+
+        bridges.sort_by_key(|bridge| std::cmp::Reverse(bridge.age));
+        bridges
+    }
+
+    // Shutting down the adapter stream sends the server back to waiting for the next one. Returns false if there's no such client
+    pub fn disconnect_client(&self, id: u64) -> bool {
+        match self.entries.lock().unwrap().clients.get(&id) {
+            Some(client) => {
+                log::info!("Disconnecting client {}", client.adapter_stream.peer_name());
+                shutdown(&client.adapter_stream);
+                true
+            },
+            None => false
+        }
+    }
+
+    // Both streams are shut down, so the bridge ends the same way as when either side closes. Returns false if there's no such bridge
+    pub fn close_bridge(&self, id: ConnectionId) -> bool {
+        match self.entries.lock().unwrap().bridges.get(&id) {
+            Some(bridge) => {
+                log::info!("Closing connection {}", id);
+                if let Some(clear_stream) = &bridge.clear_stream {
+                    shutdown(clear_stream);
+                }
+                shutdown(&bridge.adapter_stream);
+                true
+            },
+            None => false
+        }
+    }
+}
+
+fn shutdown(stream: &Stream) {
+    if let Err(err) = stream.shutdown(Shutdown::Both) {
+        log::warn!("Error shutting down {}: {}", stream.peer_name(), err);
+    }
+}
+
+enum Entry {
+    Client(u64),
+    Bridge(ConnectionId)
+}
+
+// Removes the client or bridge from the registry when it's dropped
+pub struct Registration {
+    entries: Arc<Mutex<Entries>>,
+    entry: Entry
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut entries = self.entries.lock().unwrap();
+        match self.entry {
+            Entry::Client(id) => {
+                entries.clients.remove(&id);
+            },
+            Entry::Bridge(id) => {
+                entries.bridges.remove(&id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::net::{IpAddr, Ipv4Addr, TcpListener, TcpStream};
+    use async_std::io::ReadExt;
+
+    use crate::bandwidth::BandwidthOptions;
+
+    use super::*;
+
+    async fn stream_pair() -> (Stream, TcpStream) {
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await.unwrap();
+        let connected = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+
+        (accepted.into(), connected)
+    }
+
+    #[async_std::test]
+    async fn registrations() {
+        let registry = Registry::default();
+
+        let (adapter_stream, mut client_stream) = stream_pair().await;
+        let (clear_stream, _peer_stream) = stream_pair().await;
+
+        let client_registration = registry.clone().add_client(&adapter_stream, "fingerprint");
+        let bridge_registration = registry.add_bridge(ConnectionId::random(), &clear_stream, &adapter_stream, BandwidthOptions::default().connection());
+
+        let clients = registry.clients();
+        assert_eq!(1, clients.len());
+        assert_eq!("fingerprint", clients[0].key);
+
+        let bridges = registry.bridges();
+        assert_eq!(1, bridges.len());
+        assert_eq!(clear_stream.peer_addr(), bridges[0].peer_addr);
+
+        // A datagram session has no clear stream, so closing it only shuts down the adapter stream
+        let (datagram_adapter_stream, mut datagram_client_stream) = stream_pair().await;
+        let source = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 5000);
+        let datagram_id = ConnectionId::random();
+        let datagram_registration = registry.add_datagram_bridge(datagram_id, source, None, &datagram_adapter_stream, BandwidthOptions::default().connection());
+        assert!(registry.bridges().iter().any(|bridge| bridge.id == datagram_id && bridge.peer_addr == Some(source)));
+        assert!(registry.close_bridge(datagram_id));
+        assert_eq!(0, datagram_client_stream.read(&mut [0u8; 1]).await.unwrap());
+        drop(datagram_registration);
+
+        // Disconnecting shuts down the stream, but it's listed until whatever uses it is done
+        assert!(registry.disconnect_client(clients[0].id));
+        assert!(!registry.disconnect_client(clients[0].id + 1));
+        let mut buf = [0u8; 1];
+        assert_eq!(0, client_stream.read(&mut buf).await.unwrap());
+        assert_eq!(1, registry.clients().len());
+
+        drop(client_registration);
+        drop(bridge_registration);
+
+        assert!(registry.clients().is_empty());
+        assert!(registry.bridges().is_empty());
+        assert!(!registry.close_bridge(bridges[0].id));
+    }
+}
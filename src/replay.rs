@@ -0,0 +1,333 @@
+use async_std::io;
+use async_std::net::{SocketAddr, TcpStream};
+use async_std::prelude::*;
+use core::time::Duration;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+
+use rustc_serialize::base64::{FromBase64, STANDARD, ToBase64};
+use rustc_serialize::json::Json;
+
+use crate::inspector::{Exchange, Inspector, Message};
+use crate::stream::Stream;
+
+// The destination has this long to respond to a replayed request
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Changes to a recorded request before it's replayed
+#[derive(Debug, Default, PartialEq)]
+pub struct Edits {
+    // Each replaces the headers with the same name, or is added
+    pub set_headers: Vec<(String, String)>,
+    pub remove_headers: Vec<String>,
+    pub body: Option<Vec<u8>>
+}
+
+// Sends the recorded request to the destination on its own connection, and returns the new exchange, which the inspector keeps too
+pub async fn replay(inspector: &Inspector, destination_host: &str, id: u64, edits: &Edits) -> Result<Exchange, Error> {
+    let original = match inspector.exchange(id) {
+        Some(original) => original,
+        None => return Err(Error::new(ErrorKind::NotFound, format!("No exchange {}", id)))
+    };
+
+    let request = replay_request(&original.request, edits)?;
+
+    log::info!("Replaying exchange {} to {}: {}", id, destination_host, original.request.head.start_line.join(" "));
+
+    io::timeout(REPLAY_TIMEOUT, async {
+        let mut stream = Stream::connect(destination_host).await?;
+
+        // Parsed as if it came through a bridge, where the clear stream is the destination's end
+        let connection = inspector.connection(None, false);
+        connection.adapter_data(&request);
+        let replay_id = match connection.last_request() {
+            Some(replay_id) => replay_id,
+            None => return Err(Error::new(ErrorKind::InvalidData, format!("Exchange {} can not be parsed after the edits", id)))
+        };
+
+        inspector.mark_replay(replay_id, id);
+        stream.write_all(&request).await?;
+
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let bytes_read = stream.read(&mut buf).await?;
+            if bytes_read == 0 {
+                connection.clear_fin();
+                break;
+            }
+
+            connection.clear_data(&buf[..bytes_read]);
+
+            match inspector.exchange(replay_id).and_then(|exchange| exchange.response) {
+                Some(response) if response.complete => break,
+                _ => {}
+            }
+        }
+
+        match inspector.exchange(replay_id) {
+            Some(exchange) => Ok(exchange),
+            None => Err(Error::new(ErrorKind::NotFound, format!("The replay of exchange {} was already forgotten", id)))
+        }
+    }).await
+}
+
+// The body is always sent with Content-Length, and the connection closes after the response
+fn replay_request(request: &Message, edits: &Edits) -> Result<Vec<u8>, Error> {
+    let body = match &edits.body {
+        Some(body) => body,
+        None if !request.complete => return Err(Error::new(ErrorKind::InvalidData, "The request hasn't finished, so it can't be replayed without a new body")),
+        None if (request.body.len() as u64) < request.body_size => return Err(Error::new(ErrorKind::InvalidData, format!(
+            "The request body was cut off at {} of {} bytes, so it can't be replayed without a new body",
+            request.body.len(),
+            request.body_size))),
+        None => &request.body
+    };
+
+    let removed = |name: &str| {
+        ["Content-Length", "Transfer-Encoding", "Connection"].iter().any(|removed| removed.eq_ignore_ascii_case(name))
+            || edits.remove_headers.iter().any(|removed| removed.eq_ignore_ascii_case(name))
+            || edits.set_headers.iter().any(|(set, _)| set.eq_ignore_ascii_case(name))
+    };
+
+    let mut head = format!("{}\r\n", request.head.start_line.join(" ").trim_end());
+    for (name, value) in request.head.headers.iter().filter(|(name, _)| !removed(name)).chain(edits.set_headers.iter()) {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    head.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n\r\n", body.len()));
+
+    let mut replayed = head.into_bytes();
+    replayed.extend_from_slice(body);
+    Ok(replayed)
+}
+
+// {"set_headers": [["name", "value"]], "remove_headers": ["name"], "body": "...", "body_encoding": "utf-8" or "base64"}, where everything is optional
+// An empty request body means no edits
+pub fn parse_edits(body: &[u8]) -> Result<Edits, Error> {
+    let mut edits = Edits::default();
+    if body.is_empty() {
+        return Ok(edits);
+    }
+
+    let invalid = |message: &str| Error::new(ErrorKind::InvalidData, format!("Invalid edits: {}", message));
+
+    let json = match std::str::from_utf8(body).ok().and_then(|body| Json::from_str(body).ok()) {
+        Some(json) if json.is_object() => json,
+        _ => return Err(invalid("not a JSON object"))
+    };
+
+    if let Some(set_headers) = json.find("set_headers") {
+        for header in set_headers.as_array().ok_or_else(|| invalid("set_headers must be an array"))? {
+            match header.as_array().map(|header| header.as_slice()) {
+                Some([Json::String(name), Json::String(value)]) => edits.set_headers.push((name.clone(), value.clone())),
+                _ => return Err(invalid("each of set_headers must be [name, value]"))
+            }
+        }
+    }
+
+    if let Some(remove_headers) = json.find("remove_headers") {
+        for name in remove_headers.as_array().ok_or_else(|| invalid("remove_headers must be an array"))? {
+            edits.remove_headers.push(name.as_string().ok_or_else(|| invalid("each of remove_headers must be a name"))?.to_string());
+        }
+    }
+
+    if let Some(body) = json.find("body") {
+        let body = body.as_string().ok_or_else(|| invalid("body must be a string"))?;
+        edits.body = Some(match json.find("body_encoding").and_then(|encoding| encoding.as_string()) {
+            Some("base64") => body.from_base64().map_err(|err| invalid(&err.to_string()))?,
+            Some("utf-8") | None => body.as_bytes().to_vec(),
+            Some(encoding) => return Err(invalid(&format!("unknown body_encoding \"{}\"", encoding)))
+        });
+    }
+
+    Ok(edits)
+}
+
+fn edits_json(edits: &Edits) -> Json {
+    let mut object = BTreeMap::new();
+    object.insert("set_headers".to_string(), Json::Array(edits.set_headers.iter()
+        .map(|(name, value)| Json::Array(vec![Json::String(name.clone()), Json::String(value.clone())]))
+        .collect()));
+    object.insert("remove_headers".to_string(), Json::Array(edits.remove_headers.iter().map(|name| Json::String(name.clone())).collect()));
+
+    if let Some(body) = &edits.body {
+        object.insert("body".to_string(), Json::String(body.to_base64(STANDARD)));
+        object.insert("body_encoding".to_string(), Json::String("base64".to_string()));
+    }
+
+    Json::Object(object)
+}
+
+// For `bounce replay`: asks the inspector at inspector_address to replay an exchange, and returns the new one
+pub async fn request_replay(inspector_address: SocketAddr, id: u64, edits: &Edits) -> Result<Json, Error> {
+    let body = edits_json(edits).to_string();
+
+    let mut stream = TcpStream::connect(inspector_address).await?;
+    stream.write_all(format!(
+        "POST /api/exchanges/{}/replay HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        id,
+        inspector_address,
+        body.len(),
+        body).as_bytes()).await?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let status_line = head.lines().next().unwrap_or_default();
+
+    if !status_line.starts_with("HTTP/1.1 200 ") {
+        return Err(Error::other(format!("Replay failed: {}: {}", status_line, body)));
+    }
+
+    Json::from_str(body).map_err(|err| Error::new(ErrorKind::InvalidData, format!("Inspector sent invalid JSON: {}", err)))
+}
+
+// The replayed response, like it looked on the wire
+pub fn format_exchange(exchange: &Json) -> String {
+    let mut formatted = format!("Replayed as exchange {}", exchange.find("id").and_then(|id| id.as_u64()).unwrap_or_default());
+
+    if let Some(duration_ms) = exchange.find("duration_ms").and_then(|duration_ms| duration_ms.as_f64()) {
+        formatted.push_str(&format!(" in {:.1} ms", duration_ms));
+    }
+
+    let response = match exchange.find("response") {
+        Some(response) if response.is_object() => response,
+        _ => return formatted + "\nNo response"
+    };
+
+    formatted.push('\n');
+    formatted.push_str(response.find("start_line").and_then(|start_line| start_line.as_string()).unwrap_or_default());
+    formatted.push('\n');
+
+    for header in response.find("headers").and_then(|headers| headers.as_array()).into_iter().flatten() {
+        if let (Some(name), Some(value)) = (header[0].as_string(), header[1].as_string()) {
+            formatted.push_str(&format!("{}: {}\n", name, value));
+        }
+    }
+
+    formatted.push('\n');
+    formatted.push_str(response.find("body").and_then(|body| body.as_string()).unwrap_or_default());
+    formatted
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::net::{IpAddr, Ipv4Addr, TcpListener};
+    use async_std::task;
+
+    use crate::inspector::{DEFAULT_MAX_BODY_SIZE, DEFAULT_MAX_EXCHANGES, serve_inspector};
+
+    use super::*;
+
+    // Answers one request that ends with "replayed", and returns what it was
+    async fn answer_once(listener: TcpListener, response: &'static [u8]) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !request.ends_with(b"replayed") {
+            let bytes_read = stream.read(&mut buf).await.unwrap();
+            assert_ne!(0, bytes_read, "Request ended early");
+            request.extend_from_slice(&buf[..bytes_read]);
+        }
+
+        stream.write_all(response).await.unwrap();
+        String::from_utf8(request).unwrap()
+    }
+
+    #[async_std::test]
+    async fn replays() {
+        let inspector = Inspector::new(DEFAULT_MAX_EXCHANGES, DEFAULT_MAX_BODY_SIZE);
+        let connection = inspector.connection(None, false);
+        connection.adapter_data(b"POST /hook HTTP/1.1\r\nHost: example.com\r\nX-Signature: old\r\nX-Extra: 1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n");
+        connection.clear_data(b"HTTP/1.1 500 Oops\r\nContent-Length: 0\r\n\r\n");
+
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await.unwrap();
+        let destination_host = listener.local_addr().unwrap().to_string();
+        let destination_task = task::spawn(answer_once(listener, b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\ndone"));
+
+        let edits = Edits {
+            set_headers: vec![("x-signature".to_string(), "new".to_string())],
+            remove_headers: vec!["X-Extra".to_string()],
+            body: Some(b"replayed".to_vec())
+        };
+
+        let exchange = replay(&inspector, &destination_host, 1, &edits).await.unwrap();
+        assert_eq!(
+            "POST /hook HTTP/1.1\r\nHost: example.com\r\nx-signature: new\r\nContent-Length: 8\r\nConnection: close\r\n\r\nreplayed",
+            destination_task.await);
+
+        assert_eq!(Some(1), exchange.replay_of);
+        assert_eq!(b"replayed", &exchange.request.body[..]);
+        assert_eq!(b"done", &exchange.response.unwrap().body[..]);
+        assert_eq!(2, inspector.exchanges().len());
+
+        replay(&inspector, &destination_host, 3, &Edits::default()).await.map(|_| ()).expect_err("No such exchange");
+    }
+
+    #[async_std::test]
+    async fn cut_off() {
+        let inspector = Inspector::new(DEFAULT_MAX_EXCHANGES, 4);
+        let connection = inspector.connection(None, false);
+        connection.adapter_data(b"POST / HTTP/1.1\r\nContent-Length: 8\r\n\r\nreplayed");
+
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await.unwrap();
+        let destination_host = listener.local_addr().unwrap().to_string();
+        let destination_task = task::spawn(answer_once(listener, b""));
+
+        let err = replay(&inspector, &destination_host, 1, &Edits::default()).await.map(|_| ()).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+
+        // The body can be given again. The destination closes without a response
+        let edits = Edits {
+            body: Some(b"replayed".to_vec()),
+            ..Edits::default()
+        };
+
+        let exchange = replay(&inspector, &destination_host, 1, &edits).await.unwrap();
+        assert!(exchange.response.is_none());
+        destination_task.await;
+    }
+
+    #[test]
+    fn edits() {
+        let edits = Edits {
+            set_headers: vec![("Name".to_string(), "value".to_string())],
+            remove_headers: vec!["Other".to_string()],
+            body: Some(vec![0xff, 0])
+        };
+
+        assert_eq!(edits, parse_edits(edits_json(&edits).to_string().as_bytes()).unwrap());
+        assert_eq!(Edits::default(), parse_edits(b"").unwrap());
+        assert_eq!(Some(b"text".to_vec()), parse_edits(br#"{"body":"text"}"#).unwrap().body);
+
+        parse_edits(b"[]").expect_err("Not an object");
+        parse_edits(br#"{"set_headers":[["Name"]]}"#).expect_err("No value");
+    }
+
+    #[async_std::test]
+    async fn api() {
+        let inspector = Inspector::new(DEFAULT_MAX_EXCHANGES, DEFAULT_MAX_BODY_SIZE);
+        let connection = inspector.connection(None, false);
+        connection.adapter_data(b"PUT /again HTTP/1.1\r\nContent-Length: 8\r\n\r\nreplayed");
+
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await.unwrap();
+        let destination_host = listener.local_addr().unwrap().to_string();
+        let destination_task = task::spawn(answer_once(listener, b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\ndone"));
+
+        let inspector_listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await.unwrap();
+        let inspector_address = inspector_listener.local_addr().unwrap();
+        let inspector_task = task::spawn(serve_inspector(inspector_listener, inspector.clone(), destination_host));
+
+        let exchange = request_replay(inspector_address, 1, &Edits::default()).await.unwrap();
+        assert!(destination_task.await.starts_with("PUT /again HTTP/1.1\r\n"));
+        assert_eq!("Replayed as exchange 2", &format_exchange(&exchange)[..22]);
+        assert!(format_exchange(&exchange).ends_with("HTTP/1.1 200 OK\nContent-Length: 4\n\ndone"), "{}", format_exchange(&exchange));
+
+        let err = request_replay(inspector_address, 5, &Edits::default()).await.unwrap_err();
+        assert!(err.to_string().contains("404 Not Found"), "{}", err);
+
+        inspector_task.cancel().await;
+    }
+}
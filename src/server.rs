@@ -1,138 +1,780 @@
-use async_std::net::{IpAddr, Ipv4Addr, Shutdown, TcpListener, TcpStream, SocketAddr};
+use async_std::net::{Shutdown, TcpListener, TcpStream, SocketAddr};
+#[cfg(unix)]
+use async_std::os::unix::net::{UnixListener, UnixStream};
+use async_std::io;
 use async_std::prelude::*;
 use async_std::task;
 use async_std::task::JoinHandle;
 use std::io::{ Error, ErrorKind };
-use sync_tokens::cancelation_token::{ CancelationToken, Cancelable };
+use std::sync::Arc;
+use std::time::Instant;
+use sync_tokens::cancelation_token::{ CancelationToken, Cancelable, CancelationTokenFuture };
 use sync_tokens::completion_token::{ CompletionToken, Completable };
 
-use futures::future::{Either, select};
+use core::time::Duration;
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::sink::SinkExt;
+use futures::future::{self, BoxFuture, Either, FutureExt, Shared, select};
+use futures::stream::{FuturesUnordered, StreamExt};
+use rand_chacha::ChaCha12Rng;
+use rand_core::{CryptoRng, RngCore};
 
+use crate::address::{ListenAddress, bind_tcp};
+use crate::admin::{AdminOptions, serve_admin};
 use crate::auth::authenticate;
-use crate::bridge::run_bridge;
+use crate::bandwidth::{BandwidthOptions, log_throughput};
+use crate::bridge::{BridgeOptions, ConnectionTimeouts, run_bridge};
+use crate::buffer_pool::BufferPool;
+use crate::capture::Capture;
+use crate::compression::Compression;
+use crate::connection_id::{ConnectionId, set_connection_id};
+use crate::connection_info::{ConnectionInfo, write_connection_info};
+use crate::datagram::{DatagramBridgeOptions, DatagramSession, listen_datagrams, run_datagram_session_bridge};
+use crate::health::{Health, serve_health};
+use crate::http_server::{Request, respond};
 use crate::keys::Key;
+use crate::metrics::{Metrics, serve_metrics};
+use crate::pending::{PendingOptions, PendingReceiver, run_pending_queue};
+use crate::registry::{Registration, Registry};
+use crate::shutdown::ActiveConnections;
+use crate::signal::{ACKNOWLEDGEMENT_TIMEOUT, CONNECTED, DATAGRAMS, GOINGAWAY, HEARTBEAT, HeartbeatOptions, Heartbeats, SIGNAL_LENGTH, Signal, read_acknowledgement};
+use crate::sniff::{Sniffed, sniff, sniff_control};
+use crate::status::{ServerStatus, serve_control};
+use crate::stream::Stream;
+use crate::websocket::accept_upgrade;
+use crate::xor::{Xor, Xors};
 
-pub fn run_server(port: u16, adapter_port: u16, key: Key) -> (JoinHandle<Result<(), Error>>, CompletionToken<()>, CancelationToken) {
+// How long to wait for the rest of a signal after its first byte arrives
+const SIGNAL_TIMEOUT: Duration = Duration::from_millis(500);
+
+// How many adapter (or control) streams can be sniffed and authenticated at once. More wait in the operating system's backlog
+const MAX_HANDSHAKES: usize = 64;
+
+// Sent to an incoming clear stream when the client can't connect to the destination
+const HTTP_502_RESPONSE: &[u8] = b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnavailableResponse {
+    // The incoming stream is closed without writing anything
+    Close,
+    // For HTTP traffic, the incoming stream gets a 502 before it's closed
+    Http502
+}
+
+pub fn parse_unavailable_response(response_str: &str) -> Result<UnavailableResponse, Error> {
+    match response_str {
+        "close" => Ok(UnavailableResponse::Close),
+        "http502" | "502" => Ok(UnavailableResponse::Http502),
+        _ => Err(Error::other(format!("Unknown unavailable response \"{}\", must be close or http502", response_str)))
+    }
+}
+
+pub struct ServerOptions {
+    // How long a UDP session can go without a datagram in either direction before it ends
+    pub udp_idle_timeout: Duration,
+    pub heartbeat: HeartbeatOptions,
+    pub unavailable_response: UnavailableResponse,
+    // Incoming connections are accepted right away, and wait here until a client is ready
+    pub pending: PendingOptions,
+    // Bridged connections are counted here, so that shutdown can wait for them
+    pub connections: ActiveConnections,
+    // When set, clients can also connect with a WebSocket upgrade on this path of the public TCP ports, for hosts that only route HTTP
+    pub websocket_path: Option<String>,
+    // When set, adapter streams can also connect on the public TCP ports, and are told apart from public traffic by their first bytes
+    pub shared_port: bool,
+    // How long to wait for a connection on a public TCP port to send enough to tell where it goes, when sniffing. Public protocols where the server speaks first are held up this long
+    pub sniff_timeout: Duration,
+    // Compressions that clients can use for the adapter stream. Empty for none
+    pub compression: Vec<Compression>,
+    pub bandwidth: BandwidthOptions,
+    pub timeouts: ConnectionTimeouts,
+    // Shared by all of the bridged connections
+    pub buffers: BufferPool,
+    pub metrics: Metrics,
+    // When set, metrics are served for Prometheus at /metrics on this address
+    pub metrics_address: Option<SocketAddr>,
+    // Authenticated clients and bridged connections, for the admin API
+    pub registry: Registry,
+    // When set, the admin API is served on this address
+    pub admin: Option<AdminOptions>,
+    // When set, liveness and readiness are served on this address
+    pub health_address: Option<SocketAddr>,
+    // When set, GET requests under this path on the public TCP ports are answered with liveness and readiness, instead of being bridged
+    pub health_path: Option<String>,
+    // When set, the clear side of bridged connections is written here
+    pub capture: Option<Capture>
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            udp_idle_timeout: Duration::from_secs(60),
+            heartbeat: HeartbeatOptions::default(),
+            unavailable_response: UnavailableResponse::Close,
+            pending: PendingOptions::default(),
+            connections: ActiveConnections::default(),
+            websocket_path: None,
+            shared_port: false,
+            sniff_timeout: Duration::from_millis(500),
+            compression: Vec::new(),
+            bandwidth: BandwidthOptions::default(),
+            timeouts: ConnectionTimeouts::default(),
+            buffers: BufferPool::default(),
+            metrics: Metrics::default(),
+            metrics_address: None,
+            registry: Registry::default(),
+            admin: None,
+            health_address: None,
+            health_path: None,
+            capture: None
+        }
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    // Datagrams are received while the future runs
+    Udp(Receiver<DatagramSession>, BoxFuture<'static, ()>),
+    #[cfg(unix)]
+    Unix(UnixListener)
+}
+
+// Each is given an ID when it's accepted
+enum Incoming {
+    Stream(Stream, ConnectionId),
+    Datagrams(DatagramSession, ConnectionId)
+}
+
+// Incoming TCP connections that are sniffed as adapter streams, or WebSocket upgrades on websocket_path, are sent to the adapter streams instead
+// Health checks under health_path are answered right away
+#[derive(Clone)]
+struct AdapterRoutes {
+    adapters: bool,
+    websocket_path: Option<String>,
+    health_path: Option<String>,
+    health: Health,
+    timeout: Duration,
+    adapter_sender: Sender<Result<Stream, Error>>
+}
+
+// What route_adapters needs to answer control streams and authenticate adapter streams
+#[derive(Clone)]
+struct AdapterHandshake {
+    key: Key,
+    key_fingerprint: String,
+    compression: Vec<Compression>,
+    metrics: Metrics,
+    registry: Registry,
+    status: ServerStatus,
+    sniff_timeout: Duration
+}
+
+// An authenticated adapter stream
+struct Adapter {
+    stream: Stream,
+    xors: Xors<ChaCha12Rng>,
+    compression: Option<Compression>,
+    // Listed as a client until it's bridged or ends
+    registration: Registration
+}
+
+pub fn run_server(listen_addresses: Vec<ListenAddress>, adapter_addresses: Vec<SocketAddr>, key: Key, options: ServerOptions) -> (JoinHandle<Result<(), Error>>, CompletionToken<()>, CancelationToken) {
     let (listening_token, listening_completable) = CompletionToken::new();
     let (cancelation_token, cancelable) = CancelationToken::new();
 
-    let server_future = task::spawn(run_server_int(port, adapter_port, key, listening_completable, cancelable));
+    let server_future = task::spawn(run_server_int(listen_addresses, adapter_addresses, key, options, listening_completable, cancelable));
 
     (server_future, listening_token, cancelation_token)
 }
 
-async fn run_server_int(port: u16, adapter_port: u16, key: Key, listening_completable: Completable<()>, cancelable: Cancelable) -> Result<(), Error> {
+async fn run_server_int(listen_addresses: Vec<ListenAddress>, adapter_addresses: Vec<SocketAddr>, key: Key, options: ServerOptions, listening_completable: Completable<()>, cancelable: Cancelable) -> Result<(), Error> {
+
+    if adapter_addresses.is_empty() && options.websocket_path.is_none() && !options.shared_port {
+        return Err(Error::new(ErrorKind::InvalidInput, "Adapter addresses, a WebSocket path, or a shared port are required"));
+    }
+
+    // Everything is bound before anything is spawned, so that a bad address doesn't leave tasks behind
+    let mut listeners = Vec::new();
+    for listen_address in listen_addresses.iter() {
+        listeners.push(match listen_address {
+            ListenAddress::Tcp(socket_addr) => Listener::Tcp(bind_tcp(*socket_addr)?),
+            ListenAddress::Udp(socket_addr) => {
+                let (new_sessions, receiving) = listen_datagrams(*socket_addr)?;
+                Listener::Udp(new_sessions, receiving)
+            },
+            #[cfg(unix)]
+            ListenAddress::Unix(path) => Listener::Unix(bind_unix(path).await?)
+        });
+    }
+
+    let mut adapter_listeners = Vec::new();
+    for adapter_address in adapter_addresses.iter() {
+        adapter_listeners.push(bind_tcp(*adapter_address)?);
+    }
+
+    let metrics_listener = match options.metrics_address {
+        Some(metrics_address) => Some(bind_tcp(metrics_address)?),
+        None => None
+    };
+
+    let admin_listener = match &options.admin {
+        Some(admin) => Some(bind_tcp(admin.address)?),
+        None => None
+    };
+
+    let health_listener = match options.health_address {
+        Some(health_address) => Some(bind_tcp(health_address)?),
+        None => None
+    };
+
+    // The accept tasks are canceled when the server ends, which closes the listeners
+    let mut accept_tasks = Vec::new();
 
-    let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
-    let listener = TcpListener::bind(socket_addr).await?;
+    // Each adapter listener has an ongoing task to wait for adapter sockets
+    // They go through route_adapters, which answers control streams and authenticates adapter streams for serve()
+    let (adapter_sender, accepted_adapter_receiver) = channel(0);
+    let (routed_adapter_sender, adapter_receiver) = channel(0);
+    let handshake = AdapterHandshake {
+        key_fingerprint: key.fingerprint(),
+        key,
+        compression: options.compression.clone(),
+        metrics: options.metrics.clone(),
+        registry: options.registry.clone(),
+        status: ServerStatus::new(listen_addresses.clone(), options.registry.clone(), options.connections.clone(), options.metrics.clone()),
+        sniff_timeout: options.sniff_timeout
+    };
+    accept_tasks.push(task::spawn(route_adapters(accepted_adapter_receiver, routed_adapter_sender, handshake)));
 
-    // There is always an ongoing task that accepts an incoming connection on the clear (not adapter) port
-    // This task is replaced when the socket is accepted
-    let mut incoming_future = task::spawn(accept(listener));
+    // Labeled with the incoming addresses, which is what tells servers in the same process apart
+    let mapping = listen_addresses.iter().map(|a| a.to_string()).collect::<Vec<String>>().join(",");
+    let health = Health::new(options.registry.clone(), mapping.clone());
 
-    let adapter_socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), adapter_port);
-    let adapter_listener = TcpListener::bind(adapter_socket_addr).await?;
+    let adapter_routes = if options.shared_port || options.websocket_path.is_some() || options.health_path.is_some() {
+        Some(AdapterRoutes {
+            adapters: options.shared_port,
+            websocket_path: options.websocket_path.clone(),
+            health_path: options.health_path.clone(),
+            health: health.clone(),
+            timeout: options.sniff_timeout,
+            adapter_sender: adapter_sender.clone()
+        })
+    } else {
+        None
+    };
 
-    // This is the ongoing task to wait for adapter sockets
-    let mut incoming_adapter_future = task::spawn(accept(adapter_listener));
+    // Likewise, each listener has an ongoing task that accepts incoming connections (or datagram sessions) on the clear (not adapter) port
+    // Accepted connections wait in the pending queue, and are handed to serve() through incoming_receiver
+    let (incoming_sender, accepted_receiver) = channel(0);
+
+    for listener in listeners {
+        accept_tasks.push(task::spawn(accept_incoming(listener, incoming_sender.clone(), adapter_routes.clone(), options.metrics.clone())));
+    }
+
+    let incoming_receiver = run_pending_queue(accepted_receiver, options.pending, expire_incoming, options.metrics.queue_depth.clone());
+
+    for adapter_listener in adapter_listeners {
+        accept_tasks.push(task::spawn(accept_adapters(adapter_listener, adapter_sender.clone())));
+    }
+
+    accept_tasks.push(task::spawn(log_throughput(options.bandwidth.shared.clone())));
+
+    if let Some(metrics_listener) = metrics_listener {
+        accept_tasks.push(task::spawn(serve_metrics(metrics_listener, options.metrics.clone(), options.connections.clone(), mapping)));
+    }
+
+    if let (Some(admin_listener), Some(admin)) = (admin_listener, &options.admin) {
+        accept_tasks.push(task::spawn(serve_admin(admin_listener, options.registry.clone(), admin.token.clone())));
+    }
+
+    if let Some(health_listener) = health_listener {
+        accept_tasks.push(task::spawn(serve_health(health_listener, health)));
+    }
 
     listening_completable.complete(());
 
-    log::info!("Bounce server: Listening for incoming connections on {}, accepting adapter on port {}", port, adapter_port);
-    
-    'adapter_accept: loop {
+    log::info!(
+        "Bounce server: Listening for incoming connections on {}, accepting adapter on {}",
+        listen_addresses.iter().map(|a| a.to_string()).collect::<Vec<String>>().join(", "),
+        adapter_addresses.iter().map(|a| a.to_string()).collect::<Vec<String>>().join(", "));
 
-        let (listener, mut adapter_stream) = cancelable.allow_cancel(incoming_adapter_future, Err(Error::new(ErrorKind::Interrupted, "Server terminated"))).await?;
-        incoming_adapter_future = task::spawn(accept(listener));
+    if let Some(websocket_path) = &options.websocket_path {
+        log::info!("Bounce server: Accepting adapter as WebSocket upgrades on {}", websocket_path);
+    }
 
-        log::info!("Incoming adapter stream: {:?}", adapter_stream.peer_addr().unwrap());
+    if options.shared_port {
+        log::info!("Bounce server: Accepting adapter on the incoming TCP ports");
+    }
 
-        let xors = match authenticate(key.clone(), adapter_stream.clone()).await {
-            Err(err) => {
-                log::error!("Bad client: {}", err);
-                match adapter_stream.shutdown(Shutdown::Both) {
-                    Err(err) => log::error!("Problem shutting down socket after an authentication error: {}", err),
-                    Ok(_) => {}
-                }
-                continue 'adapter_accept;
-            },
-            Ok(n) => n
+    if let Some(health_path) = &options.health_path {
+        log::info!("Bounce server: Answering health checks on {}", health_path);
+    }
+
+    let result = serve(incoming_receiver, adapter_receiver, Arc::new(options), cancelable).await;
+
+    for accept_task in accept_tasks {
+        accept_task.cancel().await;
+    }
+
+    result
+}
+
+// Each authenticated adapter stream waits for an incoming connection in its own task, so that one slow client doesn't hold up the others
+async fn serve(incoming_receiver: PendingReceiver<Incoming>, mut adapter_receiver: Receiver<Result<Adapter, Error>>, options: Arc<ServerOptions>, cancelable: Cancelable) -> Result<(), Error> {
+
+    // Every adapter task waits for the server to stop, and a cancelation future only wakes whichever task polled it last
+    let going_away = cancelable.future().shared();
+    let mut adapter_tasks = FuturesUnordered::new();
+
+    let result = loop {
+        let adapter_future = Box::pin(next_accepted(&mut adapter_receiver));
+        let adapter_task_future = match adapter_tasks.is_empty() {
+            true => Either::Right(future::pending()),
+            false => Either::Left(adapter_tasks.next())
         };
 
-        let stream;
-        
+        match select(adapter_future, select(adapter_task_future, going_away.clone())).await {
+            Either::Left((Ok(adapter), _)) => {
+                adapter_tasks.push(task::spawn(serve_adapter(adapter, incoming_receiver.clone(), options.clone(), going_away.clone())));
+            },
+            // Accept errors end the server
+            Either::Left((Err(err), _)) => return Err(err),
+            Either::Right((Either::Left((Some(Err(err)), _)), _)) => return Err(err),
+            Either::Right((Either::Left(_), _)) => {},
+            Either::Right((Either::Right(_), _)) => break Err(Error::new(ErrorKind::Interrupted, "Server terminated"))
+        }
+    };
+
+    // Waits until every idle adapter stream is told that the server is going away
+    while adapter_tasks.next().await.is_some() {}
+
+    result
+}
+
+async fn serve_adapter(adapter: Adapter, mut incoming_receiver: PendingReceiver<Incoming>, options: Arc<ServerOptions>, going_away: Shared<CancelationTokenFuture>) -> Result<(), Error> {
+    let result = serve_adapter_int(adapter, &mut incoming_receiver, &options, going_away).await;
+
+    // Anything handed over to this adapter stream, but not used, goes back to the pending queue, for another adapter stream or to expire
+    incoming_receiver.release().await;
+
+    result
+}
+
+// Returns when the adapter stream is bridged, ends, or is told that the server is going away. Errors are accept errors, which end the server
+async fn serve_adapter_int(adapter: Adapter, incoming_receiver: &mut PendingReceiver<Incoming>, options: &ServerOptions, going_away: Shared<CancelationTokenFuture>) -> Result<(), Error> {
+
+    // Listed until the adapter stream ends, or is bridged
+    let Adapter { stream: mut adapter_stream, mut xors, compression, registration: _client_registration } = adapter;
+
+    // When the client can't reach the destination, the incoming connection is turned away and the adapter stream goes back to waiting
+    'connection: loop {
+        // Until the next incoming connection, log lines aren't about any one connection
+        set_connection_id(None);
+        let mut heartbeats = Heartbeats::new(options.heartbeat);
+
         // This complicated loop:
         // - Accepts the incoming stream (via incoming_future)
-        // - ALSO waits to see if the adapter_stream terminates (via peek_future)
+        // - ALSO waits to see if the adapter_stream terminates or sends a heartbeat (via peek_future)
+        // - ALSO sends heartbeats, and notices when the client stops sending them (via heartbeat_future)
         // What happens is:
-        // - if incoming_future (incoming clear stream) completes first, incoming_future is re-assigned a new task to accept the next stream
-        // - if peek_future (waits to see if adapter_stream terminates) completes first, then adapter_stream is cleaned up and the 'adapter_accept runs again
+        // - if incoming_future (incoming clear stream) completes first, the stream is bridged
+        // - if peek_future (waits to see if adapter_stream terminates) completes first, then either the heartbeat is read and the 'idle loop runs again,
+        //   or adapter_stream is cleaned up and this adapter stream is done
+        //   (The incoming stream stays in incoming_receiver for the next adapter stream)
+        // - if heartbeat_future completes first, a heartbeat is sent, or adapter_stream is cleaned up if the client's heartbeats stopped
 
         // Worth noting: If we don't need to handle adapter_stream ending, this is significantly simpler!
 
+        let incoming = 'idle: loop {
+            let incoming_future = Box::pin(incoming_receiver.next());
+            let peek_future = Box::pin(peek(adapter_stream.clone()));
+            let heartbeat_future = Box::pin(task::sleep(heartbeats.until_due()));
 
-        let peek_future = task::spawn(peek(adapter_stream.clone()));
+            match select(incoming_future, select(peek_future, select(heartbeat_future, going_away.clone()))).await {
+                Either::Left((r, _)) => {
+                    break 'idle r?;
+                },
+                Either::Right((select_result, _)) => {
+                    match select_result {
+                        Either::Left((peek_result, _)) => {
+                            match peek_result {
+                                Ok(bytes_sent) => {
+                                    let shutdown_result = if bytes_sent > 0 {
+                                        let mut signal = [0u8; SIGNAL_LENGTH];
+                                        match io::timeout(SIGNAL_TIMEOUT, adapter_stream.read_exact(&mut signal)).await {
+                                            Ok(()) if &signal == HEARTBEAT => {
+                                                log::trace!("Heartbeat from {}", adapter_stream.peer_name());
+                                                heartbeats.received();
+                                                continue 'idle;
+                                            },
+                                            _ => {}
+                                        }
 
-        match select(incoming_future, select(peek_future, cancelable.future())).await {
-            Either::Left((r, _)) => {
-                let (listener, s) = r?;
-                incoming_future = task::spawn(accept(listener));
-                stream = s;
-            },
-            Either::Right((select_result, i)) => {
-                match select_result {
-                    Either::Left((peek_result, _)) => {
-                        incoming_future = i;
-                        match peek_result {
-                            Ok(bytes_sent) => {
-                                let shutdown_result = if bytes_sent > 0 {
-                                    log::warn!("Adapter stream sent unexpected data: {:?}", adapter_stream.peer_addr().unwrap());
-                                    adapter_stream.shutdown(Shutdown::Both)
-                                } else {
-                                    log::info!("Adapter stream ended: {:?}", adapter_stream.peer_addr().unwrap());
-                                    adapter_stream.shutdown(Shutdown::Write)
-                                };
-
-                                match shutdown_result {
-                                    Ok(_) => {},
-                                    Err(err) => log::error!("Error shutting down adapter stream: {:?}:, {}", adapter_stream.peer_addr().unwrap(), err)
+                                        log::warn!("Adapter stream sent unexpected data: {}", adapter_stream.peer_name());
+                                        adapter_stream.shutdown(Shutdown::Both)
+                                    } else {
+                                        log::info!("Adapter stream ended: {}", adapter_stream.peer_name());
+                                        adapter_stream.shutdown(Shutdown::Write)
+                                    };
+
+                                    if let Err(err) = shutdown_result {
+                                        log::error!("Error shutting down adapter stream: {}:, {}", adapter_stream.peer_name(), err)
+                                    }
+
+                                    return Ok(());
+                                },
+                                Err(err) => {
+                                    log::error!("Adapter stream aborted: {}", err);
+                                    return Ok(());
                                 }
+                            }
+                        },
+                        Either::Right((Either::Left(_), _)) => {
+                            if heartbeats.missed() {
+                                log::warn!("Adapter stream stopped sending heartbeats: {}", adapter_stream.peer_name());
+                                shutdown_adapter_stream(&adapter_stream);
+                                return Ok(());
+                            }
 
-                                continue 'adapter_accept;
-                            },
-                            Err(err) => {
-                                log::error!("Adapter stream aborted: {}", err);
-                                continue 'adapter_accept;
+                            if heartbeats.send_due() {
+                                if let Err(err) = heartbeats.send(&mut adapter_stream).await {
+                                    log::error!("Can not send heartbeat to adapter stream: {}", err);
+                                    shutdown_adapter_stream(&adapter_stream);
+                                    return Ok(());
+                                }
                             }
+                        },
+                        Either::Right((Either::Right(_), _)) => {
+                            send_going_away(&mut adapter_stream).await;
+                            return Ok(());
                         }
+                    }
+                }
+            }
+        };
+
+        match incoming {
+            Incoming::Stream(stream, id) => {
+                set_connection_id(Some(id));
+                log::info!("Incoming clear stream: {}", stream.peer_name());
+
+                let connection_info = ConnectionInfo {
+                    peer_addr: stream.peer_addr(),
+                    local_addr: stream.local_addr(),
+                    id: Some(id)
+                };
+
+                match start_connection(&mut xors.write_xor, &mut adapter_stream, CONNECTED, &connection_info, going_away.clone()).await {
+                    Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+                        log::warn!("Destination unavailable for {}", stream.peer_name());
+                        reject_stream(stream, options.unavailable_response).await;
+                        continue 'connection;
+                    },
+                    // Another client can still take the incoming stream
+                    Err(err) => {
+                        log::error!("Error starting connection: {}", err);
+                        shutdown_adapter_stream(&adapter_stream);
+                        incoming_receiver.give_back(Incoming::Stream(stream, id)).await;
+                        return Ok(());
+                    },
+                    Ok(()) => {}
+                }
+
+                let bridge_options = BridgeOptions {
+                    compression,
+                    bandwidth: options.bandwidth.connection(),
+                    timeouts: options.timeouts,
+                    buffers: options.buffers.clone(),
+                    metrics: options.metrics.clone(),
+                    capture: options.capture.as_ref().and_then(|capture| capture.connection(connection_info.peer_addr, connection_info.local_addr, true)),
+                    inspector: None
+                };
+
+                let registration = options.registry.add_bridge(id, &stream, &adapter_stream, bridge_options.bandwidth.clone());
+                run_bridge(xors, bridge_options, stream, "incoming".to_string(), adapter_stream, "bounce-outgoing".to_string(), (options.connections.track(), registration));
+            },
+            Incoming::Datagrams(session, id) => {
+                set_connection_id(Some(id));
+                log::info!("Incoming datagram session: {:?}", session.source);
+
+                let connection_info = ConnectionInfo {
+                    peer_addr: Some(session.source),
+                    local_addr: session.local_addr(),
+                    id: Some(id)
+                };
+
+                match start_connection(&mut xors.write_xor, &mut adapter_stream, DATAGRAMS, &connection_info, going_away.clone()).await {
+                    // Dropping the session ends it, so a later datagram from the same source starts over
+                    Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+                        log::warn!("Destination unavailable for datagram session {:?}", session.source);
+                        continue 'connection;
+                    },
+                    Err(err) => {
+                        log::error!("Error starting datagram session: {}", err);
+                        shutdown_adapter_stream(&adapter_stream);
+                        incoming_receiver.give_back(Incoming::Datagrams(session, id)).await;
+                        return Ok(());
                     },
-                    Either::Right(_) => return Err(Error::new(ErrorKind::Interrupted, "Server terminated"))
+                    Ok(()) => {}
                 }
+
+                // Sessions don't close, so they always end after the UDP idle timeout, or sooner if the connection idle timeout is shorter
+                let datagram_options = DatagramBridgeOptions {
+                    bandwidth: options.bandwidth.connection(),
+                    timeouts: ConnectionTimeouts {
+                        idle: Some(options.timeouts.idle.map_or(options.udp_idle_timeout, |idle| idle.min(options.udp_idle_timeout))),
+                        max_lifetime: options.timeouts.max_lifetime
+                    },
+                    metrics: options.metrics.clone()
+                };
+
+                let registration = options.registry.add_datagram_bridge(id, session.source, session.local_addr(), &adapter_stream, datagram_options.bandwidth.clone());
+                run_datagram_session_bridge(xors, datagram_options, session, adapter_stream, (options.connections.track(), registration));
             }
         }
 
-        log::info!("Incoming clear stream: {:?}", stream.peer_addr().unwrap());
+        return Ok(());
+    }
+}
+
+// Sends the signal, in the clear, followed by the encrypted connection info
+// Then waits for the client to send the signal back, after any heartbeats that it already sent, unless the server goes away first
+async fn start_connection<TRng>(xor: &mut Xor<TRng>, adapter_stream: &mut Stream, signal: &Signal, connection_info: &ConnectionInfo, going_away: Shared<CancelationTokenFuture>) -> Result<(), Error> where
+TRng: CryptoRng + RngCore + Clone {
+    let started = Box::pin(async {
+        adapter_stream.write_all(signal).await?;
+        write_connection_info(xor, adapter_stream, connection_info).await?;
+        read_acknowledgement(adapter_stream, signal, ACKNOWLEDGEMENT_TIMEOUT).await
+    });
+
+    match select(started, going_away).await {
+        Either::Left((started, _)) => started,
+        Either::Right(_) => Err(Error::new(ErrorKind::Interrupted, "Server terminated"))
+    }
+}
+
+async fn reject_stream(mut stream: Stream, unavailable_response: UnavailableResponse) {
+    if unavailable_response == UnavailableResponse::Http502 {
+        if let Err(err) = io::timeout(SIGNAL_TIMEOUT, stream.write_all(HTTP_502_RESPONSE)).await {
+            log::warn!("Can not send 502 to {}: {}", stream.peer_name(), err);
+        }
+    }
+
+    if let Err(err) = stream.shutdown(Shutdown::Both) {
+        log::warn!("Error shutting down rejected stream {}: {}", stream.peer_name(), err);
+    }
+}
+
+fn expire_incoming(incoming: Incoming) {
+    match incoming {
+        Incoming::Stream(stream, id) => {
+            log::warn!("No client was ready for {} ({}), closing it", stream.peer_name(), id);
+
+            if let Err(err) = stream.shutdown(Shutdown::Both) {
+                log::warn!("Error shutting down expired stream {}: {}", stream.peer_name(), err);
+            }
+        },
+        Incoming::Datagrams(session, id) => log::warn!("No client was ready for datagram session {:?} ({}), dropping it", session.source, id)
+    }
+}
+
+// Lets the client know to reconnect, instead of waiting for heartbeats to stop
+async fn send_going_away(adapter_stream: &mut Stream) {
+    log::info!("Telling {} that the server is going away", adapter_stream.peer_name());
+
+    if let Err(err) = io::timeout(SIGNAL_TIMEOUT, adapter_stream.write_all(GOINGAWAY)).await {
+        log::warn!("Can not tell the client that the server is going away: {}", err);
+    }
+
+    shutdown_adapter_stream(adapter_stream);
+}
 
-        match adapter_stream.write_all(b"connected").await {
-            Err(err) => {
-                log::error!("Error starting connection: {}", err);
-                continue 'adapter_accept;
+fn shutdown_adapter_stream(adapter_stream: &Stream) {
+    if let Err(err) = adapter_stream.shutdown(Shutdown::Both) {
+        log::error!("Error shutting down adapter stream: {}", err);
+    }
+}
+
+async fn accept_adapters(listener: TcpListener, mut sender: Sender<Result<Stream, Error>>) {
+    loop {
+        let accepted = listener.accept().await.map(|(s, _)| s.into());
+        let failed = accepted.is_err();
+
+        if sender.send(accepted).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+async fn accept_incoming(listener: Listener, mut sender: Sender<Result<Incoming, Error>>, adapter_routes: Option<AdapterRoutes>, metrics: Metrics) {
+    match listener {
+        Listener::Tcp(tcp_listener) => loop {
+            let accepted = match (tcp_listener.accept().await, &adapter_routes) {
+                // Sniffing waits for the first bytes, so it happens in its own task
+                (Ok((stream, _)), Some(adapter_routes)) => {
+                    task::spawn(route_tcp(stream, sender.clone(), adapter_routes.clone(), metrics.clone()));
+                    continue;
+                },
+                (accepted, _) => accepted.map(|(s, _)| {
+                    metrics.clear_stream_accepted();
+                    Incoming::Stream(s.into(), ConnectionId::random())
+                })
+            };
+
+            let failed = accepted.is_err();
+
+            if sender.send(accepted).await.is_err() || failed {
+                return;
+            }
+        },
+        // Receiving in this task, instead of its own, means that canceling it stops receiving too
+        Listener::Udp(mut new_sessions, receiving) => {
+            let forward_future = Box::pin(async move {
+                while let Some(session) = new_sessions.next().await {
+                    if sender.send(Ok(Incoming::Datagrams(session, ConnectionId::random()))).await.is_err() {
+                        return;
+                    }
+                }
+            });
+
+            select(receiving, forward_future).await;
+        },
+        #[cfg(unix)]
+        Listener::Unix(unix_listener) => loop {
+            let accepted = unix_listener.accept().await.map(|(s, _)| {
+                metrics.clear_stream_accepted();
+                Incoming::Stream(s.into(), ConnectionId::random())
+            });
+            let failed = accepted.is_err();
+
+            if sender.send(accepted).await.is_err() || failed {
+                return;
+            }
+        }
+    }
+}
+
+async fn route_tcp(stream: TcpStream, mut sender: Sender<Result<Incoming, Error>>, mut adapter_routes: AdapterRoutes, metrics: Metrics) {
+    match sniff(&stream, adapter_routes.adapters, adapter_routes.websocket_path.as_deref(), adapter_routes.health_path.as_deref(), adapter_routes.timeout).await {
+        Sniffed::Adapter => {
+            let _ = adapter_routes.adapter_sender.send(Ok(stream.into())).await;
+        },
+        Sniffed::WebSocket(upgrade) => match accept_upgrade(stream, upgrade).await {
+            Ok(websocket) => {
+                let _ = adapter_routes.adapter_sender.send(Ok(websocket.into())).await;
             },
-            Ok(()) => {}
+            Err(err) => log::warn!("Can not accept WebSocket upgrade: {}", err)
+        },
+        Sniffed::Health => {
+            let health = adapter_routes.health;
+            let health_path = adapter_routes.health_path.unwrap_or_default();
+            let handle = move |request: Request| {
+                let response = health.respond(&request, &health_path);
+                async move { response }
+            };
+
+            if let Err(err) = respond(stream, handle).await {
+                log::debug!("Health check failed: {}", err);
+            }
+        },
+        Sniffed::Public => {
+            metrics.clear_stream_accepted();
+            let _ = sender.send(Ok(Incoming::Stream(stream.into(), ConnectionId::random()))).await;
         }
+    }
+}
+
+// Control streams, like `bounce status`, are answered here. Adapter streams are authenticated before they're handed to serve()
+// Sniffing and authenticating wait on the other side, so up to MAX_HANDSHAKES happen at once. They're dropped when this is canceled
+async fn route_adapters(mut receiver: Receiver<Result<Stream, Error>>, mut sender: Sender<Result<Adapter, Error>>, handshake: AdapterHandshake) {
+    let mut handshakes = FuturesUnordered::new();
+
+    loop {
+        let accept_future = match handshakes.len() < MAX_HANDSHAKES {
+            true => Either::Left(receiver.next()),
+            false => Either::Right(future::pending())
+        };
+        let handshake_future = match handshakes.is_empty() {
+            true => Either::Right(future::pending()),
+            false => Either::Left(handshakes.next())
+        };
 
-        run_bridge(xors, stream, "incoming".to_string(), adapter_stream, "bounce-outgoing".to_string());
+        match select(accept_future, handshake_future).await {
+            Either::Left((Some(Ok(stream)), _)) => handshakes.push(route_adapter(stream, sender.clone(), handshake.clone())),
+            Either::Left((Some(Err(err)), _)) => {
+                if sender.send(Err(err)).await.is_err() {
+                    return;
+                }
+            },
+            Either::Left((None, _)) => return,
+            Either::Right(_) => {}
+        }
     }
 }
 
-async fn accept(listener: TcpListener) -> Result<(TcpListener, TcpStream), Error> {
-    match listener.accept().await {
-        Ok((s, _)) => Ok((listener, s)),
-        Err(err) => Err(err)
+async fn route_adapter(stream: Stream, mut sender: Sender<Result<Adapter, Error>>, handshake: AdapterHandshake) {
+    if sniff_control(&stream, handshake.sniff_timeout).await {
+        serve_control(stream, handshake.key, handshake.status).await;
+    } else if let Some(adapter) = authenticate_adapter(stream, &handshake).await {
+        let _ = sender.send(Ok(adapter)).await;
     }
 }
 
-async fn peek(stream: TcpStream) -> Result<usize, Error> {
+async fn authenticate_adapter(adapter_stream: Stream, handshake: &AdapterHandshake) -> Option<Adapter> {
+    log::info!("Incoming adapter stream: {}", adapter_stream.peer_name());
+
+    let started = Instant::now();
+    let authenticated = authenticate(handshake.key.clone(), adapter_stream.clone(), &handshake.compression).await;
+    handshake.metrics.handshake(authenticated.is_ok(), started.elapsed());
+
+    match authenticated {
+        Ok((xors, compression)) => Some(Adapter {
+            registration: handshake.registry.add_client(&adapter_stream, &handshake.key_fingerprint),
+            stream: adapter_stream,
+            xors,
+            compression
+        }),
+        Err(err) => {
+            log::error!("Bad client: {}", err);
+            if let Err(err) = adapter_stream.shutdown(Shutdown::Both) {
+                log::error!("Problem shutting down socket after an authentication error: {}", err);
+            }
+            None
+        }
+    }
+}
+
+// Accept errors end the server
+async fn next_accepted<T>(receiver: &mut Receiver<Result<T, Error>>) -> Result<T, Error> {
+    match receiver.next().await {
+        Some(accepted) => accepted,
+        None => Err(Error::new(ErrorKind::BrokenPipe, "No longer accepting connections"))
+    }
+}
+
+// A socket file left behind by a previous run would prevent binding, so it's removed if nothing is listening on it
+#[cfg(unix)]
+async fn bind_unix(path: &std::path::Path) -> Result<UnixListener, Error> {
+    use std::os::unix::fs::FileTypeExt;
+
+    // Only a socket is ever removed, not a file that was put at the path by mistake
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path.display())));
+        }
+
+        match UnixStream::connect(path).await {
+            Ok(_) => return Err(Error::new(ErrorKind::AddrInUse, format!("Something is already listening on {}", path.display()))),
+            Err(_) => {
+                log::info!("Removing stale socket {}", path.display());
+                std::fs::remove_file(path)?;
+            }
+        }
+    }
+
+    UnixListener::bind(path).await
+}
+
+async fn peek(stream: Stream) -> Result<usize, Error> {
     let mut peek_buf = [0u8; 1];
     let bytes = stream.peek(&mut peek_buf).await?;
     Ok(bytes)
@@ -141,7 +783,7 @@ async fn peek(stream: TcpStream) -> Result<usize, Error> {
 // Note: Tests are error conditions only, happy-path tests are in main.rs
 #[cfg(test)]
 mod tests {
-    use async_std::net::{IpAddr, Ipv4Addr, Shutdown, TcpListener, SocketAddr};
+    use async_std::net::{IpAddr, Ipv4Addr, Shutdown, TcpListener, SocketAddr, UdpSocket};
     use async_std::prelude::*;
     use async_std::task::JoinHandle;
     use std::io::Error;
@@ -151,8 +793,12 @@ mod tests {
     use super::*;
 
     async fn get_adapter_stream_and_server_future() -> (TcpStream, SocketAddr, JoinHandle<Result<(), Error>>, CancelationToken) {
+        get_adapter_stream_and_server_future_with_options(ServerOptions::default()).await
+    }
+
+    async fn get_adapter_stream_and_server_future_with_options(options: ServerOptions) -> (TcpStream, SocketAddr, JoinHandle<Result<(), Error>>, CancelationToken) {
         let key = Key {
-            key: vec![1 as u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32],
+            key: vec![1u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32],
             size: KeySize::KeySize256
         };
 
@@ -166,13 +812,13 @@ mod tests {
         drop(listener);
         drop(adapter_listener);
 
-        let (server_future, listening_token, cancelation_token) = run_server(client_address.port(), adapter_address.port(), key.clone());
+        let (server_future, listening_token, cancelation_token) = run_server(vec![ListenAddress::Tcp(client_address)], vec![adapter_address], key.clone(), options);
 
         listening_token.await;
 
         let adapter_stream = TcpStream::connect(adapter_address).await.expect("Can not connect to the server");
 
-        authenticate(key, adapter_stream.clone()).await.expect("Can not authenticate client stream");
+        authenticate(key, adapter_stream.clone(), &[]).await.expect("Can not authenticate client stream");
 
         (adapter_stream, adapter_address, server_future, cancelation_token)
     }
@@ -207,5 +853,283 @@ mod tests {
         let err = server_future.await.expect_err("Server should terminate");
         assert_eq!(ErrorKind::Interrupted, err.kind(), "");
     }
-}
 
+    #[async_std::test]
+    async fn client_stops_heartbeats() {
+
+        let options = ServerOptions {
+            heartbeat: HeartbeatOptions {
+                interval: Duration::from_millis(100),
+                misses: 2
+            },
+            ..ServerOptions::default()
+        };
+
+        let (mut adapter_stream, _, server_future, cancelation_token) = get_adapter_stream_and_server_future_with_options(options).await;
+
+        // The server sends heartbeats, and then gives up on the client
+        let mut buf = Vec::new();
+        io::timeout(Duration::from_secs(5), adapter_stream.read_to_end(&mut buf)).await.expect("Server did not close the adapter stream");
+
+        assert!(!buf.is_empty(), "No heartbeats sent");
+        for signal in buf.chunks(SIGNAL_LENGTH) {
+            assert_eq!(HEARTBEAT, signal, "Unexpected data from the server");
+        }
+
+        cancelation_token.cancel();
+        let err = server_future.await.expect_err("Server should terminate");
+        assert_eq!(ErrorKind::Interrupted, err.kind(), "");
+    }
+
+    #[async_std::test]
+    async fn no_client_ready() {
+        let key = Key {
+            key: vec![1u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32],
+            size: KeySize::KeySize256
+        };
+
+        let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let listener = TcpListener::bind(socket_addr).await.unwrap();
+        let adapter_listener = TcpListener::bind(socket_addr).await.unwrap();
+
+        let client_address = listener.local_addr().unwrap();
+        let adapter_address = adapter_listener.local_addr().unwrap();
+
+        drop(listener);
+        drop(adapter_listener);
+
+        let options = ServerOptions {
+            pending: PendingOptions {
+                max_size: 10,
+                timeout: Duration::from_millis(100)
+            },
+            ..ServerOptions::default()
+        };
+
+        let (server_future, listening_token, cancelation_token) = run_server(vec![ListenAddress::Tcp(client_address)], vec![adapter_address], key, options);
+
+        listening_token.await;
+
+        // Without a client, the incoming connection is closed once it has waited too long
+        let mut incoming_stream = TcpStream::connect(client_address).await.expect("Can not connect to the server");
+        let mut buf = Vec::new();
+        io::timeout(Duration::from_secs(5), incoming_stream.read_to_end(&mut buf)).await.expect("Server did not close the incoming stream");
+        assert!(buf.is_empty());
+
+        cancelation_token.cancel();
+        let err = server_future.await.expect_err("Server should terminate");
+        assert_eq!(ErrorKind::Interrupted, err.kind(), "");
+    }
+
+    #[async_std::test]
+    async fn stopping_releases_listeners() {
+        let key = Key {
+            key: vec![1u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32],
+            size: KeySize::KeySize256
+        };
+
+        let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let listener = TcpListener::bind(socket_addr).await.unwrap();
+        let public_socket = UdpSocket::bind(socket_addr).await.unwrap();
+        let adapter_listener = TcpListener::bind(socket_addr).await.unwrap();
+
+        let client_address = listener.local_addr().unwrap();
+        let public_address = public_socket.local_addr().unwrap();
+        let adapter_address = adapter_listener.local_addr().unwrap();
+
+        drop(listener);
+        drop(public_socket);
+        drop(adapter_listener);
+
+        let metrics = Metrics::default();
+        let options = ServerOptions {
+            metrics: metrics.clone(),
+            ..ServerOptions::default()
+        };
+
+        let (server_future, listening_token, cancelation_token) = run_server(vec![ListenAddress::Tcp(client_address), ListenAddress::Udp(public_address)], vec![adapter_address], key, options);
+
+        listening_token.await;
+
+        let mut incoming_stream = TcpStream::connect(client_address).await.expect("Can not connect to the server");
+        for _ in 0..50 {
+            if metrics.queue_depth.get() > 0 {
+                break;
+            }
+
+            task::sleep(Duration::from_millis(100)).await;
+        }
+
+        assert_eq!(1, metrics.queue_depth.get(), "The incoming connection isn't waiting for a client");
+
+        cancelation_token.cancel();
+        let err = server_future.await.expect_err("Server should terminate");
+        assert_eq!(ErrorKind::Interrupted, err.kind(), "");
+
+        // The waiting connection is closed, instead of waiting out its timeout, and the UDP port can be bound again
+        let mut buf = Vec::new();
+        io::timeout(Duration::from_secs(5), incoming_stream.read_to_end(&mut buf)).await.expect("Server did not close the incoming stream");
+        UdpSocket::bind(public_address).await.expect("Server did not release the UDP port");
+    }
+
+    #[async_std::test]
+    async fn client_does_not_acknowledge() {
+        let key = Key {
+            key: vec![1u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32],
+            size: KeySize::KeySize256
+        };
+
+        let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let listener = TcpListener::bind(socket_addr).await.unwrap();
+        let adapter_listener = TcpListener::bind(socket_addr).await.unwrap();
+
+        let client_address = listener.local_addr().unwrap();
+        let adapter_address = adapter_listener.local_addr().unwrap();
+
+        drop(listener);
+        drop(adapter_listener);
+
+        let (server_future, listening_token, cancelation_token) = run_server(vec![ListenAddress::Tcp(client_address)], vec![adapter_address], key.clone(), ServerOptions::default());
+
+        listening_token.await;
+
+        let first_adapter_stream = TcpStream::connect(adapter_address).await.expect("Can not connect to the server");
+        authenticate(key.clone(), first_adapter_stream.clone(), &[]).await.expect("Can not authenticate client stream");
+        let second_adapter_stream = TcpStream::connect(adapter_address).await.expect("Can not connect to the server");
+        authenticate(key, second_adapter_stream.clone(), &[]).await.expect("Can not authenticate client stream");
+
+        async fn read_signal_from(mut adapter_stream: TcpStream) -> Signal {
+            let mut signal = [0u8; SIGNAL_LENGTH];
+            adapter_stream.read_exact(&mut signal).await.expect("Can not read signal");
+            signal
+        }
+
+        // Whichever client gets the first connection never acknowledges it
+        let _first_incoming_stream = TcpStream::connect(client_address).await.expect("Can not connect to the server");
+        let (signal, mut waiting_adapter_stream) = match select(Box::pin(read_signal_from(first_adapter_stream.clone())), Box::pin(read_signal_from(second_adapter_stream.clone()))).await {
+            Either::Left((signal, _)) => (signal, second_adapter_stream),
+            Either::Right((signal, _)) => (signal, first_adapter_stream)
+        };
+        assert_eq!(CONNECTED, &signal);
+
+        // The other client gets the next connection, without waiting for the first to time out
+        let _second_incoming_stream = TcpStream::connect(client_address).await.expect("Can not connect to the server");
+        let mut signal = [0u8; SIGNAL_LENGTH];
+        io::timeout(Duration::from_secs(2), waiting_adapter_stream.read_exact(&mut signal)).await.expect("The second connection waited for the first client");
+        assert_eq!(CONNECTED, &signal);
+
+        cancelation_token.cancel();
+        let err = server_future.await.expect_err("Server should terminate");
+        assert_eq!(ErrorKind::Interrupted, err.kind(), "");
+    }
+
+    #[async_std::test]
+    async fn client_ends_before_acknowledging() {
+        let key = Key {
+            key: vec![1u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32],
+            size: KeySize::KeySize256
+        };
+
+        let socket_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let listener = TcpListener::bind(socket_addr).await.unwrap();
+        let adapter_listener = TcpListener::bind(socket_addr).await.unwrap();
+
+        let client_address = listener.local_addr().unwrap();
+        let adapter_address = adapter_listener.local_addr().unwrap();
+
+        drop(listener);
+        drop(adapter_listener);
+
+        let (server_future, listening_token, cancelation_token) = run_server(vec![ListenAddress::Tcp(client_address)], vec![adapter_address], key.clone(), ServerOptions::default());
+
+        listening_token.await;
+
+        let mut first_adapter_stream = TcpStream::connect(adapter_address).await.expect("Can not connect to the server");
+        authenticate(key.clone(), first_adapter_stream.clone(), &[]).await.expect("Can not authenticate client stream");
+
+        // The first client goes away instead of acknowledging
+        let mut incoming_stream = TcpStream::connect(client_address).await.expect("Can not connect to the server");
+        let mut signal = [0u8; SIGNAL_LENGTH];
+        first_adapter_stream.read_exact(&mut signal).await.expect("Can not read signal");
+        assert_eq!(CONNECTED, &signal);
+        first_adapter_stream.shutdown(Shutdown::Both).expect("Can not shut down client stream");
+
+        // The incoming stream waits for the next client, instead of being closed
+        let mut second_adapter_stream = TcpStream::connect(adapter_address).await.expect("Can not connect to the server");
+        authenticate(key, second_adapter_stream.clone(), &[]).await.expect("Can not authenticate client stream");
+        io::timeout(Duration::from_secs(5), second_adapter_stream.read_exact(&mut signal)).await.expect("The incoming stream was not given to the next client");
+        assert_eq!(CONNECTED, &signal);
+
+        let mut buf = [0u8; 1];
+        io::timeout(Duration::from_millis(200), incoming_stream.read(&mut buf)).await.expect_err("The incoming stream was closed");
+
+        cancelation_token.cancel();
+        let err = server_future.await.expect_err("Server should terminate");
+        assert_eq!(ErrorKind::Interrupted, err.kind(), "");
+    }
+
+    #[cfg(unix)]
+    #[async_std::test]
+    async fn unix_path_is_not_a_socket() {
+        let path = std::env::temp_dir().join(format!("bounce-not-a-socket-{}", std::process::id()));
+        std::fs::write(&path, b"keep").unwrap();
+
+        let err = bind_unix(&path).await.expect_err("Should not bind over a file");
+        assert_eq!(ErrorKind::AlreadyExists, err.kind());
+        assert_eq!(b"keep".to_vec(), std::fs::read(&path).expect("The file was removed"));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[async_std::test]
+    async fn limits_handshakes() {
+        let key = Key {
+            key: vec![1u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32],
+            size: KeySize::KeySize256
+        };
+
+        // Streams that never send anything stay in the handshake until they're closed
+        let handshake = AdapterHandshake {
+            key_fingerprint: key.fingerprint(),
+            key,
+            compression: Vec::new(),
+            metrics: Metrics::default(),
+            registry: Registry::default(),
+            status: ServerStatus::new(Vec::new(), Registry::default(), ActiveConnections::default(), Metrics::default()),
+            sniff_timeout: Duration::from_secs(60)
+        };
+
+        let (mut stream_sender, stream_receiver) = channel(0);
+        let (adapter_sender, _adapter_receiver) = channel(0);
+        let route_task = task::spawn(route_adapters(stream_receiver, adapter_sender, handshake));
+
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await.unwrap();
+        let mut silent_streams = Vec::new();
+        for _ in 0..MAX_HANDSHAKES {
+            let silent_stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (accepted, _) = listener.accept().await.unwrap();
+            stream_sender.send(Ok(accepted.into())).await.expect("Handshake was not started");
+            silent_streams.push(silent_stream);
+        }
+
+        // The next one isn't taken, because every handshake is running
+        let next_stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        let mut send_future = stream_sender.send(Ok(accepted.into()));
+        async_std::future::timeout(Duration::from_millis(200), &mut send_future).await.expect_err("Too many handshakes at once");
+
+        // Closing one frees a slot
+        silent_streams.remove(0).shutdown(Shutdown::Both).unwrap();
+        async_std::future::timeout(Duration::from_secs(5), send_future).await
+            .expect("Handshake did not start after another ended")
+            .expect("Handshake was not started");
+        silent_streams.push(next_stream);
+
+        // Stopping drops the handshakes, which closes their streams
+        route_task.cancel().await;
+        for mut silent_stream in silent_streams {
+            let mut buf = Vec::new();
+            io::timeout(Duration::from_secs(5), silent_stream.read_to_end(&mut buf)).await.expect("Handshake stream was not closed");
+        }
+    }
+}
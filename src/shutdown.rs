@@ -0,0 +1,146 @@
+use async_std::task;
+use async_std::task::JoinHandle;
+use core::time::Duration;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use futures::channel::mpsc::{UnboundedReceiver, unbounded};
+use futures::future::{Either, select};
+use futures::stream::StreamExt;
+use sync_tokens::cancelation_token::CancelationToken;
+
+// How often draining checks if the connections are done
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Counts bridged connections, so that shutdown can wait for them to finish
+#[derive(Clone, Default)]
+pub struct ActiveConnections {
+    count: Arc<AtomicUsize>
+}
+
+// Held by a bridge for as long as it runs
+pub struct ConnectionGuard {
+    count: Arc<AtomicUsize>
+}
+
+impl ActiveConnections {
+    pub fn track(&self) -> ConnectionGuard {
+        self.count.fetch_add(1, Ordering::SeqCst);
+
+        ConnectionGuard {
+            count: self.count.clone()
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    // Returns false if connections were still open when the timeout passed
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+
+        while self.count() > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+
+            task::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+
+        true
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ShutdownResult {
+    // Every bridged connection finished
+    Drained,
+    // This many connections were still open at the deadline, and were dropped
+    DeadlinePassed(usize)
+}
+
+// Runs a server or client until SIGTERM or SIGINT (Ctrl-C.) Then it stops accepting, and waits up to drain_timeout for bridged connections to finish
+// A second signal skips the wait
+pub async fn run_until_shutdown(future: JoinHandle<Result<(), Error>>, cancelation_token: CancelationToken, connections: ActiveConnections, drain_timeout: Duration) -> Result<ShutdownResult, Error> {
+    let mut signals = listen_for_signals()?;
+
+    let future = match select(future, signals.next()).await {
+        Either::Left((result, _)) => {
+            result?;
+            return Ok(ShutdownResult::Drained);
+        },
+        Either::Right((_, future)) => future
+    };
+
+    log::info!("Shutting down: {} connection(s) open, waiting up to {:.1}s for them to finish", connections.count(), drain_timeout.as_secs_f64());
+
+    cancelation_token.cancel();
+
+    // Canceling ends the server or client with Interrupted
+    match future.await {
+        Err(err) if err.kind() != ErrorKind::Interrupted => return Err(err),
+        _ => {}
+    }
+
+    let drained = match select(Box::pin(connections.drain(drain_timeout)), signals.next()).await {
+        Either::Left((drained, _)) => drained,
+        Either::Right(_) => {
+            log::warn!("Second signal, not waiting for connections to finish");
+            false
+        }
+    };
+
+    if drained {
+        log::info!("All connections finished");
+        Ok(ShutdownResult::Drained)
+    } else {
+        let remaining = connections.count();
+        log::warn!("Dropping {} connection(s) that did not finish in time", remaining);
+        Ok(ShutdownResult::DeadlinePassed(remaining))
+    }
+}
+
+fn listen_for_signals() -> Result<UnboundedReceiver<()>, Error> {
+    let (sender, receiver) = unbounded();
+
+    if let Err(err) = ctrlc::set_handler(move || { let _ = sender.unbounded_send(()); }) {
+        return Err(Error::other(format!("Can not listen for SIGTERM / SIGINT: {}", err)));
+    }
+
+    Ok(receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn drain() {
+        let connections = ActiveConnections::default();
+        assert!(connections.drain(Duration::from_secs(0)).await);
+
+        let guard_a = connections.track();
+        let guard_b = connections.track();
+        assert_eq!(2, connections.count());
+
+        assert!(!connections.drain(Duration::from_millis(50)).await, "Connections are still open");
+
+        drop(guard_a);
+        task::spawn(async move {
+            task::sleep(Duration::from_millis(100)).await;
+            drop(guard_b);
+        });
+
+        assert!(connections.drain(Duration::from_secs(5)).await, "Connections finished");
+        assert_eq!(0, connections.count());
+    }
+}
@@ -0,0 +1,196 @@
+use async_std::io;
+use async_std::io::{Read, Write};
+use async_std::prelude::*;
+use core::time::Duration;
+use std::io::{Error, ErrorKind};
+use std::time::Instant;
+
+// Signals are sent in the clear on an idle adapter stream. They are all the same length
+pub const SIGNAL_LENGTH: usize = 9;
+
+pub type Signal = [u8; SIGNAL_LENGTH];
+
+// The server starts a connection with CONNECTED or DATAGRAMS, and the client acknowledges by sending it back
+pub const CONNECTED: &Signal = b"connected";
+pub const DATAGRAMS: &Signal = b"datagrams";
+
+// The client sends UNAVAILABLE instead of acknowledging when it can't connect to the destination. The adapter stream stays idle afterwards
+pub const UNAVAILABLE: &Signal = b"noconnect";
+
+// How long the client tries to connect to the destination before sending UNAVAILABLE
+pub const DESTINATION_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long the server waits for the client to acknowledge. Longer than DESTINATION_CONNECT_TIMEOUT, so that a slow destination
+// is reported as unavailable instead of racing this timeout
+pub const ACKNOWLEDGEMENT_TIMEOUT: Duration = Duration::from_secs(20);
+
+// Both sides send HEARTBEAT while the adapter stream is idle
+pub const HEARTBEAT: &Signal = b"heartbeat";
+
+// The server sends GOINGAWAY on an idle adapter stream when it shuts down, so that the client reconnects right away
+pub const GOINGAWAY: &Signal = b"goingaway";
+
+// Sent, encrypted, on a control stream to ask for the server's status
+pub const STATUS: &Signal = b"getstatus";
+
+// Longer intervals are refused. Idle adapter streams are usually closed by something in between after a few minutes
+pub const MAX_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+// Heartbeat options aren't negotiated, so the server and client need the same ones. A side expects the other's heartbeats
+// at its own interval, so when the other side's interval is longer than this side's interval times misses, its adapter
+// streams are closed as dead
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HeartbeatOptions {
+    // How often to send a heartbeat on an idle adapter stream, up to MAX_HEARTBEAT_INTERVAL
+    pub interval: Duration,
+    // How many of the other side's heartbeats can be missed before the adapter stream is considered dead
+    pub misses: u32
+}
+
+impl Default for HeartbeatOptions {
+    fn default() -> Self {
+        HeartbeatOptions {
+            interval: Duration::from_secs(15),
+            misses: 3
+        }
+    }
+}
+
+// Tracks when heartbeats were last sent and received on an idle adapter stream
+pub struct Heartbeats {
+    options: HeartbeatOptions,
+    last_sent: Instant,
+    last_received: Instant
+}
+
+impl Heartbeats {
+    pub fn new(options: HeartbeatOptions) -> Heartbeats {
+        let now = Instant::now();
+
+        Heartbeats {
+            options,
+            last_sent: now,
+            last_received: now
+        }
+    }
+
+    pub fn received(&mut self) {
+        self.last_received = Instant::now();
+    }
+
+    pub fn send_due(&self) -> bool {
+        self.last_sent.elapsed() >= self.options.interval
+    }
+
+    pub fn missed(&self) -> bool {
+        self.last_received.elapsed() >= self.deadline()
+    }
+
+    // How long until either a heartbeat needs to be sent, or the other side's heartbeat is overdue
+    pub fn until_due(&self) -> Duration {
+        let until_send = self.options.interval.checked_sub(self.last_sent.elapsed()).unwrap_or_default();
+        let until_missed = self.deadline().checked_sub(self.last_received.elapsed()).unwrap_or_default();
+
+        until_send.min(until_missed)
+    }
+
+    pub async fn send<TStream>(&mut self, stream: &mut TStream) -> Result<(), Error> where
+    TStream: Write + Unpin {
+
+        io::timeout(self.options.interval, stream.write_all(HEARTBEAT)).await?;
+        self.last_sent = Instant::now();
+
+        Ok(())
+    }
+
+    fn deadline(&self) -> Duration {
+        self.options.interval * self.options.misses
+    }
+}
+
+// Reads the next signal that isn't a heartbeat
+pub async fn read_signal<TStream>(stream: &mut TStream, timeout: Duration) -> Result<Signal, Error> where
+TStream: Read + Unpin {
+
+    loop {
+        let mut signal = [0u8; SIGNAL_LENGTH];
+        io::timeout(timeout, stream.read_exact(&mut signal)).await?;
+
+        if &signal != HEARTBEAT {
+            return Ok(signal);
+        }
+    }
+}
+
+// Waits for the other side to send back the signal that started a connection
+// ConnectionRefused means that the client couldn't reach the destination, but the stream is still usable
+pub async fn read_acknowledgement<TStream>(stream: &mut TStream, expected: &Signal, timeout: Duration) -> Result<(), Error> where
+TStream: Read + Unpin {
+
+    let signal = read_signal(stream, timeout).await?;
+
+    if &signal == expected {
+        Ok(())
+    } else if &signal == UNAVAILABLE {
+        Err(Error::new(ErrorKind::ConnectionRefused, "Destination unavailable"))
+    } else {
+        Err(Error::new(ErrorKind::InvalidData, format!("Expected {}, got {:?}", String::from_utf8_lossy(expected), signal)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+
+    use super::*;
+
+    #[test]
+    fn heartbeat_deadlines() {
+        let options = HeartbeatOptions {
+            interval: Duration::from_secs(10),
+            misses: 3
+        };
+
+        let mut heartbeats = Heartbeats::new(options);
+        assert!(!heartbeats.send_due());
+        assert!(!heartbeats.missed());
+        assert!(heartbeats.until_due() <= Duration::from_secs(10));
+
+        heartbeats.last_sent -= Duration::from_secs(10);
+        assert!(heartbeats.send_due());
+        assert_eq!(Duration::from_secs(0), heartbeats.until_due());
+
+        heartbeats.last_sent = Instant::now();
+        heartbeats.last_received -= Duration::from_secs(25);
+        assert!(!heartbeats.missed());
+        assert!(heartbeats.until_due() <= Duration::from_secs(5));
+
+        heartbeats.last_received -= Duration::from_secs(5);
+        assert!(heartbeats.missed());
+
+        heartbeats.received();
+        assert!(!heartbeats.missed());
+    }
+
+    #[async_std::test]
+    async fn acknowledgement_skips_heartbeats() {
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await.unwrap();
+        let mut write_stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut read_stream, _) = listener.accept().await.unwrap();
+
+        write_stream.write_all(HEARTBEAT).await.unwrap();
+        write_stream.write_all(HEARTBEAT).await.unwrap();
+        write_stream.write_all(CONNECTED).await.unwrap();
+        write_stream.write_all(HEARTBEAT).await.unwrap();
+        write_stream.write_all(CONNECTED).await.unwrap();
+        write_stream.write_all(UNAVAILABLE).await.unwrap();
+
+        read_acknowledgement(&mut read_stream, CONNECTED, Duration::from_secs(1)).await.expect("Acknowledgement not read");
+
+        let err = read_acknowledgement(&mut read_stream, DATAGRAMS, Duration::from_secs(1)).await.expect_err("Wrong acknowledgement");
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+
+        let err = read_acknowledgement(&mut read_stream, CONNECTED, Duration::from_secs(1)).await.expect_err("Destination unavailable");
+        assert_eq!(ErrorKind::ConnectionRefused, err.kind());
+    }
+}
@@ -0,0 +1,215 @@
+use async_std::io;
+use async_std::net::TcpStream;
+use async_std::task;
+use core::time::Duration;
+use std::time::Instant;
+
+use crate::auth::{CONTROL_MAGIC, MAGIC};
+use crate::stream::Stream;
+use crate::websocket::{MAX_HEADER_SIZE, Upgrade, match_upgrade};
+
+// Peeking again right away would return the same bytes
+const SNIFF_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// What the first bytes of a connection say about it so far
+pub enum Match<T> {
+    Yes(T),
+    No,
+    NeedMore
+}
+
+// Where a connection on a public port goes
+pub enum Sniffed {
+    Adapter,
+    WebSocket(Upgrade),
+    // A request under health_path
+    Health,
+    Public
+}
+
+// Peeks at the first bytes of a connection, without reading them, to see if it's an adapter stream, a WebSocket upgrade on websocket_path, or a health check under health_path
+// Anything that doesn't match, or doesn't send enough within timeout (like protocols where the server speaks first), is public
+pub async fn sniff(stream: &TcpStream, adapters: bool, websocket_path: Option<&str>, health_path: Option<&str>, timeout: Duration) -> Sniffed {
+    let deadline = Instant::now() + timeout;
+    let mut buf = vec![0u8; MAX_HEADER_SIZE];
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let peeked = match io::timeout(remaining, stream.peek(&mut buf)).await {
+            Ok(0) | Err(_) => return Sniffed::Public,
+            Ok(bytes_peeked) => &buf[..bytes_peeked]
+        };
+
+        let mut need_more = false;
+
+        if adapters {
+            match match_magic(peeked) {
+                Match::Yes(()) => return Sniffed::Adapter,
+                Match::NeedMore => need_more = true,
+                Match::No => {}
+            }
+        }
+
+        if let Some(websocket_path) = websocket_path {
+            match match_upgrade(peeked, websocket_path) {
+                Match::Yes(upgrade) => return Sniffed::WebSocket(upgrade),
+                Match::NeedMore => need_more = true,
+                Match::No => {}
+            }
+        }
+
+        if let Some(health_path) = health_path {
+            match match_health(peeked, health_path) {
+                Match::Yes(()) => return Sniffed::Health,
+                Match::NeedMore => need_more = true,
+                Match::No => {}
+            }
+        }
+
+        // Give up as soon as nothing can match, so that public connections aren't held up
+        if !need_more || peeked.len() == buf.len() || Instant::now() >= deadline {
+            return Sniffed::Public;
+        }
+
+        task::sleep(SNIFF_POLL_INTERVAL).await;
+    }
+}
+
+// Both sides of the handshake start with the magic, and the client doesn't wait for the server to send it
+// Control streams are routed like adapter streams
+fn match_magic(peeked: &[u8]) -> Match<()> {
+    match (match_one_magic(peeked, MAGIC), match_one_magic(peeked, CONTROL_MAGIC)) {
+        (Match::Yes(()), _) | (_, Match::Yes(())) => Match::Yes(()),
+        (Match::NeedMore, _) | (_, Match::NeedMore) => Match::NeedMore,
+        _ => Match::No
+    }
+}
+
+fn match_one_magic(peeked: &[u8], magic: &[u8]) -> Match<()> {
+    if peeked.len() < magic.len() {
+        if magic.starts_with(peeked) { Match::NeedMore } else { Match::No }
+    } else if peeked.starts_with(magic) {
+        Match::Yes(())
+    } else {
+        Match::No
+    }
+}
+
+// A GET for health_path, or anything under it. Only the request line is needed
+fn match_health(peeked: &[u8], health_path: &str) -> Match<()> {
+    let line_end = match peeked.iter().position(|byte| *byte == b'\n') {
+        Some(line_end) => line_end,
+        None if b"GET ".starts_with(peeked) || peeked.starts_with(b"GET ") => return Match::NeedMore,
+        None => return Match::No
+    };
+
+    let line = String::from_utf8_lossy(&peeked[..line_end]);
+    let mut parts = line.trim_end().split(' ');
+
+    match (parts.next(), parts.next().and_then(|target| target.split('?').next())) {
+        (Some("GET"), Some(path)) if path == health_path || path.strip_prefix(health_path).is_some_and(|rest| rest.starts_with('/')) => Match::Yes(()),
+        _ => Match::No
+    }
+}
+
+// Peeks at an adapter stream to see if it's a control stream instead. Adapter streams that don't send enough within timeout are left to fail their handshake
+pub async fn sniff_control(stream: &Stream, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0u8; 6];
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let peeked = match io::timeout(remaining, stream.peek(&mut buf)).await {
+            Ok(0) | Err(_) => return false,
+            Ok(bytes_peeked) => &buf[..bytes_peeked]
+        };
+
+        match match_one_magic(peeked, CONTROL_MAGIC) {
+            Match::Yes(()) => return true,
+            Match::No => return false,
+            Match::NeedMore if Instant::now() >= deadline => return false,
+            Match::NeedMore => task::sleep(SNIFF_POLL_INTERVAL).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+    use async_std::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn magic() {
+        assert!(matches!(match_magic(b"bou"), Match::NeedMore));
+        assert!(matches!(match_magic(b"bounce"), Match::Yes(())));
+        assert!(matches!(match_magic(b"bounce and more"), Match::Yes(())));
+        assert!(matches!(match_magic(b"GET / HTTP/1.1"), Match::No));
+        assert!(matches!(match_magic(b"bx"), Match::No));
+        assert!(matches!(match_magic(b"bn"), Match::NeedMore));
+        assert!(matches!(match_magic(b"bnctrl"), Match::Yes(())));
+    }
+
+    #[test]
+    fn health() {
+        assert!(matches!(match_health(b"GE", "/healthz"), Match::NeedMore));
+        assert!(matches!(match_health(b"GET /heal", "/healthz"), Match::NeedMore));
+        assert!(matches!(match_health(b"GET /healthz HTTP/1.1\r\n", "/healthz"), Match::Yes(())));
+        assert!(matches!(match_health(b"GET /healthz/ready?verbose HTTP/1.1\r\n", "/healthz"), Match::Yes(())));
+        assert!(matches!(match_health(b"GET /healthzz HTTP/1.1\r\n", "/healthz"), Match::No));
+        assert!(matches!(match_health(b"POST /healthz HTTP/1.1\r\n", "/healthz"), Match::No));
+        assert!(matches!(match_health(b"bounce", "/healthz"), Match::No));
+    }
+
+    async fn sniff_sent(sent: &[u8], timeout: Duration) -> (Sniffed, Duration) {
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        client.write_all(sent).await.unwrap();
+
+        let (server_stream, _) = listener.accept().await.unwrap();
+
+        let start = Instant::now();
+        let sniffed = sniff(&server_stream, true, Some("/bounce"), Some("/healthz"), timeout).await;
+        let elapsed = start.elapsed();
+
+        // Nothing was read
+        let mut buf = vec![0u8; sent.len()];
+        (&server_stream).read_exact(&mut buf).await.unwrap();
+        assert_eq!(sent, &buf[..]);
+
+        (sniffed, elapsed)
+    }
+
+    #[async_std::test]
+    async fn control() {
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await.unwrap();
+
+        for (sent, expected) in [(&b"bnctrl"[..], true), (&b"bounce"[..], false), (&b"bnc"[..], false)] {
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            client.write_all(sent).await.unwrap();
+
+            let (server_stream, _) = listener.accept().await.unwrap();
+            assert_eq!(expected, sniff_control(&server_stream.into(), Duration::from_millis(100)).await, "{:?}", sent);
+        }
+    }
+
+    #[async_std::test]
+    async fn routes() {
+        let timeout = Duration::from_secs(5);
+
+        assert!(matches!(sniff_sent(b"bounce", timeout).await.0, Sniffed::Adapter));
+        assert!(matches!(sniff_sent(b"GET /bounce HTTP/1.1\r\nUpgrade: websocket\r\nSec-WebSocket-Key: key\r\n\r\n", timeout).await.0, Sniffed::WebSocket(_)));
+        assert!(matches!(sniff_sent(b"GET /healthz/live HTTP/1.1\r\n", timeout).await.0, Sniffed::Health));
+
+        // Public traffic isn't held up
+        let (sniffed, elapsed) = sniff_sent(b"GET / HTTP/1.1\r\n\r\n", timeout).await;
+        assert!(matches!(sniffed, Sniffed::Public));
+        assert!(elapsed < Duration::from_secs(1), "Took {:?}", elapsed);
+
+        // Until the timeout, it could still be either
+        let (sniffed, elapsed) = sniff_sent(b"", Duration::from_millis(100)).await;
+        assert!(matches!(sniffed, Sniffed::Public));
+        assert!(elapsed >= Duration::from_millis(100), "Took {:?}", elapsed);
+    }
+}
@@ -0,0 +1,204 @@
+use async_std::io;
+use async_std::net::Shutdown;
+use async_std::prelude::*;
+use core::time::Duration;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::time::Instant;
+
+use rustc_serialize::json::Json;
+
+use crate::address::ListenAddress;
+use crate::admin::clients_json;
+use crate::auth::authenticate_control;
+use crate::client::connect_bounce_server;
+use crate::http_proxy::HttpProxy;
+use crate::keys::Key;
+use crate::metrics::Metrics;
+use crate::registry::Registry;
+use crate::shutdown::ActiveConnections;
+use crate::signal::{SIGNAL_LENGTH, STATUS};
+use crate::stream::Stream;
+
+// How long either side waits on the other, for the whole request
+const STATUS_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Larger responses are refused by the client
+const MAX_STATUS_SIZE: usize = 16 * 1024 * 1024;
+
+// What a running server tells `bounce status`. Clones share the same state
+#[derive(Clone)]
+pub struct ServerStatus {
+    started: Instant,
+    listen_addresses: Arc<Vec<ListenAddress>>,
+    registry: Registry,
+    connections: ActiveConnections,
+    metrics: Metrics
+}
+
+impl ServerStatus {
+    pub fn new(listen_addresses: Vec<ListenAddress>, registry: Registry, connections: ActiveConnections, metrics: Metrics) -> ServerStatus {
+        ServerStatus {
+            started: Instant::now(),
+            listen_addresses: Arc::new(listen_addresses),
+            registry,
+            connections,
+            metrics
+        }
+    }
+
+    fn json(&self) -> Json {
+        let bridges = self.registry.bridges();
+
+        // Bridges are matched to TCP listen addresses by port. Other kinds of connections are only in the total
+        let listen = self.listen_addresses.iter().map(|listen_address| {
+            let active_connections = match listen_address {
+                ListenAddress::Tcp(socket_addr) => Json::U64(bridges.iter()
+                    .filter(|bridge| bridge.local_addr.map(|local_addr| local_addr.port()) == Some(socket_addr.port()))
+                    .count() as u64),
+                _ => Json::Null
+            };
+
+            let mut object = BTreeMap::new();
+            object.insert("address".to_string(), Json::String(listen_address.to_string()));
+            object.insert("active_connections".to_string(), active_connections);
+            Json::Object(object)
+        }).collect();
+
+        let mut object = BTreeMap::new();
+        object.insert("uptime_seconds".to_string(), Json::F64(self.started.elapsed().as_secs_f64()));
+        object.insert("listen".to_string(), Json::Array(listen));
+        object.insert("active_connections".to_string(), Json::U64(self.connections.count() as u64));
+        object.insert("pending".to_string(), Json::U64(self.metrics.queue_depth.get() as u64));
+        object.insert("clients".to_string(), clients_json(&self.registry));
+        Json::Object(object)
+    }
+}
+
+// A control stream asks for STATUS, and is answered with a 4-byte big-endian length and the status as JSON, all encrypted
+pub async fn serve_control(stream: Stream, key: Key, status: ServerStatus) {
+    if let Err(err) = io::timeout(STATUS_TIMEOUT, answer_control(stream.clone(), key, &status)).await {
+        log::warn!("Bad control stream: {}", err);
+    }
+
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+async fn answer_control(mut stream: Stream, key: Key, status: &ServerStatus) -> Result<(), Error> {
+    let mut xors = authenticate_control(key, stream.clone()).await?;
+
+    let mut request = [0u8; SIGNAL_LENGTH];
+    stream.read_exact(&mut request).await?;
+    xors.read_xor.process(&mut request);
+
+    if &request != STATUS {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Unknown control request: {:?}", request)));
+    }
+
+    log::info!("Sending status");
+
+    let json = status.json().to_string();
+    let mut frame = Vec::with_capacity(json.len() + 4);
+    frame.extend_from_slice(&(json.len() as u32).to_be_bytes());
+    frame.extend_from_slice(json.as_bytes());
+
+    xors.write_xor.process(&mut frame);
+    stream.write_all(&frame).await?;
+    stream.flush().await
+}
+
+// Authenticates to a running server, like a client, and asks for its status
+// Connecting is a large future, so it's boxed
+pub async fn request_status(bounce_server: &str, key: Key, proxy: Option<&HttpProxy>) -> Result<Json, Error> {
+    io::timeout(STATUS_TIMEOUT, Box::pin(async {
+        let mut stream = connect_bounce_server(bounce_server, proxy).await?;
+        let mut xors = authenticate_control(key, stream.clone()).await?;
+
+        let mut request = *STATUS;
+        xors.write_xor.process(&mut request);
+        stream.write_all(&request).await?;
+
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len).await?;
+        xors.read_xor.process(&mut len);
+
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_STATUS_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData, format!("Status is too large: {} bytes", len)));
+        }
+
+        let mut json = vec![0u8; len];
+        stream.read_exact(&mut json).await?;
+        xors.read_xor.process(&mut json);
+
+        let _ = stream.shutdown(Shutdown::Both);
+
+        let json = String::from_utf8(json).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        Json::from_str(&json).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+    })).await
+}
+
+// For people, from request_status
+pub fn format_status(status: &Json) -> String {
+    let count = |name: &str| status.find(name).and_then(Json::as_u64).unwrap_or_default();
+    let uptime = status.find("uptime_seconds").and_then(Json::as_f64).unwrap_or_default();
+
+    let mut formatted = format!("Up for {}\n", format_seconds(uptime));
+    formatted.push_str(&format!("Active connections: {}, waiting for a client: {}\n", count("active_connections"), count("pending")));
+
+    formatted.push_str("Listening on:\n");
+    for listen in status.find("listen").and_then(Json::as_array).map(Vec::as_slice).unwrap_or_default() {
+        let address = listen.find("address").and_then(Json::as_string).unwrap_or_default();
+        match listen.find("active_connections").and_then(Json::as_u64) {
+            Some(active_connections) => formatted.push_str(&format!("  {} ({} active)\n", address, active_connections)),
+            None => formatted.push_str(&format!("  {}\n", address))
+        }
+    }
+
+    let clients = status.find("clients").and_then(Json::as_array).map(Vec::as_slice).unwrap_or_default();
+    formatted.push_str(&format!("Clients waiting for a connection: {}\n", clients.len()));
+    for client in clients {
+        formatted.push_str(&format!(
+            "  {} {}, key {}, connected for {}\n",
+            client.find("id").and_then(Json::as_u64).unwrap_or_default(),
+            client.find("address").and_then(Json::as_string).unwrap_or_default(),
+            client.find("key").and_then(Json::as_string).unwrap_or_default(),
+            format_seconds(client.find("connected_seconds").and_then(Json::as_f64).unwrap_or_default())));
+    }
+
+    formatted.trim_end().to_string()
+}
+
+fn format_seconds(seconds: f64) -> String {
+    let seconds = seconds as u64;
+
+    match (seconds / 3600, seconds / 60 % 60, seconds % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {}s", m, s),
+        (h, m, s) => format!("{}h {}m {}s", h, m, s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_status() {
+        let status = Json::from_str(r#"{
+            "uptime_seconds": 3725.5,
+            "listen": [{"address": "0.0.0.0:8080", "active_connections": 2}, {"address": "udp:0.0.0.0:53", "active_connections": null}],
+            "active_connections": 3,
+            "pending": 1,
+            "clients": [{"id": 0, "address": "10.0.0.1:5000", "key": "ab12", "connected_seconds": 75.0}]
+        }"#).unwrap();
+
+        assert_eq!(
+            "Up for 1h 2m 5s\n\
+            Active connections: 3, waiting for a client: 1\n\
+            Listening on:\n  0.0.0.0:8080 (2 active)\n  udp:0.0.0.0:53\n\
+            Clients waiting for a connection: 1\n  0 10.0.0.1:5000, key ab12, connected for 1m 15s",
+            format_status(&status));
+    }
+}
@@ -0,0 +1,206 @@
+use async_std::io::{Read, Write};
+use async_std::net::{Shutdown, SocketAddr, TcpStream};
+#[cfg(unix)]
+use async_std::os::unix::net::UnixStream;
+use async_std::task::{Context, Poll};
+use core::time::Duration;
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+
+use socket2::SockRef;
+
+use crate::websocket::WebSocket;
+
+// A connected stream that bounce can bridge, regardless of the kind of socket
+#[derive(Clone)]
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    // Only used for adapter streams
+    WebSocket(WebSocket)
+}
+
+impl Stream {
+    // Connects to "host:port" or "unix:/path/to.sock"
+    pub async fn connect(address: &str) -> Result<Stream, Error> {
+        #[cfg(unix)]
+        {
+            if let Some(path) = address.strip_prefix("unix:") {
+                return Ok(Stream::Unix(UnixStream::connect(path).await?));
+            }
+        }
+
+        Ok(Stream::Tcp(TcpStream::connect(address).await?))
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), Error> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(how),
+            Stream::WebSocket(websocket) => websocket.shutdown(how)
+        }
+    }
+
+    // Waits until there's data to read, without reading it. Unix sockets can't peek
+    pub async fn peek(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Stream::Tcp(stream) => stream.peek(buf).await,
+            #[cfg(unix)]
+            Stream::Unix(_) => Err(Error::new(ErrorKind::Unsupported, "Can not peek a unix socket")),
+            Stream::WebSocket(websocket) => websocket.peek(buf).await
+        }
+    }
+
+    // SO_LINGER 0, so that closing the stream sends a TCP reset instead of a normal close
+    // Unix sockets have no reset, so they close normally
+    pub fn set_reset_on_close(&self) -> Result<(), Error> {
+        match self {
+            Stream::Tcp(stream) => SockRef::from(stream).set_linger(Some(Duration::from_secs(0))),
+            #[cfg(unix)]
+            Stream::Unix(_) => Ok(()),
+            Stream::WebSocket(websocket) => SockRef::from(websocket.tcp_stream()).set_linger(Some(Duration::from_secs(0)))
+        }
+    }
+
+    // Unix sockets have no Nagle algorithm, so this only applies to TCP
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<(), Error> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nodelay(nodelay),
+            #[cfg(unix)]
+            Stream::Unix(_) => Ok(()),
+            Stream::WebSocket(websocket) => websocket.tcp_stream().set_nodelay(nodelay)
+        }
+    }
+
+    // None for sockets that don't have an IP address
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.peer_addr().ok(),
+            #[cfg(unix)]
+            Stream::Unix(_) => None,
+            Stream::WebSocket(websocket) => websocket.peer_addr()
+        }
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Stream::Tcp(stream) => stream.local_addr().ok(),
+            #[cfg(unix)]
+            Stream::Unix(_) => None,
+            Stream::WebSocket(websocket) => websocket.local_addr()
+        }
+    }
+
+    // For logging
+    pub fn peer_name(&self) -> String {
+        match self {
+            Stream::Tcp(stream) => match stream.peer_addr() {
+                Ok(addr) => addr.to_string(),
+                Err(err) => format!("unknown ({})", err)
+            },
+            #[cfg(unix)]
+            Stream::Unix(stream) => match stream.peer_addr() {
+                Ok(addr) => match addr.as_pathname() {
+                    Some(path) => format!("unix:{}", path.display()),
+                    None => "unix:(unnamed)".to_string()
+                },
+                Err(err) => format!("unix: unknown ({})", err)
+            },
+            Stream::WebSocket(websocket) => match websocket.peer_addr() {
+                Some(addr) => format!("ws:{}", addr),
+                None => "ws: unknown".to_string()
+            }
+        }
+    }
+}
+
+impl From<TcpStream> for Stream {
+    fn from(stream: TcpStream) -> Self {
+        Stream::Tcp(stream)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixStream> for Stream {
+    fn from(stream: UnixStream) -> Self {
+        Stream::Unix(stream)
+    }
+}
+
+impl From<WebSocket> for Stream {
+    fn from(websocket: WebSocket) -> Self {
+        Stream::WebSocket(websocket)
+    }
+}
+
+impl Read for Stream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize, Error>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::WebSocket(websocket) => Pin::new(websocket).poll_read(cx, buf)
+        }
+    }
+}
+
+impl Write for Stream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::WebSocket(websocket) => Pin::new(websocket).poll_write(cx, buf)
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::WebSocket(websocket) => Pin::new(websocket).poll_flush(cx)
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            Stream::Tcp(stream) => Pin::new(stream).poll_close(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_close(cx),
+            Stream::WebSocket(websocket) => Pin::new(websocket).poll_close(cx)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::io::{ReadExt, WriteExt};
+
+    use super::*;
+
+    #[cfg(unix)]
+    #[async_std::test]
+    async fn unix_stream_round_trip() {
+        use async_std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("bounce-stream-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).await.unwrap();
+
+        let mut client = Stream::connect(&format!("unix:{}", path.display())).await.expect("Can not connect");
+        let (server, _) = listener.accept().await.unwrap();
+        let mut server = Stream::from(server);
+
+        client.write_all(b"bounce").await.unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+
+        let mut buf = Vec::new();
+        server.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(b"bounce".to_vec(), buf);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
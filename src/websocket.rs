@@ -0,0 +1,838 @@
+use async_std::io;
+use async_std::io::{Read, Write};
+use async_std::net::{Shutdown, SocketAddr, TcpStream};
+use async_std::prelude::*;
+use async_std::task::{Context, Poll};
+use core::time::Duration;
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use futures::future::poll_fn;
+use futures_rustls::TlsConnector;
+use futures_rustls::client::TlsStream;
+use futures_rustls::pki_types::ServerName;
+use futures_rustls::rustls::{ClientConfig, RootCertStore};
+use futures_rustls::rustls::crypto::ring;
+use rand::{RngCore, thread_rng};
+use rustc_serialize::base64::{STANDARD, ToBase64};
+
+use crate::http_proxy::{self, HttpProxy};
+use crate::sniff::Match;
+
+// From RFC 6455, used to compute Sec-WebSocket-Accept
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// An upgrade request or response with a longer header is rejected
+pub const MAX_HEADER_SIZE: usize = 8192;
+
+// Bounce only sends small frames, so a larger incoming frame means that something is wrong
+const MAX_INCOMING_PAYLOAD_SIZE: usize = 1024 * 1024;
+const MAX_OUTGOING_PAYLOAD_SIZE: usize = 16 * 1024;
+
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+// Pings and close frames can't carry more than this
+const MAX_CONTROL_PAYLOAD_SIZE: usize = 125;
+
+// Carries the adapter stream as WebSocket binary frames, so that it can go through HTTP-only routers
+// Clones share the same connection, like clones of a TcpStream
+#[derive(Clone)]
+pub struct WebSocket {
+    tcp: TcpStream,
+    shared: Arc<Mutex<Shared>>
+}
+
+enum Transport {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>)
+}
+
+struct Shared {
+    transport: Transport,
+    // Clients mask the frames that they send
+    mask: bool,
+    // Bytes read from the transport that aren't a complete frame yet
+    raw: Vec<u8>,
+    // The payload of the last data frame, and how much of it was read
+    payload: Vec<u8>,
+    payload_read: usize,
+    // A close frame, or the end of the transport
+    closed: bool,
+    // A frame is always written completely once it's started, so that frames never interleave
+    frame: Vec<u8>,
+    frame_written: usize,
+    frame_payload_size: usize,
+    // Replies to pings and close frames, which go out between data frames
+    control: Vec<u8>,
+    control_written: usize
+}
+
+// The parts of an upgrade request that the server needs to complete the handshake
+pub struct Upgrade {
+    header_size: usize,
+    key: String
+}
+
+#[derive(Debug)]
+struct WebSocketUrl {
+    tls: bool,
+    host: String,
+    port: u16,
+    path: String
+}
+
+pub fn is_websocket_url(address: &str) -> bool {
+    address.starts_with("ws://") || address.starts_with("wss://")
+}
+
+// Client side: connects to "ws://host[:port]/path" or "wss://host[:port]/path" and completes the upgrade
+pub async fn connect(url: &str, proxy: Option<&HttpProxy>) -> Result<WebSocket, Error> {
+    let url = parse_url(url)?;
+
+    let address = if url.host.contains(':') { format!("[{}]:{}", url.host, url.port) } else { format!("{}:{}", url.host, url.port) };
+    let tcp = http_proxy::connect(&address, proxy).await?;
+    tcp.set_nodelay(true)?;
+
+    let mut transport = if url.tls {
+        let server_name = ServerName::try_from(url.host.clone())
+            .map_err(|err| Error::new(ErrorKind::InvalidInput, format!("Invalid host name \"{}\": {}", url.host, err)))?;
+
+        Transport::Tls(Box::new(tls_connector()?.connect(server_name, tcp.clone()).await?))
+    } else {
+        Transport::Tcp(tcp.clone())
+    };
+
+    let mut key = [0u8; 16];
+    thread_rng().fill_bytes(&mut key);
+    let key = key.to_base64(STANDARD);
+
+    let default_port = if url.tls { 443 } else { 80 };
+    let host_header = if url.port == default_port { url.host.clone() } else { format!("{}:{}", url.host, url.port) };
+
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        url.path, host_header, key);
+
+    let (response, first_frames) = io::timeout(HANDSHAKE_TIMEOUT, read_response(&mut transport, request.as_bytes())).await?;
+
+    let mut lines = response.split("\r\n");
+    let status_line = lines.next().unwrap_or_default();
+    if !status_line.starts_with("HTTP/1.1 101") {
+        return Err(Error::new(ErrorKind::ConnectionRefused, format!("WebSocket upgrade refused: {}", status_line)));
+    }
+
+    if header_value(lines, "sec-websocket-accept") != Some(accept_key(&key).as_str()) {
+        return Err(Error::new(ErrorKind::InvalidData, "WebSocket upgrade response has the wrong Sec-WebSocket-Accept"));
+    }
+
+    Ok(WebSocket::new(tcp, transport, true, first_frames))
+}
+
+// Server side: checks if the start of an incoming connection is a WebSocket upgrade request for path
+pub fn match_upgrade(peeked: &[u8], path: &str) -> Match<Upgrade> {
+    let request_line_start = format!("GET {}", path);
+    let request_line_start = request_line_start.as_bytes();
+
+    // The path must be followed by the end of the path, or a query
+    let can_match = if peeked.len() <= request_line_start.len() {
+        request_line_start.starts_with(peeked)
+    } else {
+        peeked.starts_with(request_line_start) && [b' ', b'?'].contains(&peeked[request_line_start.len()])
+    };
+
+    if !can_match {
+        return Match::No;
+    }
+
+    let header_size = match find_header_end(peeked) {
+        Some(header_size) => header_size,
+        None => return Match::NeedMore
+    };
+
+    let header = String::from_utf8_lossy(&peeked[..header_size]);
+    let headers: Vec<&str> = header.split("\r\n").skip(1).collect();
+    let is_upgrade = header_value(headers.iter().copied(), "upgrade").map(|v| v.eq_ignore_ascii_case("websocket")).unwrap_or(false);
+
+    match header_value(headers.iter().copied(), "sec-websocket-key") {
+        Some(key) if is_upgrade => Match::Yes(Upgrade {
+            header_size,
+            key: key.to_string()
+        }),
+        _ => Match::No
+    }
+}
+
+// Server side: reads the upgrade request that match_upgrade found, and accepts it
+pub async fn accept_upgrade(mut stream: TcpStream, upgrade: Upgrade) -> Result<WebSocket, Error> {
+    let mut header = vec![0u8; upgrade.header_size];
+    io::timeout(HANDSHAKE_TIMEOUT, stream.read_exact(&mut header)).await?;
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(&upgrade.key));
+
+    io::timeout(HANDSHAKE_TIMEOUT, stream.write_all(response.as_bytes())).await?;
+    stream.set_nodelay(true)?;
+
+    Ok(WebSocket::new(stream.clone(), Transport::Tcp(stream), false, Vec::new()))
+}
+
+impl WebSocket {
+    fn new(tcp: TcpStream, transport: Transport, mask: bool, raw: Vec<u8>) -> WebSocket {
+        WebSocket {
+            tcp,
+            shared: Arc::new(Mutex::new(Shared {
+                transport,
+                mask,
+                raw,
+                payload: Vec::new(),
+                payload_read: 0,
+                closed: false,
+                frame: Vec::new(),
+                frame_written: 0,
+                frame_payload_size: 0,
+                control: Vec::new(),
+                control_written: 0
+            }))
+        }
+    }
+
+    // Waits until there's data to read, without reading it. Returns 0 when the WebSocket is closed
+    pub async fn peek(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        poll_fn(|cx| {
+            let mut shared = self.shared.lock().unwrap();
+            match shared.poll_fill(cx) {
+                Poll::Ready(Ok(available)) => {
+                    let bytes_peeked = available.min(buf.len());
+                    let start = shared.payload_read;
+                    buf[..bytes_peeked].copy_from_slice(&shared.payload[start..start + bytes_peeked]);
+                    Poll::Ready(Ok(bytes_peeked))
+                },
+                other => other
+            }
+        }).await
+    }
+
+    // Shuts down the underlying TCP socket, like TcpStream::shutdown
+    pub fn shutdown(&self, how: Shutdown) -> Result<(), Error> {
+        self.tcp.shutdown(how)
+    }
+
+    pub fn tcp_stream(&self) -> &TcpStream {
+        &self.tcp
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.tcp.peer_addr().ok()
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.tcp.local_addr().ok()
+    }
+}
+
+impl Shared {
+    // Reads frames until there's payload to read. Returns how much is available, or 0 when the WebSocket is closed
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize, Error>> {
+        let mut buf = [0u8; READ_BUFFER_SIZE];
+
+        loop {
+            if self.payload_read < self.payload.len() {
+                return Poll::Ready(Ok(self.payload.len() - self.payload_read));
+            }
+
+            if self.closed {
+                return Poll::Ready(Ok(0));
+            }
+
+            match parse_frame(&self.raw)? {
+                Some(Frame { opcode, masked, payload, size }) => {
+                    self.raw.drain(..size);
+
+                    // Clients mask every frame, so that proxies can't be tricked by what looks like HTTP inside them
+                    if !self.mask && !masked {
+                        return Poll::Ready(Err(Error::new(ErrorKind::InvalidData, "Unmasked WebSocket frame from the client")));
+                    }
+
+                    if opcode >= OPCODE_CLOSE && payload.len() > MAX_CONTROL_PAYLOAD_SIZE {
+                        return Poll::Ready(Err(Error::new(ErrorKind::InvalidData, format!("WebSocket control frame is too large: {} bytes", payload.len()))));
+                    }
+
+                    match opcode {
+                        OPCODE_BINARY | OPCODE_CONTINUATION => {
+                            self.payload = payload;
+                            self.payload_read = 0;
+                        },
+                        // The close frame is echoed with its status code, which completes the closing handshake
+                        OPCODE_CLOSE => {
+                            self.closed = true;
+                            self.queue_control(OPCODE_CLOSE, &payload[..payload.len().min(2)], cx)?;
+                        },
+                        // Bounce has its own heartbeats, but routers in between may ping to see if the connection is alive
+                        // Only one reply waits at a time, so that pings can't pile up while writing is blocked
+                        OPCODE_PING if self.control.is_empty() => self.queue_control(OPCODE_PONG, &payload, cx)?,
+                        OPCODE_PING => log::trace!("Not answering WebSocket ping, the last reply isn't written yet"),
+                        OPCODE_PONG => log::trace!("Ignoring WebSocket pong"),
+                        OPCODE_TEXT => return Poll::Ready(Err(Error::new(ErrorKind::InvalidData, "Unexpected WebSocket text frame"))),
+                        _ => return Poll::Ready(Err(Error::new(ErrorKind::InvalidData, format!("Unknown WebSocket opcode {}", opcode))))
+                    }
+                },
+                None => match Pin::new(&mut self.transport).poll_read(cx, &mut buf) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Ok(0)) => {
+                        if !self.raw.is_empty() {
+                            return Poll::Ready(Err(Error::new(ErrorKind::UnexpectedEof, "WebSocket closed in the middle of a frame")));
+                        }
+
+                        self.closed = true;
+                    },
+                    Poll::Ready(Ok(bytes_read)) => self.raw.extend_from_slice(&buf[..bytes_read]),
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err))
+                }
+            }
+        }
+    }
+
+    // Replies are written right away when they can be. Otherwise they're finished by the next read or write
+    fn queue_control(&mut self, opcode: u8, payload: &[u8], cx: &mut Context<'_>) -> Result<(), Error> {
+        let frame = encode_frame(opcode, payload, self.mask);
+        self.control.extend_from_slice(&frame);
+
+        match self.poll_write_control(cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Ok(())
+        }
+    }
+
+    // Never in the middle of a data frame
+    fn poll_write_control(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if !self.frame.is_empty() && self.frame_written > 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        while self.control_written < self.control.len() {
+            match Pin::new(&mut self.transport).poll_write(cx, &self.control[self.control_written..]) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(Error::new(ErrorKind::WriteZero, "WebSocket closed while writing a frame"))),
+                Poll::Ready(Ok(bytes_written)) => self.control_written += bytes_written,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err))
+            }
+        }
+
+        self.control.clear();
+        self.control_written = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    // A frame that returned Pending is finished by the next call, which is expected to pass the same buf, like a retried write on a socket
+    fn poll_write(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        if self.frame.is_empty() {
+            // A reply goes out before the next data frame starts
+            match self.poll_write_control(cx) {
+                Poll::Ready(Ok(())) => {},
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending
+            }
+
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+
+            let payload_size = buf.len().min(MAX_OUTGOING_PAYLOAD_SIZE);
+            self.frame = encode_frame(OPCODE_BINARY, &buf[..payload_size], self.mask);
+            self.frame_written = 0;
+            self.frame_payload_size = payload_size;
+        }
+
+        while self.frame_written < self.frame.len() {
+            match Pin::new(&mut self.transport).poll_write(cx, &self.frame[self.frame_written..]) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(Error::new(ErrorKind::WriteZero, "WebSocket closed while writing a frame"))),
+                Poll::Ready(Ok(bytes_written)) => self.frame_written += bytes_written,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err))
+            }
+        }
+
+        self.frame.clear();
+
+        // A reply that came in while the data frame was being written
+        if let Poll::Ready(Err(err)) = self.poll_write_control(cx) {
+            return Poll::Ready(Err(err));
+        }
+
+        Poll::Ready(Ok(self.frame_payload_size))
+    }
+}
+
+impl Read for WebSocket {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize, Error>> {
+        let mut shared = self.shared.lock().unwrap();
+
+        match shared.poll_fill(cx) {
+            Poll::Ready(Ok(available)) => {
+                let bytes_read = available.min(buf.len());
+                let start = shared.payload_read;
+                buf[..bytes_read].copy_from_slice(&shared.payload[start..start + bytes_read]);
+                shared.payload_read += bytes_read;
+                Poll::Ready(Ok(bytes_read))
+            },
+            other => other
+        }
+    }
+}
+
+impl Write for WebSocket {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        self.shared.lock().unwrap().poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.shared.lock().unwrap().transport).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.shared.lock().unwrap().transport).poll_close(cx)
+    }
+}
+
+impl Read for Transport {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<Result<usize, Error>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Transport::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf)
+        }
+    }
+}
+
+impl Write for Transport {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, Error>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Transport::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf)
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Transport::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx)
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            Transport::Tcp(stream) => Pin::new(stream).poll_close(cx),
+            Transport::Tls(stream) => Pin::new(stream.as_mut()).poll_close(cx)
+        }
+    }
+}
+
+// Sends the upgrade request and reads the response header. Returns the header, and anything after it, which is the start of the first frame
+async fn read_response(transport: &mut Transport, request: &[u8]) -> Result<(String, Vec<u8>), Error> {
+    transport.write_all(request).await?;
+
+    let mut raw = Vec::new();
+    let mut buf = [0u8; READ_BUFFER_SIZE];
+    loop {
+        if let Some(header_size) = find_header_end(&raw) {
+            let header = String::from_utf8_lossy(&raw[..header_size]).to_string();
+            raw.drain(..header_size);
+            return Ok((header, raw));
+        }
+
+        if raw.len() > MAX_HEADER_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData, "WebSocket upgrade response is too long"));
+        }
+
+        let bytes_read = transport.read(&mut buf).await?;
+        if bytes_read == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed during the WebSocket upgrade"));
+        }
+
+        raw.extend_from_slice(&buf[..bytes_read]);
+    }
+}
+
+fn tls_connector() -> Result<TlsConnector, Error> {
+    let root_store = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec()
+    };
+
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(Error::other)?
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+fn parse_url(url: &str) -> Result<WebSocketUrl, Error> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("Invalid WebSocket URL \"{}\"", url));
+
+    let (tls, rest) = match (url.strip_prefix("ws://"), url.strip_prefix("wss://")) {
+        (Some(rest), _) => (false, rest),
+        (_, Some(rest)) => (true, rest),
+        _ => return Err(invalid())
+    };
+
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/")
+    };
+
+    // IPv6 addresses are in brackets, so that their colons aren't mistaken for the port
+    let (host, port) = match authority.rfind(':') {
+        Some(colon) if !authority[colon..].contains(']') => (&authority[..colon], Some(authority[colon + 1..].parse::<u16>().map_err(|_| invalid())?)),
+        _ => (authority, None)
+    };
+
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(invalid());
+    }
+
+    Ok(WebSocketUrl {
+        tls,
+        host: host.to_string(),
+        port: port.unwrap_or(if tls { 443 } else { 80 }),
+        path: path.to_string()
+    })
+}
+
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.input_str(key);
+    sha1.input_str(WEBSOCKET_GUID);
+
+    let mut hash = [0u8; 20];
+    sha1.result(&mut hash);
+
+    hash.to_base64(STANDARD)
+}
+
+// Returns the size of the header, including the blank line at the end
+fn find_header_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|w| w == b"\r\n\r\n").map(|position| position + 4)
+}
+
+fn header_value<'a>(lines: impl Iterator<Item = &'a str>, name: &str) -> Option<&'a str> {
+    for line in lines {
+        if let Some((line_name, value)) = line.split_once(':') {
+            if line_name.trim().eq_ignore_ascii_case(name) {
+                return Some(value.trim());
+            }
+        }
+    }
+
+    None
+}
+
+fn encode_frame(opcode: u8, payload: &[u8], mask: bool) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(0x80 | opcode);
+
+    let mask_bit = if mask { 0x80 } else { 0 };
+    if payload.len() < 126 {
+        frame.push(mask_bit | payload.len() as u8);
+    } else if payload.len() <= u16::MAX as usize {
+        frame.push(mask_bit | 126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    } else {
+        frame.push(mask_bit | 127);
+        frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    }
+
+    if mask {
+        let mut masking_key = [0u8; 4];
+        thread_rng().fill_bytes(&mut masking_key);
+        frame.extend_from_slice(&masking_key);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ masking_key[i % 4]));
+    } else {
+        frame.extend_from_slice(payload);
+    }
+
+    frame
+}
+
+// A frame read from the other side
+#[derive(Debug, PartialEq)]
+struct Frame {
+    opcode: u8,
+    masked: bool,
+    // Unmasked
+    payload: Vec<u8>,
+    // Of the whole frame, including the header
+    size: usize
+}
+
+// Returns the first frame in buf, or None if buf doesn't have a complete frame yet
+fn parse_frame(buf: &[u8]) -> Result<Option<Frame>, Error> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+
+    let opcode = buf[0] & 0x0F;
+    let masked = buf[1] & 0x80 != 0;
+
+    let (payload_size, mut position) = match buf[1] & 0x7F {
+        126 => {
+            if buf.len() < 4 {
+                return Ok(None);
+            }
+
+            (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4)
+        },
+        127 => {
+            if buf.len() < 10 {
+                return Ok(None);
+            }
+
+            let mut size = [0u8; 8];
+            size.copy_from_slice(&buf[2..10]);
+            (u64::from_be_bytes(size), 10)
+        },
+        size => (size as u64, 2)
+    };
+
+    if payload_size > MAX_INCOMING_PAYLOAD_SIZE as u64 {
+        return Err(Error::new(ErrorKind::InvalidData, format!("WebSocket frame is too large: {} bytes", payload_size)));
+    }
+
+    let payload_size = payload_size as usize;
+
+    let masking_key = if masked {
+        if buf.len() < position + 4 {
+            return Ok(None);
+        }
+
+        let masking_key = [buf[position], buf[position + 1], buf[position + 2], buf[position + 3]];
+        position += 4;
+        Some(masking_key)
+    } else {
+        None
+    };
+
+    if buf.len() < position + payload_size {
+        return Ok(None);
+    }
+
+    let mut payload = buf[position..position + payload_size].to_vec();
+    if let Some(masking_key) = masking_key {
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= masking_key[i % 4];
+        }
+    }
+
+    Ok(Some(Frame {
+        opcode,
+        masked,
+        payload,
+        size: position + payload_size
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use async_std::net::{IpAddr, Ipv4Addr, TcpListener};
+    use async_std::task;
+
+    use crate::sniff::{Sniffed, sniff};
+
+    use super::*;
+
+    #[test]
+    fn urls() {
+        let url = parse_url("ws://example.com/bounce").unwrap();
+        assert!(!url.tls);
+        assert_eq!(("example.com", 80, "/bounce"), (url.host.as_str(), url.port, url.path.as_str()));
+
+        let url = parse_url("wss://example.com").unwrap();
+        assert!(url.tls);
+        assert_eq!(("example.com", 443, "/"), (url.host.as_str(), url.port, url.path.as_str()));
+
+        let url = parse_url("ws://[::1]:8080/a/b").unwrap();
+        assert_eq!(("::1", 8080, "/a/b"), (url.host.as_str(), url.port, url.path.as_str()));
+
+        parse_url("http://example.com").expect_err("Only ws and wss are supported");
+        parse_url("ws://example.com:port").expect_err("The port must be a number");
+        parse_url("ws:///bounce").expect_err("The host is required");
+    }
+
+    #[test]
+    fn accept_key_from_rfc() {
+        assert_eq!("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=", accept_key("dGhlIHNhbXBsZSBub25jZQ=="));
+    }
+
+    #[test]
+    fn frames() {
+        for size in [0usize, 125, 126, 65535, 65536] {
+            let payload: Vec<u8> = (0..size).map(|i| i as u8).collect();
+
+            for mask in [false, true] {
+                let frame = encode_frame(OPCODE_BINARY, &payload, mask);
+
+                assert_eq!(None, parse_frame(&frame[..frame.len() - 1]).unwrap(), "Incomplete frame");
+
+                let parsed = parse_frame(&frame).unwrap().expect("Complete frame");
+                assert_eq!(OPCODE_BINARY, parsed.opcode);
+                assert_eq!(mask, parsed.masked);
+                assert_eq!(payload, parsed.payload);
+                assert_eq!(frame.len(), parsed.size);
+            }
+        }
+    }
+
+    #[async_std::test]
+    async fn round_trip() {
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await.unwrap();
+        let url = format!("ws://{}/bounce", listener.local_addr().unwrap());
+
+        let client_future = task::spawn(async move { connect(&url, None).await });
+
+        let (server_stream, _) = listener.accept().await.unwrap();
+        let upgrade = match sniff(&server_stream, false, Some("/bounce"), None, Duration::from_secs(5)).await {
+            Sniffed::WebSocket(upgrade) => upgrade,
+            _ => panic!("Not an upgrade request")
+        };
+        let mut server = accept_upgrade(server_stream, upgrade).await.expect("Can not accept the upgrade");
+        let mut client = client_future.await.expect("Can not connect");
+
+        // Larger than one frame
+        let data: Vec<u8> = (0..MAX_OUTGOING_PAYLOAD_SIZE * 3).map(|i| i as u8).collect();
+
+        let write_future = task::spawn({
+            let mut client = client.clone();
+            let data = data.clone();
+            async move { client.write_all(&data).await }
+        });
+
+        let mut peek_buf = [0u8; 1];
+        assert_eq!(1, server.peek(&mut peek_buf).await.unwrap());
+        assert_eq!(data[0], peek_buf[0], "Peeking doesn't read");
+
+        let mut read_buf = vec![0u8; data.len()];
+        server.read_exact(&mut read_buf).await.unwrap();
+        assert_eq!(data, read_buf);
+        write_future.await.unwrap();
+
+        server.write_all(b"bounce").await.unwrap();
+        let mut read_buf = [0u8; 6];
+        client.read_exact(&mut read_buf).await.unwrap();
+        assert_eq!(b"bounce", &read_buf);
+
+        server.shutdown(Shutdown::Both).unwrap();
+        assert_eq!(0, client.read(&mut read_buf).await.unwrap(), "The WebSocket should close");
+    }
+
+    // A client that writes frames by hand, and the server side of its WebSocket
+    async fn raw_client_and_server() -> (TcpStream, WebSocket) {
+        let listener = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        client.write_all(b"GET /bounce HTTP/1.1\r\nHost: bounce\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: key\r\nSec-WebSocket-Version: 13\r\n\r\n").await.unwrap();
+
+        let (server_stream, _) = listener.accept().await.unwrap();
+        let upgrade = match sniff(&server_stream, false, Some("/bounce"), None, Duration::from_secs(5)).await {
+            Sniffed::WebSocket(upgrade) => upgrade,
+            _ => panic!("Not an upgrade request")
+        };
+        let server = accept_upgrade(server_stream, upgrade).await.expect("Can not accept the upgrade");
+
+        // Nothing else is sent until the client sends a frame
+        let mut response = Vec::new();
+        while find_header_end(&response).is_none() {
+            let mut buf = [0u8; 256];
+            let bytes_read = client.read(&mut buf).await.unwrap();
+            assert!(bytes_read > 0, "Connection closed during the upgrade");
+            response.extend_from_slice(&buf[..bytes_read]);
+        }
+
+        (client, server)
+    }
+
+    async fn read_raw_frame(client: &mut TcpStream) -> Frame {
+        let mut raw = Vec::new();
+        loop {
+            if let Some(frame) = parse_frame(&raw).unwrap() {
+                return frame;
+            }
+
+            let mut buf = [0u8; 256];
+            let bytes_read = io::timeout(Duration::from_secs(5), client.read(&mut buf)).await.expect("No frame from the server");
+            assert!(bytes_read > 0, "Connection closed before a whole frame");
+            raw.extend_from_slice(&buf[..bytes_read]);
+        }
+    }
+
+    #[async_std::test]
+    async fn control_frames() {
+        let (mut client, mut server) = raw_client_and_server().await;
+
+        let read_task = task::spawn(async move {
+            let mut buf = [0u8; 16];
+            let bytes_read = server.read(&mut buf).await?;
+            Ok::<Vec<u8>, Error>(buf[..bytes_read].to_vec())
+        });
+
+        // Pings are answered while the server waits for data, and the close frame is echoed with its status code
+        client.write_all(&encode_frame(OPCODE_PING, b"alive?", true)).await.unwrap();
+        let pong = read_raw_frame(&mut client).await;
+        assert_eq!((OPCODE_PONG, false, &b"alive?"[..]), (pong.opcode, pong.masked, &pong.payload[..]));
+
+        client.write_all(&encode_frame(OPCODE_CLOSE, &[0x03, 0xE8, b'b', b'y', b'e'], true)).await.unwrap();
+        let close = read_raw_frame(&mut client).await;
+        assert_eq!((OPCODE_CLOSE, false, &[0x03, 0xE8][..]), (close.opcode, close.masked, &close.payload[..]));
+
+        assert!(read_task.await.unwrap().is_empty(), "The WebSocket should close");
+    }
+
+    #[async_std::test]
+    async fn unmasked_frame_from_client() {
+        let (mut client, mut server) = raw_client_and_server().await;
+
+        client.write_all(&encode_frame(OPCODE_BINARY, b"bounce", false)).await.unwrap();
+
+        let mut buf = [0u8; 16];
+        let err = server.read(&mut buf).await.expect_err("Unmasked frames aren't allowed from the client");
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+    }
+
+    #[test]
+    fn upgrade_requests() {
+        let request = b"GET /bounce?v=1 HTTP/1.1\r\nHost: bounce\r\nUpgrade: WebSocket\r\nSec-WebSocket-Key: key\r\n\r\nframes";
+        match match_upgrade(request, "/bounce") {
+            Match::Yes(upgrade) => {
+                assert_eq!("key", upgrade.key);
+                assert_eq!(b"frames", &request[upgrade.header_size..]);
+            },
+            _ => panic!("Not matched")
+        }
+
+        assert!(matches!(match_upgrade(b"GET /bou", "/bounce"), Match::NeedMore));
+        assert!(matches!(match_upgrade(b"GET /bounce HTTP/1.1\r\nUpgrade: websocket\r\n", "/bounce"), Match::NeedMore));
+
+        let others: [&[u8]; 4] = [
+            b"GET /other HTTP/1.1\r\nHost: bounce\r\n\r\n",
+            b"GET /bounce/more HTTP/1.1\r\nHost: bounce\r\n\r\n",
+            b"GET /bounce HTTP/1.1\r\nHost: bounce\r\n\r\n",
+            b"SSH-2.0-OpenSSH\r\n"
+        ];
+
+        for other in others.iter() {
+            assert!(matches!(match_upgrade(other, "/bounce"), Match::No), "Not an upgrade: {}", String::from_utf8_lossy(other));
+        }
+    }
+}
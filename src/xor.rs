@@ -1,13 +1,17 @@
 use rand_core::{CryptoRng, RngCore};
+use std::convert::TryInto;
 
-const XOR_BUFFER_SIZE: usize = 1024;
+// How much keystream is generated at a time
+const XOR_BUFFER_SIZE: usize = 4096;
+
+const WORD_SIZE: usize = 8;
 
 #[derive(Copy)]
 #[derive(Clone)]
 pub struct Xor<TRng> where
 TRng: CryptoRng + RngCore + Clone {
     rng: TRng,
-    xor: [u8; 1024],
+    xor: [u8; XOR_BUFFER_SIZE],
     ctr: usize,
 }
 
@@ -34,22 +38,39 @@ TRng: CryptoRng + RngCore + Clone  {
         }
     }
 
-    fn next_byte(&mut self) -> u8 {
-        if self.ctr >= self.xor.len() {
-            self.rng.fill_bytes(&mut self.xor[..]);
-            self.ctr = 0;
+    // Applies as much of the buffered keystream as possible at a time
+    pub fn process(&mut self, data: &mut [u8]) {
+        let mut data = data;
+
+        while !data.is_empty() {
+            if self.ctr >= self.xor.len() {
+                self.rng.fill_bytes(&mut self.xor[..]);
+                self.ctr = 0;
+            }
+
+            let len = data.len().min(self.xor.len() - self.ctr);
+            let (chunk, rest) = data.split_at_mut(len);
+
+            xor_words(chunk, &self.xor[self.ctr..self.ctr + len]);
+
+            self.ctr += len;
+            data = rest;
         }
+    }
+}
 
-        let b = self.xor[self.ctr];
-        self.ctr = self.ctr + 1;
-        b
+// A word at a time, which the compiler can also vectorize
+fn xor_words(data: &mut [u8], keystream: &[u8]) {
+    let mut data_words = data.chunks_exact_mut(WORD_SIZE);
+    let mut keystream_words = keystream.chunks_exact(WORD_SIZE);
+
+    for (data_word, keystream_word) in (&mut data_words).zip(&mut keystream_words) {
+        let word = u64::from_ne_bytes(data_word[..].try_into().unwrap()) ^ u64::from_ne_bytes(keystream_word.try_into().unwrap());
+        data_word.copy_from_slice(&word.to_ne_bytes());
     }
 
-    pub fn process(&mut self, data: &mut [u8]) {
-        for ctr in 0..data.len() {
-            let b = data[ctr] ^ self.next_byte();
-            data[ctr] = b;
-        }
+    for (b, k) in data_words.into_remainder().iter_mut().zip(keystream_words.remainder()) {
+        *b ^= k;
     }
 }
 
@@ -69,10 +90,66 @@ mod tests {
             let mut buf = vec![0u8; XOR_BUFFER_SIZE];
             rng.fill_bytes(&mut buf);
 
-            for ctr in 0..XOR_BUFFER_SIZE {
-                assert_eq!(buf[ctr], xor.next_byte());
+            // Processing zeros gives the keystream
+            let mut keystream = vec![0u8; XOR_BUFFER_SIZE];
+            xor.process(&mut keystream);
+
+            assert_eq!(buf, keystream);
+        }
+    }
+
+    #[test]
+    fn odd_sizes() {
+        let mut xor = Xor::new(ChaCha8Rng::seed_from_u64(1));
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+
+        let mut keystream = vec![0u8; 5 * XOR_BUFFER_SIZE];
+        rng.fill_bytes(&mut keystream);
+
+        // Sizes that aren't whole words, and that cross the keystream buffer's boundaries, line up with the keystream
+        let mut processed = Vec::new();
+        for size in [1usize, 7, 9, 3000, 4099, 13, 8191, 1].iter() {
+            let mut buf = vec![0u8; *size];
+            xor.process(&mut buf);
+            processed.extend_from_slice(&buf);
+        }
+
+        assert_eq!(&keystream[..processed.len()], &processed[..]);
+    }
+
+    // Run with: cargo test --release benchmark -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn benchmark() {
+        const SIZE: usize = 256 * 1024 * 1024;
+        let mut buf = vec![0u8; 64 * 1024];
+
+        // How process used to work: a byte at a time from a 1 KiB keystream buffer
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        let mut keystream = [0u8; 1024];
+        let mut ctr = keystream.len();
+
+        let start = std::time::Instant::now();
+        for _ in 0..SIZE / buf.len() {
+            for b in buf.iter_mut() {
+                if ctr >= keystream.len() {
+                    rng.fill_bytes(&mut keystream);
+                    ctr = 0;
+                }
+
+                *b ^= keystream[ctr];
+                ctr += 1;
             }
         }
+        println!("Byte at a time: {:.0} MiB/s", SIZE as f64 / (1024.0 * 1024.0) / start.elapsed().as_secs_f64());
+
+        let mut xor = Xor::new(ChaCha8Rng::seed_from_u64(1));
+
+        let start = std::time::Instant::now();
+        for _ in 0..SIZE / buf.len() {
+            xor.process(&mut buf);
+        }
+        println!("Word at a time: {:.0} MiB/s", SIZE as f64 / (1024.0 * 1024.0) / start.elapsed().as_secs_f64());
     }
 
     #[test]
@@ -90,7 +167,7 @@ mod tests {
             let mut buf_for_xor = buf_for_test.clone();
 
             for ctr in 0..XOR_BUFFER_SIZE {
-                buf_for_test[ctr] = buf_for_test[ctr] ^ xor_buf[ctr];
+                buf_for_test[ctr] ^= xor_buf[ctr];
             }
 
             xor.process(&mut buf_for_xor);